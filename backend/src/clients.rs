@@ -0,0 +1,272 @@
+//! The map of connected clients, split into independently locked shards
+//! so sessions working on different players don't wait for each other.
+//!
+//! Single-player operations lock only the shard holding that player.
+//! Operations spanning several players lock the shards they need in
+//! ascending order, which keeps them from deadlocking with each other.
+//! Never hold one shard guard while acquiring another.
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+/// Number of shards used by `ClientMap::default`.
+const DEFAULT_SHARDS: usize = 16;
+
+type Shard<V> = HashMap<Uuid, V>;
+
+pub struct ClientMap<V> {
+    shards: Box<[RwLock<Shard<V>>]>,
+}
+
+impl<V> Default for ClientMap<V> {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
+}
+
+impl<V> ClientMap<V> {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard_index(&self, id: &Uuid) -> usize {
+        // Session ids are random, so their low bits spread evenly.
+        (id.as_u128() as usize) % self.shards.len()
+    }
+
+    /// Lock the shard holding `id` for reading.
+    pub async fn read(&self, id: &Uuid) -> RwLockReadGuard<'_, Shard<V>> {
+        self.shards[self.shard_index(id)].read().await
+    }
+
+    /// Lock the shard holding `id` for writing.
+    pub async fn write(&self, id: &Uuid) -> RwLockWriteGuard<'_, Shard<V>> {
+        self.shards[self.shard_index(id)].write().await
+    }
+
+    /// Lock every shard for reading, for operations over all players.
+    pub async fn read_all(&self) -> Shards<RwLockReadGuard<'_, Shard<V>>> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for (index, shard) in self.shards.iter().enumerate() {
+            guards.push((index, shard.read().await));
+        }
+        Shards { guards }
+    }
+
+    /// Lock every shard for writing.
+    pub async fn write_all(&self) -> Shards<RwLockWriteGuard<'_, Shard<V>>> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for (index, shard) in self.shards.iter().enumerate() {
+            guards.push((index, shard.write().await));
+        }
+        Shards { guards }
+    }
+
+    /// Lock the shards holding `a` and `b` for writing, for operations
+    /// that change two players at once.
+    pub async fn write_pair(&self, a: &Uuid, b: &Uuid) -> Shards<RwLockWriteGuard<'_, Shard<V>>> {
+        let (a, b) = (self.shard_index(a), self.shard_index(b));
+        let (low, high) = (a.min(b), a.max(b));
+        let mut guards = vec![(low, self.shards[low].write().await)];
+        if high != low {
+            guards.push((high, self.shards[high].write().await));
+        }
+        Shards { guards }
+    }
+
+    pub async fn insert(&self, id: Uuid, value: V) -> Option<V> {
+        self.write(&id).await.insert(id, value)
+    }
+
+    pub async fn contains_key(&self, id: &Uuid) -> bool {
+        self.read(id).await.contains_key(id)
+    }
+
+    /// Ids of every connected client.
+    pub async fn ids(&self) -> Vec<Uuid> {
+        self.read_all().await.keys().copied().collect()
+    }
+
+    /// Number of connected clients.
+    pub async fn len(&self) -> usize {
+        self.read_all().await.len()
+    }
+}
+
+/// A set of locked shards, seen as one map.
+pub struct Shards<G> {
+    guards: Vec<(usize, G)>,
+}
+
+impl<G, V> Shards<G>
+where
+    G: Deref<Target = Shard<V>>,
+{
+    pub fn get(&self, id: &Uuid) -> Option<&V> {
+        self.guards.iter().find_map(|(_, shard)| shard.get(id))
+    }
+
+    pub fn contains_key(&self, id: &Uuid) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn iter<'s>(&'s self) -> impl Iterator<Item = (&'s Uuid, &'s V)>
+    where
+        V: 's,
+    {
+        self.guards.iter().flat_map(|(_, shard)| shard.iter())
+    }
+
+    pub fn keys<'s>(&'s self) -> impl Iterator<Item = &'s Uuid>
+    where
+        V: 's,
+    {
+        self.iter().map(|(id, _)| id)
+    }
+
+    pub fn values<'s>(&'s self) -> impl Iterator<Item = &'s V>
+    where
+        V: 's,
+    {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.guards.iter().map(|(_, shard)| shard.len()).sum()
+    }
+}
+
+impl<G, V> Shards<G>
+where
+    G: DerefMut<Target = Shard<V>>,
+{
+    pub fn get_mut(&mut self, id: &Uuid) -> Option<&mut V> {
+        self.guards
+            .iter_mut()
+            .find_map(|(_, shard)| shard.get_mut(id))
+    }
+
+    pub fn iter_mut<'s>(&'s mut self) -> impl Iterator<Item = (&'s Uuid, &'s mut V)>
+    where
+        V: 's,
+    {
+        self.guards
+            .iter_mut()
+            .flat_map(|(_, shard)| shard.iter_mut())
+    }
+
+    /// Mutable access to two different players at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `a` and `b` are the same id.
+    pub fn get_disjoint_mut(&mut self, [a, b]: [&Uuid; 2]) -> [Option<&mut V>; 2] {
+        assert_ne!(a, b, "get_disjoint_mut needs two different ids");
+        let holds = |shard: &Shard<V>, id: &Uuid| shard.contains_key(id);
+        let first = self.guards.iter().position(|(_, shard)| holds(shard, a));
+        let second = self.guards.iter().position(|(_, shard)| holds(shard, b));
+        match (first, second) {
+            (Some(i), Some(j)) if i == j => self.guards[i].1.get_disjoint_mut([a, b]),
+            (Some(i), Some(j)) => {
+                let (low, high) = self.guards.split_at_mut(i.max(j));
+                let (low, high) = (&mut low[i.min(j)].1, &mut high[0].1);
+                let (shard_a, shard_b) = if i < j { (low, high) } else { (high, low) };
+                [shard_a.get_mut(a), shard_b.get_mut(b)]
+            }
+            (Some(_), None) => [self.get_mut(a), None],
+            (None, Some(_)) => [None, self.get_mut(b)],
+            (None, None) => [None, None],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Two ids that land in different shards of `map`.
+    fn ids_in_different_shards<V>(map: &ClientMap<V>) -> (Uuid, Uuid) {
+        let a = Uuid::new_v4();
+        loop {
+            let b = Uuid::new_v4();
+            if map.shard_index(&a) != map.shard_index(&b) {
+                return (a, b);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn writers_on_other_shards_do_not_wait() {
+        let map = ClientMap::default();
+        let (a, b) = ids_in_different_shards(&map);
+        map.insert(a, 0).await;
+        map.insert(b, 0).await;
+
+        let held = map.write(&a).await;
+        let other = tokio::time::timeout(Duration::from_millis(100), map.write(&b)).await;
+        assert!(other.is_ok(), "a different shard was blocked");
+        drop(other);
+        let same = tokio::time::timeout(Duration::from_millis(100), map.write(&a)).await;
+        assert!(same.is_err(), "the held shard was not locked");
+        drop(held);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_updates_are_not_lost() {
+        let map = Arc::new(ClientMap::default());
+        let ids: Vec<Uuid> = (0..64).map(|_| Uuid::new_v4()).collect();
+        for id in &ids {
+            map.insert(*id, 0u64).await;
+        }
+        let tasks: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                let (map, id) = (map.clone(), *id);
+                actix_web::rt::spawn(async move {
+                    for _ in 0..100 {
+                        *map.write(&id).await.get_mut(&id).unwrap() += 1;
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(map.len().await, 64);
+        assert!(map.read_all().await.values().all(|count| *count == 100));
+    }
+
+    #[actix_web::test]
+    async fn pairs_can_be_changed_across_shards() {
+        let map = ClientMap::new(4);
+        let (a, b) = ids_in_different_shards(&map);
+        map.insert(a, 1).await;
+        map.insert(b, 2).await;
+        let mut pair = map.write_pair(&a, &b).await;
+        let [Some(x), Some(y)] = pair.get_disjoint_mut([&a, &b]) else {
+            panic!("both players are connected");
+        };
+        std::mem::swap(x, y);
+        drop(pair);
+        assert_eq!(map.read(&a).await[&a], 2);
+        assert_eq!(map.read(&b).await[&b], 1);
+
+        let single = ClientMap::new(1);
+        single.insert(a, 1).await;
+        single.insert(b, 2).await;
+        let mut pair = single.write_pair(&a, &b).await;
+        assert!(matches!(
+            pair.get_disjoint_mut([&a, &b]),
+            [Some(_), Some(_)]
+        ));
+    }
+}
@@ -8,18 +8,197 @@
 //! engine.
 
 use actix::prelude::*;
-use actix_web::{get, web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_cors::Cors;
+use actix_web::{get, post, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
-use log::{error, info};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, RwLock};
+use tracing::{debug, error, info, instrument, warn, Instrument};
 use uuid::Uuid;
 
-// Hedera integration would involve signing and submitting transactions
-// using a Hedera SDK. For brevity this example does not perform any
-// blockchain interactions. See the Hedera Rust SDK for guidance.
+mod clients;
+mod metrics;
+mod storage;
+
+use clients::ClientMap;
+use metrics::Metrics;
+use storage::{MemoryStorage, SqliteStorage, Storage, StoredPlayer};
+
+/// Number of consecutive failed sends after which a session is
+/// considered dead and closed.
+const MAX_CONSECUTIVE_SEND_FAILURES: u32 = 5;
+
+/// Rate limited messages in a row after which a session is closed.
+const MAX_RATE_LIMIT_VIOLATIONS: u32 = 20;
+
+/// How often the server pings each client.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// A client that sends nothing, not even a pong, for this long is
+/// considered gone and its session is stopped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a disconnected session can be resumed with its token.
+const RESUME_GRACE_PERIOD: Duration = Duration::from_secs(60);
+/// How often expired disconnected sessions are reaped.
+const SESSION_REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Page size of `listPlayers` when the client doesn't pick one.
+const DEFAULT_PLAYER_PAGE: usize = 50;
+
+/// Largest page of `listPlayers` a client may request.
+const MAX_PLAYER_PAGE: usize = 200;
+
+/// Most players a friends list can hold.
+const MAX_FRIENDS: usize = 200;
+
+/// Largest leaderboard a client may request.
+const MAX_LEADERBOARD_LIMIT: usize = 100;
+/// How often the cached leaderboard is recomputed by default.
+const DEFAULT_LEADERBOARD_REFRESH: Duration = Duration::from_secs(5);
+
+/// How often changed players are flushed to storage when `AUTOSAVE_SECS`
+/// is unset.
+const DEFAULT_AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time clients get to receive the shutdown notice before the listener
+/// closes.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Number of top players returned for an archived season.
+const SEASON_ARCHIVE_TOP_N: usize = 10;
+
+/// How often the reward event schedule is checked for windows opening
+/// or closing.
+const REWARD_EVENT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Seconds a client refused for capacity is asked to wait before
+/// reconnecting.
+const SESSION_RETRY_AFTER_SECS: u64 = 5;
+
+/// Reports a single player may file within `REPORT_WINDOW_SECS`.
+const MAX_REPORTS_PER_WINDOW: usize = 5;
+const REPORT_WINDOW_SECS: u64 = 600;
+/// Most recent reports `/admin/reports` returns for review.
+const MAX_STORED_REPORTS: usize = 1000;
+/// Audit events kept in memory; the oldest are dropped first.
+const AUDIT_LOG_CAPACITY: usize = 10_000;
+/// Messages kept per session for a resuming client to catch up on.
+const REPLAY_BUFFER_CAPACITY: usize = 256;
+
+/// Longest cooldown between challenges at a player who keeps declining.
+const MAX_CHALLENGE_COOLDOWN: Duration = Duration::from_secs(600);
+
+/// Challenge records returned by `/challenges/recent` by default.
+const DEFAULT_CHALLENGE_PAGE: usize = 50;
+
+/// Length limits for the free text of a report.
+const MAX_REPORT_REASON_LEN: usize = 64;
+const MAX_REPORT_DETAILS_LEN: usize = 1000;
+
+/// Largest WebSocket frame accepted from a client, in bytes. Larger
+/// frames close the session before their payload is buffered.
+/// Continuation frames are not reassembled, so this bounds every
+/// message the server parses.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// WebSocket subprotocol echoed back to clients that offer it. Clients
+/// that offer none, or only others, connect without one.
+const SUBPROTOCOL: &str = "africa-universe-v1";
+
+/// Pending connections the listener queues when `BACKLOG` is unset.
+const DEFAULT_BACKLOG: u32 = 2048;
+
+/// Most messages a single `batch` may carry.
+const MAX_BATCH_LEN: usize = 20;
+
+/// Longest chat message accepted, in characters.
+const MAX_CHAT_LEN: usize = 500;
+
+/// Longest automatic chat mute, however often a player offends.
+const MAX_CHAT_MUTE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Chat channel every player is in on connect, and the one `chatSend`
+/// goes to when it names none.
+const DEFAULT_CHANNEL: &str = "global";
+/// Most chat channels a player can be in at once, the default one
+/// included.
+const MAX_CHANNELS: usize = 10;
+/// Allowed length of a chat channel name, in characters.
+const CHANNEL_NAME_LEN: std::ops::RangeInclusive<usize> = 1..=32;
+
+/// How long a purchase idempotency key is remembered.
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(300);
+
+/// Allowed length of a player-chosen username, in characters.
+const USERNAME_LEN: std::ops::RangeInclusive<usize> = 3..=20;
+
+/// Share of the category price refunded when a property is sold.
+const SELL_REFUND_PERCENT: u64 = 50;
+
+/// Upgrading a property costs this many tokens per current level.
+const UPGRADE_BASE_COST: u64 = 100;
+/// Highest level a property can be upgraded to.
+const MAX_PROPERTY_LEVEL: u32 = 10;
+
+/// Token balance every new player starts with, unless `STARTER_CONFIG`
+/// sets another.
+const STARTING_BALANCE: u64 = 1000;
+
+/// Denominator of the reward accrual: property rewards are per day and
+/// the multiplier is in percent.
+const ACCRUAL_UNIT: u64 = 86_400 * 100;
+
+/// Percentage of reward a property loses per full idle day by default.
+const DEFAULT_REWARD_DECAY_PERCENT: u32 = 2;
+
+/// How far luck can swing a battle, in percent, unless
+/// `BATTLE_LUCK_PERCENT` sets otherwise. Zero keeps battles deterministic.
+const DEFAULT_BATTLE_LUCK_PERCENT: u32 = 0;
+
+/// How long accepted battles are fought unless `BATTLE_SECS` sets
+/// otherwise. Zero resolves them right away.
+const DEFAULT_BATTLE_DURATION: Duration = Duration::ZERO;
+/// How long a player who dropped out of a battle has to resume before
+/// they forfeit, unless `BATTLE_GRACE_SECS` sets otherwise.
+const DEFAULT_BATTLE_GRACE: Duration = Duration::from_secs(30);
+
+/// Session id and name of the starter bot, which has no session.
+const BOT_ID: Uuid = Uuid::nil();
+const BOT_NAME: &str = "Starter Bot";
+/// Tokens an account can win from the bot per UTC day unless
+/// `BOT_DAILY_REWARD_CAP` sets otherwise.
+const DEFAULT_BOT_DAILY_REWARD_CAP: u64 = 200;
+/// Transfer fees are in basis points; 10 000 would burn the whole
+/// transfer, so rates must stay below it.
+const MAX_TRANSFER_FEE_BPS: u32 = 10_000;
+/// How often, and how long apart, a change to a player who is being
+/// loaded from storage is retried.
+const PLAYER_UPDATE_RETRIES: u32 = 50;
+const PLAYER_UPDATE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Events the event bus buffers for each subscriber by default.
+const DEFAULT_EVENT_BUS_CAPACITY: usize = 1024;
+
+/// Battles kept in each player's history; older ones are dropped.
+const MAX_BATTLE_HISTORY: usize = 50;
+/// Battles returned by `getBattleHistory` when no limit is given.
+const DEFAULT_BATTLE_HISTORY_PAGE: usize = 10;
+
+/// Current time as seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
 /// Information stored about each connected client. For simplicity the
 /// client actor address is optional; it's set once the WebSocket
@@ -27,29 +206,425 @@ use uuid::Uuid;
 /// are stored here and can be extended as needed.
 #[derive(Clone)]
 struct ClientInfo {
+    /// Username the player logs in as. Storage is keyed by it, so it
+    /// never changes.
+    account: String,
+    /// Name shown to everyone else. Starts out as the account name.
     username: String,
     pvp_level: u32,
     properties: Vec<Property>,
+    /// Tokens available for marketplace purchases.
+    balance: u64,
+    /// Unix time of the last daily reward claim.
+    last_claim: Option<u64>,
+    /// Unix time up to which property rewards have been credited.
+    last_accrued: Option<u64>,
+    /// Accrued fractions of a token not credited yet, in `ACCRUAL_UNIT`ths.
+    accrual_remainder: u64,
+    /// Tokens earned from property rewards over the player's lifetime.
+    lifetime_rewards: u64,
+    /// Tokens accrued since the last daily claim, which mints them.
+    unclaimed_rewards: u64,
+    /// Battles won and lost since the last season reset.
+    wins: u32,
+    losses: u32,
+    /// The player's most recent battles, oldest first, at most
+    /// `MAX_BATTLE_HISTORY` of them.
+    battle_history: Vec<BattleRecord>,
+    /// Set when idle decay lowered property rewards on connect, until
+    /// the next profile request reports it.
+    decay_applied: bool,
+    /// Accounts of the player's friends. Session ids change with every
+    /// connection, so friends are remembered by account.
+    friends: Vec<String>,
+    /// Chat channels the player has joined, by normalized name. Kept for
+    /// the session only.
+    channels: Vec<String>,
+    /// Token the owning session can be resumed with after a disconnect.
+    resume_token: Option<Uuid>,
     addr: Option<Addr<WsSession>>,
+    metadata: SessionMetadata,
+    privacy: PrivacySettings,
+    notification_prefs: NotificationPrefs,
+    /// Set by the player for the current connection only.
+    status: PlayerStatus,
+    /// Granted by the auth provider; allows moderation commands.
+    is_admin: bool,
+    /// Acknowledgements of recent purchases by idempotency key, so a
+    /// resent purchase is answered without charging again.
+    recent_purchases: HashMap<String, (ServerMessage, Instant)>,
+    /// Purchase made at the inventory cap in prompt mode, completed once
+    /// the player frees a slot. Kept for the session only.
+    held_purchase: Option<HeldPurchase>,
+    /// Messages sent to the session, shared with its connection.
+    outbox: Arc<std::sync::Mutex<Outbox>>,
+    /// Set when the player changed since they were last saved, by changes
+    /// that aren't written through right away or by a failed save.
+    /// Cleared once `run_autosave` or a write-through saves them.
+    dirty: bool,
 }
 
 impl ClientInfo {
     fn new(username: String) -> Self {
         Self {
+            account: username.clone(),
             username,
             pvp_level: 1,
             properties: Vec::new(),
+            balance: STARTING_BALANCE,
+            last_claim: None,
+            last_accrued: None,
+            accrual_remainder: 0,
+            lifetime_rewards: 0,
+            unclaimed_rewards: 0,
+            wins: 0,
+            losses: 0,
+            battle_history: Vec::new(),
+            decay_applied: false,
+            friends: Vec::new(),
+            channels: vec![DEFAULT_CHANNEL.to_owned()],
+            resume_token: None,
             addr: None,
+            metadata: SessionMetadata::default(),
+            privacy: PrivacySettings::default(),
+            notification_prefs: NotificationPrefs::default(),
+            status: PlayerStatus::Available,
+            is_admin: false,
+            recent_purchases: HashMap::new(),
+            held_purchase: None,
+            outbox: Arc::default(),
+            dirty: false,
+        }
+    }
+
+    /// Whether the player takes `msg` under their privacy settings and
+    /// notification preferences. Checked for every push, by `deliver` and
+    /// the broadcasts alike.
+    fn accepts(&self, msg: &ServerMessage) -> bool {
+        if let Some(kind) = msg.notification() {
+            if !self.notification_prefs.allows(kind) {
+                return false;
+            }
+        }
+        match msg {
+            ServerMessage::ChallengeRequest { .. } => self.privacy.allow_challenges,
+            _ => true,
+        }
+    }
+
+    /// Whether the player can be challenged right now: available, and
+    /// turning challenges away neither in their privacy settings nor in
+    /// their notification preferences.
+    fn is_challengeable(&self) -> bool {
+        self.status == PlayerStatus::Available
+            && self.privacy.allow_challenges
+            && self.notification_prefs.challenges
+    }
+
+    /// Overwrite the persisted fields with a stored copy of the player.
+    fn restore(&mut self, stored: StoredPlayer) {
+        if let Some(name) = stored.display_name {
+            self.username = name;
+        }
+        self.pvp_level = stored.pvp_level;
+        self.balance = stored.balance;
+        self.properties = stored.properties;
+        self.last_claim = stored.last_claim;
+        self.last_accrued = stored.last_accrued;
+        self.lifetime_rewards = stored.lifetime_rewards;
+        self.unclaimed_rewards = stored.unclaimed_rewards;
+        self.wins = stored.wins;
+        self.losses = stored.losses;
+        self.battle_history = stored.battle_history;
+        self.friends = stored.friends;
+    }
+
+    fn to_stored(&self) -> StoredPlayer {
+        StoredPlayer {
+            username: self.account.clone(),
+            display_name: (self.username != self.account).then(|| self.username.clone()),
+            pvp_level: self.pvp_level,
+            balance: self.balance,
+            properties: self.properties.clone(),
+            last_claim: self.last_claim,
+            last_accrued: self.last_accrued,
+            lifetime_rewards: self.lifetime_rewards,
+            unclaimed_rewards: self.unclaimed_rewards,
+            wins: self.wins,
+            losses: self.losses,
+            battle_history: self.battle_history.clone(),
+            friends: self.friends.clone(),
+        }
+    }
+}
+
+/// Credit property rewards earned since the last accrual at the current
+/// multiplier and return how many tokens were added. Rewards accrue per
+/// second; fractions carry over to the next accrual. The first accrual
+/// only starts the clock.
+fn accrue_rewards(info: &mut ClientInfo, now: u64, multiplier_percent: u32) -> u64 {
+    let Some(since) = info.last_accrued.replace(now) else {
+        return 0;
+    };
+    let elapsed = now.saturating_sub(since);
+    let earned = daily_reward(&info.properties)
+        .saturating_mul(u64::from(multiplier_percent))
+        .saturating_mul(elapsed)
+        .saturating_add(info.accrual_remainder);
+    let credited = earned / ACCRUAL_UNIT;
+    info.accrual_remainder = earned % ACCRUAL_UNIT;
+    info.balance = info.balance.saturating_add(credited);
+    info.lifetime_rewards = info.lifetime_rewards.saturating_add(credited);
+    info.unclaimed_rewards = info.unclaimed_rewards.saturating_add(credited);
+    credited
+}
+
+/// Add a battle to a player's history, dropping the oldest one if the
+/// history is full, and count the win or loss.
+fn record_battle(info: &mut ClientInfo, record: BattleRecord) {
+    if record.won {
+        info.wins = info.wins.saturating_add(1);
+    } else {
+        info.losses = info.losses.saturating_add(1);
+    }
+    if info.battle_history.len() >= MAX_BATTLE_HISTORY {
+        let excess = info.battle_history.len() + 1 - MAX_BATTLE_HISTORY;
+        info.battle_history.drain(..excess);
+    }
+    info.battle_history.push(record);
+}
+
+/// Record a finished battle in the histories of both players.
+fn record_battle_result(
+    (winner, loser): (&mut ClientInfo, &mut ClientInfo),
+    battle_id: Uuid,
+    pot: u64,
+    forfeited: bool,
+) {
+    let timestamp = unix_now();
+    let (winner_name, loser_name) = (winner.username.clone(), loser.username.clone());
+    for (info, opponent, won) in [(winner, loser_name, true), (loser, winner_name, false)] {
+        let record = BattleRecord {
+            battle_id,
+            opponent,
+            won,
+            pot,
+            forfeited,
+            timestamp,
+        };
+        record_battle(info, record);
+    }
+}
+
+/// Take the first property called `name` from a player.
+fn take_property(info: &mut ClientInfo, name: &str) -> Option<Property> {
+    let index = info.properties.iter().position(|p| p.name == name)?;
+    Some(info.properties.remove(index))
+}
+
+/// Hand the property staked on a battle from the challenger who lost it
+/// to the winner, if the challenger still owns it. Returns the property
+/// and the audit events of both players.
+fn pay_staked_property(
+    stake: &StakeKind,
+    (winner, winner_info): (Uuid, &mut ClientInfo),
+    (loser, loser_info): (Uuid, &mut ClientInfo),
+) -> Option<(Property, [AuditEvent; 2])> {
+    let property = take_property(loser_info, stake.property()?)?;
+    winner_info.properties.push(property.clone());
+    let (won, lost) = (winner_info.properties.len(), loser_info.properties.len());
+    let audit = [
+        AuditEvent::new(
+            winner,
+            winner_info,
+            AuditChange::Properties {
+                old: won - 1,
+                new: won,
+            },
+            "stake_won",
+        ),
+        AuditEvent::new(
+            loser,
+            loser_info,
+            AuditChange::Properties {
+                old: lost + 1,
+                new: lost,
+            },
+            "stake_lost",
+        ),
+    ];
+    Some((property, audit))
+}
+
+/// Lower the reward of every property by `percent` for each full day
+/// since the player last claimed or collected rewards, never below 1.
+/// Returns whether any reward changed.
+fn decay_idle_rewards(info: &mut ClientInfo, now: u64, percent: u32) -> bool {
+    let Some(since) = info.last_claim.max(info.last_accrued) else {
+        return false;
+    };
+    let idle_days = now.saturating_sub(since) / 86_400;
+    if idle_days == 0 || percent == 0 {
+        return false;
+    }
+    let factor = (1.0 - f64::from(percent.min(100)) / 100.0).powf(idle_days as f64);
+    let mut changed = false;
+    for property in &mut info.properties {
+        let decayed = ((f64::from(property.reward) * factor).floor() as u32).max(1);
+        if decayed < property.reward {
+            property.reward = decayed;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Controls how exposed a player is to everyone else. Everything is
+/// allowed by default.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct PrivacySettings {
+    allow_challenges: bool,
+    allow_whispers: bool,
+    allow_watch: bool,
+    show_in_leaderboard: bool,
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            allow_challenges: true,
+            allow_whispers: true,
+            allow_watch: true,
+            show_in_leaderboard: true,
+        }
+    }
+}
+
+/// Which pushes a player wants to get. Everything is on by default, and
+/// fields left out of `setNotificationPrefs` are turned back on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+struct NotificationPrefs {
+    /// Chat messages in joined channels.
+    chat: bool,
+    /// Players and friends coming online, going offline or renaming.
+    presence: bool,
+    /// Challenge requests. Players who turn these off can't be challenged.
+    challenges: bool,
+}
+
+impl Default for NotificationPrefs {
+    fn default() -> Self {
+        Self {
+            chat: true,
+            presence: true,
+            challenges: true,
+        }
+    }
+}
+
+impl NotificationPrefs {
+    fn allows(&self, notification: Notification) -> bool {
+        match notification {
+            Notification::Chat => self.chat,
+            Notification::Presence => self.presence,
+            Notification::Challenges => self.challenges,
+        }
+    }
+}
+
+/// Kinds of broadcast push a player can opt out of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    Chat,
+    Presence,
+    Challenges,
+}
+
+/// Whether a player is up for a fight. Only available players can be
+/// challenged. Every connection starts out available.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PlayerStatus {
+    #[default]
+    Available,
+    Busy,
+    Away,
+}
+
+/// Details about the client's connection captured during the WebSocket
+/// upgrade. Every field is optional because clients are free to omit
+/// the corresponding headers.
+#[derive(Debug, Clone, Default, Serialize)]
+struct SessionMetadata {
+    user_agent: Option<String>,
+    origin: Option<String>,
+    remote_ip: Option<String>,
+}
+
+impl SessionMetadata {
+    fn from_request(req: &HttpRequest) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            user_agent: header(actix_web::http::header::USER_AGENT),
+            origin: header(actix_web::http::header::ORIGIN),
+            // Use the socket peer rather than forwarding headers, which
+            // the client controls.
+            remote_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
         }
     }
 }
 
 /// A property owned by a player. Each property has a reward rate
 /// associated with it which will be used to compute daily rewards.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Property {
     name: String,
+    /// Marketplace category the property was bought from. Missing in
+    /// properties saved before categories were tracked.
+    #[serde(default)]
+    category: String,
     reward: u32,
+    /// Upgrade level, starting at 1.
+    #[serde(default = "Property::starting_level")]
+    level: u32,
+}
+
+impl Property {
+    fn starting_level() -> u32 {
+        1
+    }
+}
+
+/// Tokens a set of properties earns per day. Summed as `u64` so large
+/// inventories of high-reward properties can't overflow.
+fn daily_reward(properties: &[Property]) -> u64 {
+    properties.iter().map(|p| u64::from(p.reward)).sum()
+}
+
+/// How many properties of one category a player owns and what they
+/// earn per day together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+struct CategorySummary {
+    count: usize,
+    total_reward: u64,
+}
+
+/// Group properties by category. Properties saved before categories
+/// were tracked are grouped under the empty category.
+fn category_breakdown(properties: &[Property]) -> HashMap<String, CategorySummary> {
+    let mut breakdown: HashMap<String, CategorySummary> = HashMap::new();
+    for property in properties {
+        let summary = breakdown.entry(property.category.clone()).or_default();
+        summary.count += 1;
+        summary.total_reward += u64::from(property.reward);
+    }
+    breakdown
 }
 
 /// Shared server state holding information about all connected clients.
@@ -57,284 +632,10881 @@ struct Property {
 /// contains per‑client data.
 #[derive(Clone)]
 struct ServerState {
-    clients: Arc<RwLock<HashMap<Uuid, ClientInfo>>>,
+    clients: Arc<ClientMap<ClientInfo>>,
+    /// Bearer token required by the `/admin` HTTP endpoints. When unset
+    /// the admin endpoints reject every request.
+    admin_token: Option<String>,
+    /// Maximum `pvp_level` difference between two players for the
+    /// matchmaker to consider them a fair fight.
+    matchmaking_level_gap: u32,
+    /// The starter bot players can fight when nobody suitable is online.
+    /// Disabled unless `BOT_PVP_LEVEL` is set.
+    bot: Option<BotConfig>,
+    /// Tokens each account won from the bot, with the UTC day they were
+    /// won on.
+    bot_winnings: Arc<RwLock<HashMap<String, (u64, u64)>>>,
+    /// Players waiting for an opponent, in the order they joined, with
+    /// their `pvp_level` at the time.
+    matchmaking_queue: Arc<RwLock<Vec<(Uuid, u32)>>>,
+    /// Prometheus counters served at `/metrics`.
+    metrics: Arc<Metrics>,
+    /// Final standings of every completed season, oldest first.
+    seasons: Arc<RwLock<Vec<SeasonArchive>>>,
+    /// Daily windows during which rewards are boosted.
+    reward_events: Arc<Vec<RewardEvent>>,
+    /// Reward multiplier currently in effect, in percent (100 = 1x).
+    reward_multiplier: Arc<AtomicU32>,
+    /// Share of token gifts and traded properties' market price burned
+    /// as a fee, in basis points. Changed at runtime via
+    /// `/admin/economy`.
+    transfer_fee_bps: Arc<AtomicU32>,
+    /// Held while a report is filed, so concurrent reports can't get
+    /// past the per-reporter limit. Reports themselves are in `storage`.
+    filing_report: Arc<tokio::sync::Mutex<()>>,
+    /// Lowercase words chat messages may not contain, from
+    /// `BLOCKED_WORDS`. Empty turns the filter off.
+    blocked_words: Arc<Vec<String>>,
+    /// When players who keep sending blocked words are muted.
+    mute_policy: MutePolicy,
+    /// Blocked messages and mutes per account, so reconnecting doesn't
+    /// clear them.
+    chat_strikes: Arc<RwLock<HashMap<String, ChatStrikes>>>,
+    /// Maximum number of concurrent WebSocket sessions.
+    max_sessions: usize,
+    /// Most properties a single player may own.
+    max_properties: usize,
+    /// What happens to purchases at `max_properties`.
+    inventory_cap: InventoryCap,
+    /// Tokens and properties a player gets on their very first login,
+    /// and the balance resets go back to.
+    starting_balance: u64,
+    starter_properties: Arc<Vec<Property>>,
+    /// Percentage of reward properties lose per full day their owner
+    /// stays idle. Zero turns decay off.
+    reward_decay_percent: u32,
+    /// How far luck can swing a battle's reward comparison, in percent.
+    /// See `resolve_battle`.
+    battle_luck_percent: u32,
+    /// Source of all battle randomness. Seeded from `BATTLE_SEED` when
+    /// set, so a sequence of battles can be replayed.
+    battle_rng: Arc<std::sync::Mutex<StdRng>>,
+    /// Minimum time between two daily reward claims.
+    daily_claim_cooldown: Duration,
+    /// Sustained client messages per second allowed on each session.
+    message_rate: u32,
+    /// Largest burst of messages a session may send at once.
+    message_burst: u32,
+    /// Number of WebSocket sessions currently open.
+    live_sessions: Arc<AtomicUsize>,
+    /// Watched player id → ids of the players watching them.
+    watchers: Arc<RwLock<HashMap<Uuid, HashSet<Uuid>>>>,
+    /// Recent economy mutations, oldest first.
+    audit_log: Arc<RwLock<VecDeque<AuditEvent>>>,
+    /// Recent challenges and their outcomes, oldest first.
+    challenge_log: Arc<RwLock<VecDeque<ChallengeRecord>>>,
+    /// Challenge records kept; the oldest are dropped first.
+    challenge_log_capacity: usize,
+    /// Validates the bearer tokens presented in `authenticate` messages.
+    auth: Arc<dyn AuthProvider>,
+    /// Mints the on-chain side of purchase and daily rewards.
+    token_service: Arc<dyn TokenService>,
+    /// Challenges awaiting an answer, keyed by (challenger, target).
+    pending_challenges: Arc<RwLock<HashMap<(Uuid, Uuid), PendingChallenge>>>,
+    /// How long a challenge stays open before its stake is refunded.
+    challenge_timeout: Duration,
+    /// Declined challenges by (challenger, target), for backing off
+    /// repeated challenges at the same player.
+    challenge_cooldowns: Arc<RwLock<HashMap<(Uuid, Uuid), ChallengeCooldown>>>,
+    /// Cooldown after the first declined challenge. Every further
+    /// decline doubles it, up to `MAX_CHALLENGE_COOLDOWN`.
+    challenge_cooldown: Duration,
+    /// Spectators of every battle that has not finished yet, by battle id.
+    /// A battle exists from the moment it is challenged.
+    battles: Arc<RwLock<HashMap<Uuid, HashSet<Uuid>>>>,
+    /// Accepted battles still being fought, by battle id.
+    active_battles: Arc<RwLock<HashMap<Uuid, ActiveBattle>>>,
+    /// How long an accepted battle is fought before it is resolved. Zero
+    /// resolves battles as soon as they are accepted.
+    battle_duration: Duration,
+    /// How long a disconnected player's battles wait for them to resume
+    /// before they forfeit. Zero forfeits right away.
+    battle_grace: Duration,
+    /// Trade offers awaiting an answer, keyed by (offerer, target).
+    pending_trades: Arc<RwLock<HashMap<(Uuid, Uuid), TradeOffer>>>,
+    /// How long a trade offer stays open.
+    trade_offer_timeout: Duration,
+    /// How long a new connection may take to authenticate before it is
+    /// closed.
+    auth_timeout: Duration,
+    /// Every purchasable category with its price and reward. Both the
+    /// marketplace listing and purchases read from this.
+    marketplace: Arc<Vec<MarketplaceItem>>,
+    /// Durable copy of every player. `clients` acts as a cache in front
+    /// of it for connected players.
+    storage: Arc<dyn Storage>,
+    /// Recently disconnected sessions that can still be resumed, with
+    /// the time they dropped.
+    disconnected: Arc<RwLock<HashMap<Uuid, (ClientInfo, Instant)>>>,
+    /// Accounts a login or resume is loading from storage right now.
+    logins: Arc<RwLock<HashSet<String>>>,
+    /// When the server state was created, for reporting uptime.
+    started_at: Instant,
+    /// Set once startup has finished and cleared again on shutdown;
+    /// reported by `/ready`.
+    ready: Arc<AtomicBool>,
+    /// Origins browsers may call the server from.
+    allowed_origins: AllowedOrigins,
+    /// Optional protocol features this server offers.
+    features: Features,
+    /// Leaderboard snapshot served by `getLeaderboard`, recomputed by
+    /// `run_leaderboard_refresh`.
+    leaderboard: Arc<RwLock<LeaderboardCache>>,
+    /// How often the leaderboard snapshot is recomputed.
+    leaderboard_refresh: Duration,
+    /// Entries kept in the leaderboard snapshot.
+    leaderboard_cache_size: usize,
+    /// How often `run_autosave` flushes changed players to storage.
+    autosave_interval: Duration,
+    /// Indent JSON messages to sessions for easier debugging. MessagePack
+    /// sessions are unaffected.
+    pretty_json: bool,
+    /// Game events for components outside the WebSocket sessions. See
+    /// `subscribe`.
+    events: broadcast::Sender<GameEvent>,
 }
 
-impl ServerState {
-    fn new() -> Self {
+/// Version of the WebSocket protocol, reported by `hello`. Bumped on
+/// changes older clients can't cope with. Clients state the version they
+/// speak in the `v` field of each message; messages without one are
+/// taken to be current.
+const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version still understood. Version 0 is the protocol
+/// from before stakes were escrowed, see `upgrade_message`.
+const MIN_PROTOCOL_VERSION: u32 = 0;
+
+/// Optional parts of the protocol. All of them are on unless switched
+/// off with `DISABLED_FEATURES`; `hello` tells clients which are left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Features {
+    /// MessagePack connections, `?format=msgpack`.
+    msgpack: bool,
+    matchmaking: bool,
+    trading: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
         Self {
-            clients: Arc::new(RwLock::new(HashMap::new())),
+            msgpack: true,
+            matchmaking: true,
+            trading: true,
         }
     }
 }
 
-/// The WebSocket session actor. Each connected client is represented by
-/// its own instance of `WsSession`. It stores its unique id and a
-/// clone of the shared server state. Messages sent and received over
-/// the WebSocket are processed within the actor's context.
-struct WsSession {
-    id: Uuid,
-    state: ServerState,
-}
+impl Features {
+    fn flags(&self) -> [(&'static str, bool); 3] {
+        [
+            ("msgpack", self.msgpack),
+            ("matchmaking", self.matchmaking),
+            ("trading", self.trading),
+        ]
+    }
 
-impl WsSession {
-    fn new(id: Uuid, state: ServerState) -> Self {
-        Self { id, state }
+    /// Names of the enabled features.
+    fn names(&self) -> Vec<String> {
+        self.flags()
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_owned())
+            .collect()
     }
 
-    /// Helper to send JSON responses to the connected client. If
-    /// serialization fails an error will be logged and nothing sent.
-    fn send_json<T: Serialize>(&self, ctx: &mut ws::WebsocketContext<Self>, payload: &T) {
-        match serde_json::to_string(payload) {
-            Ok(text) => ctx.text(text),
-            Err(err) => error!("Failed to serialize response: {}", err),
+    fn is_enabled(&self, name: &str) -> bool {
+        self.flags()
+            .into_iter()
+            .any(|(feature, enabled)| feature == name && enabled)
+    }
+
+    /// Every feature except those in a comma-separated list such as
+    /// `trading,msgpack`.
+    fn without(spec: &str) -> Result<Self, String> {
+        let mut features = Self::default();
+        for name in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let flag = match name {
+                "msgpack" => &mut features.msgpack,
+                "matchmaking" => &mut features.matchmaking,
+                "trading" => &mut features.trading,
+                _ => return Err(format!("unknown feature '{}'", name)),
+            };
+            *flag = false;
         }
+        Ok(features)
     }
+}
 
-    /// Handle an incoming JSON message from the client. The protocol is
-    /// structured around a `type` field which determines the kind of
-    /// request. Additional data is embedded in the message. See the
-    /// documentation of each match arm for details.
-    async fn handle_client_message(&self, msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
-        match msg {
-            ClientMessage::GetProfile => {
-                // Respond with the player's own profile. Compute the total
-                // reward rate by summing the reward of each property.
-                let clients = self.state.clients.read().await;
-                if let Some(info) = clients.get(&self.id) {
-                    let daily_reward: u32 = info.properties.iter().map(|p| p.reward).sum();
-                    let payload = ServerMessage::Profile(ProfilePayload {
-                        username: info.username.clone(),
-                        pvp_level: info.pvp_level,
-                        properties: info.properties.clone(),
-                        daily_reward,
-                    });
-                    self.send_json(ctx, &payload);
-                }
-            }
-            ClientMessage::ListPlayers => {
-                // Return a list of other connected players along with their
-                // PvP level. Exclude the requesting client.
-                let clients = self.state.clients.read().await;
-                let players: Vec<PlayerInfo> = clients
-                    .iter()
-                    .filter(|(k, _)| **k != self.id)
-                    .map(|(id, info)| PlayerInfo {
-                        id: *id,
-                        username: info.username.clone(),
-                        pvp_level: info.pvp_level,
-                    })
-                    .collect();
-                let payload = ServerMessage::PlayerList { players };
-                self.send_json(ctx, &payload);
-            }
-            ClientMessage::Purchase { item_id, category } => {
-                // In a real implementation we would validate the purchase
-                // against a marketplace inventory and the player's balance.
-                // Here we simulate granting a new property with a reward
-                // based on the category.
-                let reward = match category.as_str() {
-                    "Islands" => 10,
-                    "NFT Characters" => 5,
-                    "Buildings" => 2,
-                    "Land" => 3,
-                    "Weapons" => 1,
-                    _ => 1,
-                };
-                let name = format!("{} Item", category);
-                let mut clients = self.state.clients.write().await;
-                if let Some(info) = clients.get_mut(&self.id) {
-                    info.properties.push(Property { name, reward });
-                }
-                // Acknowledge the purchase to the client.
-                let payload = ServerMessage::PurchaseAck { item_id };
-                self.send_json(ctx, &payload);
-            }
-            ClientMessage::Challenge { target, stake } => {
-                // Relay the challenge to the target player if they exist.
-                let clients = self.state.clients.read().await;
-                if let Some(target_info) = clients.get(&target) {
-                    if let Some(addr) = &target_info.addr {
-                        // Construct a challenge notification for the target.
-                        let challenge = ServerMessage::ChallengeRequest {
-                            challenger: self.id,
-                            challenger_name: clients
-                                .get(&self.id)
-                                .map(|c| c.username.clone())
-                                .unwrap_or_else(|| "Anonymous".into()),
-                            stake,
-                        };
-                        addr.do_send(challenge);
-                        // Inform the challenger that the request was sent.
-                        let ack = ServerMessage::ChallengeResponse {
-                            message: format!("Challenge sent to {}", target_info.username),
-                        };
-                        self.send_json(ctx, &ack);
-                    }
-                }
-            }
+/// Cross-origin policy, configured with `ALLOWED_ORIGINS`.
+#[derive(Debug, Clone, PartialEq)]
+enum AllowedOrigins {
+    /// Any origin; the default in debug builds.
+    Any,
+    /// Only these origins. Empty, the release default, allows none.
+    List(Vec<String>),
+}
+
+impl Default for AllowedOrigins {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Self::Any
+        } else {
+            Self::List(Vec::new())
         }
     }
 }
 
-/// Define messages that can be sent from the client to the server.
-/// These are deserialized from JSON in the WebSocket handler.
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type")]
-enum ClientMessage {
-    #[serde(rename = "getProfile")]
-    GetProfile,
-    #[serde(rename = "listPlayers")]
-    ListPlayers,
-    #[serde(rename = "purchase")]
-    Purchase { item_id: String, category: String },
-    #[serde(rename = "challenge")]
-    Challenge { target: Uuid, stake: bool },
+impl AllowedOrigins {
+    /// Parse a comma-separated list such as
+    /// `https://play.example.com,http://localhost:3000`. `*` allows any
+    /// origin.
+    fn parse(spec: &str) -> Self {
+        let origins: Vec<String> = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if origins.iter().any(|origin| origin == "*") {
+            Self::Any
+        } else {
+            Self::List(origins)
+        }
+    }
+
+    fn cors(&self) -> Cors {
+        match self {
+            Self::Any => Cors::permissive(),
+            Self::List(origins) => origins
+                .iter()
+                .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+                .allowed_methods(["GET", "POST"])
+                .allowed_headers([
+                    actix_web::http::header::AUTHORIZATION,
+                    actix_web::http::header::CONTENT_TYPE,
+                ])
+                .max_age(3600),
+        }
+    }
 }
 
-/// Define the payload sent in a profile response.
-#[derive(Debug, Serialize)]
-struct ProfilePayload {
+/// The player a bearer token belongs to.
+#[derive(Debug, Clone)]
+struct AuthIdentity {
     username: String,
-    pvp_level: u32,
-    properties: Vec<Property>,
-    daily_reward: u32,
+    /// Whether the player may use moderation commands.
+    is_admin: bool,
 }
 
-/// Simplified player info returned to other clients when listing
-/// available opponents in the PvP arena.
-#[derive(Debug, Serialize)]
-struct PlayerInfo {
-    id: Uuid,
-    username: String,
-    pvp_level: u32,
+/// Resolves bearer tokens to player identities. The lookup is async so
+/// that implementations backed by a database or an HTTP service can be
+/// plugged in.
+#[async_trait]
+trait AuthProvider: Send + Sync {
+    /// Return the identity `token` belongs to, or `None` if the token is
+    /// not valid.
+    async fn authenticate(&self, token: &str) -> Option<AuthIdentity>;
 }
 
-/// Define messages that the server can send to clients.
-#[derive(Debug, Serialize, Message)]
-#[rtype(result = "()")]
-enum ServerMessage {
-    #[serde(rename = "profile")]
-    Profile(ProfilePayload),
-    #[serde(rename = "playerList")]
-    PlayerList { players: Vec<PlayerInfo> },
-    #[serde(rename = "purchaseAck")]
-    PurchaseAck { item_id: String },
-    #[serde(rename = "challengeRequest")]
-    ChallengeRequest {
-        challenger: Uuid,
-        challenger_name: String,
-        stake: bool,
-    },
-    #[serde(rename = "challengeResponse")]
-    ChallengeResponse { message: String },
+/// Mints on-chain tokens for rewards earned in game. A Hedera
+/// implementation would sign and submit a token mint transaction with
+/// the Hedera SDK; until one exists the server uses `NoopTokenService`.
+#[async_trait]
+trait TokenService: Send + Sync {
+    /// Mint `amount` reward tokens to the account of `player`.
+    async fn mint_reward(&self, player: &str, amount: u64) -> Result<(), String>;
 }
 
-impl Handler<ServerMessage> for WsSession {
-    type Result = ();
+/// Token service that mints nothing.
+struct NoopTokenService;
 
-    fn handle(&mut self, msg: ServerMessage, ctx: &mut Self::Context) -> Self::Result {
-        // Simply forward the server message to the client over the WebSocket.
-        self.send_json(ctx, &msg);
+#[async_trait]
+impl TokenService for NoopTokenService {
+    async fn mint_reward(&self, _player: &str, _amount: u64) -> Result<(), String> {
+        Ok(())
     }
 }
 
-impl Actor for WsSession {
-    type Context = ws::WebsocketContext<Self>;
+/// Authenticates against a fixed set of tokens, configured with
+/// `AUTH_TOKENS=token:username,...`. Entries ending in `:admin`, such as
+/// `token:username:admin`, belong to moderators.
+struct StaticTokenAuth {
+    tokens: HashMap<String, AuthIdentity>,
+}
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        // When the session starts, register this client's address in the
-        // global state so that other clients can message it. Also
-        // generate a default username based on the id.
-        let id = self.id;
-        let addr = ctx.address();
-        let state = self.state.clone();
-        actix::spawn(async move {
-            let mut clients = state.clients.write().await;
-            let username = format!("User-{}", &id.to_string()[..8]);
-            clients
-                .entry(id)
-                .and_modify(|info| info.addr = Some(addr.clone()))
-                .or_insert_with(|| ClientInfo::new(username)).addr = Some(addr.clone());
-        });
-        info!("Client {} connected", self.id);
+impl StaticTokenAuth {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let tokens = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (entry, is_admin) = match entry.strip_suffix(":admin") {
+                    Some(entry) => (entry, true),
+                    None => (entry, false),
+                };
+                match entry.split_once(':') {
+                    Some((token, username)) if !token.is_empty() && !username.is_empty() => {
+                        let identity = AuthIdentity {
+                            username: username.to_owned(),
+                            is_admin,
+                        };
+                        Ok((token.to_owned(), identity))
+                    }
+                    _ => Err(format!("invalid auth token entry '{}'", entry)),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { tokens })
     }
+}
 
-    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
-        // Remove the client from the state on disconnect.
-        let id = self.id;
-        let state = self.state.clone();
-        actix::spawn(async move {
-            let mut clients = state.clients.write().await;
-            clients.remove(&id);
-        });
-        info!("Client {} disconnected", id);
-        Running::Stop
+#[async_trait]
+impl AuthProvider for StaticTokenAuth {
+    async fn authenticate(&self, token: &str) -> Option<AuthIdentity> {
+        self.tokens.get(token).cloned()
     }
 }
 
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
-    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match item {
-            Ok(ws::Message::Text(text)) => {
-                // Parse JSON from client into a strongly typed message.
-                match serde_json::from_str::<ClientMessage>(&text) {
-                    Ok(msg) => {
-                        let state = self.state.clone();
-                        let mut ctx_clone = ctx.clone();
-                        // Actix's WebSocketContext isn't Send/Sync so we
-                        // handle messages on the Arbiter thread with a
-                        // blocking block. This is a simplified pattern.
-                        actix::spawn(async move {
-                            self.handle_client_message(msg, &mut ctx_clone).await;
-                        });
-                    }
-                    Err(err) => {
-                        error!("Invalid message from client {}: {}", self.id, err);
-                    }
-                }
-            }
-            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Pong(_)) => (),
-            Ok(ws::Message::Binary(_)) => (),
-            Ok(ws::Message::Close(reason)) => {
-                ctx.close(reason);
-                ctx.stop();
-            }
-            _ => (),
+/// Development provider that accepts any non-empty token and uses it as
+/// the username. Never use this in production.
+struct DevAuth;
+
+#[async_trait]
+impl AuthProvider for DevAuth {
+    async fn authenticate(&self, token: &str) -> Option<AuthIdentity> {
+        let token = token.trim();
+        (!token.is_empty()).then(|| AuthIdentity {
+            username: token.to_owned(),
+            is_admin: false,
+        })
+    }
+}
+
+/// Record of a single change to a player's economy state, kept for
+/// investigating balance and inventory complaints.
+#[derive(Debug, Clone, Serialize)]
+struct AuditEvent {
+    /// Name the player logs in as, which stays the same across sessions.
+    account: String,
+    /// Session the change was made in.
+    session: Uuid,
+    username: String,
+    change: AuditChange,
+    reason: String,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+}
+
+/// The value that changed and its old and new values.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+enum AuditChange {
+    /// Number of owned properties.
+    Properties { old: usize, new: usize },
+    /// Token balance.
+    Balance { old: u64, new: u64 },
+    /// A property exchanged for another one in a trade.
+    Property { old: String, new: String },
+}
+
+impl AuditEvent {
+    fn new(
+        session: Uuid,
+        player: &ClientInfo,
+        change: AuditChange,
+        reason: impl Into<String>,
+    ) -> Self {
+        Self {
+            account: player.account.clone(),
+            session,
+            username: player.username.clone(),
+            change,
+            reason: reason.into(),
+            timestamp: unix_now(),
         }
     }
 }
 
-/// WebSocket endpoint. Upgrades an HTTP request to a WebSocket
-/// connection and creates a new session actor. Each new connection
-/// receives a unique UUID.
-#[get("/ws")]
-async fn websocket_handler(
-    req: HttpRequest,
-    stream: web::Payload,
-    data: web::Data<ServerState>,
-) -> Result<HttpResponse, Error> {
-    let id = Uuid::new_v4();
-    let session = WsSession::new(id, data.get_ref().clone());
-    let resp = ws::start(session, &req, stream);
-    resp
+/// A slot in the session limit, held by a `WsSession` for its whole
+/// lifetime and released when the session is dropped.
+struct SessionPermit {
+    live_sessions: Arc<AtomicUsize>,
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init();
-    let state = ServerState::new();
-    // Start the HTTP server on port 8080. In production you should
-    // configure CORS and TLS as appropriate. The server will serve
-    // only the WebSocket endpoint; the static front‑end files can be
-    // served by a separate web server or CDN.
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(state.clone()))
-            .service(websocket_handler)
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        self.live_sessions.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A report filed by one player against another.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PlayerReport {
+    reporter: Uuid,
+    reporter_name: String,
+    target: Uuid,
+    target_name: String,
+    reason: String,
+    details: String,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+}
+
+/// A daily window (UTC hours, end exclusive) during which rewards are
+/// multiplied, e.g. double rewards from 18:00 to 20:00. Windows with
+/// `end_hour < start_hour` wrap past midnight.
+#[derive(Debug, Clone, PartialEq)]
+struct RewardEvent {
+    start_hour: u32,
+    end_hour: u32,
+    multiplier_percent: u32,
+}
+
+impl RewardEvent {
+    fn contains(&self, hour: u32) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// Parse a reward event schedule such as `18-20:200,22-2:150`, where
+/// each entry is `start-end:percent` in UTC hours.
+fn parse_reward_events(spec: &str) -> Result<Vec<RewardEvent>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || format!("invalid reward event '{}'", entry);
+            let (hours, percent) = entry.split_once(':').ok_or_else(invalid)?;
+            let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+            let event = RewardEvent {
+                start_hour: start.trim().parse().map_err(|_| invalid())?,
+                end_hour: end.trim().parse().map_err(|_| invalid())?,
+                multiplier_percent: percent.trim().parse().map_err(|_| invalid())?,
+            };
+            if event.start_hour > 23 || event.end_hour > 24 || event.start_hour == event.end_hour {
+                return Err(invalid());
+            }
+            Ok(event)
+        })
+        .collect()
+}
+
+/// A category players can buy, with its price in tokens and the daily
+/// reward of the property it grants.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct MarketplaceItem {
+    category: String,
+    price: u64,
+    reward: u32,
+}
+
+/// Marketplace used when `ITEM_PRICES` does not override it.
+fn default_marketplace() -> Vec<MarketplaceItem> {
+    [
+        ("Islands", 500, 10),
+        ("NFT Characters", 250, 5),
+        ("Buildings", 100, 2),
+        ("Land", 150, 3),
+        ("Weapons", 50, 1),
+    ]
+    .into_iter()
+    .map(|(category, price, reward)| MarketplaceItem {
+        category: category.to_owned(),
+        price,
+        reward,
     })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
-}
\ No newline at end of file
+    .collect()
+}
+
+/// Fee on a transfer of `amount` at `rate_bps` basis points, rounded
+/// down so the smallest transfers stay free.
+fn transfer_fee(amount: u64, rate_bps: u32) -> u64 {
+    // Below `amount` as long as the rate is below 100%.
+    (u128::from(amount) * u128::from(rate_bps) / 10_000) as u64
+}
+
+/// Apply price overrides to the marketplace. Categories that are not
+/// listed yet are added with the minimum reward.
+fn apply_prices(marketplace: &mut Vec<MarketplaceItem>, prices: HashMap<String, u64>) {
+    for (category, price) in prices {
+        match marketplace
+            .iter_mut()
+            .find(|item| item.category == category)
+        {
+            Some(item) => item.price = price,
+            None => marketplace.push(MarketplaceItem {
+                category,
+                price,
+                reward: 1,
+            }),
+        }
+    }
+}
+
+/// Parse a price table such as `Islands:500,Land:150`, where each entry
+/// is `category:tokens`.
+fn parse_prices(spec: &str) -> Result<HashMap<String, u64>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || format!("invalid price '{}'", entry);
+            let (category, price) = entry.rsplit_once(':').ok_or_else(invalid)?;
+            let category = category.trim();
+            if category.is_empty() {
+                return Err(invalid());
+            }
+            let price = price.trim().parse().map_err(|_| invalid())?;
+            Ok((category.to_owned(), price))
+        })
+        .collect()
+}
+
+/// What new players are given, read from the `STARTER_CONFIG` file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StarterConfig {
+    #[serde(default = "StarterConfig::default_balance")]
+    starting_balance: u64,
+    #[serde(default)]
+    starter_properties: Vec<Property>,
+}
+
+impl StarterConfig {
+    fn default_balance() -> u64 {
+        STARTING_BALANCE
+    }
+}
+
+/// Parse and check a starter config such as
+/// `{"starting_balance": 500, "starter_properties": [{"name": "Hut",
+/// "category": "Buildings", "reward": 1}]}`. Starter properties count
+/// against `max_properties`.
+fn parse_starter_config(json: &str, max_properties: usize) -> Result<StarterConfig, String> {
+    let config: StarterConfig =
+        serde_json::from_str(json).map_err(|err| format!("invalid starter config: {}", err))?;
+    if config.starter_properties.len() > max_properties {
+        return Err(format!(
+            "starter config gives {} properties but players may own only {}",
+            config.starter_properties.len(),
+            max_properties
+        ));
+    }
+    for property in &config.starter_properties {
+        if property.name.trim().is_empty() {
+            return Err("starter properties need a name".into());
+        }
+        if !(1..=MAX_PROPERTY_LEVEL).contains(&property.level) {
+            return Err(format!(
+                "starter property '{}' has level {}, levels run from 1 to {}",
+                property.name, property.level, MAX_PROPERTY_LEVEL
+            ));
+        }
+    }
+    Ok(config)
+}
+
+/// Clean up a username for display: drop control and invisible
+/// formatting characters, trim whitespace and cut it to the longest
+/// allowed username. Returns `None` if nothing is left.
+fn sanitize_username(raw: &str) -> Option<String> {
+    let invisible = |c: char| {
+        c.is_control()
+            || matches!(
+                c,
+                '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}'
+            )
+    };
+    let cleaned: String = raw.chars().filter(|c| !invisible(*c)).collect();
+    let cut: String = cleaned.trim().chars().take(*USERNAME_LEN.end()).collect();
+    let name = cut.trim_end();
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Name given to a player whose name from authentication is unusable.
+fn fallback_username(id: Uuid) -> String {
+    format!("Player-{}", &id.simple().to_string()[..8])
+}
+
+/// Check a player-chosen username: 3 to 20 ASCII letters, digits,
+/// underscores or hyphens.
+fn validate_username(username: &str) -> Result<(), &'static str> {
+    if !USERNAME_LEN.contains(&username.chars().count()) {
+        return Err("username must be 3 to 20 characters");
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("username may only contain letters, digits, '_' and '-'");
+    }
+    Ok(())
+}
+
+/// Normalize a chat channel name: trimmed and lowercased, 1 to 32 ASCII
+/// letters, digits, underscores or hyphens.
+fn normalize_channel(channel: &str) -> Result<String, &'static str> {
+    let channel = channel.trim().to_ascii_lowercase();
+    if !CHANNEL_NAME_LEN.contains(&channel.chars().count()) {
+        return Err("channel names must be 1 to 32 characters");
+    }
+    if !channel
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("channel names may only contain letters, digits, '_' and '-'");
+    }
+    Ok(channel)
+}
+
+/// Parse a comma-separated list of blocked chat words, lowercased.
+fn parse_blocked_words(spec: &str) -> Vec<String> {
+    spec.split(',')
+        .map(|word| word.trim().to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Whether `text` contains one of `blocked` as a whole word, ignoring
+/// case.
+fn contains_blocked_word(text: &str, blocked: &[String]) -> bool {
+    !blocked.is_empty()
+        && text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .any(|word| blocked.contains(&word.to_lowercase()))
+}
+
+/// How blocked chat messages turn into mutes: `strikes` of them within
+/// `window` mute the player for `mute`, doubling with every further mute
+/// up to `MAX_CHAT_MUTE`. A player with no strikes for `reset` starts
+/// over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MutePolicy {
+    strikes: usize,
+    window: Duration,
+    mute: Duration,
+    reset: Duration,
+}
+
+impl Default for MutePolicy {
+    fn default() -> Self {
+        Self {
+            strikes: 3,
+            window: Duration::from_secs(60),
+            mute: Duration::from_secs(60),
+            reset: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl MutePolicy {
+    /// Parse overrides such as `strikes=5,window=30,mute=120,reset=600`,
+    /// with durations in seconds. Settings left out keep their defaults.
+    fn parse(spec: &str) -> Result<Self, String> {
+        let mut policy = Self::default();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let invalid = || format!("invalid mute policy entry '{}'", entry);
+            let (key, value) = entry.split_once('=').ok_or_else(invalid)?;
+            let value: u64 = value.trim().parse().map_err(|_| invalid())?;
+            if value == 0 {
+                return Err(invalid());
+            }
+            match key.trim() {
+                "strikes" => policy.strikes = usize::try_from(value).map_err(|_| invalid())?,
+                "window" => policy.window = Duration::from_secs(value),
+                "mute" => policy.mute = Duration::from_secs(value),
+                "reset" => policy.reset = Duration::from_secs(value),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Blocked chat messages of one account and the mutes they earned.
+#[derive(Debug, Default)]
+struct ChatStrikes {
+    /// When recent blocked messages were sent, oldest first.
+    recent: VecDeque<Instant>,
+    /// Mutes since the strikes were last reset.
+    mutes: u32,
+    /// The account may not chat before this.
+    muted_until: Option<Instant>,
+}
+
+impl ChatStrikes {
+    /// Count a blocked message sent at `now`. Returns how long the
+    /// account is muted for if this strike mutes it.
+    fn strike(&mut self, now: Instant, policy: &MutePolicy) -> Option<Duration> {
+        if self.is_reset(now, policy) {
+            *self = Self::default();
+        }
+        self.recent
+            .retain(|at| now.saturating_duration_since(*at) < policy.window);
+        self.recent.push_back(now);
+        if self.recent.len() < policy.strikes {
+            return None;
+        }
+        self.recent.clear();
+        let factor = 2u32.saturating_pow(self.mutes);
+        self.mutes = self.mutes.saturating_add(1);
+        let mute = policy.mute.saturating_mul(factor).min(MAX_CHAT_MUTE);
+        self.muted_until = Some(now + mute);
+        Some(mute)
+    }
+
+    /// Whether the account kept quiet for long enough to start over.
+    fn is_reset(&self, now: Instant, policy: &MutePolicy) -> bool {
+        let last = self.recent.back().copied().or(self.muted_until);
+        last.is_none_or(|last| now.saturating_duration_since(last) >= policy.reset)
+    }
+
+    /// How much of a mute is left at `now`, if any.
+    fn muted_for(&self, now: Instant) -> Option<Duration> {
+        self.muted_until
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|left| !left.is_zero())
+    }
+}
+
+/// Multiplier (in percent) in effect at the given hour of the day. When
+/// windows overlap the most generous one wins.
+fn reward_multiplier_at(events: &[RewardEvent], hour: u32) -> u32 {
+    events
+        .iter()
+        .filter(|event| event.contains(hour))
+        .map(|event| event.multiplier_percent)
+        .max()
+        .unwrap_or(100)
+}
+
+/// What a season reset puts back to starting values.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SeasonResetScope {
+    /// Only PvP levels are reset; players keep their properties.
+    #[default]
+    Levels,
+    /// PvP levels and the economy (owned properties) are reset.
+    Economy,
+}
+
+/// A player's final standing in a completed season.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SeasonStanding {
+    username: String,
+    pvp_level: u32,
+    daily_reward: u64,
+}
+
+/// One row of the live leaderboard.
+#[derive(Debug, Clone, Serialize)]
+struct LeaderboardEntry {
+    rank: usize,
+    username: String,
+    daily_reward: u64,
+    pvp_level: u32,
+}
+
+impl LeaderboardEntry {
+    fn new(rank: usize, info: &ClientInfo) -> Self {
+        Self {
+            rank,
+            username: info.username.clone(),
+            daily_reward: daily_reward(&info.properties),
+            pvp_level: info.pvp_level,
+        }
+    }
+}
+
+/// The leaderboard as of its last refresh. Reading it is cheap, but it
+/// can be up to `leaderboard_refresh` old: ranks trail behind purchases
+/// and battles, and players who connected since are not ranked yet.
+#[derive(Debug, Default)]
+struct LeaderboardCache {
+    /// Best players first, at most `leaderboard_cache_size` of them.
+    /// Players who opted out of the leaderboard are left out.
+    top: Vec<(Uuid, LeaderboardEntry)>,
+    /// Rank of every connected player. Players who opted out get the rank
+    /// they would have, so they can still see where they stand.
+    ranks: HashMap<Uuid, usize>,
+}
+
+impl LeaderboardCache {
+    /// Rank connected players by daily reward, then PvP level, keeping
+    /// the top `size` entries.
+    fn build<'a>(
+        clients: impl IntoIterator<Item = (&'a Uuid, &'a ClientInfo)>,
+        size: usize,
+    ) -> Self {
+        let mut ranked: Vec<(Uuid, &ClientInfo, u64)> = clients
+            .into_iter()
+            .map(|(id, info)| (*id, info, daily_reward(&info.properties)))
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.2.cmp(&a.2)
+                .then_with(|| b.1.pvp_level.cmp(&a.1.pvp_level))
+                .then_with(|| a.1.username.cmp(&b.1.username))
+        });
+        let mut cache = Self::default();
+        let mut visible = 0;
+        for (id, info, _) in ranked {
+            let rank = visible + 1;
+            cache.ranks.insert(id, rank);
+            if !info.privacy.show_in_leaderboard {
+                continue;
+            }
+            visible += 1;
+            if cache.top.len() < size {
+                cache.top.push((id, LeaderboardEntry::new(rank, info)));
+            }
+        }
+        cache
+    }
+
+    /// The top `limit` entries for `requester`, and the rank to show
+    /// `requester` at below them if they are not among them.
+    fn view(&self, requester: Uuid, limit: usize) -> (Vec<LeaderboardEntry>, Option<usize>) {
+        let limit = limit.min(MAX_LEADERBOARD_LIMIT);
+        let top = &self.top[..limit.min(self.top.len())];
+        let entries = top.iter().map(|(_, entry)| entry.clone()).collect();
+        if top.iter().any(|(id, _)| *id == requester) {
+            return (entries, None);
+        }
+        let unranked = self.ranks.len() + 1;
+        let rank = self.ranks.get(&requester).copied().unwrap_or(unranked);
+        (entries, Some(rank))
+    }
+}
+
+/// Archived results of a completed season. Standings are sorted from
+/// best to worst.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SeasonArchive {
+    season: u32,
+    standings: Vec<SeasonStanding>,
+}
+
+impl ServerState {
+    fn new() -> Self {
+        Self {
+            clients: Arc::new(ClientMap::default()),
+            admin_token: None,
+            matchmaking_level_gap: 2,
+            bot: None,
+            bot_winnings: Arc::new(RwLock::new(HashMap::new())),
+            matchmaking_queue: Arc::new(RwLock::new(Vec::new())),
+            metrics: Arc::new(Metrics::new()),
+            seasons: Arc::new(RwLock::new(Vec::new())),
+            reward_events: Arc::new(Vec::new()),
+            reward_multiplier: Arc::new(AtomicU32::new(100)),
+            transfer_fee_bps: Arc::new(AtomicU32::new(0)),
+            filing_report: Arc::new(tokio::sync::Mutex::new(())),
+            blocked_words: Arc::new(Vec::new()),
+            mute_policy: MutePolicy::default(),
+            chat_strikes: Arc::new(RwLock::new(HashMap::new())),
+            max_sessions: 10_000,
+            max_properties: 500,
+            inventory_cap: InventoryCap::Reject,
+            starting_balance: STARTING_BALANCE,
+            starter_properties: Arc::new(Vec::new()),
+            reward_decay_percent: DEFAULT_REWARD_DECAY_PERCENT,
+            battle_luck_percent: DEFAULT_BATTLE_LUCK_PERCENT,
+            battle_rng: Arc::new(std::sync::Mutex::new(StdRng::from_os_rng())),
+            daily_claim_cooldown: Duration::from_secs(24 * 60 * 60),
+            message_rate: 20,
+            message_burst: 40,
+            live_sessions: Arc::new(AtomicUsize::new(0)),
+            watchers: Arc::new(RwLock::new(HashMap::new())),
+            audit_log: Arc::new(RwLock::new(VecDeque::new())),
+            challenge_log: Arc::new(RwLock::new(VecDeque::new())),
+            challenge_log_capacity: 1000,
+            auth: Arc::new(DevAuth),
+            token_service: Arc::new(NoopTokenService),
+            pending_challenges: Arc::new(RwLock::new(HashMap::new())),
+            challenge_timeout: Duration::from_secs(60),
+            challenge_cooldowns: Arc::new(RwLock::new(HashMap::new())),
+            challenge_cooldown: Duration::from_secs(5),
+            battles: Arc::new(RwLock::new(HashMap::new())),
+            active_battles: Arc::new(RwLock::new(HashMap::new())),
+            battle_duration: DEFAULT_BATTLE_DURATION,
+            battle_grace: DEFAULT_BATTLE_GRACE,
+            pending_trades: Arc::new(RwLock::new(HashMap::new())),
+            trade_offer_timeout: Duration::from_secs(120),
+            auth_timeout: Duration::from_secs(15),
+            marketplace: Arc::new(default_marketplace()),
+            storage: Arc::new(MemoryStorage::default()),
+            disconnected: Arc::new(RwLock::new(HashMap::new())),
+            logins: Arc::new(RwLock::new(HashSet::new())),
+            started_at: Instant::now(),
+            ready: Arc::new(AtomicBool::new(false)),
+            events: broadcast::channel(DEFAULT_EVENT_BUS_CAPACITY).0,
+            features: Features::default(),
+            leaderboard: Arc::new(RwLock::new(LeaderboardCache::default())),
+            leaderboard_refresh: DEFAULT_LEADERBOARD_REFRESH,
+            leaderboard_cache_size: MAX_LEADERBOARD_LIMIT,
+            autosave_interval: DEFAULT_AUTOSAVE_INTERVAL,
+            pretty_json: false,
+            allowed_origins: AllowedOrigins::default(),
+        }
+    }
+
+    /// Replace the battle RNG with one seeded from `seed`, making battle
+    /// outcomes reproducible.
+    fn seed_battles(&mut self, seed: u64) {
+        self.battle_rng = Arc::new(std::sync::Mutex::new(StdRng::seed_from_u64(seed)));
+    }
+
+    /// Take the disconnected session `resume_token` belongs to, as long
+    /// as it is still within the grace period.
+    async fn take_resumable(&self, resume_token: Uuid) -> Option<(Uuid, ClientInfo)> {
+        let mut disconnected = self.disconnected.write().await;
+        let id = disconnected
+            .iter()
+            .find(|(_, (info, since))| {
+                info.resume_token == Some(resume_token) && since.elapsed() < RESUME_GRACE_PERIOD
+            })
+            .map(|(id, _)| *id)?;
+        disconnected.remove(&id).map(|(info, _)| (id, info))
+    }
+
+    /// Drop disconnected sessions whose grace period has passed, along
+    /// with what they left open, and return how many were removed.
+    async fn reap_disconnected(&self) -> usize {
+        let expired: Vec<Uuid> = {
+            let disconnected = self.disconnected.read().await;
+            disconnected
+                .iter()
+                .filter(|(_, (_, since))| since.elapsed() >= RESUME_GRACE_PERIOD)
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        // Settle while the sessions are still parked, so their stakes can
+        // be refunded to storage.
+        for id in &expired {
+            self.abandon_session(*id).await;
+        }
+        let mut disconnected = self.disconnected.write().await;
+        expired
+            .iter()
+            .filter(|id| disconnected.remove(id).is_some())
+            .count()
+    }
+
+    /// Every session that can still be resumed, longest disconnected
+    /// first.
+    async fn list_disconnected(&self) -> Vec<DisconnectedSession> {
+        let disconnected = self.disconnected.read().await;
+        let mut sessions: Vec<DisconnectedSession> = disconnected
+            .iter()
+            .map(|(id, (info, since))| DisconnectedSession {
+                id: *id,
+                username: info.username.clone(),
+                disconnected_secs: since.elapsed().as_secs(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.disconnected_secs));
+        sessions
+    }
+
+    /// Forget disconnected sessions of `username` so a stale copy can't
+    /// be resumed after the player logged in again. What they left open
+    /// is settled with the new session.
+    async fn forget_disconnected(&self, account: &str) {
+        let forgotten: Vec<Uuid> = {
+            let disconnected = self.disconnected.read().await;
+            disconnected
+                .iter()
+                .filter(|(_, (info, _))| info.account == account)
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        for id in &forgotten {
+            self.abandon_session(*id).await;
+        }
+        let mut disconnected = self.disconnected.write().await;
+        for id in forgotten {
+            disconnected.remove(&id);
+        }
+    }
+
+    /// Forfeit the battles and cancel the challenges and trade offers of
+    /// a session that won't be resumed, refunding its escrowed stakes,
+    /// and tell the other players.
+    async fn abandon_session(&self, id: Uuid) {
+        self.forfeit_active_battles(id).await;
+        let mut counterparts = self.remove_pending_challenges(id).await;
+        self.remove_challenge_cooldowns(id).await;
+        counterparts.extend(self.remove_pending_trades(id).await);
+        counterparts.sort_unstable();
+        counterparts.dedup();
+        for counterpart in counterparts {
+            let notice = ServerMessage::OpponentDisconnected {
+                id,
+                grace_seconds: None,
+            };
+            self.deliver(counterpart, notice).await;
+        }
+    }
+
+    /// Players with a pending challenge or trade involving `id`.
+    async fn pending_counterparts(&self, id: Uuid) -> Vec<Uuid> {
+        let other =
+            |(a, b): (Uuid, Uuid)| (a == id || b == id).then_some(if a == id { b } else { a });
+        let mut counterparts: Vec<Uuid> = {
+            let pending = self.pending_challenges.read().await;
+            pending.keys().copied().filter_map(other).collect()
+        };
+        let pending = self.pending_trades.read().await;
+        counterparts.extend(pending.keys().copied().filter_map(other));
+        counterparts.sort_unstable();
+        counterparts.dedup();
+        counterparts
+    }
+
+    /// The challenges, trade offers and battles `id` still has open, for
+    /// a session that was just resumed.
+    async fn pending_actions(&self, id: Uuid) -> ServerMessage {
+        let involves = |(a, b): &(Uuid, Uuid)| *a == id || *b == id;
+        let challenges = {
+            let pending = self.pending_challenges.read().await;
+            pending
+                .iter()
+                .filter(|(key, _)| involves(key))
+                .map(
+                    |((challenger, target), challenge)| PendingChallengeSummary {
+                        battle_id: challenge.battle_id,
+                        challenger: *challenger,
+                        target: *target,
+                        stake: challenge.stake.clone(),
+                    },
+                )
+                .collect()
+        };
+        let trades = {
+            let pending = self.pending_trades.read().await;
+            let now = Instant::now();
+            pending
+                .iter()
+                .filter(|(key, offer)| involves(key) && offer.expires_at > now)
+                .map(|((from, target), offer)| PendingTradeSummary {
+                    from: *from,
+                    target: *target,
+                    offer_property: offer.offer_property.clone(),
+                    request_property: offer.request_property.clone(),
+                })
+                .collect()
+        };
+        let battles = {
+            let active = self.active_battles.read().await;
+            active
+                .iter()
+                .filter(|(_, battle)| battle.has_player(id))
+                .map(|(battle_id, _)| *battle_id)
+                .collect()
+        };
+        ServerMessage::PendingActions {
+            challenges,
+            trades,
+            battles,
+        }
+    }
+
+    /// The error to answer chat from `account` with while it is muted.
+    async fn check_chat_mute(&self, account: &str) -> Option<ServerMessage> {
+        let strikes = self.chat_strikes.read().await;
+        let left = strikes.get(account)?.muted_for(Instant::now())?;
+        let retry_after_secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        Some(ServerMessage::Error {
+            code: "muted".into(),
+            detail: format!("you are muted for {} more seconds", retry_after_secs),
+            retry_after_secs: Some(retry_after_secs),
+            server_version: None,
+        })
+    }
+
+    /// Count a blocked chat message from `account`, and return the
+    /// notice to send it if that earned a mute.
+    async fn strike_chat(&self, account: &str) -> Option<ServerMessage> {
+        let mute = {
+            let mut strikes = self.chat_strikes.write().await;
+            let entry = strikes.entry(account.to_owned()).or_default();
+            entry.strike(Instant::now(), &self.mute_policy)?
+        };
+        info!("Muted {} in chat for {:?}", account, mute);
+        Some(ServerMessage::Muted {
+            until: unix_now() + mute.as_secs(),
+        })
+    }
+
+    /// Forget the strikes of accounts that have started over.
+    async fn prune_chat_strikes(&self) {
+        let now = Instant::now();
+        let mut strikes = self.chat_strikes.write().await;
+        strikes.retain(|_, strikes| !strikes.is_reset(now, &self.mute_policy));
+    }
+
+    /// Reserve `account` for a login or resume that is about to load it
+    /// from storage. Fails if the account is connected or already being
+    /// loaded, so two sessions never hold copies of the same player.
+    /// Release it with `finish_login` once the session is registered.
+    async fn begin_login(&self, account: &str) -> bool {
+        if !self.logins.write().await.insert(account.to_owned()) {
+            return false;
+        }
+        // Sessions are registered before their reservation is released,
+        // so a live account is always in one place or the other.
+        let connected = self
+            .clients
+            .read_all()
+            .await
+            .values()
+            .any(|info| info.account == account);
+        if connected {
+            self.finish_login(account).await;
+        }
+        !connected
+    }
+
+    async fn finish_login(&self, account: &str) {
+        self.logins.write().await.remove(account);
+    }
+
+    /// Apply `change` to the player of session `id` whether or not they
+    /// are connected. A disconnected player is changed in storage, which
+    /// they are reloaded from when they resume. Returns `None` if the
+    /// session is gone for good.
+    async fn update_player<T>(
+        &self,
+        id: Uuid,
+        change: impl FnOnce(&mut ClientInfo) -> T,
+    ) -> Option<T> {
+        for _ in 0..PLAYER_UPDATE_RETRIES {
+            {
+                let mut clients = self.clients.write(&id).await;
+                if let Some(info) = clients.get_mut(&id) {
+                    info.dirty = true;
+                    return Some(change(info));
+                }
+            }
+            let account = {
+                let disconnected = self.disconnected.read().await;
+                disconnected.get(&id).map(|(info, _)| info.account.clone())
+            };
+            // Otherwise the session is being resumed right now, or the
+            // player signed in again and it never will be.
+            if let Some(account) = account {
+                if self.begin_login(&account).await {
+                    let changed = self.update_stored(&account, change).await;
+                    self.finish_login(&account).await;
+                    return changed;
+                }
+                // Signed in again: the new session holds the player now.
+                let mut clients = self.clients.write_all().await;
+                let live = clients.iter_mut().find(|(_, info)| info.account == account);
+                if let Some((_, info)) = live {
+                    info.dirty = true;
+                    return Some(change(info));
+                }
+            }
+            tokio::time::sleep(PLAYER_UPDATE_RETRY_DELAY).await;
+        }
+        None
+    }
+
+    /// Apply `change` to the stored copy of `account`, which must be
+    /// reserved with `begin_login`.
+    async fn update_stored<T>(
+        &self,
+        account: &str,
+        change: impl FnOnce(&mut ClientInfo) -> T,
+    ) -> Option<T> {
+        let stored = match self.storage.load_player(account).await {
+            Ok(stored) => stored?,
+            Err(err) => {
+                error!("Failed to load player {}: {}", account, err);
+                return None;
+            }
+        };
+        let mut info = ClientInfo::new(account.to_owned());
+        info.restore(stored);
+        let changed = change(&mut info);
+        self.save_players(&[info.to_stored()]).await;
+        Some(changed)
+    }
+
+    /// Current name of the player of session `id`, connected or not.
+    async fn player_name(&self, id: Uuid) -> Option<String> {
+        if let Some(info) = self.clients.read(&id).await.get(&id) {
+            return Some(info.username.clone());
+        }
+        let disconnected = self.disconnected.read().await;
+        disconnected.get(&id).map(|(info, _)| info.username.clone())
+    }
+
+    /// Apply idle decay to a player who just connected. Must run before
+    /// their first accrual, which ends the idle period.
+    async fn decay_idle_rewards(&self, id: Uuid) {
+        let decayed = {
+            let mut clients = self.clients.write(&id).await;
+            let Some(info) = clients.get_mut(&id) else {
+                return;
+            };
+            let decayed = decay_idle_rewards(info, unix_now(), self.reward_decay_percent);
+            info.decay_applied |= decayed;
+            info.dirty |= decayed;
+            decayed
+        };
+        if decayed {
+            info!("Idle decay lowered the property rewards of {}", id);
+        }
+    }
+
+    /// Credit the rewards a connected player earned since their last
+    /// accrual and return the amount. Accrual runs on every profile
+    /// request, so it is left to `run_autosave` to save.
+    async fn accrue(&self, id: Uuid) -> u64 {
+        let multiplier = self.reward_multiplier.load(Ordering::Relaxed);
+        let accrued = {
+            let mut clients = self.clients.write(&id).await;
+            let Some(info) = clients.get_mut(&id) else {
+                return 0;
+            };
+            let old = info.balance;
+            let credited = accrue_rewards(info, unix_now(), multiplier);
+            info.dirty |= credited > 0;
+            let change = AuditChange::Balance {
+                old,
+                new: info.balance,
+            };
+            (credited > 0).then(|| {
+                let audit = AuditEvent::new(id, info, change, "reward_accrual");
+                (credited, audit)
+            })
+        };
+        let Some((credited, audit)) = accrued else {
+            return 0;
+        };
+        self.audit([audit]).await;
+        credited
+    }
+
+    /// Write the cached state of the given connected players through to
+    /// storage. Failures are logged; the cache stays authoritative.
+    async fn persist(&self, ids: impl IntoIterator<Item = Uuid>) {
+        let mut players = Vec::new();
+        for id in ids {
+            let mut clients = self.clients.write(&id).await;
+            if let Some(info) = clients.get_mut(&id) {
+                info.dirty = false;
+                players.push((id, info.to_stored()));
+            }
+        }
+        self.save_connected(players).await;
+    }
+
+    /// Save every connected player that changed since their last save
+    /// and return how many were written.
+    async fn autosave(&self) -> usize {
+        let players: Vec<(Uuid, StoredPlayer)> = {
+            let mut clients = self.clients.write_all().await;
+            clients
+                .iter_mut()
+                .filter(|(_, info)| info.dirty)
+                .map(|(id, info)| {
+                    info.dirty = false;
+                    (*id, info.to_stored())
+                })
+                .collect()
+        };
+        self.save_connected(players).await
+    }
+
+    /// Save snapshots of connected players, flagging the ones that failed
+    /// so the next autosave retries them. Returns how many were written.
+    async fn save_connected(&self, players: Vec<(Uuid, StoredPlayer)>) -> usize {
+        let mut saved = 0;
+        for (id, player) in players {
+            if self.save_players(std::slice::from_ref(&player)).await > 0 {
+                saved += 1;
+                continue;
+            }
+            let mut clients = self.clients.write(&id).await;
+            if let Some(info) = clients.get_mut(&id) {
+                info.dirty = true;
+            }
+        }
+        saved
+    }
+
+    /// Save players and return how many were written.
+    async fn save_players(&self, players: &[StoredPlayer]) -> usize {
+        let mut saved = 0;
+        for player in players {
+            match self.storage.save_player(player).await {
+                Ok(()) => saved += 1,
+                Err(err) => error!("Failed to save player {}: {}", player.username, err),
+            }
+        }
+        saved
+    }
+
+    /// Append events to the audit log. Call this after releasing the
+    /// client map lock so auditing never extends the critical section.
+    async fn audit(&self, events: impl IntoIterator<Item = AuditEvent>) {
+        let mut log = self.audit_log.write().await;
+        for event in events {
+            if log.len() >= AUDIT_LOG_CAPACITY {
+                log.pop_front();
+            }
+            log.push_back(event);
+        }
+    }
+
+    /// Mint `amount` reward tokens for `account`. A failure is logged and
+    /// returned as an error for the client; the in-game grant stands.
+    async fn mint_reward(&self, account: &str, amount: u64) -> Option<ServerMessage> {
+        let err = self
+            .token_service
+            .mint_reward(account, amount)
+            .await
+            .err()?;
+        error!("Failed to mint {} tokens for {}: {}", amount, account, err);
+        let detail = "reward tokens could not be minted; they are still credited in game";
+        Some(ServerMessage::error("mint_failed", detail))
+    }
+
+    /// Append to the challenge log, dropping the oldest records once it
+    /// is full.
+    async fn record_challenge(
+        &self,
+        (challenger, target): (Uuid, Uuid),
+        challenge: &PendingChallenge,
+        outcome: ChallengeOutcome,
+    ) {
+        let record = ChallengeRecord {
+            id: challenge.battle_id,
+            challenger,
+            target,
+            stake: challenge.stake.tokens(),
+            stake_property: challenge.stake.property().map(str::to_owned),
+            outcome,
+            timestamp: unix_now(),
+        };
+        self.publish(match outcome {
+            ChallengeOutcome::Sent => GameEvent::ChallengeSent(record.clone()),
+            _ => GameEvent::ChallengeResolved(record.clone()),
+        });
+        let capacity = self.challenge_log_capacity;
+        if capacity == 0 {
+            return;
+        }
+        let mut log = self.challenge_log.write().await;
+        while log.len() >= capacity {
+            log.pop_front();
+        }
+        log.push_back(record);
+    }
+
+    /// Outcome of the last challenge `challenger` sent `target` that is
+    /// still in the replay log.
+    async fn last_challenge_outcome(
+        &self,
+        (challenger, target): (Uuid, Uuid),
+    ) -> Option<ChallengeOutcome> {
+        let log = self.challenge_log.read().await;
+        log.iter()
+            .rev()
+            .find(|record| record.challenger == challenger && record.target == target)
+            .map(|record| record.outcome)
+    }
+
+    /// Recompute the leaderboard snapshot from the connected players.
+    async fn refresh_leaderboard(&self) {
+        let cache = {
+            let clients = self.clients.read_all().await;
+            LeaderboardCache::build(clients.iter(), self.leaderboard_cache_size)
+        };
+        *self.leaderboard.write().await = cache;
+    }
+
+    /// Receive every game event published from now on. Receivers that
+    /// fall more than the bus capacity behind miss the oldest events.
+    fn subscribe(&self) -> broadcast::Receiver<GameEvent> {
+        self.events.subscribe()
+    }
+
+    /// Hand an event to every subscriber. Nobody listening is fine.
+    fn publish(&self, event: GameEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Push a public state change of `id` to everyone watching them.
+    async fn notify_watchers(&self, id: Uuid, username: String, event: PlayerEvent) {
+        let watchers: Vec<Uuid> = match self.watchers.read().await.get(&id) {
+            Some(watchers) => watchers.iter().copied().collect(),
+            None => return,
+        };
+        let update = ServerMessage::PlayerUpdate {
+            id,
+            username,
+            event,
+        };
+        for watcher in watchers {
+            self.deliver(watcher, update.clone()).await;
+        }
+    }
+
+    /// Tell every connected player who lists `account` as a friend that
+    /// it came online or went offline.
+    async fn notify_friends(&self, id: Uuid, account: &str, username: &str, online: bool) {
+        let friends: Vec<Uuid> = {
+            let clients = self.clients.read_all().await;
+            clients
+                .iter()
+                .filter(|(other, info)| **other != id && info.friends.iter().any(|f| f == account))
+                .map(|(other, _)| *other)
+                .collect()
+        };
+        let (id, username) = (id, username.to_owned());
+        let notice = if online {
+            ServerMessage::FriendOnline { id, username }
+        } else {
+            ServerMessage::FriendOffline { id, username }
+        };
+        for friend in friends {
+            self.deliver(friend, notice.clone()).await;
+        }
+    }
+
+    /// Drop every watch subscription involving `id`, whether as the
+    /// watched player or as a watcher.
+    async fn remove_watcher_links(&self, id: Uuid) {
+        let mut watchers = self.watchers.write().await;
+        watchers.remove(&id);
+        watchers.retain(|_, set| {
+            set.remove(&id);
+            !set.is_empty()
+        });
+    }
+
+    /// Cancel every pending challenge sent by or to `id` and refund the
+    /// escrowed stakes. Returns the other players of those challenges.
+    async fn remove_pending_challenges(&self, id: Uuid) -> Vec<Uuid> {
+        let cancelled: Vec<((Uuid, Uuid), PendingChallenge)> = {
+            let mut pending = self.pending_challenges.write().await;
+            pending
+                .extract_if(|(challenger, target), _| *challenger == id || *target == id)
+                .collect()
+        };
+        let mut counterparts = Vec::with_capacity(cancelled.len());
+        for (key, challenge) in cancelled {
+            self.refund_stake(key.0, challenge.stake.tokens()).await;
+            self.cancel_battle(challenge.battle_id).await;
+            let outcome = ChallengeOutcome::Cancelled;
+            self.record_challenge(key, &challenge, outcome).await;
+            counterparts.push(if key.0 == id { key.1 } else { key.0 });
+        }
+        counterparts
+    }
+
+    /// Start or lengthen the cooldown before `challenger` may challenge
+    /// `target` again after a decline.
+    async fn back_off_challenges(&self, challenger: Uuid, target: Uuid) {
+        let base = self.challenge_cooldown;
+        let mut cooldowns = self.challenge_cooldowns.write().await;
+        let cooldown = cooldowns.entry((challenger, target)).or_default();
+        let factor = 2u32.saturating_pow(cooldown.declines);
+        let wait = base.saturating_mul(factor).min(MAX_CHALLENGE_COOLDOWN);
+        cooldown.declines = cooldown.declines.saturating_add(1);
+        cooldown.until = Some(Instant::now() + wait);
+    }
+
+    /// Forget the challenge cooldowns involving `id`.
+    async fn remove_challenge_cooldowns(&self, id: Uuid) {
+        let mut cooldowns = self.challenge_cooldowns.write().await;
+        cooldowns.retain(|(challenger, target), _| *challenger != id && *target != id);
+    }
+
+    /// Cancel challenges nobody answered in time, refund their stakes and
+    /// tell the challengers. Returns how many expired.
+    async fn expire_challenges(&self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<((Uuid, Uuid), PendingChallenge)> = {
+            let mut pending = self.pending_challenges.write().await;
+            pending
+                .extract_if(|_, challenge| challenge.expires_at <= now)
+                .collect()
+        };
+        for ((challenger, target), challenge) in &expired {
+            self.refund_stake(*challenger, challenge.stake.tokens())
+                .await;
+            self.cancel_battle(challenge.battle_id).await;
+            let notice = ServerMessage::ChallengeExpired { target: *target };
+            self.deliver(*challenger, notice).await;
+            let outcome = ChallengeOutcome::Expired;
+            self.record_challenge((*challenger, *target), challenge, outcome)
+                .await;
+        }
+        expired.len()
+    }
+
+    /// Add `id` to the spectators of a battle that hasn't finished.
+    /// Returns false if there is no such battle.
+    async fn spectate(&self, battle_id: Uuid, id: Uuid) -> bool {
+        let mut battles = self.battles.write().await;
+        battles
+            .get_mut(&battle_id)
+            .map(|spectators| spectators.insert(id))
+            .is_some()
+    }
+
+    /// Stop spectating every battle, e.g. when the spectator disconnects.
+    async fn remove_spectator(&self, id: Uuid) {
+        for spectators in self.battles.write().await.values_mut() {
+            spectators.remove(&id);
+        }
+    }
+
+    /// Send `msg` to everyone spectating `battle_id`.
+    async fn notify_spectators(&self, battle_id: Uuid, msg: ServerMessage) {
+        let spectators: Vec<Uuid> = match self.battles.read().await.get(&battle_id) {
+            Some(spectators) => spectators.iter().copied().collect(),
+            None => return,
+        };
+        for spectator in spectators {
+            self.deliver(spectator, msg.clone()).await;
+        }
+    }
+
+    /// Forget a battle that will never be fought and tell its spectators.
+    async fn cancel_battle(&self, battle_id: Uuid) {
+        let status = BattleStatus::Cancelled;
+        let update = ServerMessage::BattleUpdate { battle_id, status };
+        self.notify_spectators(battle_id, update).await;
+        self.battles.write().await.remove(&battle_id);
+    }
+
+    /// Return an escrowed stake to the challenger, connected or not.
+    async fn refund_stake(&self, challenger: Uuid, stake: u64) {
+        if stake == 0 {
+            return;
+        }
+        let audit = self
+            .update_player(challenger, |info| {
+                let old = info.balance;
+                info.balance = info.balance.saturating_add(stake);
+                let change = AuditChange::Balance {
+                    old,
+                    new: info.balance,
+                };
+                AuditEvent::new(challenger, info, change, "stake_refund")
+            })
+            .await;
+        self.audit(audit).await;
+        self.persist([challenger]).await;
+    }
+
+    /// Escrow the defender's stake of an accepted challenge, or check
+    /// that a staked property can still change hands. On failure the
+    /// challenger's stake is refunded and the reason returned.
+    async fn escrow_defender_stake(
+        &self,
+        challenger: Uuid,
+        defender: Uuid,
+        stake: &StakeKind,
+    ) -> Result<(), ServerMessage> {
+        let audit = {
+            let mut clients = self.clients.write_pair(&challenger, &defender).await;
+            let [Some(challenger_info), Some(defender_info)] =
+                clients.get_disjoint_mut([&challenger, &defender])
+            else {
+                drop(clients);
+                self.refund_stake(challenger, stake.tokens()).await;
+                let err =
+                    ServerMessage::error("unknown_target", "challenger is no longer connected");
+                return Err(err);
+            };
+            if let Some(name) = stake.property() {
+                if !challenger_info.properties.iter().any(|p| p.name == name) {
+                    let err = ServerMessage::error(
+                        "stake_unavailable",
+                        "the challenger no longer owns the staked property",
+                    );
+                    return Err(err);
+                }
+                let max_properties = self.max_properties;
+                if defender_info.properties.len() >= max_properties {
+                    let detail =
+                        format!("inventories are limited to {} properties", max_properties);
+                    return Err(ServerMessage::error("inventory_full", detail));
+                }
+            }
+            let stake = stake.tokens();
+            let Some(balance) = defender_info.balance.checked_sub(stake) else {
+                drop(clients);
+                self.refund_stake(challenger, stake).await;
+                let err = ServerMessage::error("insufficient_stake", "you cannot cover the stake");
+                return Err(err);
+            };
+            let change = AuditChange::Balance {
+                old: defender_info.balance,
+                new: balance,
+            };
+            defender_info.balance = balance;
+            (stake > 0).then(|| AuditEvent::new(defender, defender_info, change, "stake_escrow"))
+        };
+        self.audit(audit).await;
+        Ok(())
+    }
+
+    /// Fight an accepted battle and pay both stakes to the winner. If a
+    /// player left in the meantime both stakes are refunded instead and
+    /// `None` is returned.
+    async fn fight_battle(&self, battle: &ActiveBattle) -> Option<BattleOutcome> {
+        let (challenger, defender) = (battle.challenger, battle.defender);
+        let stake = battle.challenge.stake.tokens();
+        let mut clients = self.clients.write_pair(&challenger, &defender).await;
+        let [Some(challenger_info), Some(defender_info)] =
+            clients.get_disjoint_mut([&challenger, &defender])
+        else {
+            drop(clients);
+            self.refund_stake(challenger, stake).await;
+            self.refund_stake(defender, stake).await;
+            return None;
+        };
+        let (winner, loser) = {
+            let mut rng = self
+                .battle_rng
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+            resolve_battle(
+                (challenger, challenger_info),
+                (defender, defender_info),
+                self.battle_luck_percent,
+                &mut *rng,
+            )
+        };
+        let (winner_info, loser_info) = if winner == challenger {
+            (challenger_info, defender_info)
+        } else {
+            (defender_info, challenger_info)
+        };
+        winner_info.pvp_level += 1;
+        let pot = stake.saturating_mul(2);
+        let audit = (pot > 0).then(|| {
+            let old = winner_info.balance;
+            winner_info.balance = winner_info.balance.saturating_add(pot);
+            let change = AuditChange::Balance {
+                old,
+                new: winner_info.balance,
+            };
+            AuditEvent::new(winner, winner_info, change, "stake_won")
+        });
+        let property = (loser == challenger)
+            .then(|| {
+                pay_staked_property(
+                    &battle.challenge.stake,
+                    (winner, winner_info),
+                    (loser, loser_info),
+                )
+            })
+            .flatten();
+        let outcome = BattleOutcome {
+            winner,
+            winner_name: winner_info.username.clone(),
+            winner_level: winner_info.pvp_level,
+            loser,
+            loser_name: loser_info.username.clone(),
+            pot,
+            property: property.as_ref().map(|(property, _)| property.clone()),
+        };
+        let battle_id = battle.challenge.battle_id;
+        record_battle_result((winner_info, loser_info), battle_id, pot, false);
+        drop(clients);
+        self.audit(audit).await;
+        self.audit(property.into_iter().flat_map(|(_, audit)| audit))
+            .await;
+        self.persist([challenger, defender]).await;
+        Some(outcome)
+    }
+
+    /// Fight `battle` for `battle_duration`, after which it is resolved
+    /// unless a player forfeited first.
+    async fn start_battle(&self, battle: ActiveBattle) {
+        let battle_id = battle.challenge.battle_id;
+        self.active_battles.write().await.insert(battle_id, battle);
+        self.schedule_battle_end(battle_id, self.battle_duration);
+    }
+
+    fn schedule_battle_end(&self, battle_id: Uuid, after: Duration) {
+        let state = self.clone();
+        actix_web::rt::spawn(async move {
+            tokio::time::sleep(after).await;
+            state.end_battle(battle_id).await;
+        });
+    }
+
+    /// Resolve an active battle whose time is up and tell both players.
+    /// Battles that were forfeited, or paused and not fought to the end
+    /// yet, are left alone.
+    async fn end_battle(&self, battle_id: Uuid) {
+        let battle = {
+            let mut active = self.active_battles.write().await;
+            match active.get(&battle_id) {
+                Some(battle) if battle.away.is_empty() && battle.ends_at <= Instant::now() => {
+                    active.remove(&battle_id)
+                }
+                _ => None,
+            }
+        };
+        let Some(battle) = battle else {
+            return;
+        };
+        let key = (battle.challenger, battle.defender);
+        let Some(outcome) = self.fight_battle(&battle).await else {
+            self.cancel_battle(battle_id).await;
+            let outcome = ChallengeOutcome::Cancelled;
+            self.record_challenge(key, &battle.challenge, outcome).await;
+            return;
+        };
+        let result = self
+            .conclude_battle(key, &battle.challenge, outcome, false)
+            .await;
+        for player in [key.0, key.1] {
+            self.deliver(player, result.clone()).await;
+        }
+    }
+
+    /// Count and log a finished battle, tell its spectators and the
+    /// players' watchers, and return the result for the players.
+    async fn conclude_battle(
+        &self,
+        key: (Uuid, Uuid),
+        challenge: &PendingChallenge,
+        outcome: BattleOutcome,
+        forfeited: bool,
+    ) -> ServerMessage {
+        let BattleOutcome {
+            winner,
+            winner_name,
+            winner_level,
+            loser,
+            loser_name,
+            pot,
+            property,
+        } = outcome;
+        let battle_id = challenge.battle_id;
+        if forfeited {
+            info!("{} forfeited battle {} to {}", loser, battle_id, winner);
+        } else {
+            info!("Battle between {} and {} won by {}", key.0, key.1, winner);
+        }
+        let (side, logged) = if winner == key.0 {
+            ("challenger", ChallengeOutcome::ChallengerWon)
+        } else {
+            ("defender", ChallengeOutcome::TargetWon)
+        };
+        self.metrics.battles.with_label_values(&[side]).inc();
+        self.record_challenge(key, challenge, logged).await;
+        let result = ServerMessage::BattleResult {
+            battle_id,
+            winner,
+            loser,
+            pot,
+            forfeited,
+            property,
+            bot: false,
+        };
+        self.notify_spectators(battle_id, result.clone()).await;
+        self.battles.write().await.remove(&battle_id);
+        let won = PlayerEvent::BattleWon {
+            opponent: loser,
+            pvp_level: winner_level,
+        };
+        self.notify_watchers(winner, winner_name, won).await;
+        let lost = PlayerEvent::BattleLost { opponent: winner };
+        self.notify_watchers(loser, loser_name, lost).await;
+        result
+    }
+
+    /// End the battle `battle_id` with a loss for `id`, who must be one
+    /// of its players. The opponent is paid whatever is in escrow: both
+    /// stakes once the challenge was accepted. Forfeiting a challenge
+    /// that is still pending counts as accepting and losing it, so a
+    /// target puts up their stake as well. Nobody gains a level from a
+    /// battle that wasn't fought.
+    async fn forfeit_challenge(
+        &self,
+        id: Uuid,
+        battle_id: Uuid,
+    ) -> Result<(BattleOutcome, (Uuid, Uuid), PendingChallenge), ServerMessage> {
+        let active = {
+            let mut active = self.active_battles.write().await;
+            match active.get(&battle_id) {
+                Some(battle) if !battle.has_player(id) => {
+                    drop(active);
+                    let err = ServerMessage::error("not_in_battle", "you are not in that battle");
+                    return Err(err);
+                }
+                Some(_) => active.remove(&battle_id),
+                None => None,
+            }
+        };
+        if let Some(battle) = active {
+            let key = (battle.challenger, battle.defender);
+            let challenge = battle.challenge.clone();
+            return match self.settle_forfeit(battle, id).await {
+                Some(outcome) => Ok((outcome, key, challenge)),
+                None => {
+                    let err =
+                        ServerMessage::error("unknown_target", "opponent is no longer connected");
+                    Err(err)
+                }
+            };
+        }
+        let (key, challenge) = self.take_pending_challenge(id, battle_id).await?;
+        let escrowed = id == key.1;
+        if escrowed {
+            let escrow = self.escrow_defender_stake(key.0, key.1, &challenge.stake);
+            if let Err(err) = escrow.await {
+                self.cancel_battle(battle_id).await;
+                let declined = ServerMessage::ChallengeDeclined { target: id };
+                self.deliver(key.0, declined).await;
+                let outcome = ChallengeOutcome::Cancelled;
+                self.record_challenge(key, &challenge, outcome).await;
+                return Err(err);
+            }
+        }
+        let (challenger, target) = key;
+        let winner = if id == challenger { target } else { challenger };
+        let mut clients = self.clients.write_pair(&winner, &id).await;
+        let [Some(winner_info), Some(loser_info)] = clients.get_disjoint_mut([&winner, &id]) else {
+            drop(clients);
+            self.refund_stake(challenger, challenge.stake.tokens())
+                .await;
+            if escrowed {
+                self.refund_stake(target, challenge.stake.tokens()).await;
+            }
+            self.cancel_battle(battle_id).await;
+            let outcome = ChallengeOutcome::Cancelled;
+            self.record_challenge(key, &challenge, outcome).await;
+            let err = ServerMessage::error("unknown_target", "opponent is no longer connected");
+            return Err(err);
+        };
+        let pot = match escrowed {
+            true => challenge.stake.tokens().saturating_mul(2),
+            false => challenge.stake.tokens(),
+        };
+        let audit = (pot > 0).then(|| {
+            let old = winner_info.balance;
+            winner_info.balance = winner_info.balance.saturating_add(pot);
+            let change = AuditChange::Balance {
+                old,
+                new: winner_info.balance,
+            };
+            AuditEvent::new(winner, winner_info, change, "stake_won")
+        });
+        let property = (id == challenger)
+            .then(|| pay_staked_property(&challenge.stake, (winner, winner_info), (id, loser_info)))
+            .flatten();
+        let outcome = BattleOutcome {
+            winner,
+            winner_name: winner_info.username.clone(),
+            winner_level: winner_info.pvp_level,
+            loser: id,
+            loser_name: loser_info.username.clone(),
+            pot,
+            property: property.as_ref().map(|(property, _)| property.clone()),
+        };
+        record_battle_result((winner_info, loser_info), battle_id, pot, true);
+        drop(clients);
+        self.audit(audit).await;
+        self.audit(property.into_iter().flat_map(|(_, audit)| audit))
+            .await;
+        self.persist([winner, id]).await;
+        Ok((outcome, key, challenge))
+    }
+
+    /// Pay both stakes of an active battle that `loser` forfeited to
+    /// their opponent and record it for both players, including those
+    /// who are disconnected. If the opponent is gone for good the loser
+    /// gets their stake back and the battle is cancelled instead.
+    async fn settle_forfeit(&self, battle: ActiveBattle, loser: Uuid) -> Option<BattleOutcome> {
+        let key = (battle.challenger, battle.defender);
+        let battle_id = battle.challenge.battle_id;
+        let winner = battle.opponent_of(loser);
+        let names = (
+            self.player_name(winner).await,
+            self.player_name(loser).await,
+        );
+        let (Some(winner_name), Some(loser_name)) = names else {
+            let stake = battle.challenge.stake.tokens();
+            let mut audit = Vec::new();
+            for player in [winner, loser] {
+                let refund = self
+                    .update_player(player, |info| {
+                        let old = info.balance;
+                        info.balance = info.balance.saturating_add(stake);
+                        let change = AuditChange::Balance {
+                            old,
+                            new: info.balance,
+                        };
+                        (stake > 0).then(|| AuditEvent::new(player, info, change, "stake_refund"))
+                    })
+                    .await;
+                audit.extend(refund.flatten());
+            }
+            self.audit(audit).await;
+            self.cancel_battle(battle_id).await;
+            let outcome = ChallengeOutcome::Cancelled;
+            self.record_challenge(key, &battle.challenge, outcome).await;
+            return None;
+        };
+        let pot = battle.challenge.stake.tokens().saturating_mul(2);
+        let timestamp = unix_now();
+        let record = |opponent: &str, won: bool| BattleRecord {
+            battle_id,
+            opponent: opponent.to_owned(),
+            won,
+            pot,
+            forfeited: true,
+            timestamp,
+        };
+        let won = record(&loser_name, true);
+        let paid = self
+            .update_player(winner, |info| {
+                let old = info.balance;
+                info.balance = info.balance.saturating_add(pot);
+                record_battle(info, won);
+                let change = AuditChange::Balance {
+                    old,
+                    new: info.balance,
+                };
+                let audit = (pot > 0).then(|| AuditEvent::new(winner, info, change, "stake_won"));
+                (info.pvp_level, audit)
+            })
+            .await;
+        let lost = record(&winner_name, false);
+        let staked = battle
+            .challenge
+            .stake
+            .property()
+            .filter(|_| loser == battle.challenger);
+        let taken = self
+            .update_player(loser, |info| {
+                record_battle(info, lost);
+                staked.and_then(|name| take_property(info, name))
+            })
+            .await
+            .flatten();
+        if let Some(property) = &taken {
+            let property = property.clone();
+            self.update_player(winner, |info| info.properties.push(property))
+                .await;
+        }
+        let (winner_level, audit) = paid.unwrap_or_default();
+        self.audit(audit).await;
+        self.persist([winner, loser]).await;
+        Some(BattleOutcome {
+            winner,
+            winner_name,
+            winner_level,
+            loser,
+            loser_name,
+            pot,
+            property: taken,
+        })
+    }
+
+    /// Pause the battles `id` is fighting because they disconnected, and
+    /// tell their opponents how long they have to come back. Battles of
+    /// a player who doesn't resume in time are forfeited.
+    async fn pause_battles(&self, id: Uuid) {
+        if self.battle_grace.is_zero() {
+            self.forfeit_active_battles(id).await;
+            return;
+        }
+        let now = Instant::now();
+        let paused: Vec<(Uuid, Uuid)> = {
+            let mut active = self.active_battles.write().await;
+            active
+                .iter_mut()
+                .filter(|(_, battle)| battle.has_player(id))
+                .map(|(battle_id, battle)| {
+                    if battle.away.is_empty() {
+                        battle.remaining = battle.ends_at.saturating_duration_since(now);
+                    }
+                    battle.away.retain(|(player, _)| *player != id);
+                    battle.away.push((id, now));
+                    (*battle_id, battle.opponent_of(id))
+                })
+                .collect()
+        };
+        let grace = self.battle_grace;
+        let grace_seconds = grace.as_secs() + u64::from(grace.subsec_nanos() > 0);
+        for (battle_id, opponent) in paused {
+            info!("Battle {} paused: {} disconnected", battle_id, id);
+            let notice = ServerMessage::OpponentDisconnected {
+                id,
+                grace_seconds: Some(grace_seconds),
+            };
+            self.deliver(opponent, notice).await;
+            let status = BattleStatus::Paused;
+            let update = ServerMessage::BattleUpdate { battle_id, status };
+            self.notify_spectators(battle_id, update).await;
+            let state = self.clone();
+            actix_web::rt::spawn(async move {
+                tokio::time::sleep(state.battle_grace).await;
+                state.forfeit_if_away(battle_id, id).await;
+            });
+        }
+    }
+
+    /// Forfeit the battle `battle_id` for `id` if they are still away
+    /// after the grace period.
+    async fn forfeit_if_away(&self, battle_id: Uuid, id: Uuid) {
+        let battle = {
+            let mut active = self.active_battles.write().await;
+            let expired = active.get(&battle_id).is_some_and(|battle| {
+                battle
+                    .away
+                    .iter()
+                    .any(|(player, since)| *player == id && since.elapsed() >= self.battle_grace)
+            });
+            if !expired {
+                return;
+            }
+            active.remove(&battle_id)
+        };
+        let Some(battle) = battle else {
+            return;
+        };
+        let key = (battle.challenger, battle.defender);
+        let challenge = battle.challenge.clone();
+        let Some(outcome) = self.settle_forfeit(battle, id).await else {
+            return;
+        };
+        let winner = outcome.winner;
+        let result = self.conclude_battle(key, &challenge, outcome, true).await;
+        self.deliver(winner, result).await;
+    }
+
+    /// Pick up the battles `id` dropped out of. A battle carries on from
+    /// where it was paused once both players are back.
+    async fn resume_battles(&self, id: Uuid) {
+        let now = Instant::now();
+        let resumed: Vec<(Uuid, Uuid, Option<Duration>)> = {
+            let mut active = self.active_battles.write().await;
+            active
+                .iter_mut()
+                .filter(|(_, battle)| battle.away.iter().any(|(player, _)| *player == id))
+                .map(|(battle_id, battle)| {
+                    battle.away.retain(|(player, _)| *player != id);
+                    let restarted = battle.away.is_empty().then(|| {
+                        battle.ends_at = now + battle.remaining;
+                        battle.remaining
+                    });
+                    (*battle_id, battle.opponent_of(id), restarted)
+                })
+                .collect()
+        };
+        for (battle_id, opponent, restarted) in resumed {
+            let Some(remaining) = restarted else {
+                continue;
+            };
+            info!("Battle {} resumed", battle_id);
+            let status = BattleStatus::Resumed;
+            let update = ServerMessage::BattleUpdate { battle_id, status };
+            for player in [id, opponent] {
+                self.deliver(player, update.clone()).await;
+            }
+            self.notify_spectators(battle_id, update).await;
+            self.schedule_battle_end(battle_id, remaining);
+        }
+    }
+
+    /// Take the pending challenge `battle_id` off the pending map, as
+    /// long as `id` is one of its players.
+    async fn take_pending_challenge(
+        &self,
+        id: Uuid,
+        battle_id: Uuid,
+    ) -> Result<((Uuid, Uuid), PendingChallenge), ServerMessage> {
+        let mut pending = self.pending_challenges.write().await;
+        let key = pending
+            .iter()
+            .find(|(_, challenge)| challenge.battle_id == battle_id)
+            .map(|(key, _)| *key);
+        match key {
+            Some((challenger, target)) if challenger != id && target != id => {
+                let err = ServerMessage::error("not_in_battle", "you are not in that battle");
+                Err(err)
+            }
+            Some(key) => Ok((key, pending.remove(&key).expect("key was just found"))),
+            None => {
+                let err = ServerMessage::error("unknown_battle", "battle is not in progress");
+                Err(err)
+            }
+        }
+    }
+
+    /// Forfeit every battle `id` is fighting, e.g. because they left,
+    /// and tell their opponents.
+    async fn forfeit_active_battles(&self, id: Uuid) {
+        let battle_ids: Vec<Uuid> = {
+            let active = self.active_battles.read().await;
+            active
+                .iter()
+                .filter(|(_, battle)| battle.has_player(id))
+                .map(|(battle_id, _)| *battle_id)
+                .collect()
+        };
+        for battle_id in battle_ids {
+            let Ok((outcome, key, challenge)) = self.forfeit_challenge(id, battle_id).await else {
+                continue;
+            };
+            let winner = outcome.winner;
+            let result = self.conclude_battle(key, &challenge, outcome, true).await;
+            self.deliver(winner, result).await;
+        }
+    }
+
+    /// What stops the player `info` from simply buying an item at
+    /// `price`, short of funds: an earlier purchase with the same key or
+    /// a full inventory. Shared by real and dry-run purchases.
+    fn purchase_block(
+        &self,
+        info: &ClientInfo,
+        price: u64,
+        idempotency_key: Option<&String>,
+        now: Instant,
+    ) -> Option<PurchaseBlock> {
+        let replayed = idempotency_key
+            .and_then(|key| info.recent_purchases.get(key))
+            .filter(|(_, at)| now.duration_since(*at) < IDEMPOTENCY_KEY_TTL);
+        if let Some((ack, _)) = replayed {
+            return Some(PurchaseBlock::Replayed(ack.clone()));
+        }
+        let count = info.properties.len();
+        if count < self.max_properties {
+            return None;
+        }
+        let must_free = count + 1 - self.max_properties;
+        // Without a slot to free there is nothing to prompt for.
+        if self.inventory_cap == InventoryCap::Reject || must_free > count {
+            let max = self.max_properties;
+            let detail = format!("inventories are limited to {} properties", max);
+            let err = ServerMessage::error("inventory_full", detail);
+            return Some(PurchaseBlock::Refused(err));
+        }
+        // Purchases that can't be afforded fail on their funds instead.
+        (info.balance >= price).then_some(PurchaseBlock::MustFree(must_free))
+    }
+
+    /// Marketplace listing for `category`, if it can be bought.
+    fn marketplace_item(&self, category: &str) -> Option<&MarketplaceItem> {
+        self.marketplace
+            .iter()
+            .find(|item| item.category == category)
+    }
+
+    /// Queue `id` for matchmaking. If a queued player is within the level
+    /// gap the closest one (longest waiting on ties) is taken off the
+    /// queue and returned instead.
+    async fn join_matchmaking(&self, id: Uuid, pvp_level: u32) -> Option<Uuid> {
+        let gap = self.matchmaking_level_gap;
+        let mut queue = self.matchmaking_queue.write().await;
+        queue.retain(|(queued, _)| *queued != id);
+        let closest = queue
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, level))| level.abs_diff(pvp_level) <= gap)
+            .min_by_key(|(_, (_, level))| level.abs_diff(pvp_level))
+            .map(|(index, _)| index);
+        match closest {
+            Some(index) => Some(queue.remove(index).0),
+            None => {
+                queue.push((id, pvp_level));
+                None
+            }
+        }
+    }
+
+    /// Whether a player other than `id` is available to fight someone of
+    /// `pvp_level`, by the same rules as challengeable players are listed.
+    async fn has_human_opponent(&self, id: Uuid, pvp_level: u32) -> bool {
+        let gap = self.matchmaking_level_gap;
+        let clients = self.clients.read_all().await;
+        let available = clients.iter().any(|(other, info)| {
+            *other != id && info.is_challengeable() && info.pvp_level.abs_diff(pvp_level) <= gap
+        });
+        available
+    }
+
+    /// Fight the starter bot for `stake` tokens, as long as nobody else
+    /// is available to fight. A loss costs the stake; a win pays up to
+    /// the stake, but no more than what is left of the daily bot reward
+    /// cap. Bot battles don't raise levels or count as wins or losses.
+    async fn fight_bot(&self, id: Uuid, stake: u64) -> ServerMessage {
+        let Some(bot) = self.bot else {
+            return ServerMessage::error("unknown_target", "player is not connected");
+        };
+        let own_level = {
+            let clients = self.clients.read(&id).await;
+            clients.get(&id).map(|info| info.pvp_level)
+        };
+        let Some(own_level) = own_level else {
+            return ServerMessage::error("unknown_target", "player is not connected");
+        };
+        if self.has_human_opponent(id, own_level).await {
+            let err = "other players are available to fight";
+            return ServerMessage::error("opponent_available", err);
+        }
+        let today = unix_now() / 86_400;
+        // Held throughout so concurrent wins can't overrun the cap.
+        let mut winnings = self.bot_winnings.write().await;
+        let fought = {
+            let mut clients = self.clients.write(&id).await;
+            let Some(info) = clients.get_mut(&id) else {
+                return ServerMessage::error("unknown_target", "player is not connected");
+            };
+            if info.balance < stake {
+                return ServerMessage::error("insufficient_stake", "you cannot cover the stake");
+            }
+            let mut opponent = ClientInfo::new(BOT_NAME.to_owned());
+            opponent.pvp_level = bot.pvp_level;
+            let (winner, loser) = {
+                let mut rng = self
+                    .battle_rng
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner());
+                resolve_battle(
+                    (id, info),
+                    (BOT_ID, &opponent),
+                    self.battle_luck_percent,
+                    &mut *rng,
+                )
+            };
+            let won = winnings.entry(info.account.clone()).or_insert((today, 0));
+            if won.0 != today {
+                *won = (today, 0);
+            }
+            let old = info.balance;
+            let pot = if winner == id {
+                let pot = stake.min(bot.daily_reward_cap.saturating_sub(won.1));
+                won.1 += pot;
+                info.balance = old.saturating_add(pot);
+                pot
+            } else {
+                info.balance = old - stake;
+                stake
+            };
+            let change = AuditChange::Balance {
+                old,
+                new: info.balance,
+            };
+            let audit = (pot > 0).then(|| AuditEvent::new(id, info, change, "bot_battle"));
+            (winner, loser, pot, audit)
+        };
+        drop(winnings);
+        let (winner, loser, pot, audit) = fought;
+        info!("Battle between {} and the bot won by {}", id, winner);
+        self.audit(audit).await;
+        self.persist([id]).await;
+        ServerMessage::BattleResult {
+            battle_id: Uuid::new_v4(),
+            winner,
+            loser,
+            pot,
+            forfeited: false,
+            property: None,
+            bot: true,
+        }
+    }
+
+    /// Take `id` off the matchmaking queue. Returns whether it was queued.
+    async fn leave_matchmaking(&self, id: Uuid) -> bool {
+        let mut queue = self.matchmaking_queue.write().await;
+        let before = queue.len();
+        queue.retain(|(queued, _)| *queued != id);
+        queue.len() != before
+    }
+
+    /// Forget every trade offer sent by or to `id` and return the other
+    /// players of those offers.
+    async fn remove_pending_trades(&self, id: Uuid) -> Vec<Uuid> {
+        let mut pending = self.pending_trades.write().await;
+        pending
+            .extract_if(|(from, target), _| *from == id || *target == id)
+            .map(|((from, target), _)| if from == id { target } else { from })
+            .collect()
+    }
+
+    /// Swap the properties of an accepted trade between both players and
+    /// burn each side's transfer fee, charged on the market price of the
+    /// property they receive. Returns the offerer's and the accepter's
+    /// fee, or why the trade fell through: either side no longer owns
+    /// their property or can't pay their fee.
+    async fn complete_trade(
+        &self,
+        from: Uuid,
+        target: Uuid,
+        offer: &TradeOffer,
+    ) -> Result<[u64; 2], &'static str> {
+        if from == target {
+            return Err("property_unavailable");
+        }
+        let mut clients = self.clients.write_pair(&from, &target).await;
+        let [Some(offerer), Some(accepter)] = clients.get_disjoint_mut([&from, &target]) else {
+            return Err("property_unavailable");
+        };
+        let owned =
+            |info: &ClientInfo, name: &str| info.properties.iter().position(|p| p.name == name);
+        let (Some(given), Some(received)) = (
+            owned(offerer, &offer.offer_property),
+            owned(accepter, &offer.request_property),
+        ) else {
+            return Err("property_unavailable");
+        };
+        let rate = self.transfer_fee_bps.load(Ordering::Relaxed);
+        let fee_on = |property: &Property| {
+            self.marketplace_item(&property.category)
+                .map_or(0, |item| transfer_fee(item.price, rate))
+        };
+        let fees = [
+            fee_on(&accepter.properties[received]),
+            fee_on(&offerer.properties[given]),
+        ];
+        if offerer.balance < fees[0] || accepter.balance < fees[1] {
+            return Err("insufficient_funds");
+        }
+        // A one-for-one swap leaves both inventories the same size, so a
+        // trade can't push anyone past `max_properties`.
+        std::mem::swap(
+            &mut offerer.properties[given],
+            &mut accepter.properties[received],
+        );
+        let mut audit = vec![
+            AuditEvent::new(
+                from,
+                offerer,
+                AuditChange::Property {
+                    old: offer.offer_property.clone(),
+                    new: offer.request_property.clone(),
+                },
+                format!("trade:{}", target),
+            ),
+            AuditEvent::new(
+                target,
+                accepter,
+                AuditChange::Property {
+                    old: offer.request_property.clone(),
+                    new: offer.offer_property.clone(),
+                },
+                format!("trade:{}", from),
+            ),
+        ];
+        for (id, info, fee, partner) in [
+            (from, offerer, fees[0], target),
+            (target, accepter, fees[1], from),
+        ] {
+            if fee == 0 {
+                continue;
+            }
+            let change = AuditChange::Balance {
+                old: info.balance,
+                new: info.balance - fee,
+            };
+            info.balance -= fee;
+            audit.push(AuditEvent::new(
+                id,
+                info,
+                change,
+                format!("trade_fee:{}", partner),
+            ));
+        }
+        drop(clients);
+        self.metrics.tokens_burned.inc_by(fees[0] + fees[1]);
+        self.audit(audit).await;
+        self.persist([from, target]).await;
+        Ok(fees)
+    }
+
+    /// Move tokens or a property from `from` to `target`. Returns what
+    /// was given, or the error to report to the sender.
+    async fn give_gift(
+        &self,
+        from: Uuid,
+        target: Uuid,
+        request: GiftRequest,
+    ) -> Result<Gift, ServerMessage> {
+        if from == target {
+            return Err(ServerMessage::error(
+                "invalid_target",
+                "cannot gift yourself",
+            ));
+        }
+        let mut clients = self.clients.write_pair(&from, &target).await;
+        let [Some(sender), recipient] = clients.get_disjoint_mut([&from, &target]) else {
+            return Err(ServerMessage::error(
+                "unknown_target",
+                "you are not connected",
+            ));
+        };
+        let Some(recipient) = recipient else {
+            return Err(ServerMessage::error(
+                "unknown_target",
+                "player is not connected",
+            ));
+        };
+        let (gift, audit) = match request {
+            GiftRequest::Tokens(amount) => {
+                if amount == 0 {
+                    let err = ServerMessage::error("invalid_amount", "gifts must be at least 1");
+                    return Err(err);
+                }
+                let Some(balance) = sender.balance.checked_sub(amount) else {
+                    let detail = format!("gifting costs {} tokens", amount);
+                    return Err(ServerMessage::error("insufficient_funds", detail));
+                };
+                let sent = AuditChange::Balance {
+                    old: sender.balance,
+                    new: balance,
+                };
+                let fee = transfer_fee(amount, self.transfer_fee_bps.load(Ordering::Relaxed));
+                let amount = amount - fee;
+                let Some(received_balance) = recipient.balance.checked_add(amount) else {
+                    let detail = "the recipient cannot hold that many tokens";
+                    return Err(ServerMessage::error("balance_overflow", detail));
+                };
+                let received = AuditChange::Balance {
+                    old: recipient.balance,
+                    new: received_balance,
+                };
+                sender.balance = balance;
+                recipient.balance = received_balance;
+                self.metrics.tokens_burned.inc_by(fee);
+                (Gift::Tokens { amount, fee }, [sent, received])
+            }
+            GiftRequest::Property(name) => {
+                let Some(index) = sender.properties.iter().position(|p| p.name == name) else {
+                    let err = ServerMessage::error("not_owned", "you do not own that property");
+                    return Err(err);
+                };
+                let max_properties = self.max_properties;
+                if recipient.properties.len() >= max_properties {
+                    let detail = format!(
+                        "the recipient already owns the limit of {} properties",
+                        max_properties
+                    );
+                    return Err(ServerMessage::error("inventory_full", detail));
+                }
+                let sent = AuditChange::Properties {
+                    old: sender.properties.len(),
+                    new: sender.properties.len() - 1,
+                };
+                let received = AuditChange::Properties {
+                    old: recipient.properties.len(),
+                    new: recipient.properties.len() + 1,
+                };
+                let property = sender.properties.remove(index);
+                recipient.properties.push(property.clone());
+                (Gift::Property { property }, [sent, received])
+            }
+        };
+        let [sent, received] = audit;
+        let audit = [
+            AuditEvent::new(from, sender, sent, format!("gift_to:{}", target)),
+            AuditEvent::new(target, recipient, received, format!("gift_from:{}", from)),
+        ];
+        drop(clients);
+        self.audit(audit).await;
+        self.persist([from, target]).await;
+        Ok(gift)
+    }
+
+    /// Reserve a session slot, or return `None` when the server is at
+    /// capacity.
+    fn acquire_session(&self) -> Option<SessionPermit> {
+        self.live_sessions
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| {
+                (live < self.max_sessions).then_some(live + 1)
+            })
+            .ok()
+            .map(|_| SessionPermit {
+                live_sessions: self.live_sessions.clone(),
+            })
+    }
+
+    /// Recompute the reward multiplier for the given UTC hour. Returns
+    /// the event notification to push to players if a window opened or
+    /// closed.
+    fn refresh_reward_multiplier(&self, hour: u32) -> Option<ServerMessage> {
+        let multiplier = reward_multiplier_at(&self.reward_events, hour);
+        let previous = self.reward_multiplier.swap(multiplier, Ordering::Relaxed);
+        if multiplier == previous {
+            None
+        } else if multiplier == 100 {
+            Some(ServerMessage::EventEnded)
+        } else {
+            Some(ServerMessage::EventStarted {
+                multiplier_percent: multiplier,
+            })
+        }
+    }
+
+    /// Deliver a server push to another connected session. Every
+    /// cross-session message goes through here so delivery rules are
+    /// applied in one place: pushes the recipient doesn't accept (see
+    /// `ClientInfo::accepts`) are dropped. Returns whether the message was
+    /// handed to the recipient's mailbox.
+    async fn deliver(&self, to: Uuid, msg: ServerMessage) -> bool {
+        let addr = {
+            let clients = self.clients.read(&to).await;
+            match clients.get(&to) {
+                Some(info) if !info.accepts(&msg) => return false,
+                Some(info) => info.addr.clone(),
+                None => None,
+            }
+        };
+        let Some(addr) = addr else {
+            // Kept for the client to catch up on if it resumes.
+            if let Some((info, _)) = self.disconnected.read().await.get(&to) {
+                if info.accepts(&msg) {
+                    let mut outbox = info.outbox.lock().unwrap_or_else(|err| err.into_inner());
+                    outbox.push(msg, None);
+                }
+            }
+            return false;
+        };
+        let delivered = addr.try_send(msg).is_ok();
+        if !delivered {
+            self.metrics.dropped_outbound.inc();
+        }
+        delivered
+    }
+
+    /// Tell every client the server is going away and save all connected
+    /// players.
+    async fn shutdown(&self, reason: &str) {
+        self.ready.store(false, Ordering::Release);
+        let notice = ServerMessage::ServerShutdown {
+            reason: reason.to_owned(),
+        };
+        let recipients = self.broadcast(notice, None).await;
+        info!("Shutdown notice sent to {} clients", recipients);
+        let ids = self.clients.ids().await;
+        self.persist(ids).await;
+    }
+
+    /// Push `msg` to every connected session except `skip` and return how
+    /// many it was delivered to.
+    async fn broadcast(&self, msg: ServerMessage, skip: Option<Uuid>) -> usize {
+        self.broadcast_where(msg, |id, _| Some(*id) != skip).await
+    }
+
+    /// Send `msg` to the members of a chat channel. Members whose mailbox
+    /// is full miss the message rather than hold up the sender, and are
+    /// counted in the per-session dropped chat metric.
+    async fn broadcast_to_channel(&self, channel: &str, msg: ServerMessage) -> usize {
+        let (recipients, dropped) = self
+            .fan_out(msg, |_, info| info.channels.iter().any(|c| c == channel))
+            .await;
+        for id in dropped {
+            let session = id.to_string();
+            self.metrics
+                .chat_dropped
+                .with_label_values(&[&session])
+                .inc();
+        }
+        recipients
+    }
+
+    /// Send `msg` to every connected client `include` accepts, leaving
+    /// out those who don't take it, as `deliver` does. Returns how many
+    /// clients it was handed to.
+    async fn broadcast_where<F>(&self, msg: ServerMessage, include: F) -> usize
+    where
+        F: Fn(&Uuid, &ClientInfo) -> bool,
+    {
+        self.fan_out(msg, include).await.0
+    }
+
+    /// Like `broadcast_where`, also returning the sessions the message
+    /// was dropped for.
+    async fn fan_out<F>(&self, msg: ServerMessage, include: F) -> (usize, Vec<Uuid>)
+    where
+        F: Fn(&Uuid, &ClientInfo) -> bool,
+    {
+        // Snapshot the addresses so the lock isn't held while sending.
+        // Sessions that haven't registered an address yet are skipped.
+        let addrs: Vec<(Uuid, Addr<WsSession>)> = {
+            let clients = self.clients.read_all().await;
+            clients
+                .iter()
+                .filter(|(id, info)| include(id, info) && info.accepts(&msg))
+                .filter_map(|(id, info)| Some((*id, info.addr.clone()?)))
+                .collect()
+        };
+        // Clients may disconnect between the snapshot and the send; their
+        // mailbox is closed by then so `try_send` fails and they are simply
+        // not counted. A full mailbox fails the same way.
+        let dropped: Vec<Uuid> = addrs
+            .iter()
+            .filter(|(_, addr)| addr.try_send(msg.clone()).is_err())
+            .map(|(id, _)| *id)
+            .collect();
+        self.metrics.dropped_outbound.inc_by(dropped.len() as u64);
+        (addrs.len() - dropped.len(), dropped)
+    }
+
+    /// Record a report filed by `reporter` against `target` for moderator
+    /// review. Returns the acknowledgement or the reason the report was
+    /// refused.
+    async fn file_report(
+        &self,
+        reporter: Uuid,
+        target: Uuid,
+        reason: &str,
+        details: &str,
+    ) -> ServerMessage {
+        if target == reporter {
+            return ServerMessage::error("invalid_target", "cannot report yourself");
+        }
+        if reason.is_empty()
+            || reason.len() > MAX_REPORT_REASON_LEN
+            || details.len() > MAX_REPORT_DETAILS_LEN
+        {
+            return ServerMessage::error("invalid_report", "reason is missing or too long");
+        }
+        let names = {
+            let clients = self.clients.read_all().await;
+            clients.get(&target).map(|info| {
+                let reporter_name = clients.get(&reporter).map(|c| c.username.clone());
+                (reporter_name.unwrap_or_default(), info.username.clone())
+            })
+        };
+        let Some((reporter_name, target_name)) = names else {
+            return ServerMessage::error("unknown_target", "player is not connected");
+        };
+        let now = unix_now();
+        let _filing = self.filing_report.lock().await;
+        // Saturating, so a clock that steps back can't underflow.
+        let since = now.saturating_sub(REPORT_WINDOW_SECS - 1);
+        let recent = match self.storage.count_reports_since(reporter, since).await {
+            Ok(recent) => recent,
+            Err(err) => {
+                error!("Failed to count reports by {}: {}", reporter, err);
+                return ServerMessage::error("storage_unavailable", "try again later");
+            }
+        };
+        if recent >= MAX_REPORTS_PER_WINDOW {
+            return ServerMessage::error("rate_limited", "too many reports, try again later");
+        }
+        let report = PlayerReport {
+            reporter,
+            reporter_name,
+            target,
+            target_name,
+            reason: reason.to_owned(),
+            details: details.to_owned(),
+            timestamp: now,
+        };
+        if let Err(err) = self.storage.save_report(&report).await {
+            error!("Failed to save a report by {}: {}", reporter, err);
+            return ServerMessage::error("storage_unavailable", "try again later");
+        }
+        info!("Client {} reported {} for {}", reporter, target, reason);
+        ServerMessage::ReportReceived { target }
+    }
+
+    /// End the current season: archive the standings of all connected
+    /// players, reset them to starting values according to `scope` and
+    /// return the number of the season that was closed.
+    async fn reset_season(&self, scope: SeasonResetScope) -> u32 {
+        // Players who are offline only exist in storage and are reset
+        // there, so the season applies to everyone.
+        let stored = match self.storage.list_players().await {
+            Ok(players) => players,
+            Err(err) => {
+                error!("Failed to list stored players for season reset: {}", err);
+                Vec::new()
+            }
+        };
+        let mut seasons = self.seasons.write().await;
+        let mut clients = self.clients.write_all().await;
+        let mut audit = Vec::new();
+        let online: HashSet<&str> = clients.values().map(|info| info.account.as_str()).collect();
+        let mut offline: Vec<StoredPlayer> = stored
+            .into_iter()
+            .filter(|player| !online.contains(player.username.as_str()))
+            .collect();
+        let mut standings: Vec<SeasonStanding> = clients
+            .values()
+            .map(|info| SeasonStanding {
+                username: info.username.clone(),
+                pvp_level: info.pvp_level,
+                daily_reward: daily_reward(&info.properties),
+            })
+            .chain(offline.iter().map(|player| {
+                SeasonStanding {
+                    username: player
+                        .display_name
+                        .clone()
+                        .unwrap_or_else(|| player.username.clone()),
+                    pvp_level: player.pvp_level,
+                    daily_reward: daily_reward(&player.properties),
+                }
+            }))
+            .collect();
+        standings.sort_by(|a, b| {
+            b.pvp_level
+                .cmp(&a.pvp_level)
+                .then_with(|| b.daily_reward.cmp(&a.daily_reward))
+                .then_with(|| a.username.cmp(&b.username))
+        });
+        for (id, info) in clients.iter_mut() {
+            info.pvp_level = 1;
+            (info.wins, info.losses) = (0, 0);
+            if let SeasonResetScope::Economy = scope {
+                let old = info.properties.len();
+                info.properties.clear();
+                let change = AuditChange::Properties { old, new: 0 };
+                audit.push(AuditEvent::new(*id, info, change, "season_reset"));
+                let old = std::mem::replace(&mut info.balance, self.starting_balance);
+                let change = AuditChange::Balance {
+                    old,
+                    new: self.starting_balance,
+                };
+                audit.push(AuditEvent::new(*id, info, change, "season_reset"));
+            }
+        }
+        for player in &mut offline {
+            player.pvp_level = 1;
+            (player.wins, player.losses) = (0, 0);
+            if let SeasonResetScope::Economy = scope {
+                player.properties.clear();
+                player.balance = self.starting_balance;
+            }
+        }
+        let ids: Vec<Uuid> = clients.keys().copied().collect();
+        drop(clients);
+        let season = seasons.last().map_or(1, |archive| archive.season + 1);
+        let archive = SeasonArchive { season, standings };
+        if let Err(err) = self.storage.save_season(&archive).await {
+            error!("Failed to save the archive of season {}: {}", season, err);
+        }
+        seasons.push(archive);
+        drop(seasons);
+        self.audit(audit).await;
+        self.persist(ids).await;
+        self.save_players(&offline).await;
+        season
+    }
+
+    /// Overwrite a connected player's properties, balance and PvP level,
+    /// as an admin reset or seed does. Returns `None` if the player is not
+    /// connected.
+    async fn overwrite_player(
+        &self,
+        target: Uuid,
+        properties: Vec<Property>,
+        balance: u64,
+        pvp_level: u32,
+        reason: &str,
+    ) -> Option<()> {
+        let audit = {
+            let mut clients = self.clients.write(&target).await;
+            let info = clients.get_mut(&target)?;
+            let change = AuditChange::Properties {
+                old: info.properties.len(),
+                new: properties.len(),
+            };
+            let mut audit = vec![AuditEvent::new(target, info, change, reason)];
+            let old = std::mem::replace(&mut info.balance, balance);
+            let change = AuditChange::Balance { old, new: balance };
+            audit.push(AuditEvent::new(target, info, change, reason));
+            info.properties = properties;
+            info.pvp_level = pvp_level;
+            audit
+        };
+        self.audit(audit).await;
+        self.persist([target]).await;
+        Some(())
+    }
+
+    /// Check the `Authorization: Bearer <token>` header of an admin
+    /// request against the configured admin token.
+    fn is_admin_request(&self, req: &HttpRequest) -> bool {
+        let Some(expected) = &self.admin_token else {
+            return false;
+        };
+        req.headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+    }
+}
+
+/// Compare two secrets without exiting at the first differing byte, so
+/// the time taken doesn't reveal how much of a guess was right. Only the
+/// length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The WebSocket session actor. Each connected client is represented by
+/// its own instance of `WsSession`. It stores its unique id and a
+/// clone of the shared server state. Messages sent and received over
+/// the WebSocket are processed within the actor's context.
+struct WsSession {
+    id: Uuid,
+    state: ServerState,
+    metadata: SessionMetadata,
+    /// Sends that failed in a row. Reset by every successful send.
+    send_failures: Cell<u32>,
+    /// When the client last sent anything, including pongs.
+    last_heartbeat: Instant,
+    /// Limits how fast the client may send requests.
+    rate_limiter: TokenBucket,
+    /// Requests rejected by the rate limiter in a row.
+    rate_violations: u32,
+    /// Secret the client can present to resume this session.
+    resume_token: Uuid,
+    /// Encoding negotiated for this connection.
+    format: WireFormat,
+    /// WebSocket subprotocol agreed in the handshake, if any.
+    subprotocol: Option<&'static str>,
+    /// Closes the session if it doesn't authenticate in time.
+    auth_timer: Option<SpawnHandle>,
+    /// Numbers and keeps what is sent; carried over when resuming.
+    outbox: Arc<std::sync::Mutex<Outbox>>,
+    /// Last `seq` the client of a resumed session saw. What it missed is
+    /// replayed ahead of the `welcome`.
+    replay_after: Option<u64>,
+    /// Parent span of everything logged for this session.
+    span: tracing::Span,
+    _permit: SessionPermit,
+}
+
+impl WsSession {
+    fn new(
+        id: Uuid,
+        resume_token: Uuid,
+        format: WireFormat,
+        subprotocol: Option<&'static str>,
+        state: ServerState,
+        metadata: SessionMetadata,
+        permit: SessionPermit,
+    ) -> Self {
+        let rate_limiter = TokenBucket::new(state.message_rate, state.message_burst);
+        Self {
+            id,
+            resume_token,
+            format,
+            subprotocol,
+            state,
+            metadata,
+            send_failures: Cell::new(0),
+            last_heartbeat: Instant::now(),
+            rate_limiter,
+            rate_violations: 0,
+            auth_timer: None,
+            outbox: Arc::default(),
+            replay_after: None,
+            span: tracing::info_span!("session", session_id = %id),
+            _permit: permit,
+        }
+    }
+
+    /// Send a push to the client.
+    fn send_json(&self, ctx: &mut ws::WebsocketContext<Self>, message: &ServerMessage) {
+        self.send_reply(ctx, message, None);
+    }
+
+    /// Send a message to the client, numbered with the session's next
+    /// `seq` and kept for replay.
+    fn send_reply(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        message: &ServerMessage,
+        request_id: Option<u64>,
+    ) {
+        let seq = self
+            .outbox
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(message.clone(), request_id);
+        let reply = Reply {
+            message,
+            request_id,
+            seq,
+        };
+        self.send_frame(ctx, &reply);
+    }
+
+    /// Helper to send JSON responses to the connected client. If
+    /// serialization fails or the socket is already closing the message
+    /// is dropped and counted. After too many consecutive failures the
+    /// session is stopped so it doesn't linger in the roster.
+    /// Messages go out as JSON text frames, or as MessagePack binary
+    /// frames on sessions that negotiated it.
+    fn send_frame<T: Serialize>(&self, ctx: &mut ws::WebsocketContext<Self>, payload: &T) {
+        if !ctx.state().alive() {
+            self.record_send_failure(ctx);
+            return;
+        }
+        let encoded = match self.format {
+            WireFormat::Json if self.state.pretty_json => serde_json::to_string_pretty(payload)
+                .map(|text| ctx.text(text))
+                .map_err(|err| err.to_string()),
+            WireFormat::Json => serde_json::to_string(payload)
+                .map(|text| ctx.text(text))
+                .map_err(|err| err.to_string()),
+            WireFormat::MessagePack => encode_msgpack(payload)
+                .map(|bytes| ctx.binary(bytes))
+                .map_err(|err| err.to_string()),
+        };
+        match encoded {
+            Ok(()) => self.send_failures.set(0),
+            Err(err) => {
+                error!("Failed to serialize response: {}", err);
+                self.record_send_failure(ctx);
+            }
+        }
+    }
+
+    /// Resend the kept messages after `after`. They keep their `seq`, so
+    /// this runs before anything new is numbered.
+    fn replay(&self, ctx: &mut ws::WebsocketContext<Self>, after: u64) {
+        let (missed, incomplete) = self
+            .outbox
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .since(after);
+        for sent in &missed {
+            let reply = Reply {
+                message: &sent.message,
+                request_id: sent.request_id,
+                seq: sent.seq,
+            };
+            self.send_frame(ctx, &reply);
+        }
+        if incomplete {
+            let detail = "some messages are no longer available to replay";
+            self.send_json(ctx, &ServerMessage::error("replay_incomplete", detail));
+        }
+    }
+
+    /// Take a token from the rate limiter. Returns false, after telling the
+    /// client, if the request must be dropped; persistent flooding stops
+    /// the session.
+    fn admit_request(&mut self, ctx: &mut ws::WebsocketContext<Self>, cost: u32) -> bool {
+        if self.rate_limiter.try_take(Instant::now(), cost) {
+            self.rate_violations = 0;
+            return true;
+        }
+        self.rate_violations += 1;
+        if self.rate_violations >= MAX_RATE_LIMIT_VIOLATIONS {
+            warn!("Closing client {} for flooding", self.id);
+            ctx.stop();
+            return false;
+        }
+        let err = ServerMessage::error("rate_limited", "too many messages, slow down");
+        self.send_json(ctx, &err);
+        false
+    }
+
+    /// Turn a message in its generic form into a request: check its
+    /// protocol version, upgrade older shapes and deserialize it.
+    fn decode_request(&self, mut value: serde_json::Value) -> Result<ClientRequest, ServerMessage> {
+        upgrade_message(&mut value)?;
+        serde_json::from_value::<ClientRequest>(value.clone()).map_err(|err| {
+            error!("Invalid message from client {}: {}", self.id, err);
+            ServerMessage::error("bad_request", describe_invalid_message(&value.to_string()))
+        })
+    }
+
+    /// Run a decoded request, or report why it could not be decoded.
+    fn dispatch(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        parsed: Result<ClientRequest, ServerMessage>,
+    ) {
+        match parsed {
+            Ok(ClientRequest { request_id, msg }) => {
+                let kind = msg.kind();
+                let received = &self.state.metrics.messages_received;
+                received.with_label_values(&[kind]).inc();
+                // The handler runs on a detached session handle and
+                // the replies are sent once it resolves. `ctx.wait`
+                // holds back further frames until then, so requests
+                // are answered in the order they arrive.
+                let fut = self.session_handle(ctx).handle_request(msg);
+                ctx.wait(fut.into_actor(self).map(move |replies, act, ctx| {
+                    let authenticated = replies
+                        .iter()
+                        .any(|reply| matches!(reply, ServerMessage::Authenticated { .. }));
+                    if let Some(timer) = act.auth_timer.take_if(|_| authenticated) {
+                        ctx.cancel_future(timer);
+                    }
+                    for message in &replies {
+                        act.send_reply(ctx, message, request_id);
+                    }
+                }));
+            }
+            Err(payload) => self.send_json(ctx, &payload),
+        }
+    }
+
+    /// Stop the session unless it has authenticated by now. Resumed
+    /// sessions are registered without authenticating again, so this
+    /// checks the client map rather than relying on the timer alone.
+    fn enforce_auth_timeout(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.auth_timer = None;
+        let (state, id) = (self.state.clone(), self.id);
+        let registered = async move { state.clients.contains_key(&id).await };
+        ctx.wait(registered.into_actor(self).map(|registered, act, ctx| {
+            if registered {
+                return;
+            }
+            let _span = act.span.clone().entered();
+            info!("Client {} did not authenticate in time", act.id);
+            let err = ServerMessage::error("auth_timeout", "authenticate sooner after connecting");
+            act.send_json(ctx, &err);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("auth_timeout".into()),
+            }));
+            ctx.stop();
+        }));
+    }
+
+    /// Create a handle on this session for running request handlers.
+    fn session_handle(&self, ctx: &ws::WebsocketContext<Self>) -> SessionHandle {
+        SessionHandle {
+            id: self.id,
+            state: self.state.clone(),
+            addr: ctx.address(),
+            metadata: self.metadata.clone(),
+            resume_token: self.resume_token,
+            outbox: self.outbox.clone(),
+        }
+    }
+
+    /// Count a dropped outbound message and stop the session once the
+    /// consecutive failure threshold is reached.
+    fn record_send_failure(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.state.metrics.dropped_outbound.inc();
+        let failures = self.send_failures.get() + 1;
+        self.send_failures.set(failures);
+        if failures >= MAX_CONSECUTIVE_SEND_FAILURES && ctx.state().alive() {
+            error!(
+                "Closing client {} after {} consecutive send failures",
+                self.id, failures
+            );
+            ctx.stop();
+        }
+    }
+}
+
+/// Token bucket rate limiter: holds up to `capacity` tokens, refilled
+/// continuously at `refill_per_sec`, and each request takes one.
+struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        let capacity = f64::from(burst.max(1));
+        Self {
+            capacity,
+            refill_per_sec: f64::from(rate),
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `cost` tokens at time `now`, or none and return false if
+    /// fewer are left.
+    fn try_take(&mut self, now: Instant, cost: u32) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= f64::from(cost) {
+            self.tokens -= f64::from(cost);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A detached handle on a session carrying everything a request handler
+/// needs. Handlers run as futures outside the actor, so they operate on
+/// this clone instead of borrowing the `WsSession`.
+#[derive(Clone)]
+struct SessionHandle {
+    id: Uuid,
+    state: ServerState,
+    addr: Addr<WsSession>,
+    metadata: SessionMetadata,
+    resume_token: Uuid,
+    outbox: Arc<std::sync::Mutex<Outbox>>,
+}
+
+impl SessionHandle {
+    /// Give `target` a gift and tell them about it.
+    async fn gift(&self, target: Uuid, request: GiftRequest) -> Vec<ServerMessage> {
+        let gift = match self.state.give_gift(self.id, target, request).await {
+            Ok(gift) => gift,
+            Err(err) => return vec![err],
+        };
+        info!("{} gave {} a gift: {:?}", self.id, target, gift);
+        let received = ServerMessage::GiftReceived {
+            from: self.id,
+            kind: gift.clone(),
+        };
+        self.state.deliver(target, received).await;
+        vec![ServerMessage::GiftAck { target, kind: gift }]
+    }
+
+    /// Charge the category price and grant a new property with the
+    /// category's reward. At the inventory cap the purchase is refused,
+    /// or in prompt mode held until the player frees enough slots.
+    async fn purchase(
+        &self,
+        item_id: String,
+        category: String,
+        idempotency_key: Option<String>,
+    ) -> Vec<ServerMessage> {
+        let Some(item) = self.state.marketplace_item(&category) else {
+            let reason = "unknown_category".to_owned();
+            return vec![ServerMessage::PurchaseFailed { item_id, reason }];
+        };
+        let (price, reward) = (item.price, item.reward);
+        let name = format!("{} Item", category);
+        let granted = {
+            let mut clients = self.state.clients.write(&self.id).await;
+            let Some(info) = clients.get_mut(&self.id) else {
+                return Vec::new();
+            };
+            let now = Instant::now();
+            info.recent_purchases
+                .retain(|_, (_, at)| now.duration_since(*at) < IDEMPOTENCY_KEY_TTL);
+            match self
+                .state
+                .purchase_block(info, price, idempotency_key.as_ref(), now)
+            {
+                Some(PurchaseBlock::Replayed(reply) | PurchaseBlock::Refused(reply)) => {
+                    return vec![reply];
+                }
+                Some(PurchaseBlock::MustFree(must_free)) => {
+                    info.held_purchase = Some(HeldPurchase {
+                        item_id: item_id.clone(),
+                        category,
+                        idempotency_key,
+                    });
+                    return vec![ServerMessage::InventoryFull { item_id, must_free }];
+                }
+                None => (),
+            }
+            match info.balance.checked_sub(price) {
+                Some(balance) => {
+                    let old_balance = std::mem::replace(&mut info.balance, balance);
+                    info.properties.push(Property {
+                        name,
+                        category: category.clone(),
+                        reward,
+                        level: 1,
+                    });
+                    if let Some(key) = idempotency_key {
+                        let ack = ServerMessage::PurchaseAck {
+                            item_id: item_id.clone(),
+                            balance,
+                        };
+                        info.recent_purchases.insert(key, (ack, now));
+                    }
+                    let count = info.properties.len();
+                    let reason = format!("purchase:{}", item_id);
+                    let properties = AuditChange::Properties {
+                        old: count - 1,
+                        new: count,
+                    };
+                    let charge = AuditChange::Balance {
+                        old: old_balance,
+                        new: balance,
+                    };
+                    let audit = [
+                        AuditEvent::new(self.id, info, properties, reason.clone()),
+                        AuditEvent::new(self.id, info, charge, reason),
+                    ];
+                    let names = (info.account.clone(), info.username.clone());
+                    Some((names, balance, audit))
+                }
+                None => None,
+            }
+        };
+        let Some(((account, username), balance, audit)) = granted else {
+            let reason = "insufficient_funds".to_owned();
+            return vec![ServerMessage::PurchaseFailed { item_id, reason }];
+        };
+        {
+            self.state.audit(audit).await;
+            self.state.persist([self.id]).await;
+            self.state.publish(GameEvent::Purchase {
+                id: self.id,
+                account: account.clone(),
+                item_id: item_id.clone(),
+                category: category.clone(),
+                price,
+            });
+            let event = PlayerEvent::Purchased { category };
+            self.state.notify_watchers(self.id, username, event).await;
+        }
+        // Acknowledge the purchase to the client.
+        self.state.metrics.purchases_completed.inc();
+        let minted = self.state.mint_reward(&account, u64::from(reward)).await;
+        let ack = ServerMessage::PurchaseAck { item_id, balance };
+        std::iter::once(ack).chain(minted).collect()
+    }
+
+    /// Retry the purchase held at the inventory cap, if any, now that
+    /// the player may have freed a slot.
+    async fn complete_held_purchase(&self) -> Vec<ServerMessage> {
+        let held = {
+            let mut clients = self.state.clients.write(&self.id).await;
+            clients
+                .get_mut(&self.id)
+                .and_then(|info| info.held_purchase.take())
+        };
+        match held {
+            Some(HeldPurchase {
+                item_id,
+                category,
+                idempotency_key,
+            }) => self.purchase(item_id, category, idempotency_key).await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether this session's player is a moderator.
+    async fn is_admin(&self) -> bool {
+        let clients = self.state.clients.read(&self.id).await;
+        clients.get(&self.id).is_some_and(|info| info.is_admin)
+    }
+
+    /// Run `handle_client_message` in a span carrying the message type and
+    /// target, and log how it went.
+    #[instrument(
+        name = "request",
+        skip_all,
+        fields(kind = msg.kind(), target_id = msg.target().map(tracing::field::display))
+    )]
+    async fn handle_request(self, msg: ClientMessage) -> Vec<ServerMessage> {
+        let replies = self.handle_client_message(msg).await;
+        let outcome = replies
+            .iter()
+            .find_map(|reply| match reply {
+                ServerMessage::Error { code, .. } => Some(code.as_str()),
+                _ => None,
+            })
+            .unwrap_or("ok");
+        debug!(outcome, replies = replies.len(), "request handled");
+        replies
+    }
+
+    /// Handle an incoming JSON message from the client and return the
+    /// replies to send back. The protocol is
+    /// structured around a `type` field which determines the kind of
+    /// request. Additional data is embedded in the message. See the
+    /// documentation of each match arm for details.
+    ///
+    /// A session is only registered in the client map once it has
+    /// authenticated; until then every message except `hello` and
+    /// `authenticate` is rejected.
+    async fn handle_client_message(self, msg: ClientMessage) -> Vec<ServerMessage> {
+        let authenticated = self.state.clients.contains_key(&self.id).await;
+        let public = matches!(
+            msg,
+            ClientMessage::Hello | ClientMessage::Authenticate { .. }
+        );
+        if !authenticated && !public {
+            let err =
+                ServerMessage::error("unauthenticated", "authenticate before sending requests");
+            return vec![err];
+        }
+        if let Some(feature) = msg.feature() {
+            if !self.state.features.is_enabled(feature) {
+                let detail = format!("{} is disabled on this server", feature);
+                return vec![ServerMessage::error("feature_disabled", detail)];
+            }
+        }
+        match msg {
+            ClientMessage::Batch { messages } => {
+                if messages.len() > MAX_BATCH_LEN {
+                    let detail = format!("batches are limited to {} messages", MAX_BATCH_LEN);
+                    return vec![ServerMessage::error("batch_too_large", detail)];
+                }
+                if messages
+                    .iter()
+                    .any(|msg| matches!(msg, ClientMessage::Batch { .. }))
+                {
+                    let err = ServerMessage::error("nested_batch", "batches cannot be nested");
+                    return vec![err];
+                }
+                // One at a time, so each message sees the effects of the
+                // ones before it.
+                let mut results = Vec::with_capacity(messages.len());
+                let received = &self.state.metrics.messages_received;
+                for msg in messages {
+                    received.with_label_values(&[msg.kind()]).inc();
+                    let replies = Box::pin(self.clone().handle_client_message(msg)).await;
+                    results.push(replies);
+                }
+                vec![ServerMessage::BatchResult { results }]
+            }
+            ClientMessage::Hello => vec![ServerMessage::ServerInfo {
+                protocol_version: PROTOCOL_VERSION,
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+                features: self.state.features.names(),
+            }],
+            ClientMessage::Authenticate { token } => {
+                if authenticated {
+                    let err = ServerMessage::error(
+                        "already_authenticated",
+                        "session is already authenticated",
+                    );
+                    return vec![err];
+                }
+                let Some(identity) = self.state.auth.authenticate(&token).await else {
+                    info!("Client {} presented an invalid token", self.id);
+                    let err = ServerMessage::error("invalid_token", "token was not accepted");
+                    return vec![err];
+                };
+                if !self.state.begin_login(&identity.username).await {
+                    info!(
+                        "Client {} tried to log in to {} twice",
+                        self.id, identity.username
+                    );
+                    let detail = "account is already signed in on another connection";
+                    return vec![ServerMessage::error("already_connected", detail)];
+                }
+                let stored = match self.state.storage.load_player(&identity.username).await {
+                    Ok(stored) => stored,
+                    Err(err) => {
+                        self.state.finish_login(&identity.username).await;
+                        // Registering defaults now would overwrite the
+                        // saved player on the next write.
+                        error!("Failed to load player {}: {}", identity.username, err);
+                        let err = ServerMessage::error("storage_unavailable", "try again later");
+                        return vec![err];
+                    }
+                };
+                // Register the session so other clients can message it.
+                let mut info = ClientInfo::new(identity.username.clone());
+                match stored {
+                    Some(stored) => info.restore(stored),
+                    // Only players who were never saved get the starter kit.
+                    None => {
+                        info.balance = self.state.starting_balance;
+                        info.properties = self.state.starter_properties.to_vec();
+                        info.dirty = true;
+                    }
+                }
+                // Tokens decide the account name, so don't show it as is.
+                info.username = sanitize_username(&info.username).unwrap_or_else(|| {
+                    warn!(
+                        "Unusable username from {}, using a default",
+                        identity.username
+                    );
+                    fallback_username(self.id)
+                });
+                info.resume_token = Some(self.resume_token);
+                info.outbox = self.outbox.clone();
+                info.is_admin = identity.is_admin;
+                info.addr = Some(self.addr.clone());
+                info.metadata = self.metadata.clone();
+                let pvp_level = info.pvp_level;
+                let username = info.username.clone();
+                self.state.clients.insert(self.id, info).await;
+                self.state.forget_disconnected(&identity.username).await;
+                self.state.finish_login(&identity.username).await;
+                self.state.decay_idle_rewards(self.id).await;
+                // Credit what the properties earned while offline.
+                self.state.accrue(self.id).await;
+                self.state
+                    .notify_friends(self.id, &identity.username, &username, true)
+                    .await;
+                self.state.publish(GameEvent::PlayerJoined {
+                    id: self.id,
+                    account: identity.username.clone(),
+                    username: username.clone(),
+                });
+                info!("Client {} authenticated as {}", self.id, identity.username);
+                let joined = ServerMessage::PlayerJoined {
+                    id: self.id,
+                    username: username.clone(),
+                    pvp_level,
+                };
+                self.state.broadcast(joined, Some(self.id)).await;
+                vec![ServerMessage::Authenticated {
+                    session_id: self.id,
+                    username,
+                }]
+            }
+            ClientMessage::SetUsername { username } => {
+                let username = sanitize_username(&username).unwrap_or_default();
+                if let Err(detail) = validate_username(&username) {
+                    return vec![ServerMessage::error("invalid_username", detail)];
+                }
+                // Offline players keep their names. They can't rename until
+                // they log in, and then they are checked in memory below.
+                let stored = match self.state.storage.find_by_name(&username).await {
+                    Ok(stored) => stored,
+                    Err(err) => {
+                        error!("Failed to look up username {}: {}", username, err);
+                        let err = ServerMessage::error("storage_unavailable", "try again later");
+                        return vec![err];
+                    }
+                };
+                {
+                    // Lock every shard so nobody else can take the name
+                    // between the check and the rename.
+                    let mut clients = self.state.clients.write_all().await;
+                    let taken = clients.iter().any(|(id, info)| {
+                        *id != self.id
+                            && (info.username.eq_ignore_ascii_case(&username)
+                                || info.account.eq_ignore_ascii_case(&username))
+                    });
+                    // Connected players were judged by their current name.
+                    let taken = taken
+                        || stored
+                            .iter()
+                            .any(|account| !clients.values().any(|info| info.account == *account));
+                    if taken {
+                        drop(clients);
+                        let err = ServerMessage::error("username_taken", "name is already in use");
+                        return vec![err];
+                    }
+                    let Some(info) = clients.get_mut(&self.id) else {
+                        return Vec::new();
+                    };
+                    info.username = username.clone();
+                }
+                self.state.persist([self.id]).await;
+                let renamed = ServerMessage::PlayerRenamed {
+                    id: self.id,
+                    username: username.clone(),
+                };
+                self.state.broadcast(renamed, Some(self.id)).await;
+                vec![ServerMessage::UsernameChanged { username }]
+            }
+            ClientMessage::KickPlayer { target } => {
+                if !self.is_admin().await {
+                    return vec![ServerMessage::error("forbidden", "admins only")];
+                }
+                let kicked = {
+                    let mut clients = self.state.clients.write(&target).await;
+                    clients.get_mut(&target).map(|info| {
+                        // A kicked player must log in again.
+                        info.resume_token = None;
+                    })
+                };
+                if kicked.is_none() {
+                    let err = ServerMessage::error("unknown_target", "player is not connected");
+                    return vec![err];
+                }
+                warn!("Admin {} kicked {}", self.id, target);
+                let reason = "kicked by a moderator".to_owned();
+                // The session closes itself once the notice is sent.
+                self.state
+                    .deliver(target, ServerMessage::Kicked { reason })
+                    .await;
+                vec![ServerMessage::AdminActionDone {
+                    action: "kick".to_owned(),
+                    target,
+                }]
+            }
+            ClientMessage::ListDisconnected => {
+                if !self.is_admin().await {
+                    return vec![ServerMessage::error("forbidden", "admins only")];
+                }
+                let sessions = self.state.list_disconnected().await;
+                vec![ServerMessage::DisconnectedList { sessions }]
+            }
+            ClientMessage::ReapSession { id } => {
+                if !self.is_admin().await {
+                    return vec![ServerMessage::error("forbidden", "admins only")];
+                }
+                if !self.state.disconnected.read().await.contains_key(&id) {
+                    let err = ServerMessage::error("unknown_session", "session is not resumable");
+                    return vec![err];
+                }
+                // Settle while the session is still parked, so its stakes
+                // are refunded to storage, as when the grace period ends.
+                self.state.abandon_session(id).await;
+                self.state.disconnected.write().await.remove(&id);
+                warn!("Admin {} reaped disconnected session {}", self.id, id);
+                vec![ServerMessage::Reaped { id }]
+            }
+            ClientMessage::GrantTokens { target, amount } => {
+                if !self.is_admin().await {
+                    return vec![ServerMessage::error("forbidden", "admins only")];
+                }
+                let granted = {
+                    let mut clients = self.state.clients.write(&target).await;
+                    clients.get_mut(&target).map(|info| {
+                        let old = info.balance;
+                        info.balance = info.balance.saturating_add(amount);
+                        let change = AuditChange::Balance {
+                            old,
+                            new: info.balance,
+                        };
+                        let reason = format!("admin_grant:{}", self.id);
+                        let audit = AuditEvent::new(target, info, change, reason);
+                        (info.balance, audit)
+                    })
+                };
+                let Some((new_balance, audit)) = granted else {
+                    let err = ServerMessage::error("unknown_target", "player is not connected");
+                    return vec![err];
+                };
+                warn!("Admin {} granted {} tokens to {}", self.id, amount, target);
+                self.state.audit([audit]).await;
+                self.state.persist([target]).await;
+                let notice = ServerMessage::TokensGranted {
+                    amount,
+                    new_balance,
+                };
+                self.state.deliver(target, notice).await;
+                vec![ServerMessage::AdminActionDone {
+                    action: "grant_tokens".to_owned(),
+                    target,
+                }]
+            }
+            ClientMessage::ResetPlayer { target } => {
+                if !self.is_admin().await {
+                    return vec![ServerMessage::error("forbidden", "admins only")];
+                }
+                let reason = format!("admin_reset:{}", self.id);
+                let reset = self
+                    .state
+                    .overwrite_player(target, Vec::new(), self.state.starting_balance, 1, &reason)
+                    .await;
+                if reset.is_none() {
+                    let err = ServerMessage::error("unknown_target", "player is not connected");
+                    return vec![err];
+                }
+                warn!("Admin {} reset {}", self.id, target);
+                let notice = ServerMessage::PlayerReset { id: target };
+                self.state.deliver(target, notice.clone()).await;
+                vec![notice]
+            }
+            ClientMessage::SeedPlayer {
+                target,
+                properties,
+                balance,
+                pvp_level,
+            } => {
+                if !self.is_admin().await {
+                    return vec![ServerMessage::error("forbidden", "admins only")];
+                }
+                if pvp_level == 0 {
+                    let err = ServerMessage::error("invalid_level", "PvP levels start at 1");
+                    return vec![err];
+                }
+                let max_properties = self.state.max_properties;
+                if properties.len() > max_properties {
+                    let detail =
+                        format!("inventories are limited to {} properties", max_properties);
+                    return vec![ServerMessage::error("inventory_full", detail)];
+                }
+                let count = properties.len();
+                let reason = format!("admin_seed:{}", self.id);
+                let seeded = self
+                    .state
+                    .overwrite_player(target, properties, balance, pvp_level, &reason)
+                    .await;
+                if seeded.is_none() {
+                    let err = ServerMessage::error("unknown_target", "player is not connected");
+                    return vec![err];
+                }
+                warn!(
+                    "Admin {} seeded {} with {} properties, {} tokens and level {}",
+                    self.id, target, count, balance, pvp_level
+                );
+                let notice = ServerMessage::PlayerSeeded { id: target };
+                self.state.deliver(target, notice.clone()).await;
+                vec![notice]
+            }
+            ClientMessage::GetProfile => {
+                // Respond with the player's own profile. Credit accrued
+                // rewards first, then compute the total reward rate by
+                // summing the reward of each property.
+                let accrued = self.state.accrue(self.id).await;
+                let mut clients = self.state.clients.write(&self.id).await;
+                let Some(info) = clients.get_mut(&self.id) else {
+                    return Vec::new();
+                };
+                let decay_applied = std::mem::take(&mut info.decay_applied);
+                vec![ServerMessage::Profile(Box::new(ProfilePayload {
+                    username: info.username.clone(),
+                    pvp_level: info.pvp_level,
+                    properties: info.properties.clone(),
+                    daily_reward: daily_reward(&info.properties),
+                    category_breakdown: category_breakdown(&info.properties),
+                    balance: info.balance,
+                    reward_multiplier_percent: self.state.reward_multiplier.load(Ordering::Relaxed),
+                    accrued,
+                    lifetime_rewards: info.lifetime_rewards,
+                    decay_applied,
+                }))]
+            }
+            ClientMessage::GetBattleHistory { limit } => {
+                let clients = self.state.clients.read(&self.id).await;
+                let Some(info) = clients.get(&self.id) else {
+                    return Vec::new();
+                };
+                let limit = limit.unwrap_or(DEFAULT_BATTLE_HISTORY_PAGE);
+                vec![ServerMessage::BattleHistory {
+                    wins: info.wins,
+                    losses: info.losses,
+                    recent: info
+                        .battle_history
+                        .iter()
+                        .rev()
+                        .take(limit)
+                        .cloned()
+                        .collect(),
+                }]
+            }
+            ClientMessage::GetInventory {
+                category,
+                min_reward,
+            } => {
+                let clients = self.state.clients.read(&self.id).await;
+                let Some(info) = clients.get(&self.id) else {
+                    return Vec::new();
+                };
+                let properties = info
+                    .properties
+                    .iter()
+                    .filter(|p| category.as_ref().is_none_or(|c| p.category == *c))
+                    .filter(|p| min_reward.is_none_or(|min| p.reward >= min))
+                    .cloned()
+                    .collect();
+                vec![ServerMessage::Inventory { properties }]
+            }
+            ClientMessage::GetPlayerProfile { target } => {
+                let clients = self.state.clients.read(&target).await;
+                let Some(info) = clients.get(&target) else {
+                    drop(clients);
+                    let err = ServerMessage::error("unknown_target", "player is not connected");
+                    return vec![err];
+                };
+                vec![ServerMessage::PublicProfile {
+                    id: target,
+                    username: info.username.clone(),
+                    pvp_level: info.pvp_level,
+                    property_count: info.properties.len(),
+                    daily_reward: daily_reward(&info.properties),
+                }]
+            }
+            ClientMessage::ListPlayers {
+                only_challengeable,
+                offset,
+                limit,
+            } => {
+                // Return a page of other connected players along with their
+                // PvP level, sorted by username. Exclude the requesting
+                // client. When only challengeable players are requested, keep
+                // available ones within the matchmaking gap and order them
+                // by closeness of level first.
+                let clients = self.state.clients.read_all().await;
+                let own_level = clients.get(&self.id).map(|c| c.pvp_level).unwrap_or(1);
+                let gap = self.state.matchmaking_level_gap;
+                let mut players: Vec<PlayerInfo> = clients
+                    .iter()
+                    .filter(|(k, _)| **k != self.id)
+                    .filter(|(_, info)| {
+                        !only_challengeable
+                            || (info.is_challengeable()
+                                && info.pvp_level.abs_diff(own_level) <= gap)
+                    })
+                    .map(|(id, info)| PlayerInfo {
+                        id: *id,
+                        username: info.username.clone(),
+                        pvp_level: info.pvp_level,
+                        status: info.status,
+                        bot: false,
+                    })
+                    .collect();
+                drop(clients);
+                players.sort_by(|a, b| {
+                    let closeness = |p: &PlayerInfo| {
+                        if only_challengeable {
+                            p.pvp_level.abs_diff(own_level)
+                        } else {
+                            0
+                        }
+                    };
+                    closeness(a)
+                        .cmp(&closeness(b))
+                        .then_with(|| a.username.cmp(&b.username))
+                        .then_with(|| a.id.cmp(&b.id))
+                });
+                // With nobody to fight, offer the bot if there is one.
+                if let Some(bot) = self.state.bot.filter(|_| only_challengeable) {
+                    if players.is_empty() {
+                        players.push(PlayerInfo {
+                            id: BOT_ID,
+                            username: BOT_NAME.to_owned(),
+                            pvp_level: bot.pvp_level,
+                            status: PlayerStatus::Available,
+                            bot: true,
+                        });
+                    }
+                }
+                let total = players.len();
+                let limit = limit.unwrap_or(DEFAULT_PLAYER_PAGE).min(MAX_PLAYER_PAGE);
+                let players: Vec<PlayerInfo> =
+                    players.into_iter().skip(offset).take(limit).collect();
+                vec![ServerMessage::PlayerList {
+                    players,
+                    total,
+                    offset,
+                }]
+            }
+            ClientMessage::SearchPlayers { prefix, limit } => {
+                let prefix = prefix.trim().to_lowercase();
+                if prefix.is_empty() {
+                    return vec![ServerMessage::error("invalid_prefix", "prefix is empty")];
+                }
+                let clients = self.state.clients.read_all().await;
+                let mut players: Vec<PlayerInfo> = clients
+                    .iter()
+                    .filter(|(k, _)| **k != self.id)
+                    .filter(|(_, info)| info.username.to_lowercase().starts_with(&prefix))
+                    .map(|(id, info)| PlayerInfo {
+                        id: *id,
+                        username: info.username.clone(),
+                        pvp_level: info.pvp_level,
+                        status: info.status,
+                        bot: false,
+                    })
+                    .collect();
+                drop(clients);
+                players.sort_by(|a, b| a.username.cmp(&b.username).then_with(|| a.id.cmp(&b.id)));
+                let total = players.len();
+                players.truncate(limit.min(MAX_PLAYER_PAGE));
+                vec![ServerMessage::PlayerList {
+                    players,
+                    total,
+                    offset: 0,
+                }]
+            }
+            ClientMessage::JoinMatchmaking => {
+                let own = {
+                    let clients = self.state.clients.read(&self.id).await;
+                    clients
+                        .get(&self.id)
+                        .map(|info| (info.username.clone(), info.pvp_level))
+                };
+                let Some((own_name, own_level)) = own else {
+                    return Vec::new();
+                };
+                let Some(opponent) = self.state.join_matchmaking(self.id, own_level).await else {
+                    return vec![ServerMessage::MatchmakingQueued {}];
+                };
+                let opponent_name = {
+                    let clients = self.state.clients.read(&opponent).await;
+                    clients.get(&opponent).map(|info| info.username.clone())
+                };
+                let found = ServerMessage::MatchFound {
+                    opponent: self.id,
+                    opponent_name: own_name,
+                };
+                let delivered = match opponent_name {
+                    Some(_) => self.state.deliver(opponent, found).await,
+                    None => false,
+                };
+                match opponent_name {
+                    Some(opponent_name) if delivered => {
+                        info!("Matched {} with {}", self.id, opponent);
+                        vec![ServerMessage::MatchFound {
+                            opponent,
+                            opponent_name,
+                        }]
+                    }
+                    _ => {
+                        // The opponent went away before hearing about the
+                        // match; wait for the next one instead.
+                        self.state.join_matchmaking(self.id, own_level).await;
+                        vec![ServerMessage::MatchmakingQueued {}]
+                    }
+                }
+            }
+            ClientMessage::LeaveMatchmaking => {
+                self.state.leave_matchmaking(self.id).await;
+                vec![ServerMessage::MatchmakingLeft {}]
+            }
+            ClientMessage::GetMarketplace => {
+                let items = self.state.marketplace.to_vec();
+                vec![ServerMessage::Marketplace { items }]
+            }
+            ClientMessage::Purchase {
+                item_id,
+                category,
+                idempotency_key,
+                dry_run,
+            } => {
+                if !dry_run {
+                    return self.purchase(item_id, category, idempotency_key).await;
+                }
+                let Some(item) = self.state.marketplace_item(&category) else {
+                    let reason = "unknown_category".to_owned();
+                    return vec![ServerMessage::PurchaseFailed { item_id, reason }];
+                };
+                let price = item.price;
+                let clients = self.state.clients.read(&self.id).await;
+                let Some(info) = clients.get(&self.id) else {
+                    return Vec::new();
+                };
+                // Answered like the real purchase, short of holding it.
+                let now = Instant::now();
+                match self
+                    .state
+                    .purchase_block(info, price, idempotency_key.as_ref(), now)
+                {
+                    Some(PurchaseBlock::Replayed(reply) | PurchaseBlock::Refused(reply)) => {
+                        return vec![reply];
+                    }
+                    Some(PurchaseBlock::MustFree(must_free)) => {
+                        return vec![ServerMessage::InventoryFull { item_id, must_free }];
+                    }
+                    None => (),
+                }
+                let remaining = info.balance.checked_sub(price);
+                vec![ServerMessage::PurchasePreview {
+                    item_id,
+                    price,
+                    resulting_balance: remaining.unwrap_or(info.balance),
+                    affordable: remaining.is_some(),
+                }]
+            }
+            ClientMessage::GiftTokens { target, amount } => {
+                self.gift(target, GiftRequest::Tokens(amount)).await
+            }
+            ClientMessage::GiftProperty {
+                target,
+                property_name,
+            } => {
+                self.gift(target, GiftRequest::Property(property_name))
+                    .await
+            }
+            ClientMessage::Sell { property_name } => {
+                let sold = {
+                    let mut clients = self.state.clients.write(&self.id).await;
+                    let Some(info) = clients.get_mut(&self.id) else {
+                        return Vec::new();
+                    };
+                    let position = info.properties.iter().position(|p| p.name == property_name);
+                    position.map(|index| {
+                        let property = info.properties.remove(index);
+                        let price = self.state.marketplace_item(&property.category);
+                        let refund = price
+                            .map_or(0, |item| item.price)
+                            .saturating_mul(SELL_REFUND_PERCENT)
+                            / 100;
+                        let old_balance = info.balance;
+                        info.balance = info.balance.saturating_add(refund);
+                        let count = info.properties.len();
+                        let reason = format!("sell:{}", property_name);
+                        let audit = [
+                            AuditEvent::new(
+                                self.id,
+                                info,
+                                AuditChange::Properties {
+                                    old: count + 1,
+                                    new: count,
+                                },
+                                reason.clone(),
+                            ),
+                            AuditEvent::new(
+                                self.id,
+                                info,
+                                AuditChange::Balance {
+                                    old: old_balance,
+                                    new: info.balance,
+                                },
+                                reason,
+                            ),
+                        ];
+                        (refund, info.balance, audit)
+                    })
+                };
+                let Some((refund, new_balance, audit)) = sold else {
+                    let err = ServerMessage::error("not_owned", "you do not own that property");
+                    return vec![err];
+                };
+                self.state.audit(audit).await;
+                self.state.persist([self.id]).await;
+                let ack = ServerMessage::SellAck {
+                    property_name,
+                    refund,
+                    new_balance,
+                };
+                let held = self.complete_held_purchase().await;
+                std::iter::once(ack).chain(held).collect()
+            }
+            ClientMessage::AbandonProperty { property_name } => {
+                let abandoned = {
+                    let mut clients = self.state.clients.write(&self.id).await;
+                    let Some(info) = clients.get_mut(&self.id) else {
+                        return Vec::new();
+                    };
+                    // Only the first of several same-named properties goes.
+                    let position = info.properties.iter().position(|p| p.name == property_name);
+                    position.map(|index| {
+                        info.properties.remove(index);
+                        let count = info.properties.len();
+                        AuditEvent::new(
+                            self.id,
+                            info,
+                            AuditChange::Properties {
+                                old: count + 1,
+                                new: count,
+                            },
+                            format!("abandon:{}", property_name),
+                        )
+                    })
+                };
+                let Some(audit) = abandoned else {
+                    let err = ServerMessage::error("not_owned", "you do not own that property");
+                    return vec![err];
+                };
+                self.state.audit([audit]).await;
+                self.state.persist([self.id]).await;
+                let abandoned = ServerMessage::PropertyAbandoned { property_name };
+                let held = self.complete_held_purchase().await;
+                std::iter::once(abandoned).chain(held).collect()
+            }
+            ClientMessage::UpgradeProperty { property_name } => {
+                let upgraded = {
+                    let mut clients = self.state.clients.write(&self.id).await;
+                    let Some(info) = clients.get_mut(&self.id) else {
+                        return Vec::new();
+                    };
+                    let Some(index) = info.properties.iter().position(|p| p.name == property_name)
+                    else {
+                        drop(clients);
+                        let err = ServerMessage::error("not_owned", "you do not own that property");
+                        return vec![err];
+                    };
+                    let level = info.properties[index].level;
+                    if level >= MAX_PROPERTY_LEVEL {
+                        drop(clients);
+                        let err = ServerMessage::error("max_level", "property is fully upgraded");
+                        return vec![err];
+                    }
+                    let cost = UPGRADE_BASE_COST * u64::from(level);
+                    let Some(balance) = info.balance.checked_sub(cost) else {
+                        drop(clients);
+                        let detail = format!("upgrade costs {} tokens", cost);
+                        return vec![ServerMessage::error("insufficient_funds", detail)];
+                    };
+                    let old_balance = std::mem::replace(&mut info.balance, balance);
+                    let property = &mut info.properties[index];
+                    // Each level adds the property's level 1 yield again.
+                    property.reward = property
+                        .reward
+                        .saturating_add((property.reward / level).max(1));
+                    property.level += 1;
+                    let (new_reward, new_level) = (property.reward, property.level);
+                    let change = AuditChange::Balance {
+                        old: old_balance,
+                        new: balance,
+                    };
+                    let reason = format!("upgrade:{}", property_name);
+                    let audit = AuditEvent::new(self.id, info, change, reason);
+                    (new_reward, new_level, audit)
+                };
+                let (new_reward, new_level, audit) = upgraded;
+                self.state.audit([audit]).await;
+                self.state.persist([self.id]).await;
+                vec![ServerMessage::PropertyUpgraded {
+                    property_name,
+                    new_reward,
+                    new_level,
+                }]
+            }
+            ClientMessage::Challenge {
+                target,
+                stake_amount,
+                stake_property,
+            } => {
+                // Relay the challenge to the target player if they exist.
+                if target == self.id {
+                    let err = ServerMessage::error("invalid_target", "cannot challenge yourself");
+                    return vec![err];
+                }
+                if target == BOT_ID && self.state.bot.is_some() {
+                    if stake_property.is_some() {
+                        let err =
+                            ServerMessage::error("invalid_stake", "the bot only fights for tokens");
+                        return vec![err];
+                    }
+                    return vec![self.state.fight_bot(self.id, stake_amount).await];
+                }
+                let stake = match stake_property {
+                    Some(_) if stake_amount > 0 => {
+                        let err = ServerMessage::error(
+                            "invalid_stake",
+                            "stake either tokens or a property, not both",
+                        );
+                        return vec![err];
+                    }
+                    Some(name) => StakeKind::Property(name),
+                    None => StakeKind::Balance(stake_amount),
+                };
+                let key = (self.id, target);
+                let cooling_until = {
+                    let cooldowns = self.state.challenge_cooldowns.read().await;
+                    cooldowns.get(&key).and_then(|cooldown| cooldown.until)
+                };
+                if let Some(until) = cooling_until.filter(|until| *until > Instant::now()) {
+                    let wait = until.saturating_duration_since(Instant::now());
+                    let retry_after_secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                    return vec![ServerMessage::Error {
+                        code: "challenge_cooldown".into(),
+                        detail: "this player declined your recent challenges".into(),
+                        retry_after_secs: Some(retry_after_secs),
+                        server_version: None,
+                    }];
+                }
+                if self
+                    .state
+                    .pending_challenges
+                    .read()
+                    .await
+                    .contains_key(&key)
+                {
+                    let err = ServerMessage::error(
+                        "challenge_pending",
+                        "you already challenged this player",
+                    );
+                    return vec![err];
+                }
+                let escrowed = {
+                    let mut clients = self.state.clients.write_pair(&self.id, &target).await;
+                    let Some(target_info) = clients.get(&target) else {
+                        drop(clients);
+                        let err = ServerMessage::error("unknown_target", "player is not connected");
+                        return vec![err];
+                    };
+                    if !target_info.is_challengeable() {
+                        let err = if target_info.privacy.allow_challenges {
+                            ServerMessage::error(
+                                "player_unavailable",
+                                "player is not available for challenges",
+                            )
+                        } else {
+                            ServerMessage::error(
+                                "challenges_not_allowed",
+                                "player does not accept challenges",
+                            )
+                        };
+                        drop(clients);
+                        return vec![err];
+                    }
+                    let target_covers = target_info.balance >= stake_amount;
+                    let target_name = target_info.username.clone();
+                    let Some(own_info) = clients.get_mut(&self.id) else {
+                        return Vec::new();
+                    };
+                    if let Some(name) = stake.property() {
+                        if !own_info.properties.iter().any(|p| p.name == name) {
+                            drop(clients);
+                            let err =
+                                ServerMessage::error("not_owned", "you do not own that property");
+                            return vec![err];
+                        }
+                    }
+                    let balance = own_info.balance.checked_sub(stake_amount);
+                    let (Some(balance), true) = (balance, target_covers) else {
+                        drop(clients);
+                        let err = ServerMessage::error(
+                            "insufficient_stake",
+                            "both players must be able to cover the stake",
+                        );
+                        return vec![err];
+                    };
+                    // Hold the challenger's stake until the challenge is
+                    // answered, expires or is cancelled.
+                    let change = AuditChange::Balance {
+                        old: own_info.balance,
+                        new: balance,
+                    };
+                    own_info.balance = balance;
+                    let audit = (stake_amount > 0)
+                        .then(|| AuditEvent::new(self.id, own_info, change, "stake_escrow"));
+                    (own_info.username.clone(), target_name, audit)
+                };
+                let (challenger_name, target_name, audit) = escrowed;
+                self.state.audit(audit).await;
+                // Register the challenge first so a fast accept finds it.
+                let battle_id = Uuid::new_v4();
+                let challenge = PendingChallenge {
+                    battle_id,
+                    stake: stake.clone(),
+                    expires_at: Instant::now() + self.state.challenge_timeout,
+                };
+                let record = challenge.clone();
+                self.state
+                    .battles
+                    .write()
+                    .await
+                    .insert(battle_id, HashSet::new());
+                self.state
+                    .pending_challenges
+                    .write()
+                    .await
+                    .insert(key, challenge);
+                // Construct a challenge notification for the target.
+                let request = ServerMessage::ChallengeRequest {
+                    challenger: self.id,
+                    challenger_name,
+                    stake_amount,
+                    stake,
+                    battle_id,
+                };
+                if !self.state.deliver(target, request).await {
+                    let cancelled = self.state.pending_challenges.write().await.remove(&key);
+                    if let Some(challenge) = cancelled {
+                        self.state
+                            .refund_stake(self.id, challenge.stake.tokens())
+                            .await;
+                        self.state.cancel_battle(challenge.battle_id).await;
+                    }
+                    let err =
+                        ServerMessage::error("delivery_failed", "challenge could not be delivered");
+                    return vec![err];
+                }
+                self.state.metrics.challenges_sent.inc();
+                let outcome = ChallengeOutcome::Sent;
+                self.state.record_challenge(key, &record, outcome).await;
+                // Inform the challenger that the request was sent.
+                vec![ServerMessage::ChallengeResponse {
+                    message: format!("Challenge sent to {}", target_name),
+                    battle_id,
+                }]
+            }
+            ClientMessage::AcceptChallenge { challenger } => {
+                let removed = self
+                    .state
+                    .pending_challenges
+                    .write()
+                    .await
+                    .remove(&(challenger, self.id));
+                let Some(challenge) = removed else {
+                    // Stale or unknown challenge, nothing to resolve.
+                    let err =
+                        ServerMessage::error("no_pending_challenge", "challenge is not pending");
+                    return vec![err];
+                };
+                let battle_id = challenge.battle_id;
+                if challenge.expires_at <= Instant::now() {
+                    // Expired but not reaped yet.
+                    self.state
+                        .refund_stake(challenger, challenge.stake.tokens())
+                        .await;
+                    self.state.cancel_battle(battle_id).await;
+                    let notice = ServerMessage::ChallengeExpired { target: self.id };
+                    self.state.deliver(challenger, notice).await;
+                    let (key, outcome) = ((challenger, self.id), ChallengeOutcome::Expired);
+                    self.state.record_challenge(key, &challenge, outcome).await;
+                    let err = ServerMessage::error("no_pending_challenge", "challenge has expired");
+                    return vec![err];
+                }
+                let status = BattleStatus::Started;
+                let update = ServerMessage::BattleUpdate { battle_id, status };
+                self.state
+                    .notify_spectators(battle_id, update.clone())
+                    .await;
+                let key = (challenger, self.id);
+                let escrow = self
+                    .state
+                    .escrow_defender_stake(challenger, self.id, &challenge.stake)
+                    .await;
+                if let Err(err) = escrow {
+                    self.state.cancel_battle(battle_id).await;
+                    let declined = ServerMessage::ChallengeDeclined { target: self.id };
+                    self.state.deliver(challenger, declined).await;
+                    let outcome = ChallengeOutcome::Cancelled;
+                    self.state.record_challenge(key, &challenge, outcome).await;
+                    return vec![err];
+                }
+                self.state.challenge_cooldowns.write().await.remove(&key);
+                let duration = self.state.battle_duration;
+                let battle = ActiveBattle::new(challenger, self.id, challenge, duration);
+                if !self.state.battle_duration.is_zero() {
+                    self.state.start_battle(battle).await;
+                    self.state.deliver(challenger, update.clone()).await;
+                    return vec![update];
+                }
+                let Some(outcome) = self.state.fight_battle(&battle).await else {
+                    self.state.cancel_battle(battle_id).await;
+                    let outcome = ChallengeOutcome::Cancelled;
+                    self.state
+                        .record_challenge(key, &battle.challenge, outcome)
+                        .await;
+                    let err =
+                        ServerMessage::error("unknown_target", "challenger is no longer connected");
+                    return vec![err];
+                };
+                let result = self
+                    .state
+                    .conclude_battle(key, &battle.challenge, outcome, false)
+                    .await;
+                self.state.deliver(challenger, result.clone()).await;
+                vec![result]
+            }
+            ClientMessage::DeclineChallenge { challenger } => {
+                let removed = self
+                    .state
+                    .pending_challenges
+                    .write()
+                    .await
+                    .remove(&(challenger, self.id));
+                let Some(challenge) = removed else {
+                    let err =
+                        ServerMessage::error("no_pending_challenge", "challenge is not pending");
+                    return vec![err];
+                };
+                self.state
+                    .refund_stake(challenger, challenge.stake.tokens())
+                    .await;
+                self.state.cancel_battle(challenge.battle_id).await;
+                let declined = ServerMessage::ChallengeDeclined { target: self.id };
+                self.state.deliver(challenger, declined).await;
+                let (key, outcome) = ((challenger, self.id), ChallengeOutcome::Declined);
+                self.state.record_challenge(key, &challenge, outcome).await;
+                self.state.back_off_challenges(challenger, self.id).await;
+                Vec::new()
+            }
+            ClientMessage::CancelChallenge { target } => {
+                let key = (self.id, target);
+                let removed = self.state.pending_challenges.write().await.remove(&key);
+                let Some(challenge) = removed else {
+                    // Accepted challenges leave the pending map right away,
+                    // so tell them apart by their logged outcome.
+                    let accepted = matches!(
+                        self.state.last_challenge_outcome(key).await,
+                        Some(ChallengeOutcome::ChallengerWon | ChallengeOutcome::TargetWon)
+                    );
+                    let err = if accepted {
+                        ServerMessage::error("already_accepted", "challenge was already accepted")
+                    } else {
+                        ServerMessage::error("no_pending_challenge", "challenge is not pending")
+                    };
+                    return vec![err];
+                };
+                self.state
+                    .refund_stake(self.id, challenge.stake.tokens())
+                    .await;
+                self.state.cancel_battle(challenge.battle_id).await;
+                let cancelled = ServerMessage::ChallengeCancelled {
+                    challenger: self.id,
+                };
+                self.state.deliver(target, cancelled.clone()).await;
+                let outcome = ChallengeOutcome::Cancelled;
+                self.state.record_challenge(key, &challenge, outcome).await;
+                vec![cancelled]
+            }
+            ClientMessage::Forfeit { battle_id } => {
+                let forfeited = self.state.forfeit_challenge(self.id, battle_id).await;
+                let (outcome, key, challenge) = match forfeited {
+                    Ok(forfeited) => forfeited,
+                    Err(err) => return vec![err],
+                };
+                let winner = outcome.winner;
+                let result = self
+                    .state
+                    .conclude_battle(key, &challenge, outcome, true)
+                    .await;
+                self.state.deliver(winner, result.clone()).await;
+                vec![result]
+            }
+            ClientMessage::SpectateBattle { battle_id } => {
+                if self.state.spectate(battle_id, self.id).await {
+                    vec![ServerMessage::Spectating { battle_id }]
+                } else {
+                    let err = ServerMessage::error("unknown_battle", "battle is not in progress");
+                    vec![err]
+                }
+            }
+            ClientMessage::OfferTrade {
+                target,
+                offer_property,
+                request_property,
+            } => {
+                if target == self.id {
+                    return vec![ServerMessage::error(
+                        "invalid_target",
+                        "cannot trade with yourself",
+                    )];
+                }
+                let owns_offer = {
+                    let clients = self.state.clients.read_all().await;
+                    if !clients.contains_key(&target) {
+                        drop(clients);
+                        let err = ServerMessage::error("unknown_target", "player is not connected");
+                        return vec![err];
+                    }
+                    clients.get(&self.id).is_some_and(|info| {
+                        info.properties.iter().any(|p| p.name == offer_property)
+                    })
+                };
+                if !owns_offer {
+                    let err =
+                        ServerMessage::error("not_owned", "you do not own the offered property");
+                    return vec![err];
+                }
+                let offer = TradeOffer {
+                    offer_property: offer_property.clone(),
+                    request_property: request_property.clone(),
+                    expires_at: Instant::now() + self.state.trade_offer_timeout,
+                };
+                {
+                    // A new offer to the same player replaces the old one.
+                    let mut pending = self.state.pending_trades.write().await;
+                    let now = Instant::now();
+                    pending.retain(|_, offer| offer.expires_at > now);
+                    pending.insert((self.id, target), offer);
+                }
+                let notice = ServerMessage::TradeOffer {
+                    from: self.id,
+                    offer_property,
+                    request_property,
+                };
+                self.state.deliver(target, notice).await;
+                Vec::new()
+            }
+            ClientMessage::RespondTrade { from, accept } => {
+                let offer = self
+                    .state
+                    .pending_trades
+                    .write()
+                    .await
+                    .remove(&(from, self.id))
+                    .filter(|offer| offer.expires_at > Instant::now());
+                let Some(offer) = offer else {
+                    let err =
+                        ServerMessage::error("no_pending_trade", "trade offer is not pending");
+                    return vec![err];
+                };
+                let rejected = |partner, reason: &str| ServerMessage::TradeRejected {
+                    partner,
+                    reason: reason.to_owned(),
+                };
+                if !accept {
+                    self.state
+                        .deliver(from, rejected(self.id, "declined"))
+                        .await;
+                    return vec![rejected(from, "declined")];
+                }
+                let [offerer_fee, fee] =
+                    match self.state.complete_trade(from, self.id, &offer).await {
+                        Ok(fees) => fees,
+                        Err(reason) => {
+                            self.state.deliver(from, rejected(self.id, reason)).await;
+                            return vec![rejected(from, reason)];
+                        }
+                    };
+                info!("Trade between {} and {} completed", from, self.id);
+                let offerer = ServerMessage::TradeCompleted {
+                    partner: self.id,
+                    gave: offer.offer_property.clone(),
+                    received: offer.request_property.clone(),
+                    fee: offerer_fee,
+                };
+                self.state.deliver(from, offerer).await;
+                vec![ServerMessage::TradeCompleted {
+                    partner: from,
+                    gave: offer.request_property,
+                    received: offer.offer_property,
+                    fee,
+                }]
+            }
+            ClientMessage::JoinChannel { channel } => {
+                let channel = match normalize_channel(&channel) {
+                    Ok(channel) => channel,
+                    Err(detail) => {
+                        return vec![ServerMessage::error("invalid_channel", detail)];
+                    }
+                };
+                let mut clients = self.state.clients.write(&self.id).await;
+                let Some(info) = clients.get_mut(&self.id) else {
+                    return Vec::new();
+                };
+                if !info.channels.contains(&channel) {
+                    if info.channels.len() >= MAX_CHANNELS {
+                        let detail = format!("at most {} channels can be joined", MAX_CHANNELS);
+                        return vec![ServerMessage::error("too_many_channels", detail)];
+                    }
+                    info.channels.push(channel.clone());
+                }
+                vec![ServerMessage::ChannelJoined { channel }]
+            }
+            ClientMessage::LeaveChannel { channel } => {
+                let channel = channel.trim().to_ascii_lowercase();
+                let mut clients = self.state.clients.write(&self.id).await;
+                let Some(info) = clients.get_mut(&self.id) else {
+                    return Vec::new();
+                };
+                let Some(index) = info.channels.iter().position(|c| *c == channel) else {
+                    return vec![ServerMessage::error(
+                        "not_in_channel",
+                        "you have not joined that channel",
+                    )];
+                };
+                info.channels.remove(index);
+                vec![ServerMessage::ChannelLeft { channel }]
+            }
+            ClientMessage::ChatSend { channel, text } => {
+                let channel = channel.trim().to_ascii_lowercase();
+                let text = text.trim();
+                if text.is_empty() {
+                    return vec![ServerMessage::error("chat_empty", "message is empty")];
+                }
+                if text.chars().count() > MAX_CHAT_LEN {
+                    let detail = format!("messages are limited to {} characters", MAX_CHAT_LEN);
+                    return vec![ServerMessage::error("chat_too_long", detail)];
+                }
+                let member = {
+                    let clients = self.state.clients.read(&self.id).await;
+                    clients.get(&self.id).map(|info| {
+                        let joined = info.channels.contains(&channel);
+                        (info.account.clone(), info.username.clone(), joined)
+                    })
+                };
+                let Some((account, username, joined)) = member else {
+                    return Vec::new();
+                };
+                if let Some(err) = self.state.check_chat_mute(&account).await {
+                    return vec![err];
+                }
+                if !joined {
+                    return vec![ServerMessage::error(
+                        "not_in_channel",
+                        "join the channel before sending to it",
+                    )];
+                }
+                if contains_blocked_word(text, &self.state.blocked_words) {
+                    let err =
+                        ServerMessage::error("chat_blocked", "message contains blocked words");
+                    let muted = self.state.strike_chat(&account).await;
+                    return std::iter::once(err).chain(muted).collect();
+                }
+                // The sender gets its own message back through the
+                // broadcast so every member sees the same order.
+                let chat = ServerMessage::ChatMessage {
+                    channel: channel.clone(),
+                    from: self.id,
+                    username,
+                    text: text.to_owned(),
+                    timestamp: unix_now(),
+                };
+                self.state.broadcast_to_channel(&channel, chat).await;
+                Vec::new()
+            }
+            ClientMessage::ClaimDailyReward => {
+                let now = unix_now();
+                let cooldown = self.state.daily_claim_cooldown.as_secs();
+                // Rewards are credited as they accrue; the claim collects
+                // what accrued since the last one and mints it.
+                self.state.accrue(self.id).await;
+                let claimed = {
+                    let mut clients = self.state.clients.write(&self.id).await;
+                    let Some(info) = clients.get_mut(&self.id) else {
+                        return Vec::new();
+                    };
+                    if let Some(next_claim_at) = info.last_claim.map(|last| last + cooldown) {
+                        if now < next_claim_at {
+                            return vec![ServerMessage::RewardUnavailable { next_claim_at }];
+                        }
+                    }
+                    let amount = std::mem::take(&mut info.unclaimed_rewards);
+                    info.last_claim = Some(now);
+                    (amount, info.account.clone())
+                };
+                let (amount, account) = claimed;
+                self.state.persist([self.id]).await;
+                let minted = match amount {
+                    0 => None,
+                    _ => self.state.mint_reward(&account, amount).await,
+                };
+                let claimed = ServerMessage::RewardClaimed {
+                    amount,
+                    next_claim_at: now + cooldown,
+                };
+                std::iter::once(claimed).chain(minted).collect()
+            }
+            ClientMessage::GetLeaderboard { limit } => {
+                let (mut entries, own_rank) =
+                    self.state.leaderboard.read().await.view(self.id, limit);
+                if let Some(rank) = own_rank {
+                    // The player's own entry is always current.
+                    let clients = self.state.clients.read(&self.id).await;
+                    if let Some(info) = clients.get(&self.id) {
+                        entries.push(LeaderboardEntry::new(rank, info));
+                    }
+                }
+                vec![ServerMessage::Leaderboard { entries }]
+            }
+            ClientMessage::GetSeasonArchive { season } => {
+                // Without a season return the list of archived seasons,
+                // otherwise the top players of the requested one. Both
+                // are read from storage, where archives are kept.
+                let storage = &self.state.storage;
+                let payload = match season {
+                    None => match storage.list_seasons().await {
+                        Ok(seasons) => ServerMessage::SeasonList {
+                            seasons: seasons.iter().map(|s| s.season).collect(),
+                        },
+                        Err(err) => {
+                            error!("Failed to list archived seasons: {}", err);
+                            ServerMessage::error("storage_unavailable", "try again later")
+                        }
+                    },
+                    Some(season) => match storage.load_season(season).await {
+                        Err(err) => {
+                            error!("Failed to load the archive of season {}: {}", season, err);
+                            ServerMessage::error("storage_unavailable", "try again later")
+                        }
+                        Ok(Some(archive)) => ServerMessage::SeasonArchive {
+                            season,
+                            standings: archive
+                                .standings
+                                .iter()
+                                .take(SEASON_ARCHIVE_TOP_N)
+                                .cloned()
+                                .collect(),
+                        },
+                        Ok(None) => ServerMessage::error(
+                            "unknown_season",
+                            format!("season {} has not been archived", season),
+                        ),
+                    },
+                };
+                vec![payload]
+            }
+            ClientMessage::WatchPlayer { target } => {
+                let allowed = {
+                    let clients = self.state.clients.read(&target).await;
+                    clients.get(&target).map(|info| info.privacy.allow_watch)
+                };
+                let payload = match allowed {
+                    _ if target == self.id => {
+                        ServerMessage::error("invalid_target", "cannot watch yourself")
+                    }
+                    None => ServerMessage::error("unknown_target", "player is not connected"),
+                    Some(false) => {
+                        ServerMessage::error("watch_not_allowed", "player does not allow watchers")
+                    }
+                    Some(true) => {
+                        let mut watchers = self.state.watchers.write().await;
+                        watchers.entry(target).or_default().insert(self.id);
+                        ServerMessage::Watching { target }
+                    }
+                };
+                vec![payload]
+            }
+            ClientMessage::AddFriend { target } => {
+                if target == self.id {
+                    return vec![ServerMessage::error(
+                        "invalid_target",
+                        "cannot befriend yourself",
+                    )];
+                }
+                let friend = {
+                    let clients = self.state.clients.read(&target).await;
+                    clients
+                        .get(&target)
+                        .map(|info| (info.account.clone(), info.username.clone()))
+                };
+                let Some((account, username)) = friend else {
+                    return vec![ServerMessage::error(
+                        "unknown_target",
+                        "player is not connected",
+                    )];
+                };
+                let added = {
+                    let mut clients = self.state.clients.write(&self.id).await;
+                    let Some(info) = clients.get_mut(&self.id) else {
+                        return Vec::new();
+                    };
+                    if info.friends.contains(&account) {
+                        Ok(false)
+                    } else if info.friends.len() >= MAX_FRIENDS {
+                        Err(ServerMessage::error("friends_full", "friends list is full"))
+                    } else {
+                        info.friends.push(account);
+                        Ok(true)
+                    }
+                };
+                match added {
+                    Ok(added) => {
+                        if added {
+                            self.state.persist([self.id]).await;
+                        }
+                        vec![ServerMessage::FriendAdded {
+                            id: target,
+                            username,
+                        }]
+                    }
+                    Err(err) => vec![err],
+                }
+            }
+            ClientMessage::GetFriends => {
+                let accounts = {
+                    let clients = self.state.clients.read(&self.id).await;
+                    clients
+                        .get(&self.id)
+                        .map(|info| info.friends.clone())
+                        .unwrap_or_default()
+                };
+                let online: HashMap<String, (Uuid, String)> = {
+                    let clients = self.state.clients.read_all().await;
+                    clients
+                        .iter()
+                        .filter(|(_, info)| accounts.contains(&info.account))
+                        .map(|(id, info)| (info.account.clone(), (*id, info.username.clone())))
+                        .collect()
+                };
+                let mut friends = Vec::with_capacity(accounts.len());
+                for account in accounts {
+                    if let Some((id, username)) = online.get(&account) {
+                        friends.push(FriendInfo {
+                            id: Some(*id),
+                            username: username.clone(),
+                            online: true,
+                        });
+                        continue;
+                    }
+                    let username = match self.state.storage.load_player(&account).await {
+                        Ok(Some(stored)) => stored.display_name.unwrap_or(account),
+                        // The friend's account is gone; leave it out.
+                        Ok(None) => continue,
+                        Err(err) => {
+                            warn!("Failed to load friend {}: {}", account, err);
+                            account
+                        }
+                    };
+                    friends.push(FriendInfo {
+                        id: None,
+                        username,
+                        online: false,
+                    });
+                }
+                vec![ServerMessage::Friends { friends }]
+            }
+            ClientMessage::UnwatchPlayer { target } => {
+                let mut watchers = self.state.watchers.write().await;
+                if let Some(set) = watchers.get_mut(&target) {
+                    set.remove(&self.id);
+                    if set.is_empty() {
+                        watchers.remove(&target);
+                    }
+                }
+                vec![ServerMessage::Unwatched { target }]
+            }
+            ClientMessage::SetStatus { status } => {
+                let mut clients = self.state.clients.write(&self.id).await;
+                let Some(info) = clients.get_mut(&self.id) else {
+                    return Vec::new();
+                };
+                info.status = status;
+                vec![ServerMessage::StatusChanged { status }]
+            }
+            ClientMessage::GetNotificationPrefs => {
+                let clients = self.state.clients.read(&self.id).await;
+                let prefs = clients.get(&self.id).map(|info| info.notification_prefs);
+                prefs
+                    .map(ServerMessage::NotificationPrefs)
+                    .into_iter()
+                    .collect()
+            }
+            ClientMessage::SetNotificationPrefs { prefs } => {
+                let mut clients = self.state.clients.write(&self.id).await;
+                let Some(info) = clients.get_mut(&self.id) else {
+                    return Vec::new();
+                };
+                info.notification_prefs = prefs;
+                vec![ServerMessage::NotificationPrefs(prefs)]
+            }
+            ClientMessage::GetPrivacy => {
+                let privacy = self
+                    .state
+                    .clients
+                    .read(&self.id)
+                    .await
+                    .get(&self.id)
+                    .map(|c| c.privacy);
+                privacy.map(ServerMessage::Privacy).into_iter().collect()
+            }
+            ClientMessage::UpdatePrivacy {
+                allow_challenges,
+                allow_whispers,
+                allow_watch,
+                show_in_leaderboard,
+            } => {
+                // Only the settings present in the message are changed.
+                let privacy = {
+                    let mut clients = self.state.clients.write(&self.id).await;
+                    clients.get_mut(&self.id).map(|info| {
+                        let privacy = &mut info.privacy;
+                        privacy.allow_challenges =
+                            allow_challenges.unwrap_or(privacy.allow_challenges);
+                        privacy.allow_whispers = allow_whispers.unwrap_or(privacy.allow_whispers);
+                        privacy.allow_watch = allow_watch.unwrap_or(privacy.allow_watch);
+                        privacy.show_in_leaderboard =
+                            show_in_leaderboard.unwrap_or(privacy.show_in_leaderboard);
+                        *privacy
+                    })
+                };
+                let Some(privacy) = privacy else {
+                    return Vec::new();
+                };
+                if !privacy.allow_watch {
+                    // Opting out also ends existing subscriptions.
+                    self.state.watchers.write().await.remove(&self.id);
+                }
+                vec![ServerMessage::Privacy(privacy)]
+            }
+            ClientMessage::ReportPlayer {
+                target,
+                reason,
+                details,
+            } => {
+                let payload = self
+                    .state
+                    .file_report(self.id, target, reason.trim(), details.trim())
+                    .await;
+                vec![payload]
+            }
+        }
+    }
+}
+
+fn default_channel() -> String {
+    DEFAULT_CHANNEL.to_owned()
+}
+
+/// Define messages that can be sent from the client to the server.
+/// These are deserialized from JSON in the WebSocket handler.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ClientMessage {
+    /// Ask which protocol version and features the server supports.
+    /// Allowed before authenticating.
+    #[serde(rename = "hello")]
+    Hello,
+    /// Several messages handled in order with a single reply.
+    #[serde(rename = "batch")]
+    Batch { messages: Vec<ClientMessage> },
+    #[serde(rename = "authenticate")]
+    Authenticate { token: String },
+    #[serde(rename = "getProfile")]
+    GetProfile,
+    /// The player's win/loss record and most recent battles.
+    #[serde(rename = "getBattleHistory")]
+    GetBattleHistory {
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// The player's own properties, optionally narrowed down. Filters
+    /// that are left out match everything.
+    #[serde(rename = "getInventory")]
+    GetInventory {
+        #[serde(default)]
+        category: Option<String>,
+        #[serde(default)]
+        min_reward: Option<u32>,
+    },
+    #[serde(rename = "kickPlayer")]
+    KickPlayer { target: Uuid },
+    #[serde(rename = "grantTokens")]
+    GrantTokens { target: Uuid, amount: u64 },
+    /// Admin only: sessions that dropped and can still be resumed.
+    #[serde(rename = "listDisconnected")]
+    ListDisconnected,
+    /// Admin only: forget a resumable session before its grace period
+    /// ends.
+    #[serde(rename = "reapSession")]
+    ReapSession { id: Uuid },
+    /// Admin only: take a player back to a fresh account.
+    #[serde(rename = "resetPlayer")]
+    ResetPlayer { target: Uuid },
+    /// Admin only: give a player exactly the given state, for setting up
+    /// test scenarios.
+    #[serde(rename = "seedPlayer")]
+    SeedPlayer {
+        target: Uuid,
+        properties: Vec<Property>,
+        balance: u64,
+        pvp_level: u32,
+    },
+    #[serde(rename = "setUsername")]
+    SetUsername { username: String },
+    #[serde(rename = "listPlayers")]
+    ListPlayers {
+        #[serde(default)]
+        only_challengeable: bool,
+        #[serde(default)]
+        offset: usize,
+        /// Page size; defaults to 50 and is capped at 200.
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Other players whose username starts with `prefix`, ignoring
+    /// case. `limit` is capped at 200.
+    #[serde(rename = "searchPlayers")]
+    SearchPlayers { prefix: String, limit: usize },
+    #[serde(rename = "getPlayerProfile")]
+    GetPlayerProfile { target: Uuid },
+    #[serde(rename = "joinMatchmaking")]
+    JoinMatchmaking,
+    #[serde(rename = "leaveMatchmaking")]
+    LeaveMatchmaking,
+    #[serde(rename = "getMarketplace")]
+    GetMarketplace,
+    #[serde(rename = "purchase")]
+    Purchase {
+        item_id: String,
+        category: String,
+        /// Resending a purchase with the same key within a few minutes
+        /// returns the original acknowledgement instead of buying again.
+        #[serde(default)]
+        idempotency_key: Option<String>,
+        /// Only report what the purchase would cost, without buying.
+        #[serde(default)]
+        dry_run: bool,
+    },
+    /// Give tokens to another player, expecting nothing back.
+    #[serde(rename = "giftTokens")]
+    GiftTokens { target: Uuid, amount: u64 },
+    /// Give one of the player's properties to another player.
+    #[serde(rename = "giftProperty")]
+    GiftProperty { target: Uuid, property_name: String },
+    #[serde(rename = "sell")]
+    Sell { property_name: String },
+    /// Give up a property without a refund.
+    #[serde(rename = "abandonProperty")]
+    AbandonProperty { property_name: String },
+    #[serde(rename = "upgradeProperty")]
+    UpgradeProperty { property_name: String },
+    #[serde(rename = "challenge")]
+    Challenge {
+        target: Uuid,
+        /// Tokens each player puts in the pot; the winner takes both.
+        #[serde(default)]
+        stake_amount: u64,
+        /// Stake one of the challenger's properties, by name, instead of
+        /// tokens. Only the challenger stakes it; the target risks
+        /// nothing by accepting.
+        #[serde(default)]
+        stake_property: Option<String>,
+    },
+    #[serde(rename = "acceptChallenge")]
+    AcceptChallenge { challenger: Uuid },
+    #[serde(rename = "declineChallenge")]
+    DeclineChallenge { challenger: Uuid },
+    /// Take back a challenge the target hasn't answered yet.
+    #[serde(rename = "cancelChallenge")]
+    CancelChallenge { target: Uuid },
+    /// Give up a challenge one is part of; the opponent wins.
+    #[serde(rename = "forfeit")]
+    Forfeit { battle_id: Uuid },
+    #[serde(rename = "spectateBattle")]
+    SpectateBattle { battle_id: Uuid },
+    #[serde(rename = "offerTrade")]
+    OfferTrade {
+        target: Uuid,
+        offer_property: String,
+        request_property: String,
+    },
+    #[serde(rename = "respondTrade")]
+    RespondTrade { from: Uuid, accept: bool },
+    #[serde(rename = "joinChannel")]
+    JoinChannel { channel: String },
+    #[serde(rename = "leaveChannel")]
+    LeaveChannel { channel: String },
+    /// Chat to the members of `channel`, the default one if unset.
+    #[serde(rename = "chatSend")]
+    ChatSend {
+        #[serde(default = "default_channel")]
+        channel: String,
+        text: String,
+    },
+    #[serde(rename = "claimDailyReward")]
+    ClaimDailyReward,
+    #[serde(rename = "getLeaderboard")]
+    GetLeaderboard { limit: usize },
+    #[serde(rename = "getSeasonArchive")]
+    GetSeasonArchive {
+        #[serde(default)]
+        season: Option<u32>,
+    },
+    #[serde(rename = "watchPlayer")]
+    WatchPlayer { target: Uuid },
+    #[serde(rename = "addFriend")]
+    AddFriend { target: Uuid },
+    #[serde(rename = "getFriends")]
+    GetFriends,
+    #[serde(rename = "unwatchPlayer")]
+    UnwatchPlayer { target: Uuid },
+    #[serde(rename = "setStatus")]
+    SetStatus { status: PlayerStatus },
+    #[serde(rename = "getNotificationPrefs")]
+    GetNotificationPrefs,
+    /// Replace the player's notification preferences for this session.
+    #[serde(rename = "setNotificationPrefs")]
+    SetNotificationPrefs { prefs: NotificationPrefs },
+    #[serde(rename = "getPrivacy")]
+    GetPrivacy,
+    #[serde(rename = "updatePrivacy")]
+    UpdatePrivacy {
+        #[serde(default)]
+        allow_challenges: Option<bool>,
+        #[serde(default)]
+        allow_whispers: Option<bool>,
+        #[serde(default)]
+        allow_watch: Option<bool>,
+        #[serde(default)]
+        show_in_leaderboard: Option<bool>,
+    },
+    #[serde(rename = "reportPlayer")]
+    ReportPlayer {
+        target: Uuid,
+        reason: String,
+        #[serde(default)]
+        details: String,
+    },
+}
+
+/// Query parameters accepted by the WebSocket endpoint.
+#[derive(Deserialize)]
+struct ConnectParams {
+    /// Token from a previous `welcome`, to resume that session.
+    #[serde(default)]
+    resume_token: Option<Uuid>,
+    /// Last `seq` the client saw before the drop. Resuming replays the
+    /// kept messages after it.
+    #[serde(default)]
+    last_seq: Option<u64>,
+    #[serde(default)]
+    format: WireFormat,
+}
+
+/// Encoding of the messages on a connection, chosen with `?format=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WireFormat {
+    /// JSON in text frames.
+    #[default]
+    Json,
+    /// MessagePack in binary frames, for bandwidth-sensitive clients.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+/// Define the payload sent in a profile response.
+#[derive(Debug, Clone, Serialize)]
+struct ProfilePayload {
+    username: String,
+    pvp_level: u32,
+    properties: Vec<Property>,
+    daily_reward: u64,
+    /// Properties and their daily reward per marketplace category.
+    category_breakdown: HashMap<String, CategorySummary>,
+    balance: u64,
+    /// Reward multiplier currently in effect, in percent.
+    reward_multiplier_percent: u32,
+    /// Tokens credited from property rewards by this request.
+    accrued: u64,
+    /// Tokens earned from property rewards over the player's lifetime.
+    lifetime_rewards: u64,
+    /// Whether idle decay lowered property rewards since the last
+    /// profile request.
+    decay_applied: bool,
+}
+
+/// Simplified player info returned to other clients when listing
+/// available opponents in the PvP arena.
+#[derive(Debug, Clone, Serialize)]
+struct PlayerInfo {
+    id: Uuid,
+    username: String,
+    pvp_level: u32,
+    status: PlayerStatus,
+    /// The starter bot rather than another player.
+    bot: bool,
+}
+
+/// An entry of a player's friends list.
+#[derive(Debug, Clone, Serialize)]
+struct FriendInfo {
+    /// Session id while the friend is online.
+    id: Option<Uuid>,
+    username: String,
+    online: bool,
+}
+
+/// Severity of an admin announcement. Clients use it to pick the
+/// styling of the notice.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AnnouncementLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// A public change to a player's state, pushed to their watchers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind")]
+enum PlayerEvent {
+    #[serde(rename = "purchased")]
+    Purchased { category: String },
+    #[serde(rename = "battleWon")]
+    BattleWon { opponent: Uuid, pvp_level: u32 },
+    #[serde(rename = "battleLost")]
+    BattleLost { opponent: Uuid },
+}
+
+impl ClientMessage {
+    /// Rate limiter tokens the message takes: one per message it runs, so
+    /// a batch pays for everything in it. Oversized batches run nothing.
+    fn cost(&self) -> u32 {
+        match self {
+            ClientMessage::Batch { messages } if messages.len() <= MAX_BATCH_LEN => {
+                u32::try_from(messages.len()).unwrap_or(u32::MAX).max(1)
+            }
+            _ => 1,
+        }
+    }
+
+    /// The message `type` as sent on the wire.
+    fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Hello => "hello",
+            ClientMessage::Batch { .. } => "batch",
+            ClientMessage::Authenticate { .. } => "authenticate",
+            ClientMessage::GetProfile => "getProfile",
+            ClientMessage::GetBattleHistory { .. } => "getBattleHistory",
+            ClientMessage::GetInventory { .. } => "getInventory",
+            ClientMessage::KickPlayer { .. } => "kickPlayer",
+            ClientMessage::GrantTokens { .. } => "grantTokens",
+            ClientMessage::ListDisconnected => "listDisconnected",
+            ClientMessage::ReapSession { .. } => "reapSession",
+            ClientMessage::ResetPlayer { .. } => "resetPlayer",
+            ClientMessage::SeedPlayer { .. } => "seedPlayer",
+            ClientMessage::SetUsername { .. } => "setUsername",
+            ClientMessage::ListPlayers { .. } => "listPlayers",
+            ClientMessage::SearchPlayers { .. } => "searchPlayers",
+            ClientMessage::GetPlayerProfile { .. } => "getPlayerProfile",
+            ClientMessage::JoinMatchmaking => "joinMatchmaking",
+            ClientMessage::LeaveMatchmaking => "leaveMatchmaking",
+            ClientMessage::GetMarketplace => "getMarketplace",
+            ClientMessage::Purchase { .. } => "purchase",
+            ClientMessage::GiftTokens { .. } => "giftTokens",
+            ClientMessage::GiftProperty { .. } => "giftProperty",
+            ClientMessage::Sell { .. } => "sell",
+            ClientMessage::AbandonProperty { .. } => "abandonProperty",
+            ClientMessage::UpgradeProperty { .. } => "upgradeProperty",
+            ClientMessage::Challenge { .. } => "challenge",
+            ClientMessage::AcceptChallenge { .. } => "acceptChallenge",
+            ClientMessage::DeclineChallenge { .. } => "declineChallenge",
+            ClientMessage::CancelChallenge { .. } => "cancelChallenge",
+            ClientMessage::Forfeit { .. } => "forfeit",
+            ClientMessage::SpectateBattle { .. } => "spectateBattle",
+            ClientMessage::OfferTrade { .. } => "offerTrade",
+            ClientMessage::RespondTrade { .. } => "respondTrade",
+            ClientMessage::JoinChannel { .. } => "joinChannel",
+            ClientMessage::LeaveChannel { .. } => "leaveChannel",
+            ClientMessage::ChatSend { .. } => "chatSend",
+            ClientMessage::ClaimDailyReward => "claimDailyReward",
+            ClientMessage::GetLeaderboard { .. } => "getLeaderboard",
+            ClientMessage::GetSeasonArchive { .. } => "getSeasonArchive",
+            ClientMessage::WatchPlayer { .. } => "watchPlayer",
+            ClientMessage::AddFriend { .. } => "addFriend",
+            ClientMessage::GetFriends => "getFriends",
+            ClientMessage::UnwatchPlayer { .. } => "unwatchPlayer",
+            ClientMessage::GetNotificationPrefs => "getNotificationPrefs",
+            ClientMessage::SetNotificationPrefs { .. } => "setNotificationPrefs",
+            ClientMessage::GetPrivacy => "getPrivacy",
+            ClientMessage::SetStatus { .. } => "setStatus",
+            ClientMessage::UpdatePrivacy { .. } => "updatePrivacy",
+            ClientMessage::ReportPlayer { .. } => "reportPlayer",
+        }
+    }
+
+    /// The player a request is aimed at, if any.
+    fn target(&self) -> Option<Uuid> {
+        match self {
+            ClientMessage::KickPlayer { target }
+            | ClientMessage::GrantTokens { target, .. }
+            | ClientMessage::ResetPlayer { target }
+            | ClientMessage::SeedPlayer { target, .. }
+            | ClientMessage::GetPlayerProfile { target }
+            | ClientMessage::Challenge { target, .. }
+            | ClientMessage::CancelChallenge { target }
+            | ClientMessage::GiftTokens { target, .. }
+            | ClientMessage::GiftProperty { target, .. }
+            | ClientMessage::OfferTrade { target, .. }
+            | ClientMessage::WatchPlayer { target }
+            | ClientMessage::UnwatchPlayer { target }
+            | ClientMessage::AddFriend { target }
+            | ClientMessage::ReportPlayer { target, .. } => Some(*target),
+            ClientMessage::AcceptChallenge { challenger }
+            | ClientMessage::DeclineChallenge { challenger } => Some(*challenger),
+            ClientMessage::RespondTrade { from, .. } => Some(*from),
+            _ => None,
+        }
+    }
+
+    /// The optional feature this request belongs to, if any.
+    fn feature(&self) -> Option<&'static str> {
+        match self {
+            ClientMessage::JoinMatchmaking | ClientMessage::LeaveMatchmaking => Some("matchmaking"),
+            ClientMessage::OfferTrade { .. } | ClientMessage::RespondTrade { .. } => {
+                Some("trading")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A `ClientMessage` with the optional `request_id` clients may add to
+/// any message to match it with its replies.
+#[derive(Debug, Deserialize)]
+struct ClientRequest {
+    #[serde(default)]
+    request_id: Option<u64>,
+    #[serde(flatten)]
+    msg: ClientMessage,
+}
+
+/// A message as sent to the client, carrying its `seq` and, for replies
+/// to a request, the request's `request_id` next to the message, e.g.
+/// `{"purchaseAck": {..}, "request_id": 7, "seq": 12}`. Pushes the
+/// client didn't ask for have no `request_id`.
+#[derive(Serialize)]
+struct Reply<'a> {
+    #[serde(flatten)]
+    message: &'a ServerMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<u64>,
+    /// Numbers the messages of a session from 1, across resumes.
+    /// Replayed messages keep their original number.
+    seq: u64,
+}
+
+/// Define messages that the server can send to clients.
+#[derive(Debug, Clone, Serialize, Message)]
+#[rtype(result = "()")]
+enum ServerMessage {
+    /// First message on every connection, after what a resumed session
+    /// replays.
+    #[serde(rename = "welcome")]
+    Welcome {
+        session_id: Uuid,
+        resume_token: Uuid,
+    },
+    #[serde(rename = "playerJoined")]
+    PlayerJoined {
+        id: Uuid,
+        username: String,
+        pvp_level: u32,
+    },
+    #[serde(rename = "playerLeft")]
+    PlayerLeft { id: Uuid },
+    /// A player with a pending challenge, trade or battle involving the
+    /// recipient disconnected. It stays open for `grace_seconds` while
+    /// the player can resume; without it the challenge or trade is
+    /// cancelled.
+    #[serde(rename = "opponentDisconnected")]
+    OpponentDisconnected {
+        id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        grace_seconds: Option<u64>,
+    },
+    /// Sent to a resumed session: everything it left open that is
+    /// still waiting.
+    #[serde(rename = "pendingActions")]
+    PendingActions {
+        challenges: Vec<PendingChallengeSummary>,
+        trades: Vec<PendingTradeSummary>,
+        /// Ids of the battles being fought.
+        battles: Vec<Uuid>,
+    },
+    #[serde(rename = "playerRenamed")]
+    PlayerRenamed { id: Uuid, username: String },
+    #[serde(rename = "usernameChanged")]
+    UsernameChanged { username: String },
+    /// Sent to a kicked player right before their session closes.
+    #[serde(rename = "kicked")]
+    Kicked { reason: String },
+    #[serde(rename = "tokensGranted")]
+    TokensGranted { amount: u64, new_balance: u64 },
+    #[serde(rename = "adminActionDone")]
+    AdminActionDone { action: String, target: Uuid },
+    #[serde(rename = "disconnectedList")]
+    DisconnectedList { sessions: Vec<DisconnectedSession> },
+    #[serde(rename = "reaped")]
+    Reaped { id: Uuid },
+    /// Sent to the admin and the player after an admin reset.
+    #[serde(rename = "playerReset")]
+    PlayerReset { id: Uuid },
+    /// Sent to the admin and the player after an admin seed.
+    #[serde(rename = "playerSeeded")]
+    PlayerSeeded { id: Uuid },
+    #[serde(rename = "matchmakingQueued")]
+    MatchmakingQueued {},
+    #[serde(rename = "matchmakingLeft")]
+    MatchmakingLeft {},
+    #[serde(rename = "matchFound")]
+    MatchFound {
+        opponent: Uuid,
+        opponent_name: String,
+    },
+    #[serde(rename = "authenticated")]
+    Authenticated { session_id: Uuid, username: String },
+    /// The replies to each message of a batch, in the batch's order.
+    #[serde(rename = "batchResult")]
+    BatchResult { results: Vec<Vec<ServerMessage>> },
+    #[serde(rename = "serverInfo")]
+    ServerInfo {
+        protocol_version: u32,
+        /// Oldest version still accepted in the `v` field.
+        min_protocol_version: u32,
+        features: Vec<String>,
+    },
+    /// Boxed since it is by far the largest message.
+    #[serde(rename = "profile")]
+    Profile(Box<ProfilePayload>),
+    #[serde(rename = "inventory")]
+    Inventory { properties: Vec<Property> },
+    /// Another player's profile without their inventory or balance.
+    #[serde(rename = "publicProfile")]
+    PublicProfile {
+        id: Uuid,
+        username: String,
+        pvp_level: u32,
+        property_count: usize,
+        daily_reward: u64,
+    },
+    #[serde(rename = "playerList")]
+    PlayerList {
+        players: Vec<PlayerInfo>,
+        /// Number of players across all pages.
+        total: usize,
+        offset: usize,
+    },
+    #[serde(rename = "marketplace")]
+    Marketplace { items: Vec<MarketplaceItem> },
+    #[serde(rename = "purchaseAck")]
+    PurchaseAck { item_id: String, balance: u64 },
+    #[serde(rename = "purchaseFailed")]
+    PurchaseFailed { item_id: String, reason: String },
+    /// The inventory is full. The purchase goes through once the player
+    /// sells or abandons `must_free` properties; a dry run is not held.
+    #[serde(rename = "inventoryFull")]
+    InventoryFull { item_id: String, must_free: usize },
+    /// Answer to a dry-run purchase. When the item is not affordable the
+    /// resulting balance is the current one.
+    #[serde(rename = "purchasePreview")]
+    PurchasePreview {
+        item_id: String,
+        price: u64,
+        resulting_balance: u64,
+        affordable: bool,
+    },
+    #[serde(rename = "propertyUpgraded")]
+    PropertyUpgraded {
+        property_name: String,
+        new_reward: u32,
+        new_level: u32,
+    },
+    #[serde(rename = "sellAck")]
+    SellAck {
+        property_name: String,
+        refund: u64,
+        new_balance: u64,
+    },
+    #[serde(rename = "propertyAbandoned")]
+    PropertyAbandoned { property_name: String },
+    #[serde(rename = "challengeRequest")]
+    ChallengeRequest {
+        challenger: Uuid,
+        challenger_name: String,
+        /// Tokens staked; zero for a property stake.
+        stake_amount: u64,
+        /// What is at stake. A property stake is the challenger's alone:
+        /// accepting it costs the target nothing, win or lose.
+        stake: StakeKind,
+        /// Id others can spectate the battle with.
+        battle_id: Uuid,
+    },
+    #[serde(rename = "challengeResponse")]
+    ChallengeResponse { message: String, battle_id: Uuid },
+    #[serde(rename = "challengeDeclined")]
+    ChallengeDeclined { target: Uuid },
+    /// Sent to both players when the challenger takes a challenge back.
+    #[serde(rename = "challengeCancelled")]
+    ChallengeCancelled { challenger: Uuid },
+    #[serde(rename = "giftAck")]
+    GiftAck { target: Uuid, kind: Gift },
+    #[serde(rename = "giftReceived")]
+    GiftReceived { from: Uuid, kind: Gift },
+    /// Most recent battles first.
+    #[serde(rename = "battleHistory")]
+    BattleHistory {
+        wins: u32,
+        losses: u32,
+        recent: Vec<BattleRecord>,
+    },
+    #[serde(rename = "battleResult")]
+    BattleResult {
+        battle_id: Uuid,
+        winner: Uuid,
+        loser: Uuid,
+        pot: u64,
+        /// The loser gave up instead of fighting.
+        forfeited: bool,
+        /// The property the challenger staked, if the target won it.
+        /// Property stakes are one-sided, so a winning challenger never
+        /// gets one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        property: Option<Property>,
+        /// Fought against the starter bot.
+        bot: bool,
+    },
+    #[serde(rename = "spectating")]
+    Spectating { battle_id: Uuid },
+    /// Progress of a spectated battle.
+    #[serde(rename = "battleUpdate")]
+    BattleUpdate {
+        battle_id: Uuid,
+        status: BattleStatus,
+    },
+    #[serde(rename = "challengeExpired")]
+    ChallengeExpired { target: Uuid },
+    #[serde(rename = "announcement")]
+    Announcement {
+        text: String,
+        level: AnnouncementLevel,
+    },
+    #[serde(rename = "error")]
+    Error {
+        code: String,
+        detail: String,
+        /// Seconds until the request may succeed, for errors that only
+        /// hold for a while.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_secs: Option<u64>,
+        /// Protocol version the server speaks, for `unsupported_version`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        server_version: Option<u32>,
+    },
+    #[serde(rename = "serverShutdown")]
+    ServerShutdown { reason: String },
+    #[serde(rename = "seasonReset")]
+    SeasonReset { season: u32 },
+    #[serde(rename = "eventStarted")]
+    EventStarted { multiplier_percent: u32 },
+    #[serde(rename = "eventEnded")]
+    EventEnded,
+    #[serde(rename = "watching")]
+    Watching { target: Uuid },
+    #[serde(rename = "unwatched")]
+    Unwatched { target: Uuid },
+    #[serde(rename = "friendAdded")]
+    FriendAdded { id: Uuid, username: String },
+    #[serde(rename = "friends")]
+    Friends { friends: Vec<FriendInfo> },
+    #[serde(rename = "friendOnline")]
+    FriendOnline { id: Uuid, username: String },
+    #[serde(rename = "friendOffline")]
+    FriendOffline { id: Uuid, username: String },
+    #[serde(rename = "playerUpdate")]
+    PlayerUpdate {
+        id: Uuid,
+        username: String,
+        event: PlayerEvent,
+    },
+    #[serde(rename = "statusChanged")]
+    StatusChanged { status: PlayerStatus },
+    #[serde(rename = "notificationPrefs")]
+    NotificationPrefs(NotificationPrefs),
+    #[serde(rename = "privacy")]
+    Privacy(PrivacySettings),
+    #[serde(rename = "reportReceived")]
+    ReportReceived { target: Uuid },
+    #[serde(rename = "seasonList")]
+    SeasonList { seasons: Vec<u32> },
+    #[serde(rename = "tradeOffer")]
+    TradeOffer {
+        from: Uuid,
+        offer_property: String,
+        request_property: String,
+    },
+    /// Sent to both parties of a trade, from their own point of view.
+    #[serde(rename = "tradeCompleted")]
+    TradeCompleted {
+        partner: Uuid,
+        gave: String,
+        received: String,
+        /// Tokens this side paid as the transfer fee, burned.
+        fee: u64,
+    },
+    #[serde(rename = "tradeRejected")]
+    TradeRejected { partner: Uuid, reason: String },
+    #[serde(rename = "channelJoined")]
+    ChannelJoined { channel: String },
+    #[serde(rename = "channelLeft")]
+    ChannelLeft { channel: String },
+    #[serde(rename = "chatMessage")]
+    ChatMessage {
+        channel: String,
+        from: Uuid,
+        username: String,
+        text: String,
+        timestamp: u64,
+    },
+    /// Rewards accrued since the last claim were collected and minted.
+    /// They were already in the balance. Times are unix seconds.
+    #[serde(rename = "rewardClaimed")]
+    RewardClaimed { amount: u64, next_claim_at: u64 },
+    #[serde(rename = "rewardUnavailable")]
+    RewardUnavailable { next_claim_at: u64 },
+    /// Too many blocked chat messages; the player may not chat until
+    /// `until`, in unix seconds.
+    #[serde(rename = "muted")]
+    Muted { until: u64 },
+    #[serde(rename = "leaderboard")]
+    Leaderboard { entries: Vec<LeaderboardEntry> },
+    #[serde(rename = "seasonArchive")]
+    SeasonArchive {
+        season: u32,
+        standings: Vec<SeasonStanding>,
+    },
+}
+
+/// A challenge waiting for the target's answer. The challenger's stake
+/// is held in escrow meanwhile.
+#[derive(Debug, Clone)]
+struct PendingChallenge {
+    battle_id: Uuid,
+    stake: StakeKind,
+    expires_at: Instant,
+}
+
+/// How the starter bot fights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BotConfig {
+    pvp_level: u32,
+    /// Tokens an account can win from the bot per UTC day.
+    daily_reward_cap: u64,
+}
+
+/// A pending challenge as listed in `PendingActions`.
+#[derive(Debug, Clone, Serialize)]
+struct PendingChallengeSummary {
+    battle_id: Uuid,
+    challenger: Uuid,
+    target: Uuid,
+    stake: StakeKind,
+}
+
+/// A pending trade offer as listed in `PendingActions`.
+#[derive(Debug, Clone, Serialize)]
+struct PendingTradeSummary {
+    from: Uuid,
+    target: Uuid,
+    offer_property: String,
+    request_property: String,
+}
+
+/// What a battle is fought for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StakeKind {
+    /// Tokens each player puts in escrow; the winner takes both.
+    Balance(u64),
+    /// A property of the challenger's, by name, which goes to the target
+    /// if they win. It is not escrowed, so ownership is checked again
+    /// when the challenge is accepted and when the battle is settled.
+    /// The stake is one-sided: the target puts nothing up, so a
+    /// challenger who wins takes nothing from them.
+    Property(String),
+}
+
+impl StakeKind {
+    /// Tokens each player puts in escrow.
+    fn tokens(&self) -> u64 {
+        match self {
+            StakeKind::Balance(amount) => *amount,
+            StakeKind::Property(_) => 0,
+        }
+    }
+
+    fn property(&self) -> Option<&str> {
+        match self {
+            StakeKind::Balance(_) => None,
+            StakeKind::Property(name) => Some(name),
+        }
+    }
+}
+
+/// An accepted challenge being fought, with both stakes in escrow.
+#[derive(Debug, Clone)]
+struct ActiveBattle {
+    challenger: Uuid,
+    defender: Uuid,
+    challenge: PendingChallenge,
+    /// When the battle is resolved, unless it is paused.
+    ends_at: Instant,
+    /// Fighting time left when the battle was paused.
+    remaining: Duration,
+    /// Players who disconnected mid-battle, and since when. The battle
+    /// is paused until they are all back.
+    away: Vec<(Uuid, Instant)>,
+}
+
+impl ActiveBattle {
+    fn new(
+        challenger: Uuid,
+        defender: Uuid,
+        challenge: PendingChallenge,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            challenger,
+            defender,
+            challenge,
+            ends_at: Instant::now() + duration,
+            remaining: duration,
+            away: Vec::new(),
+        }
+    }
+
+    fn has_player(&self, id: Uuid) -> bool {
+        self.challenger == id || self.defender == id
+    }
+
+    /// The other player of the battle `id` is fighting.
+    fn opponent_of(&self, id: Uuid) -> Uuid {
+        if id == self.challenger {
+            self.defender
+        } else {
+            self.challenger
+        }
+    }
+}
+
+/// Backoff state of challenges from one player to another.
+#[derive(Debug, Default)]
+struct ChallengeCooldown {
+    /// Declines since the last accepted challenge.
+    declines: u32,
+    /// No new challenge is allowed before this.
+    until: Option<Instant>,
+}
+
+/// A resumable session as listed for admins.
+#[derive(Debug, Clone, Serialize)]
+struct DisconnectedSession {
+    id: Uuid,
+    username: String,
+    disconnected_secs: u64,
+}
+
+/// How purchases are handled once a player owns `max_properties`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InventoryCap {
+    /// Refuse the purchase.
+    Reject,
+    /// Hold the purchase and ask the player to sell or abandon enough
+    /// properties to make room for it.
+    Prompt,
+}
+
+/// The messages sent to one session, numbered so clients can spot gaps
+/// and catch up on what they missed after resuming.
+#[derive(Debug, Default)]
+struct Outbox {
+    /// `seq` of the last message sent; the first one is 1.
+    last_seq: u64,
+    /// The most recent messages, oldest first, at most
+    /// `REPLAY_BUFFER_CAPACITY` of them.
+    sent: VecDeque<SentMessage>,
+}
+
+#[derive(Debug, Clone)]
+struct SentMessage {
+    seq: u64,
+    message: ServerMessage,
+    request_id: Option<u64>,
+}
+
+impl Outbox {
+    /// Number `message` and keep it for replay. Returns its `seq`.
+    fn push(&mut self, message: ServerMessage, request_id: Option<u64>) -> u64 {
+        self.last_seq += 1;
+        if self.sent.len() >= REPLAY_BUFFER_CAPACITY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(SentMessage {
+            seq: self.last_seq,
+            message,
+            request_id,
+        });
+        self.last_seq
+    }
+
+    /// The kept messages after `seq`, and whether any after it were
+    /// already dropped from the buffer.
+    fn since(&self, seq: u64) -> (Vec<SentMessage>, bool) {
+        let missed = self
+            .sent
+            .iter()
+            .filter(|sent| sent.seq > seq)
+            .cloned()
+            .collect();
+        let oldest = self.sent.front().map_or(self.last_seq + 1, |sent| sent.seq);
+        (missed, oldest > seq + 1)
+    }
+}
+
+/// Why a purchase stops before the player is charged.
+enum PurchaseBlock {
+    /// The ack of an earlier purchase with the same idempotency key.
+    Replayed(ServerMessage),
+    /// The inventory is full and the purchase can't be held.
+    Refused(ServerMessage),
+    /// The inventory is full until `must_free` properties are freed.
+    MustFree(usize),
+}
+
+/// A purchase waiting for room in the player's inventory.
+#[derive(Debug, Clone)]
+struct HeldPurchase {
+    item_id: String,
+    category: String,
+    idempotency_key: Option<String>,
+}
+
+/// What a player asked to gift.
+enum GiftRequest {
+    Tokens(u64),
+    /// A property, by name.
+    Property(String),
+}
+
+/// A gift that changed hands.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Gift {
+    /// `amount` is what the recipient got, after the burned `fee`.
+    Tokens {
+        amount: u64,
+        fee: u64,
+    },
+    Property {
+        property: Property,
+    },
+}
+
+/// A finished battle as seen by one of its players.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BattleRecord {
+    battle_id: Uuid,
+    /// Name the opponent had at the time.
+    opponent: String,
+    won: bool,
+    /// Tokens paid to the winner.
+    pot: u64,
+    /// The loser gave up instead of fighting.
+    forfeited: bool,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+}
+
+/// Something that happened in the game, published on the event bus.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum GameEvent {
+    PlayerJoined {
+        id: Uuid,
+        account: String,
+        username: String,
+    },
+    PlayerLeft {
+        id: Uuid,
+        account: String,
+    },
+    Purchase {
+        id: Uuid,
+        account: String,
+        item_id: String,
+        category: String,
+        price: u64,
+    },
+    ChallengeSent(ChallengeRecord),
+    ChallengeResolved(ChallengeRecord),
+}
+
+/// A challenge in the replay log.
+#[derive(Debug, Clone, Serialize)]
+struct ChallengeRecord {
+    /// Battle id assigned when the challenge was sent.
+    id: Uuid,
+    challenger: Uuid,
+    target: Uuid,
+    /// Tokens staked by each player.
+    stake: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stake_property: Option<String>,
+    outcome: ChallengeOutcome,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+}
+
+/// What happened to a challenge. Every challenge is logged once as
+/// `Sent` and once more when it is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChallengeOutcome {
+    Sent,
+    ChallengerWon,
+    TargetWon,
+    Declined,
+    Expired,
+    /// A player left, or the battle could not be fought.
+    Cancelled,
+}
+
+/// Stage of a battle as reported to spectators.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BattleStatus {
+    /// The challenge was accepted and the battle is being fought.
+    Started,
+    /// A player disconnected and the battle waits for them to resume.
+    Paused,
+    /// Both players are back and the battle is being fought again.
+    Resumed,
+    /// The challenge was declined, expired or a player left.
+    Cancelled,
+}
+
+/// Result of a resolved battle.
+#[derive(Debug)]
+struct BattleOutcome {
+    winner: Uuid,
+    winner_name: String,
+    winner_level: u32,
+    loser: Uuid,
+    loser_name: String,
+    /// Tokens paid to the winner, both stakes together.
+    pot: u64,
+    /// The staked property the winner took from the challenger.
+    property: Option<Property>,
+}
+
+/// A trade proposed by one player to another: the offerer gives
+/// `offer_property` in exchange for the target's `request_property`.
+#[derive(Debug, Clone)]
+struct TradeOffer {
+    offer_property: String,
+    request_property: String,
+    expires_at: Instant,
+}
+
+/// Decide a battle between a challenger and a defender and return
+/// `(winner, loser)`. The higher PvP level wins; equal levels are
+/// decided by total daily reward, and a full tie goes to the defender.
+///
+/// With a `luck_percent` above zero each side's daily reward is first
+/// scaled by a roll drawn uniformly from `100 - luck..=100 + luck`
+/// percent: two draws from `rng` per battle, the challenger's first.
+/// The same seed and sequence of battles therefore gives the same
+/// outcomes. At zero luck nothing is drawn and the seed doesn't matter.
+fn resolve_battle(
+    challenger: (Uuid, &ClientInfo),
+    defender: (Uuid, &ClientInfo),
+    luck_percent: u32,
+    rng: &mut impl Rng,
+) -> (Uuid, Uuid) {
+    let luck = u64::from(luck_percent.min(100));
+    let mut strength = |info: &ClientInfo| {
+        let roll = if luck > 0 {
+            rng.random_range(100 - luck..=100 + luck)
+        } else {
+            100
+        };
+        (info.pvp_level, daily_reward(&info.properties) * roll)
+    };
+    let (challenger_strength, defender_strength) = (strength(challenger.1), strength(defender.1));
+    if challenger_strength > defender_strength {
+        (challenger.0, defender.0)
+    } else {
+        (defender.0, challenger.0)
+    }
+}
+
+impl ServerMessage {
+    /// Build an error response with a machine readable `code` and a
+    /// human readable `detail`.
+    fn error(code: &str, detail: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code: code.into(),
+            detail: detail.into(),
+            retry_after_secs: None,
+            server_version: None,
+        }
+    }
+
+    /// The kind of notification this push is, for messages players can
+    /// opt out of.
+    fn notification(&self) -> Option<Notification> {
+        match self {
+            ServerMessage::ChatMessage { .. } => Some(Notification::Chat),
+            ServerMessage::PlayerJoined { .. }
+            | ServerMessage::PlayerLeft { .. }
+            | ServerMessage::PlayerRenamed { .. }
+            | ServerMessage::FriendOnline { .. }
+            | ServerMessage::FriendOffline { .. } => Some(Notification::Presence),
+            ServerMessage::ChallengeRequest { .. } => Some(Notification::Challenges),
+            _ => None,
+        }
+    }
+}
+
+/// Check the `v` field of a message and rewrite messages of older
+/// protocol versions into their current shape. Anything that isn't an
+/// object is left for deserialization to reject.
+fn upgrade_message(value: &mut serde_json::Value) -> Result<(), ServerMessage> {
+    let Some(object) = value.as_object_mut() else {
+        return Ok(());
+    };
+    let Some(version) = object.remove("v") else {
+        return Ok(());
+    };
+    let supported = MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+    let version = version
+        .as_u64()
+        .and_then(|version| u32::try_from(version).ok())
+        .filter(|version| supported.contains(version))
+        .ok_or_else(|| ServerMessage::Error {
+            code: "unsupported_version".into(),
+            detail: format!(
+                "protocol versions {} to {} are supported, got {}",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, version
+            ),
+            retry_after_secs: None,
+            server_version: Some(PROTOCOL_VERSION),
+        })?;
+    if version == 0 && object.get("type").and_then(|kind| kind.as_str()) == Some("challenge") {
+        // Version 0 challenges carried a `stake` flag that was never
+        // charged, which is a challenge without a stake now.
+        if matches!(object.get("stake"), Some(serde_json::Value::Bool(_))) {
+            object.remove("stake");
+        }
+    }
+    Ok(())
+}
+
+/// Explain why `text` could not be parsed into a `ClientMessage`. The
+/// description names the message type and the offending field where
+/// possible (e.g. "purchase requires item_id") so that client
+/// developers can fix their requests without reading server logs.
+fn describe_invalid_message(text: &str) -> String {
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(_) => return "message is not valid JSON".into(),
+    };
+    let Some(object) = value.as_object() else {
+        return "message must be a JSON object".into();
+    };
+    let kind = match object.get("type") {
+        Some(serde_json::Value::String(kind)) => kind.clone(),
+        Some(_) => return "type field must be a string".into(),
+        None => return "message requires a type field".into(),
+    };
+    let err = match ClientMessage::deserialize(&value) {
+        Ok(_) => return format!("{} message could not be parsed", kind),
+        Err(err) => err.to_string(),
+    };
+    if err.starts_with("unknown variant") {
+        return format!("unknown message type '{}'", kind);
+    }
+    if let Some(field) = err
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next())
+    {
+        return format!("{} requires {}", kind, field);
+    }
+    // The field is present but has the wrong shape. Serde doesn't name
+    // it, so find the first field that fails the same way on its own.
+    // Each probe copies only that field, keeping this linear in the
+    // size of the message.
+    let malformed = object
+        .iter()
+        .filter(|(key, _)| *key != "type")
+        .find(|(key, field)| {
+            let probe = serde_json::Value::Object(serde_json::Map::from_iter([
+                ("type".to_owned(), serde_json::Value::String(kind.clone())),
+                ((*key).clone(), (*field).clone()),
+            ]));
+            ClientMessage::deserialize(&probe).is_err_and(|probe_err| probe_err.to_string() == err)
+        })
+        .map(|(key, _)| key);
+    match malformed {
+        Some(field) => format!("{} field {} is malformed", kind, field),
+        None => format!("{} message is malformed", kind),
+    }
+}
+
+/// Encode a message as MessagePack. Structs become maps and ids are
+/// written as strings, so the payload mirrors the JSON protocol.
+fn encode_msgpack<T: Serialize>(payload: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let mut bytes = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut bytes)
+        .with_struct_map()
+        .with_human_readable();
+    payload.serialize(&mut serializer)?;
+    Ok(bytes)
+}
+
+/// Decode a MessagePack message written like `encode_msgpack` does.
+fn decode_msgpack<T: serde::de::DeserializeOwned>(
+    bytes: &[u8],
+) -> Result<T, rmp_serde::decode::Error> {
+    let mut deserializer = rmp_serde::Deserializer::new(bytes).with_human_readable();
+    T::deserialize(&mut deserializer)
+}
+
+/// `describe_invalid_message` for a MessagePack frame.
+fn describe_invalid_msgpack(bytes: &[u8]) -> String {
+    match decode_msgpack::<serde_json::Value>(bytes) {
+        Ok(value) => describe_invalid_message(&value.to_string()),
+        Err(_) => "message is not valid MessagePack".into(),
+    }
+}
+
+impl Handler<ServerMessage> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerMessage, ctx: &mut Self::Context) -> Self::Result {
+        // Simply forward the server message to the client over the WebSocket.
+        self.send_json(ctx, &msg);
+        if let ServerMessage::Kicked { reason } = msg {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some(reason),
+            }));
+            ctx.stop();
+        }
+    }
+}
+
+impl Actor for WsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        self.state.metrics.active_connections.inc();
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if act.last_heartbeat.elapsed() > CLIENT_TIMEOUT {
+                let _span = act.span.enter();
+                info!("Client {} timed out, closing the session", act.id);
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+        let timeout = self.state.auth_timeout;
+        let timer = ctx.run_later(timeout, |act, ctx| act.enforce_auth_timeout(ctx));
+        self.auth_timer = Some(timer);
+        if let Some(after) = self.replay_after.take() {
+            self.replay(ctx, after);
+        }
+        let welcome = ServerMessage::Welcome {
+            session_id: self.id,
+            resume_token: self.resume_token,
+        };
+        self.send_json(ctx, &welcome);
+        // The session is registered in the global state once the client
+        // authenticates, see `ClientMessage::Authenticate`.
+        info!(
+            "Client {} connected (ip: {}, origin: {}, user agent: {}, subprotocol: {})",
+            self.id,
+            self.metadata.remote_ip.as_deref().unwrap_or("unknown"),
+            self.metadata.origin.as_deref().unwrap_or("none"),
+            self.metadata.user_agent.as_deref().unwrap_or("none"),
+            self.subprotocol.unwrap_or("none"),
+        );
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        let _span = self.span.enter();
+        // Remove the client from the state on disconnect.
+        self.state.metrics.active_connections.dec();
+        let id = self.id;
+        let state = self.state.clone();
+        actix::spawn(
+            async move {
+                state.pause_battles(id).await;
+                let removed = {
+                    // Hold the shard until the player is saved and parked,
+                    // so a new login of the account can't load a stale copy.
+                    let mut clients = state.clients.write(&id).await;
+                    match clients.remove(&id) {
+                        Some(mut info) => {
+                            info.dirty = state.save_players(&[info.to_stored()]).await == 0;
+                            let names = (info.account.clone(), info.username.clone());
+                            // Keep the session around so the client can resume it.
+                            info.addr = None;
+                            let mut disconnected = state.disconnected.write().await;
+                            disconnected.insert(id, (info, Instant::now()));
+                            Some(names)
+                        }
+                        None => None,
+                    }
+                };
+                let parked = removed.is_some();
+                if let Some((account, username)) = removed {
+                    state
+                        .broadcast(ServerMessage::PlayerLeft { id }, None)
+                        .await;
+                    state.notify_friends(id, &account, &username, false).await;
+                    state.publish(GameEvent::PlayerLeft { id, account });
+                }
+                state.remove_watcher_links(id).await;
+                if parked {
+                    // Challenges and trade offers stay open while the
+                    // session can be resumed.
+                    let grace_seconds = Some(RESUME_GRACE_PERIOD.as_secs());
+                    for counterpart in state.pending_counterparts(id).await {
+                        let notice = ServerMessage::OpponentDisconnected { id, grace_seconds };
+                        state.deliver(counterpart, notice).await;
+                    }
+                } else {
+                    state.abandon_session(id).await;
+                }
+                state.leave_matchmaking(id).await;
+                state.remove_spectator(id).await;
+                let session = id.to_string();
+                let _ = state.metrics.chat_dropped.remove_label_values(&[&session]);
+            }
+            .instrument(self.span.clone()),
+        );
+        info!("Client {} disconnected", id);
+        Running::Stop
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
+    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        // Any frame proves the connection is still alive.
+        if item.is_ok() {
+            self.last_heartbeat = Instant::now();
+        }
+        match item {
+            Ok(ws::Message::Text(text)) => {
+                // Parse JSON from client into a strongly typed message.
+                let parsed = serde_json::from_str::<serde_json::Value>(&text)
+                    .map_err(|err| {
+                        error!("Invalid message from client {}: {}", self.id, err);
+                        ServerMessage::error("bad_request", describe_invalid_message(&text))
+                    })
+                    .and_then(|value| self.decode_request(value));
+                let cost = parsed.as_ref().map_or(1, |request| request.msg.cost());
+                if !self.admit_request(ctx, cost) {
+                    return;
+                }
+                self.dispatch(ctx, parsed);
+            }
+            // Binary frames carry MessagePack and are only understood by
+            // sessions that asked for it.
+            Ok(ws::Message::Binary(bytes)) if self.format == WireFormat::MessagePack => {
+                let parsed = decode_msgpack::<serde_json::Value>(&bytes)
+                    .map_err(|err| {
+                        error!("Invalid message from client {}: {}", self.id, err);
+                        ServerMessage::error("bad_request", describe_invalid_msgpack(&bytes))
+                    })
+                    .and_then(|value| self.decode_request(value));
+                let cost = parsed.as_ref().map_or(1, |request| request.msg.cost());
+                if !self.admit_request(ctx, cost) {
+                    return;
+                }
+                self.dispatch(ctx, parsed);
+            }
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Pong(_)) => (),
+            Ok(ws::Message::Binary(_)) => (),
+            Ok(ws::Message::Close(reason)) => {
+                match &reason {
+                    Some(ws::CloseReason { code, description }) => info!(
+                        "Client {} closed the connection ({:?}: {})",
+                        self.id,
+                        code,
+                        description.as_deref().unwrap_or("no description"),
+                    ),
+                    None => info!("Client {} closed the connection", self.id),
+                }
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(ws::ProtocolError::Overflow) => {
+                info!("Client {} sent an oversized frame", self.id);
+                let detail = format!("frames are limited to {} bytes", MAX_FRAME_SIZE);
+                self.send_json(ctx, &ServerMessage::error("message_too_large", detail));
+                ctx.close(Some(ws::CloseCode::Size.into()));
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// The subprotocol to use for a handshake: `SUBPROTOCOL` if the client
+/// offered it in any of its `Sec-WebSocket-Protocol` headers.
+fn negotiate_subprotocol(req: &HttpRequest) -> Option<&'static str> {
+    req.headers()
+        .get_all(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|offered| offered.trim() == SUBPROTOCOL)
+        .then_some(SUBPROTOCOL)
+}
+
+/// WebSocket endpoint. Upgrades an HTTP request to a WebSocket
+/// connection and creates a new session actor. Each new connection
+/// receives a unique UUID. When the server is at its session limit the
+/// upgrade is refused with 503 and a `Retry-After` hint.
+#[get("/ws")]
+async fn websocket_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    params: web::Query<ConnectParams>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse, Error> {
+    if params.format == WireFormat::MessagePack && !data.features.msgpack {
+        return Ok(HttpResponse::BadRequest().body("msgpack is disabled on this server"));
+    }
+    let Some(permit) = data.acquire_session() else {
+        info!("Refusing connection: session limit reached");
+        return Ok(HttpResponse::ServiceUnavailable()
+            .insert_header((
+                actix_web::http::header::RETRY_AFTER,
+                SESSION_RETRY_AFTER_SECS.to_string(),
+            ))
+            .finish());
+    };
+    let resumed = match params.resume_token {
+        Some(token) => data.take_resumable(token).await,
+        None => None,
+    };
+    let resumed = match resumed {
+        Some((_, info)) if !data.begin_login(&info.account).await => {
+            warn!(
+                "Not resuming {}: the account is signed in again",
+                info.username
+            );
+            None
+        }
+        resumed => resumed,
+    };
+    let id = resumed.as_ref().map_or_else(Uuid::new_v4, |(id, _)| *id);
+    // Tokens are single use; every connection gets a fresh one.
+    let resume_token = Uuid::new_v4();
+    let metadata = SessionMetadata::from_request(&req);
+    let subprotocol = negotiate_subprotocol(&req);
+    let mut session = WsSession::new(
+        id,
+        resume_token,
+        params.format,
+        subprotocol,
+        data.get_ref().clone(),
+        metadata.clone(),
+        permit,
+    );
+    if let Some((_, info)) = &resumed {
+        session.outbox = info.outbox.clone();
+        session.replay_after = params.last_seq;
+    }
+    let started = ws::WsResponseBuilder::new(session, &req, stream)
+        .frame_size(MAX_FRAME_SIZE)
+        .protocols(&[SUBPROTOCOL])
+        .start_with_addr();
+    let (addr, response) = match started {
+        Ok(started) => started,
+        Err(err) => {
+            if let Some((_, info)) = &resumed {
+                data.finish_login(&info.account).await;
+            }
+            return Err(err);
+        }
+    };
+    if let Some((_, mut info)) = resumed {
+        // Storage is authoritative while the player is offline, e.g. a
+        // season reset may have happened in the meantime.
+        match data.storage.load_player(&info.account).await {
+            Ok(Some(stored)) => info.restore(stored),
+            Ok(None) => (),
+            Err(err) => warn!("Resuming {} from cache: {}", info.username, err),
+        }
+        info!("Client {} resumed its session as {}", id, info.username);
+        info.addr = Some(addr.clone());
+        info.status = PlayerStatus::Available;
+        info.metadata = metadata;
+        info.resume_token = Some(resume_token);
+        let joined = ServerMessage::PlayerJoined {
+            id,
+            username: info.username.clone(),
+            pvp_level: info.pvp_level,
+        };
+        let (account, username) = (info.account.clone(), info.username.clone());
+        data.clients.insert(id, info).await;
+        data.finish_login(&account).await;
+        data.decay_idle_rewards(id).await;
+        data.accrue(id).await;
+        data.resume_battles(id).await;
+        let pending = data.pending_actions(id).await;
+        data.deliver(id, pending).await;
+        data.notify_friends(id, &account, &username, true).await;
+        data.publish(GameEvent::PlayerJoined {
+            id,
+            account,
+            username,
+        });
+        data.broadcast(joined, Some(id)).await;
+    }
+    Ok(response)
+}
+
+/// Body of an admin broadcast request.
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    text: String,
+    #[serde(default)]
+    level: AnnouncementLevel,
+}
+
+/// Admin endpoint that pushes an announcement to every connected
+/// client. Requires the admin bearer token and responds with the
+/// number of clients the announcement was delivered to.
+#[post("/admin/broadcast")]
+async fn admin_broadcast(
+    req: HttpRequest,
+    body: web::Json<BroadcastRequest>,
+    data: web::Data<ServerState>,
+) -> HttpResponse {
+    if !data.is_admin_request(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let BroadcastRequest { text, level } = body.into_inner();
+    let recipients = data
+        .broadcast(ServerMessage::Announcement { text, level }, None)
+        .await;
+    info!("Admin broadcast delivered to {} clients", recipients);
+    HttpResponse::Ok().json(serde_json::json!({ "recipients": recipients }))
+}
+
+/// Admin endpoint reporting server health counters.
+#[get("/admin/stats")]
+async fn admin_stats(req: HttpRequest, data: web::Data<ServerState>) -> HttpResponse {
+    if !data.is_admin_request(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let connected_clients = data.clients.len().await;
+    HttpResponse::Ok().json(serde_json::json!({
+        "connected_clients": connected_clients,
+        "dropped_outbound_messages": data.metrics.dropped_outbound.get(),
+    }))
+}
+
+/// Liveness probe: answers as long as the process is serving requests.
+#[get("/health")]
+async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness probe: 503 until startup has finished, e.g. storage is
+/// connected, and again once shutdown has begun.
+#[get("/ready")]
+async fn ready(data: web::Data<ServerState>) -> HttpResponse {
+    let ready = data.ready.load(Ordering::Acquire);
+    let body = serde_json::json!({ "ready": ready });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Prometheus scrape endpoint.
+#[get("/metrics")]
+async fn metrics_endpoint(data: web::Data<ServerState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(Metrics::content_type())
+        .body(data.metrics.render())
+}
+
+/// Public health endpoint for monitoring: player and property counts
+/// plus uptime. Unlike the admin stats it needs no token.
+#[get("/stats")]
+async fn server_stats(data: web::Data<ServerState>) -> HttpResponse {
+    let (connected_players, total_properties) = {
+        let clients = data.clients.read_all().await;
+        let properties: usize = clients.values().map(|info| info.properties.len()).sum();
+        (clients.len(), properties)
+    };
+    HttpResponse::Ok().json(serde_json::json!({
+        "connected_players": connected_players,
+        "total_properties": total_properties,
+        "uptime_seconds": data.started_at.elapsed().as_secs(),
+        "dropped_outbound_messages": data.metrics.dropped_outbound.get(),
+    }))
+}
+
+/// Connected session as reported to admins.
+#[derive(Serialize)]
+struct AdminSessionInfo {
+    id: Uuid,
+    username: String,
+    metadata: SessionMetadata,
+}
+
+/// Admin endpoint listing connected sessions with their connection
+/// metadata.
+#[get("/admin/sessions")]
+async fn admin_sessions(req: HttpRequest, data: web::Data<ServerState>) -> HttpResponse {
+    if !data.is_admin_request(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let sessions: Vec<AdminSessionInfo> = {
+        let clients = data.clients.read_all().await;
+        clients
+            .iter()
+            .map(|(id, info)| AdminSessionInfo {
+                id: *id,
+                username: info.username.clone(),
+                metadata: info.metadata.clone(),
+            })
+            .collect()
+    };
+    HttpResponse::Ok().json(sessions)
+}
+
+/// Admin endpoint listing player reports awaiting review, oldest first.
+#[get("/admin/reports")]
+async fn admin_reports(req: HttpRequest, data: web::Data<ServerState>) -> HttpResponse {
+    if !data.is_admin_request(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match data.storage.list_reports(MAX_STORED_REPORTS).await {
+        Ok(reports) => HttpResponse::Ok().json(reports),
+        Err(err) => {
+            error!("Failed to list reports: {}", err);
+            HttpResponse::ServiceUnavailable().finish()
+        }
+    }
+}
+
+/// Query parameters of the admin audit endpoint.
+#[derive(Deserialize)]
+struct AuditQuery {
+    account: String,
+}
+
+/// Admin endpoint returning the recent audit events of one account,
+/// given by the name it logs in as, over all of its sessions, oldest
+/// first.
+#[get("/admin/audit")]
+async fn admin_audit(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    data: web::Data<ServerState>,
+) -> HttpResponse {
+    if !data.is_admin_request(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let events: Vec<AuditEvent> = data
+        .audit_log
+        .read()
+        .await
+        .iter()
+        .filter(|event| event.account == query.account)
+        .cloned()
+        .collect();
+    HttpResponse::Ok().json(events)
+}
+
+/// Query parameters of the recent challenges endpoint.
+#[derive(Deserialize)]
+struct RecentChallengesQuery {
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// The most recent challenge records, oldest first.
+#[get("/challenges/recent")]
+async fn recent_challenges(
+    query: web::Query<RecentChallengesQuery>,
+    data: web::Data<ServerState>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_CHALLENGE_PAGE);
+    let records: Vec<ChallengeRecord> = {
+        let log = data.challenge_log.read().await;
+        log.iter()
+            .skip(log.len().saturating_sub(limit))
+            .cloned()
+            .collect()
+    };
+    HttpResponse::Ok().json(records)
+}
+
+/// Body of an admin season reset request.
+#[derive(Deserialize)]
+struct SeasonResetRequest {
+    #[serde(default)]
+    scope: SeasonResetScope,
+}
+
+/// Admin endpoint that closes the current season, archives its
+/// standings and notifies every connected client of the reset.
+#[post("/admin/season/reset")]
+async fn admin_season_reset(
+    req: HttpRequest,
+    body: web::Json<SeasonResetRequest>,
+    data: web::Data<ServerState>,
+) -> HttpResponse {
+    if !data.is_admin_request(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let season = data.reset_season(body.scope).await;
+    data.broadcast(ServerMessage::SeasonReset { season }, None)
+        .await;
+    info!("Season {} closed ({:?} reset)", season, body.scope);
+    HttpResponse::Ok().json(serde_json::json!({ "season": season }))
+}
+
+/// Body of an admin economy update.
+#[derive(Deserialize)]
+struct EconomyConfigRequest {
+    /// New transfer fee, in basis points.
+    transfer_fee_bps: u32,
+}
+
+/// Admin endpoint that changes the transfer fee while the server runs.
+/// The new rate applies to the next gift or trade.
+#[post("/admin/economy")]
+async fn admin_economy(
+    req: HttpRequest,
+    body: web::Json<EconomyConfigRequest>,
+    data: web::Data<ServerState>,
+) -> HttpResponse {
+    if !data.is_admin_request(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let rate = body.transfer_fee_bps;
+    if rate >= MAX_TRANSFER_FEE_BPS {
+        let detail = format!("transfer_fee_bps must be below {}", MAX_TRANSFER_FEE_BPS);
+        return HttpResponse::BadRequest().json(ServerMessage::error("invalid_fee", detail));
+    }
+    let previous = data.transfer_fee_bps.swap(rate, Ordering::Relaxed);
+    info!("Transfer fee changed from {} to {} bps", previous, rate);
+    HttpResponse::Ok().json(serde_json::json!({ "transfer_fee_bps": rate }))
+}
+
+/// Install the global log subscriber. Verbosity is controlled with
+/// `RUST_LOG` using `tracing` env filter syntax (for example
+/// `info,africa_universe_server=debug` to see every request) and
+/// `LOG_FORMAT` selects `compact`, `pretty` or `json` output. The
+/// default is `compact` in debug builds and `json` in release builds.
+/// Records emitted by dependencies through `log` are forwarded to the
+/// subscriber.
+fn init_logging() {
+    use tracing_subscriber::EnvFilter;
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().init(),
+        Ok("pretty") => builder.pretty().init(),
+        Ok(_) => builder.compact().init(),
+        Err(_) if cfg!(debug_assertions) => builder.compact().init(),
+        Err(_) => builder.json().init(),
+    }
+}
+
+/// Periodically open and close reward events according to the
+/// schedule, notifying every connected client of changes.
+async fn run_reward_events(state: ServerState) {
+    let mut interval = tokio::time::interval(REWARD_EVENT_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let hour = ((unix_now() / 3600) % 24) as u32;
+        let Some(event) = state.refresh_reward_multiplier(hour) else {
+            continue;
+        };
+        info!("Reward multiplier changed: {:?}", event);
+        state.broadcast(event, None).await;
+    }
+}
+
+/// Log every game event. Falling behind only loses events, so it is
+/// reported and the log carries on.
+async fn run_event_log(mut events: broadcast::Receiver<GameEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => info!("Game event: {:?}", event),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Event log fell behind and skipped {} events", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Build the application with every route, sharing `state` between
+/// workers. Used by `main` and by the tests.
+fn build_app(
+    state: ServerState,
+) -> App<
+    impl actix_web::dev::ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = actix_web::dev::ServiceResponse<impl actix_web::body::MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .wrap(state.allowed_origins.cors())
+        .app_data(web::Data::new(state))
+        .service(websocket_handler)
+        .service(admin_broadcast)
+        .service(server_stats)
+        .service(health)
+        .service(ready)
+        .service(metrics_endpoint)
+        .service(admin_stats)
+        .service(admin_season_reset)
+        .service(admin_economy)
+        .service(admin_sessions)
+        .service(admin_reports)
+        .service(admin_audit)
+        .service(recent_challenges)
+}
+
+/// Wait for SIGTERM or Ctrl-C, then notify clients, flush players to
+/// storage and stop the server after a short grace period.
+async fn shutdown_on_signal(state: ServerState, server: actix_web::dev::ServerHandle) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => (),
+                    _ = tokio::signal::ctrl_c() => (),
+                }
+            }
+            Err(err) => {
+                warn!("Cannot listen for SIGTERM: {}", err);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Shutting down");
+    state.shutdown("server is shutting down").await;
+    tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+    server.stop(true).await;
+}
+
+/// Keep the leaderboard snapshot fresh.
+async fn run_leaderboard_refresh(state: ServerState) {
+    let mut interval = tokio::time::interval(state.leaderboard_refresh);
+    loop {
+        interval.tick().await;
+        state.refresh_leaderboard().await;
+    }
+}
+
+/// Periodically flush players that changed since their last save, so a
+/// crash loses at most one interval of changes.
+async fn run_autosave(state: ServerState) {
+    let mut interval = tokio::time::interval(state.autosave_interval);
+    loop {
+        interval.tick().await;
+        let saved = state.autosave().await;
+        if saved > 0 {
+            info!("Autosaved {} players", saved);
+        }
+    }
+}
+
+/// Periodically drop disconnected sessions that were not resumed in
+/// time, cancel challenges nobody answered and forget old chat strikes.
+async fn run_session_reaper(state: ServerState) {
+    let mut interval = tokio::time::interval(SESSION_REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let reaped = state.reap_disconnected().await;
+        if reaped > 0 {
+            info!("Reaped {} expired sessions", reaped);
+        }
+        let expired = state.expire_challenges().await;
+        if expired > 0 {
+            info!("Expired {} unanswered challenges", expired);
+        }
+        state.prune_chat_strikes().await;
+    }
+}
+
+/// Load a TLS certificate chain and its private key from PEM files.
+/// The key may be PKCS#8, RSA or SEC1; the first one in the file is used.
+fn load_tls_config(cert_path: &str, key_path: &str) -> std::io::Result<rustls::ServerConfig> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .map_err(|err| {
+                std::io::Error::new(err.kind(), format!("cannot open {}: {}", path, err))
+            })
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .map_err(|err| invalid(format!("{} is not valid PEM: {}", cert_path, err)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{} contains no certificates", cert_path)));
+    }
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)
+        .map_err(|err| invalid(format!("{} is not valid PEM: {}", key_path, err)))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("{} contains no private key", key_path)))?;
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key),
+        )
+        .map_err(|err| invalid(format!("invalid TLS certificate or key: {}", err)))
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    init_logging();
+    let mut state = ServerState::new();
+    state.admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    match std::env::var("AUTH_TOKENS") {
+        Ok(spec) => {
+            let auth = StaticTokenAuth::parse(&spec)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+            state.auth = Arc::new(auth);
+        }
+        Err(_) => warn!("AUTH_TOKENS is not set; accepting any token as the username"),
+    }
+    if let Ok(max) = std::env::var("MAX_SESSIONS") {
+        state.max_sessions = max.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("MAX_SESSIONS must be a number, got '{}'", max),
+            )
+        })?;
+    }
+    if let Ok(pretty) = std::env::var("PRETTY_JSON") {
+        state.pretty_json = match pretty.as_str() {
+            "1" | "true" => true,
+            "" | "0" | "false" => false,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("PRETTY_JSON must be true or false, got '{}'", pretty),
+                ))
+            }
+        };
+    }
+    if let Ok(percent) = std::env::var("REWARD_DECAY_PERCENT") {
+        state.reward_decay_percent = percent
+            .parse()
+            .ok()
+            .filter(|percent| *percent <= 100)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("REWARD_DECAY_PERCENT must be 0 to 100, got '{}'", percent),
+                )
+            })?;
+    }
+    if let Ok(percent) = std::env::var("BATTLE_LUCK_PERCENT") {
+        state.battle_luck_percent = percent
+            .parse()
+            .ok()
+            .filter(|percent| *percent <= 100)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("BATTLE_LUCK_PERCENT must be 0 to 100, got '{}'", percent),
+                )
+            })?;
+    }
+    if let Ok(seed) = std::env::var("BATTLE_SEED") {
+        let seed = seed.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("BATTLE_SEED must be a number, got '{}'", seed),
+            )
+        })?;
+        state.seed_battles(seed);
+        info!("Battles are seeded with {}", seed);
+    }
+    if let Ok(max) = std::env::var("MAX_PROPERTIES") {
+        state.max_properties = max.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("MAX_PROPERTIES must be a number, got '{}'", max),
+            )
+        })?;
+    }
+    if let Ok(mode) = std::env::var("INVENTORY_CAP") {
+        state.inventory_cap = match mode.as_str() {
+            "reject" => InventoryCap::Reject,
+            "prompt" => InventoryCap::Prompt,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("INVENTORY_CAP must be reject or prompt, got '{}'", mode),
+                ))
+            }
+        };
+    }
+    if let Ok(path) = std::env::var("STARTER_CONFIG") {
+        let invalid = |err: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("STARTER_CONFIG {}: {}", path, err),
+            )
+        };
+        let json = std::fs::read_to_string(&path).map_err(|err| invalid(err.to_string()))?;
+        let config = parse_starter_config(&json, state.max_properties).map_err(invalid)?;
+        info!(
+            "New players start with {} tokens and {} properties",
+            config.starting_balance,
+            config.starter_properties.len()
+        );
+        state.starting_balance = config.starting_balance;
+        state.starter_properties = Arc::new(config.starter_properties);
+    }
+    if let Ok(spec) = std::env::var("DISABLED_FEATURES") {
+        state.features = Features::without(&spec)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    }
+    if let Ok(spec) = std::env::var("ALLOWED_ORIGINS") {
+        state.allowed_origins = AllowedOrigins::parse(&spec);
+    }
+    if let Ok(gap) = std::env::var("MATCHMAKING_LEVEL_GAP") {
+        state.matchmaking_level_gap = gap.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("MATCHMAKING_LEVEL_GAP must be a number, got '{}'", gap),
+            )
+        })?;
+    }
+    if let Ok(secs) = std::env::var("LEADERBOARD_REFRESH_SECS") {
+        state.leaderboard_refresh = secs
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "LEADERBOARD_REFRESH_SECS must be a positive number, got '{}'",
+                        secs
+                    ),
+                )
+            })?;
+    }
+    if let Ok(secs) = std::env::var("BATTLE_SECS") {
+        state.battle_duration = secs.parse().map(Duration::from_secs).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("BATTLE_SECS must be a number, got '{}'", secs),
+            )
+        })?;
+    }
+    if let Ok(secs) = std::env::var("BATTLE_GRACE_SECS") {
+        state.battle_grace = secs
+            .parse()
+            .ok()
+            .map(Duration::from_secs)
+            .filter(|grace| *grace < RESUME_GRACE_PERIOD)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "BATTLE_GRACE_SECS must be a number below {}, got '{}'",
+                        RESUME_GRACE_PERIOD.as_secs(),
+                        secs
+                    ),
+                )
+            })?;
+    }
+    if let Ok(rate) = std::env::var("TRANSFER_FEE_BPS") {
+        let rate = rate
+            .parse()
+            .ok()
+            .filter(|rate| *rate < MAX_TRANSFER_FEE_BPS)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "TRANSFER_FEE_BPS must be a number below {}, got '{}'",
+                        MAX_TRANSFER_FEE_BPS, rate
+                    ),
+                )
+            })?;
+        state.transfer_fee_bps.store(rate, Ordering::Relaxed);
+    }
+    if let Ok(level) = std::env::var("BOT_PVP_LEVEL") {
+        let pvp_level = level.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("BOT_PVP_LEVEL must be a number, got '{}'", level),
+            )
+        })?;
+        state.bot = Some(BotConfig {
+            pvp_level,
+            daily_reward_cap: DEFAULT_BOT_DAILY_REWARD_CAP,
+        });
+    }
+    if let Ok(cap) = std::env::var("BOT_DAILY_REWARD_CAP") {
+        let Some(bot) = state.bot.as_mut() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "BOT_DAILY_REWARD_CAP needs the bot enabled with BOT_PVP_LEVEL",
+            ));
+        };
+        bot.daily_reward_cap = cap.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("BOT_DAILY_REWARD_CAP must be a number, got '{}'", cap),
+            )
+        })?;
+    }
+    if let Ok(secs) = std::env::var("AUTOSAVE_SECS") {
+        state.autosave_interval = secs
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("AUTOSAVE_SECS must be a positive number, got '{}'", secs),
+                )
+            })?;
+    }
+    if let Ok(size) = std::env::var("LEADERBOARD_CACHE_SIZE") {
+        state.leaderboard_cache_size = size.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("LEADERBOARD_CACHE_SIZE must be a number, got '{}'", size),
+            )
+        })?;
+    }
+    if let Ok(capacity) = std::env::var("CHALLENGE_LOG_CAPACITY") {
+        state.challenge_log_capacity = capacity.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "CHALLENGE_LOG_CAPACITY must be a number, got '{}'",
+                    capacity
+                ),
+            )
+        })?;
+    }
+    if let Ok(spec) = std::env::var("BLOCKED_WORDS") {
+        state.blocked_words = Arc::new(parse_blocked_words(&spec));
+    }
+    if let Ok(spec) = std::env::var("MUTE_POLICY") {
+        state.mute_policy = MutePolicy::parse(&spec)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    }
+    if let Ok(spec) = std::env::var("REWARD_EVENTS") {
+        let events = parse_reward_events(&spec)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        state.reward_events = Arc::new(events);
+    }
+    let database_url =
+        std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://africa_universe.db".into());
+    let storage = SqliteStorage::connect(&database_url)
+        .await
+        .map_err(std::io::Error::other)?;
+    let seasons = storage
+        .list_seasons()
+        .await
+        .map_err(std::io::Error::other)?;
+    state.seasons = Arc::new(RwLock::new(seasons));
+    state.storage = Arc::new(storage);
+    if let Ok(spec) = std::env::var("ITEM_PRICES") {
+        let overrides = parse_prices(&spec)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let mut marketplace = default_marketplace();
+        apply_prices(&mut marketplace, overrides);
+        state.marketplace = Arc::new(marketplace);
+    }
+    let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0".into());
+    let port: u16 = match std::env::var("PORT") {
+        Ok(port) => port.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("PORT must be a number between 0 and 65535, got '{}'", port),
+            )
+        })?,
+        Err(_) => 8080,
+    };
+    // Serve HTTPS and WSS directly when both PEM files are given.
+    let tls = match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Some(load_tls_config(&cert, &key)?),
+        (Err(_), Err(_)) => None,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "TLS_CERT and TLS_KEY must be set together",
+            ))
+        }
+    };
+    let workers = match std::env::var("WORKERS") {
+        Ok(workers) => match workers.parse::<usize>() {
+            Ok(workers) if workers > 0 => workers,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("WORKERS must be a positive number, got '{}'", workers),
+                ))
+            }
+        },
+        Err(_) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let backlog: u32 = match std::env::var("BACKLOG") {
+        Ok(backlog) => backlog.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("BACKLOG must be a number, got '{}'", backlog),
+            )
+        })?,
+        Err(_) => DEFAULT_BACKLOG,
+    };
+    let event_bus_capacity = match std::env::var("EVENT_BUS_CAPACITY") {
+        Ok(capacity) => match capacity.parse::<usize>() {
+            Ok(capacity) if capacity > 0 => capacity,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "EVENT_BUS_CAPACITY must be a positive number, got '{}'",
+                        capacity
+                    ),
+                ))
+            }
+        },
+        Err(_) => DEFAULT_EVENT_BUS_CAPACITY,
+    };
+    state.events = broadcast::channel(event_bus_capacity).0;
+    actix_web::rt::spawn(run_event_log(state.subscribe()));
+    actix_web::rt::spawn(run_reward_events(state.clone()));
+    actix_web::rt::spawn(run_session_reaper(state.clone()));
+    actix_web::rt::spawn(run_leaderboard_refresh(state.clone()));
+    actix_web::rt::spawn(run_autosave(state.clone()));
+    let shutdown_state = state.clone();
+    // Start the HTTP server on BIND_ADDR:PORT, over TLS if configured.
+    // The server will serve only the WebSocket endpoint; the static
+    // front‑end files can be served by a separate web server or CDN.
+    let server = HttpServer::new(move || build_app(state.clone()))
+        // Signals are handled by `shutdown_on_signal` so clients can be
+        // notified before the listener closes.
+        .disable_signals()
+        .workers(workers)
+        .backlog(backlog);
+    let tls_enabled = tls.is_some();
+    let server = match tls {
+        Some(tls) => server.bind_rustls((bind_addr.as_str(), port), tls)?,
+        None => server.bind((bind_addr.as_str(), port))?,
+    };
+    info!("Running {} workers with a backlog of {}", workers, backlog);
+    if !tls_enabled {
+        info!("TLS_CERT and TLS_KEY are not set; serving plaintext");
+    }
+    for addr in server.addrs() {
+        info!("Listening on {}", addr);
+    }
+    // Storage is connected and the listener bound; start taking traffic.
+    shutdown_state.ready.store(true, Ordering::Release);
+    let server = server.run();
+    actix_web::rt::spawn(shutdown_on_signal(shutdown_state, server.handle()));
+    server.await
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_reward_event_schedule() {
+        let events = parse_reward_events("18-20:200, 22-2:150").unwrap();
+        assert_eq!(
+            events,
+            vec![
+                RewardEvent {
+                    start_hour: 18,
+                    end_hour: 20,
+                    multiplier_percent: 200
+                },
+                RewardEvent {
+                    start_hour: 22,
+                    end_hour: 2,
+                    multiplier_percent: 150
+                },
+            ]
+        );
+        assert!(parse_reward_events("18-20").is_err());
+        assert!(parse_reward_events("25-2:200").is_err());
+    }
+
+    #[test]
+    fn reward_multiplier_applies_only_in_window() {
+        let events = parse_reward_events("18-20:200,22-2:150").unwrap();
+        assert_eq!(reward_multiplier_at(&events, 17), 100);
+        assert_eq!(reward_multiplier_at(&events, 18), 200);
+        assert_eq!(reward_multiplier_at(&events, 19), 200);
+        assert_eq!(reward_multiplier_at(&events, 20), 100);
+        assert_eq!(reward_multiplier_at(&events, 23), 150);
+        assert_eq!(reward_multiplier_at(&events, 1), 150);
+        assert_eq!(reward_multiplier_at(&events, 2), 100);
+    }
+
+    #[test]
+    fn refresh_reports_event_transitions() {
+        let mut state = ServerState::new();
+        state.reward_events = Arc::new(parse_reward_events("18-20:200").unwrap());
+        assert!(state.refresh_reward_multiplier(10).is_none());
+        assert!(matches!(
+            state.refresh_reward_multiplier(18),
+            Some(ServerMessage::EventStarted {
+                multiplier_percent: 200
+            })
+        ));
+        assert!(state.refresh_reward_multiplier(19).is_none());
+        assert!(matches!(
+            state.refresh_reward_multiplier(20),
+            Some(ServerMessage::EventEnded)
+        ));
+    }
+
+    #[test]
+    fn secrets_are_compared_in_full() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn transfer_fees_round_down() {
+        assert_eq!(transfer_fee(0, 250), 0);
+        assert_eq!(transfer_fee(39, 250), 0);
+        assert_eq!(transfer_fee(40, 250), 1);
+        assert_eq!(transfer_fee(9_999, 1), 0);
+        assert_eq!(transfer_fee(10_000, 1), 1);
+        assert_eq!(transfer_fee(u64::MAX, 0), 0);
+        // The highest rate still leaves something for the recipient.
+        assert_eq!(transfer_fee(1, MAX_TRANSFER_FEE_BPS - 1), 0);
+        assert_eq!(
+            transfer_fee(u64::MAX, MAX_TRANSFER_FEE_BPS - 1),
+            u64::MAX - u64::MAX / 10_000 - 1
+        );
+    }
+
+    #[test]
+    fn parses_price_table() {
+        let prices = parse_prices("Islands:900, NFT Characters:300").unwrap();
+        assert_eq!(prices["Islands"], 900);
+        assert_eq!(prices["NFT Characters"], 300);
+        assert!(parse_prices("Islands").is_err());
+        assert!(parse_prices(":10").is_err());
+        assert!(parse_prices("Land:cheap").is_err());
+
+        let mut marketplace = default_marketplace();
+        apply_prices(&mut marketplace, prices);
+        let islands = marketplace
+            .iter()
+            .find(|i| i.category == "Islands")
+            .unwrap();
+        assert_eq!((islands.price, islands.reward), (900, 10));
+        assert_eq!(marketplace.len(), default_marketplace().len());
+    }
+
+    #[test]
+    fn blocked_words_match_whole_words_only() {
+        let blocked = parse_blocked_words(" Darn, heck ,");
+        assert_eq!(blocked, ["darn", "heck"]);
+        assert!(contains_blocked_word("oh DARN it", &blocked));
+        assert!(contains_blocked_word("what the heck!", &blocked));
+        assert!(!contains_blocked_word("darnell is here", &blocked));
+        assert!(!contains_blocked_word("darn", &[]));
+    }
+
+    #[test]
+    fn chat_mutes_escalate_and_reset() {
+        let policy = MutePolicy::parse("strikes=2, mute=10").unwrap();
+        assert_eq!(policy.window, MutePolicy::default().window);
+        assert!(MutePolicy::parse("strikes=0").is_err());
+        assert!(MutePolicy::parse("volume=3").is_err());
+
+        let mut strikes = ChatStrikes::default();
+        let start = Instant::now();
+        assert_eq!(strikes.strike(start, &policy), None);
+        // Strikes outside the window don't add up.
+        let later = start + policy.window;
+        assert_eq!(strikes.strike(later, &policy), None);
+        let muted = strikes.strike(later + Duration::from_secs(1), &policy);
+        assert_eq!(muted, Some(Duration::from_secs(10)));
+        assert!(strikes.muted_for(later + Duration::from_secs(5)).is_some());
+        assert_eq!(strikes.muted_for(later + Duration::from_secs(11)), None);
+
+        // The next mute is twice as long.
+        let again = later + Duration::from_secs(20);
+        strikes.strike(again, &policy);
+        assert_eq!(
+            strikes.strike(again, &policy),
+            Some(Duration::from_secs(20))
+        );
+        // After a quiet hour the escalation starts over.
+        let quiet = again + Duration::from_secs(20) + policy.reset;
+        strikes.strike(quiet, &policy);
+        assert_eq!(
+            strikes.strike(quiet, &policy),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn leaderboard_ranks_by_reward_and_includes_requester() {
+        let mut clients = HashMap::new();
+        let requester = Uuid::new_v4();
+        for (name, reward, level) in [("ada", 5, 1), ("bea", 5, 3), ("cy", 9, 1), ("dee", 0, 1)] {
+            let mut info = ClientInfo::new(name.into());
+            info.pvp_level = level;
+            info.properties.push(Property {
+                name: "Item".into(),
+                category: String::new(),
+                reward,
+                level: 1,
+            });
+            let id = if name == "dee" {
+                requester
+            } else {
+                Uuid::new_v4()
+            };
+            clients.insert(id, info);
+        }
+        let mut hidden = ClientInfo::new("eve".into());
+        hidden.privacy.show_in_leaderboard = false;
+        clients.insert(Uuid::new_v4(), hidden);
+
+        let cache = LeaderboardCache::build(&clients, 3);
+        let (entries, own_rank) = cache.view(requester, 2);
+        let names: Vec<_> = entries
+            .iter()
+            .map(|e| (e.rank, e.username.as_str()))
+            .collect();
+        assert_eq!(names, [(1, "cy"), (2, "bea")]);
+        assert_eq!(own_rank, Some(4));
+
+        // The snapshot only holds three entries, however many are asked for.
+        let (entries, own_rank) = cache.view(requester, usize::MAX);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(own_rank, Some(4));
+        let (entries, own_rank) = LeaderboardCache::build(&clients, 10).view(requester, 10);
+        assert_eq!(entries.len(), 4);
+        assert_eq!((entries[3].username.as_str(), own_rank), ("dee", None));
+        // Players who connected after the refresh are ranked last.
+        assert_eq!(cache.view(Uuid::new_v4(), 1).1, Some(clients.len() + 1));
+    }
+
+    #[test]
+    fn starter_configs_are_validated() {
+        let config = parse_starter_config(
+            r#"{"starting_balance": 50, "starter_properties": [{"name": "Hut", "category": "Buildings", "reward": 1}]}"#,
+            5,
+        )
+        .unwrap();
+        assert_eq!(config.starting_balance, 50);
+        assert_eq!(config.starter_properties[0].level, 1);
+        assert_eq!(
+            parse_starter_config("{}", 5).unwrap().starting_balance,
+            STARTING_BALANCE
+        );
+
+        let two = r#"{"starter_properties": [
+            {"name": "Hut", "category": "Buildings", "reward": 1},
+            {"name": "Hut", "category": "Buildings", "reward": 1}
+        ]}"#;
+        assert!(parse_starter_config(two, 1)
+            .unwrap_err()
+            .contains("may own only 1"));
+        let high = r#"{"starter_properties": [{"name": "Fort", "category": "Buildings", "reward": 1, "level": 99}]}"#;
+        assert!(parse_starter_config(high, 5)
+            .unwrap_err()
+            .contains("level 99"));
+        let typo = r#"{"starting_balanse": 10}"#;
+        assert!(parse_starter_config(typo, 5)
+            .unwrap_err()
+            .contains("unknown field"));
+        assert!(parse_starter_config("[", 5).is_err());
+    }
+
+    #[test]
+    fn channel_names_are_normalized() {
+        assert_eq!(normalize_channel(" Traders ").as_deref(), Ok("traders"));
+        assert_eq!(
+            normalize_channel("west-africa_1").as_deref(),
+            Ok("west-africa_1")
+        );
+        assert!(normalize_channel("").is_err());
+        assert!(normalize_channel(&"x".repeat(33)).is_err());
+        assert!(normalize_channel("no spaces").is_err());
+        assert!(normalize_channel("café").is_err());
+    }
+
+    #[test]
+    fn usernames_are_sanitized() {
+        assert_eq!(sanitize_username("  amara \n").as_deref(), Some("amara"));
+        assert_eq!(sanitize_username("ama\nra\u{7}").as_deref(), Some("amara"));
+        assert_eq!(sanitize_username("🦁 Simba").as_deref(), Some("🦁 Simba"));
+        assert_eq!(
+            sanitize_username("evil\u{202E}name").as_deref(),
+            Some("evilname")
+        );
+        let long = "x".repeat(500);
+        assert_eq!(sanitize_username(&long).map(|name| name.len()), Some(20));
+        // Cutting doesn't leave trailing spaces.
+        assert_eq!(
+            sanitize_username("nineteen characters word").as_deref(),
+            Some("nineteen characters")
+        );
+        assert_eq!(sanitize_username(" \r\n\t "), None);
+        assert_eq!(sanitize_username("\u{200B}"), None);
+        let fallback = fallback_username(Uuid::nil());
+        assert_eq!(fallback, "Player-00000000");
+        assert_eq!(sanitize_username(&fallback), Some(fallback));
+    }
+
+    #[test]
+    fn outbox_keeps_only_the_latest_messages() {
+        let mut outbox = Outbox::default();
+        for _ in 0..REPLAY_BUFFER_CAPACITY + 2 {
+            outbox.push(ServerMessage::error("test", "test"), None);
+        }
+        let last = REPLAY_BUFFER_CAPACITY as u64 + 2;
+        assert_eq!(outbox.sent.len(), REPLAY_BUFFER_CAPACITY);
+        let (missed, incomplete) = outbox.since(last - 1);
+        assert_eq!(
+            missed.iter().map(|sent| sent.seq).collect::<Vec<_>>(),
+            [last]
+        );
+        assert!(!incomplete);
+        assert!(outbox.since(last).0.is_empty());
+        // The first two are gone.
+        let (missed, incomplete) = outbox.since(1);
+        assert_eq!(missed.len(), REPLAY_BUFFER_CAPACITY);
+        assert!(incomplete);
+        assert!(!outbox.since(2).1);
+    }
+
+    #[test]
+    fn token_bucket_allows_bursts_then_refills() {
+        let mut bucket = TokenBucket::new(2, 5);
+        let start = bucket.last_refill;
+        assert!((0..5).all(|_| bucket.try_take(start, 1)));
+        assert!(!bucket.try_take(start, 1));
+        // Half a second refills one token at two per second.
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(later, 1));
+        assert!(!bucket.try_take(later, 1));
+        // Refills never exceed the capacity.
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(
+            (0..10).filter(|_| bucket.try_take(much_later, 1)).count(),
+            5
+        );
+        // Taking several at once leaves the bucket alone if it can't.
+        let refilled = much_later + Duration::from_secs(60);
+        assert!(!bucket.try_take(refilled, 6));
+        assert!(bucket.try_take(refilled, 5));
+    }
+
+    #[test]
+    fn properties_saved_without_a_level_start_at_one() {
+        let property: Property =
+            serde_json::from_str(r#"{"name": "Land Item", "reward": 3}"#).unwrap();
+        assert_eq!(property.level, 1);
+    }
+
+    #[test]
+    fn battle_is_won_by_level_then_reward_then_defender() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut strong = ClientInfo::new("strong".into());
+        strong.pvp_level = 3;
+        let weak = ClientInfo::new("weak".into());
+        let rng = &mut StdRng::seed_from_u64(0);
+        assert_eq!(resolve_battle((a, &strong), (b, &weak), 0, rng), (a, b));
+        assert_eq!(resolve_battle((b, &weak), (a, &strong), 0, rng), (a, b));
+
+        let mut rich = ClientInfo::new("rich".into());
+        rich.properties.push(Property {
+            name: "Land Item".into(),
+            category: "Land".into(),
+            reward: 3,
+            level: 1,
+        });
+        assert_eq!(resolve_battle((a, &rich), (b, &weak), 0, rng), (a, b));
+        assert_eq!(resolve_battle((a, &weak), (b, &weak), 0, rng), (b, a));
+    }
+
+    #[test]
+    fn properties_are_summarized_by_category() {
+        let property = |category: &str, reward| Property {
+            name: format!("{} Item", category),
+            category: category.into(),
+            reward,
+            level: 1,
+        };
+        let properties = [
+            property("Land", 3),
+            property("Islands", 10),
+            property("Land", 4),
+            property("", 1),
+        ];
+        let breakdown = category_breakdown(&properties);
+        assert_eq!(breakdown.len(), 3);
+        let summary = |count, total_reward| CategorySummary {
+            count,
+            total_reward,
+        };
+        assert_eq!(breakdown["Land"], summary(2, 7));
+        assert_eq!(breakdown["Islands"], summary(1, 10));
+        assert_eq!(breakdown[""], summary(1, 1));
+        assert!(category_breakdown(&[]).is_empty());
+    }
+
+    #[test]
+    fn seeded_luck_gives_reproducible_battles() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let property = |reward| Property {
+            name: "Land Item".into(),
+            category: "Land".into(),
+            reward,
+            level: 1,
+        };
+        let mut rich = ClientInfo::new("rich".into());
+        rich.properties.push(property(3));
+        let mut poor = ClientInfo::new("poor".into());
+        poor.properties.push(property(2));
+        let battles = |seed| {
+            let rng = &mut StdRng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| resolve_battle((a, &poor), (b, &rich), 50, rng).0)
+                .collect::<Vec<_>>()
+        };
+        let outcomes = battles(7);
+        assert_eq!(outcomes, battles(7));
+        // Luck is enough for either side to win ...
+        assert!(outcomes.contains(&a) && outcomes.contains(&b));
+        // ... but never outweighs a higher level.
+        poor.pvp_level = 2;
+        let rng = &mut StdRng::seed_from_u64(7);
+        assert!((0..100).all(|_| resolve_battle((a, &poor), (b, &rich), 100, rng).0 == a));
+    }
+
+    #[test]
+    fn daily_rewards_do_not_overflow() {
+        let mut info = ClientInfo::new("tycoon".into());
+        info.properties = (0..3)
+            .map(|n| Property {
+                name: format!("Island {}", n),
+                category: "Islands".into(),
+                reward: u32::MAX,
+                level: MAX_PROPERTY_LEVEL,
+            })
+            .collect();
+        let expected = 3 * u64::from(u32::MAX);
+        assert_eq!(daily_reward(&info.properties), expected);
+        assert_eq!(LeaderboardEntry::new(1, &info).daily_reward, expected);
+
+        // A day at the base multiplier pays the whole widened sum.
+        info.last_accrued = Some(0);
+        assert_eq!(accrue_rewards(&mut info, 86_400, 100), expected);
+    }
+
+    #[actix_web::test]
+    async fn stats_count_players_and_properties() {
+        let state = ServerState::new();
+        let mut info = ClientInfo::new("amara".into());
+        info.properties.push(Property {
+            name: "Land Item".into(),
+            category: "Land".into(),
+            reward: 3,
+            level: 1,
+        });
+        state.clients.insert(Uuid::new_v4(), info).await;
+        let kofi = ClientInfo::new("kofi".into());
+        state.clients.insert(Uuid::new_v4(), kofi).await;
+        let app = actix_web::test::init_service(build_app(state)).await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/stats")
+            .to_request();
+        let stats: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stats["connected_players"], 2);
+        assert_eq!(stats["total_properties"], 1);
+        assert!(stats["uptime_seconds"].is_u64());
+    }
+
+    #[actix_web::test]
+    async fn audit_events_are_looked_up_by_account() {
+        let mut state = ServerState::new();
+        state.admin_token = Some("secret".into());
+        let mut amara = ClientInfo::new("amara".into());
+        amara.username = "Queen Amara".into();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let change = || AuditChange::Balance { old: 1, new: 2 };
+        state
+            .audit([
+                AuditEvent::new(first, &amara, change(), "grant"),
+                AuditEvent::new(
+                    Uuid::new_v4(),
+                    &ClientInfo::new("kofi".into()),
+                    change(),
+                    "grant",
+                ),
+                AuditEvent::new(second, &amara, change(), "grant"),
+            ])
+            .await;
+        let app = actix_web::test::init_service(build_app(state)).await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/admin/audit?account=amara")
+            .insert_header((actix_web::http::header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let events: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        let events = events.as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["session"], first.to_string());
+        assert_eq!(events[1]["session"], second.to_string());
+        assert_eq!(events[1]["username"], "Queen Amara");
+    }
+
+    #[actix_web::test]
+    async fn autosave_flushes_only_changed_players() {
+        let state = ServerState::new();
+        let id = Uuid::new_v4();
+        let mut info = ClientInfo::new("amara".into());
+        info.properties.push(Property {
+            name: "Land Item".into(),
+            category: "Land".into(),
+            reward: 3,
+            level: 1,
+        });
+        info.last_accrued = Some(unix_now() - 86_400);
+        state.clients.insert(id, info).await;
+        assert_eq!(state.autosave().await, 0);
+        assert!(state.storage.load_player("amara").await.unwrap().is_none());
+
+        // Accrual isn't written through; the next autosave picks it up.
+        assert_eq!(state.accrue(id).await, 3);
+        assert!(state.storage.load_player("amara").await.unwrap().is_none());
+        assert_eq!(state.autosave().await, 1);
+        let stored = state.storage.load_player("amara").await.unwrap().unwrap();
+        assert_eq!(stored.balance, STARTING_BALANCE + 3);
+        assert_eq!(state.autosave().await, 0);
+
+        // A write-through also counts as saved.
+        state.clients.write(&id).await.get_mut(&id).unwrap().dirty = true;
+        state.persist([id]).await;
+        assert_eq!(state.autosave().await, 0);
+    }
+
+    #[actix_web::test]
+    async fn challenge_log_keeps_the_most_recent_records() {
+        let mut state = ServerState::new();
+        state.challenge_log_capacity = 3;
+        let players = (Uuid::new_v4(), Uuid::new_v4());
+        let challenges: Vec<PendingChallenge> = (0..4u64)
+            .map(|stake| PendingChallenge {
+                battle_id: Uuid::new_v4(),
+                stake: StakeKind::Balance(stake),
+                expires_at: Instant::now(),
+            })
+            .collect();
+        for challenge in &challenges {
+            let outcome = ChallengeOutcome::Sent;
+            state.record_challenge(players, challenge, outcome).await;
+        }
+        let app = actix_web::test::init_service(build_app(state)).await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/challenges/recent")
+            .to_request();
+        let records: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        let stakes: Vec<_> = records
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| record["stake"].as_u64().unwrap())
+            .collect();
+        assert_eq!(stakes, [1, 2, 3]);
+        assert_eq!(records[0]["outcome"], "sent");
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/challenges/recent?limit=1")
+            .to_request();
+        let records: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(records[0]["id"], challenges[3].battle_id.to_string());
+        assert_eq!(records.as_array().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn ready_waits_for_startup() {
+        let state = ServerState::new();
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
+        let app = &app;
+        let status = |uri| async move {
+            let req = actix_web::test::TestRequest::get().uri(uri).to_request();
+            actix_web::test::call_service(&app, req).await.status()
+        };
+        assert_eq!(status("/health").await, actix_web::http::StatusCode::OK);
+        let unavailable = actix_web::http::StatusCode::SERVICE_UNAVAILABLE;
+        assert_eq!(status("/ready").await, unavailable);
+        state.ready.store(true, Ordering::Release);
+        assert_eq!(status("/ready").await, actix_web::http::StatusCode::OK);
+        state.shutdown("maintenance").await;
+        assert_eq!(status("/ready").await, unavailable);
+    }
+
+    #[actix_web::test]
+    async fn reports_are_rate_limited_per_reporter() {
+        let state = ServerState::new();
+        let (reporter, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let clients = &state.clients;
+        clients
+            .insert(reporter, ClientInfo::new("reporter".into()))
+            .await;
+        clients
+            .insert(target, ClientInfo::new("target".into()))
+            .await;
+        // A report stamped ahead of a clock that stepped back still counts.
+        let ahead = PlayerReport {
+            reporter,
+            reporter_name: "reporter".into(),
+            target,
+            target_name: "target".into(),
+            reason: "spam".into(),
+            details: String::new(),
+            timestamp: unix_now() + 3600,
+        };
+        state.storage.save_report(&ahead).await.unwrap();
+        for _ in 1..MAX_REPORTS_PER_WINDOW {
+            let ack = state.file_report(reporter, target, "spam", "").await;
+            assert!(matches!(ack, ServerMessage::ReportReceived { .. }));
+        }
+        let refused = state.file_report(reporter, target, "spam", "").await;
+        assert!(matches!(refused, ServerMessage::Error { code, .. } if code == "rate_limited"));
+        let reports = state
+            .storage
+            .list_reports(MAX_STORED_REPORTS)
+            .await
+            .unwrap();
+        assert_eq!(reports.len(), MAX_REPORTS_PER_WINDOW);
+    }
+
+    #[actix_web::test]
+    async fn matchmaking_keeps_players_outside_the_level_gap_apart() {
+        let state = ServerState::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(state.join_matchmaking(alice, 1).await, None);
+        assert_eq!(state.join_matchmaking(bob, 9).await, None);
+        assert!(state.leave_matchmaking(alice).await);
+        assert_eq!(state.join_matchmaking(Uuid::new_v4(), 8).await, Some(bob));
+    }
+
+    #[test]
+    fn rewards_accrue_per_second() {
+        let mut info = ClientInfo::new("amara".into());
+        for reward in [10, 5] {
+            info.properties.push(Property {
+                name: "Item".into(),
+                category: String::new(),
+                reward,
+                level: 1,
+            });
+        }
+        let start = 1_700_000_000;
+        // The first accrual only starts the clock.
+        assert_eq!(accrue_rewards(&mut info, start, 100), 0);
+        // Two days at 15 tokens a day.
+        assert_eq!(accrue_rewards(&mut info, start + 2 * 86_400, 100), 30);
+        // A third of a day is 5 tokens; fractions carry over so two
+        // sixths add up the same.
+        let now = start + 2 * 86_400 + 14_400;
+        assert_eq!(accrue_rewards(&mut info, now, 100), 2);
+        assert_eq!(accrue_rewards(&mut info, now + 14_400, 100), 3);
+        // Boosted rewards accrue faster.
+        assert_eq!(accrue_rewards(&mut info, now + 14_400 + 43_200, 200), 15);
+        assert_eq!(info.balance, STARTING_BALANCE + 50);
+        assert_eq!(info.lifetime_rewards, 50);
+        assert_eq!(info.unclaimed_rewards, 50);
+    }
+
+    #[test]
+    fn idle_rewards_decay_per_full_day() {
+        let mut info = ClientInfo::new("amara".into());
+        for reward in [100, 1] {
+            info.properties.push(Property {
+                name: "Item".into(),
+                category: String::new(),
+                reward,
+                level: 1,
+            });
+        }
+        let start = 1_700_000_000;
+        // New players have nothing to be idle since.
+        assert!(!decay_idle_rewards(&mut info, start, 10));
+        info.last_claim = Some(start);
+        // Less than a day is not idle.
+        assert!(!decay_idle_rewards(&mut info, start + 86_399, 10));
+        // Three days at 10% a day compound: 100 * 0.9^3 = 72.9.
+        assert!(decay_idle_rewards(&mut info, start + 3 * 86_400 + 60, 10));
+        let rewards: Vec<u32> = info.properties.iter().map(|p| p.reward).collect();
+        assert_eq!(rewards, [72, 1]);
+        // Collecting rewards more recently than the claim shortens the
+        // idle period, and rewards never drop below 1.
+        info.last_accrued = Some(start + 3 * 86_400);
+        assert!(decay_idle_rewards(&mut info, start + 60 * 86_400, 50));
+        let rewards: Vec<u32> = info.properties.iter().map(|p| p.reward).collect();
+        assert_eq!(rewards, [1, 1]);
+        assert!(!decay_idle_rewards(&mut info, start + 90 * 86_400, 50));
+    }
+
+    #[test]
+    fn tls_config_rejects_bad_files() {
+        let dir = std::env::temp_dir();
+        let empty = dir.join(format!("tls-{}.pem", Uuid::new_v4()));
+        std::fs::write(&empty, "not a certificate\n").unwrap();
+        let empty = empty.to_str().unwrap();
+        let missing = dir.join(format!("tls-{}.pem", Uuid::new_v4()));
+        let missing = missing.to_str().unwrap();
+
+        let err = load_tls_config(missing, empty).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        let err = load_tls_config(empty, empty).unwrap_err();
+        assert!(
+            err.to_string().contains("contains no certificates"),
+            "{}",
+            err
+        );
+        let _ = std::fs::remove_file(empty);
+    }
+
+    #[actix_web::test]
+    async fn preflight_is_answered_for_allowed_origins() {
+        let mut state = ServerState::new();
+        state.allowed_origins = AllowedOrigins::parse("https://play.example.com");
+        let app = actix_web::test::init_service(build_app(state)).await;
+        for path in ["/stats", "/metrics"] {
+            let req = actix_web::test::TestRequest::default()
+                .method(actix_web::http::Method::OPTIONS)
+                .uri(path)
+                .insert_header(("Origin", "https://play.example.com"))
+                .insert_header(("Access-Control-Request-Method", "GET"))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert!(resp.status().is_success(), "preflight on {}", path);
+            let allowed = resp.headers().get("access-control-allow-origin");
+            assert_eq!(allowed.unwrap(), "https://play.example.com");
+        }
+
+        let req = actix_web::test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/stats")
+            .insert_header(("Origin", "https://evil.example.com"))
+            .insert_header(("Access-Control-Request-Method", "GET"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+        assert_eq!(AllowedOrigins::parse(" * "), AllowedOrigins::Any);
+    }
+
+    #[actix_web::test]
+    async fn season_reset_restores_starting_values_and_archives() {
+        let state = ServerState::new();
+        let id = Uuid::new_v4();
+        {
+            let mut info = ClientInfo::new("veteran".into());
+            info.pvp_level = 7;
+            info.balance = 40;
+            info.properties.push(Property {
+                name: "Islands Item".into(),
+                category: "Islands".into(),
+                reward: 10,
+                level: 1,
+            });
+            state.clients.insert(id, info).await;
+        }
+
+        assert_eq!(state.reset_season(SeasonResetScope::Levels).await, 1);
+        {
+            let clients = state.clients.read(&id).await;
+            assert_eq!(clients[&id].pvp_level, 1);
+            assert_eq!(clients[&id].properties.len(), 1);
+        }
+
+        assert_eq!(state.reset_season(SeasonResetScope::Economy).await, 2);
+        {
+            let clients = state.clients.read(&id).await;
+            assert!(clients[&id].properties.is_empty());
+            assert_eq!(clients[&id].balance, STARTING_BALANCE);
+        }
+
+        let seasons = state.seasons.read().await;
+        assert_eq!(seasons.len(), 2);
+        assert_eq!(seasons[0].standings[0].username, "veteran");
+        assert_eq!(seasons[0].standings[0].pvp_level, 7);
+        assert_eq!(seasons[1].standings[0].pvp_level, 1);
+        assert_eq!(seasons[1].standings[0].daily_reward, 10);
+        // Both archives outlive the process.
+        assert_eq!(state.storage.list_seasons().await.unwrap(), *seasons);
+    }
+
+    #[test]
+    fn describes_invalid_json() {
+        assert_eq!(
+            describe_invalid_message("{not json"),
+            "message is not valid JSON"
+        );
+        assert_eq!(
+            describe_invalid_message("[1, 2]"),
+            "message must be a JSON object"
+        );
+    }
+
+    #[test]
+    fn describes_missing_or_unknown_type() {
+        assert_eq!(
+            describe_invalid_message(r#"{"item_id": "x"}"#),
+            "message requires a type field"
+        );
+        assert_eq!(
+            describe_invalid_message(r#"{"type": 7}"#),
+            "type field must be a string"
+        );
+        assert_eq!(
+            describe_invalid_message(r#"{"type": "fly"}"#),
+            "unknown message type 'fly'"
+        );
+    }
+
+    #[test]
+    fn describes_missing_field() {
+        assert_eq!(
+            describe_invalid_message(r#"{"type": "purchase", "category": "Land"}"#),
+            "purchase requires item_id"
+        );
+        assert_eq!(
+            describe_invalid_message(r#"{"type": "challenge", "stake": true}"#),
+            "challenge requires target"
+        );
+    }
+
+    #[test]
+    fn describes_malformed_field() {
+        assert_eq!(
+            describe_invalid_message(r#"{"type": "challenge", "target": "nope", "stake": true}"#),
+            "challenge field target is malformed"
+        );
+        assert_eq!(
+            describe_invalid_message(r#"{"type": "purchase", "item_id": "a", "category": 3}"#),
+            "purchase field category is malformed"
+        );
+    }
+}
+
+/// In-process harness for exercising the WebSocket protocol end to end.
+/// `TestServer::start` runs the app on an ephemeral port and
+/// `TestClient` wraps a WebSocket connection to it.
+#[cfg(test)]
+mod harness {
+    use super::*;
+    use awc::ws::{Frame, Message};
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    /// How long a client waits for an expected server message.
+    const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub struct TestServer {
+        srv: actix_test::TestServer,
+    }
+
+    impl TestServer {
+        pub fn start() -> Self {
+            Self::with_state(ServerState::new())
+        }
+
+        pub fn with_state(state: ServerState) -> Self {
+            let srv = actix_test::start(move || build_app(state.clone()));
+            Self { srv }
+        }
+
+        /// Perform the WebSocket handshake without waiting for the
+        /// session to register.
+        pub async fn handshake(&self) -> Result<TestClient, awc::error::WsClientError> {
+            self.handshake_at("/ws").await
+        }
+
+        /// Like `handshake`, with a custom path and query string.
+        pub async fn handshake_at(
+            &self,
+            path: &str,
+        ) -> Result<TestClient, awc::error::WsClientError> {
+            let (_, framed) = awc::Client::new().ws(self.srv.url(path)).connect().await?;
+            Ok(TestClient { framed })
+        }
+
+        /// Open a WebSocket connection and authenticate it with a unique
+        /// token, which the default development provider accepts as the
+        /// username.
+        pub async fn connect(&self) -> TestClient {
+            static NEXT_PLAYER: AtomicUsize = AtomicUsize::new(0);
+            let n = NEXT_PLAYER.fetch_add(1, Ordering::Relaxed);
+            self.connect_as(&format!("player{}", n)).await
+        }
+
+        /// Open a WebSocket connection authenticated with `token`.
+        pub async fn connect_as(&self, token: &str) -> TestClient {
+            let mut client = self.handshake().await.expect("websocket handshake failed");
+            client
+                .send(serde_json::json!({ "type": "authenticate", "token": token }))
+                .await;
+            client.recv("authenticated").await;
+            client
+        }
+    }
+
+    pub struct TestClient {
+        framed: actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>,
+    }
+
+    impl TestClient {
+        /// Send a client message, given as its JSON representation.
+        pub async fn send(&mut self, msg: serde_json::Value) {
+            self.send_text(&msg.to_string()).await;
+        }
+
+        /// Send a client message encoded as MessagePack.
+        pub async fn send_msgpack(&mut self, msg: serde_json::Value) {
+            let bytes = encode_msgpack(&msg).expect("failed to encode message");
+            self.framed
+                .send(Message::Binary(bytes.into()))
+                .await
+                .expect("failed to send message");
+        }
+
+        /// Send a raw text frame, which need not be valid JSON.
+        pub async fn send_text(&mut self, text: &str) {
+            self.framed
+                .send(Message::Text(text.to_owned().into()))
+                .await
+                .expect("failed to send message");
+        }
+
+        /// Close the connection cleanly.
+        pub async fn close(mut self) {
+            self.framed
+                .send(Message::Close(None))
+                .await
+                .expect("failed to close connection");
+        }
+
+        /// Wait for the next server message of the given kind and return
+        /// its body. Messages of other kinds are skipped.
+        pub async fn recv(&mut self, kind: &str) -> serde_json::Value {
+            self.recv_message(kind).await[kind].take()
+        }
+
+        /// Like `recv`, but return the whole message rather than its body.
+        pub async fn recv_message(&mut self, kind: &str) -> serde_json::Value {
+            let wait = async {
+                loop {
+                    let value = self.next_message().await;
+                    if value.get(kind).is_some() {
+                        return value;
+                    }
+                }
+            };
+            tokio::time::timeout(RECV_TIMEOUT, wait)
+                .await
+                .unwrap_or_else(|_| panic!("timed out waiting for {}", kind))
+        }
+
+        /// Wait for the next server message, whatever its kind, and
+        /// return it whole.
+        pub async fn recv_next(&mut self) -> serde_json::Value {
+            tokio::time::timeout(RECV_TIMEOUT, self.next_message())
+                .await
+                .expect("timed out waiting for a message")
+        }
+
+        async fn next_message(&mut self) -> serde_json::Value {
+            loop {
+                let frame = self
+                    .framed
+                    .next()
+                    .await
+                    .expect("connection closed")
+                    .expect("protocol error");
+                match frame {
+                    Frame::Text(text) => {
+                        return serde_json::from_slice(&text).expect("server sent invalid JSON")
+                    }
+                    Frame::Binary(bytes) => {
+                        return decode_msgpack(&bytes).expect("server sent invalid MessagePack")
+                    }
+                    _ => continue,
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn pushes_the_recipient_turned_off_are_dropped_on_delivery() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id: Uuid = serde_json::from_value(other_player_id(&mut alice).await).unwrap();
+        let alice_id: Uuid = serde_json::from_value(other_player_id(&mut bob).await).unwrap();
+        bob.send(serde_json::json!({ "type": "updatePrivacy", "allow_challenges": false }))
+            .await;
+        bob.recv("privacy").await;
+        bob.send(serde_json::json!({
+            "type": "setNotificationPrefs",
+            "prefs": { "chat": false, "presence": false },
+        }))
+        .await;
+        bob.recv("notificationPrefs").await;
+
+        // Whichever handler sends them, these never reach bob.
+        let refused = [
+            ServerMessage::ChallengeRequest {
+                challenger: alice_id,
+                challenger_name: "alice".into(),
+                stake_amount: 0,
+                stake: StakeKind::Balance(0),
+                battle_id: Uuid::new_v4(),
+            },
+            ServerMessage::ChatMessage {
+                channel: DEFAULT_CHANNEL.into(),
+                from: alice_id,
+                username: "alice".into(),
+                text: "hi".into(),
+                timestamp: unix_now(),
+            },
+            ServerMessage::FriendOnline {
+                id: alice_id,
+                username: "alice".into(),
+            },
+        ];
+        for msg in refused {
+            assert!(!state.deliver(bob_id, msg).await);
+        }
+        alice
+            .send(serde_json::json!({ "type": "chatSend", "text": "hello" }))
+            .await;
+        alice.recv("chatMessage").await;
+        let notice = ServerMessage::Announcement {
+            text: "maintenance".into(),
+            level: AnnouncementLevel::default(),
+        };
+        assert!(state.deliver(bob_id, notice).await);
+        assert!(bob.recv_next().await.get("announcement").is_some());
+    }
+
+    #[actix_web::test]
+    async fn get_profile_returns_default_profile() {
+        let server = TestServer::start();
+        let mut client = server.connect_as("amara").await;
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["pvp_level"], 1);
+        assert_eq!(profile["daily_reward"], 0);
+        assert_eq!(profile["username"], "amara");
+    }
+
+    #[actix_web::test]
+    async fn replies_echo_the_request_id() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({ "type": "getProfile", "request_id": 7 }))
+            .await;
+        assert_eq!(client.recv_message("profile").await["request_id"], 7);
+        client
+            .send(serde_json::json!({ "type": "chatSend", "text": "hi", "request_id": 8 }))
+            .await;
+        // Chat is a broadcast, so it carries no request id.
+        let chat = client.recv_message("chatMessage").await;
+        assert!(chat.get("request_id").is_none());
+        client
+            .send(serde_json::json!({ "type": "sell", "property_name": "?", "request_id": 9 }))
+            .await;
+        let message = client.recv_message("error").await;
+        assert_eq!(message["error"]["code"], "not_owned");
+        assert_eq!(message["request_id"], 9);
+    }
+
+    #[actix_web::test]
+    async fn batches_run_in_order() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({
+                "type": "batch",
+                "messages": [
+                    { "type": "purchase", "item_id": "land-1", "category": "Land" },
+                    { "type": "getProfile" },
+                    { "type": "getMarketplace" },
+                ],
+            }))
+            .await;
+        let results = client.recv("batchResult").await["results"].clone();
+        assert_eq!(results.as_array().unwrap().len(), 3);
+        let balance = results[0][0]["purchaseAck"]["balance"].clone();
+        assert_eq!(results[1][0]["profile"]["balance"], balance);
+        assert!(results[2][0]["marketplace"]["items"].is_array());
+
+        let nested = serde_json::json!({ "type": "batch", "messages": [] });
+        client
+            .send(serde_json::json!({ "type": "batch", "messages": [nested] }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "nested_batch");
+        let profile = serde_json::json!({ "type": "getProfile" });
+        let messages = vec![profile; MAX_BATCH_LEN + 1];
+        client
+            .send(serde_json::json!({ "type": "batch", "messages": messages }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "batch_too_large");
+    }
+
+    #[actix_web::test]
+    async fn game_events_reach_subscribers() {
+        let state = ServerState::new();
+        let mut events = state.subscribe();
+        let server = TestServer::with_state(state);
+        let mut client = server.connect_as("amara").await;
+        buy(&mut client, "land-1", "Land").await;
+
+        let GameEvent::PlayerJoined { account, .. } = events.recv().await.unwrap() else {
+            panic!("expected the join first");
+        };
+        assert_eq!(account, "amara");
+        let GameEvent::Purchase { category, .. } = events.recv().await.unwrap() else {
+            panic!("expected the purchase");
+        };
+        assert_eq!(category, "Land");
+    }
+
+    #[actix_web::test]
+    async fn lagging_subscribers_skip_old_events() {
+        let mut state = ServerState::new();
+        state.events = broadcast::channel(2).0;
+        let mut events = state.subscribe();
+        for _ in 0..3 {
+            let id = Uuid::new_v4();
+            let account = "amara".to_owned();
+            state.publish(GameEvent::PlayerLeft { id, account });
+        }
+        assert!(matches!(
+            events.recv().await,
+            Err(broadcast::error::RecvError::Lagged(1))
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Ok(GameEvent::PlayerLeft { .. })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn admins_can_reset_and_seed_players() {
+        let mut state = ServerState::new();
+        let auth = StaticTokenAuth::parse("mod:nia:admin,player:kofi").unwrap();
+        state.auth = Arc::new(auth);
+        let server = TestServer::with_state(state);
+        let mut nia = server.connect_as("mod").await;
+        let mut kofi = server.connect_as("player").await;
+        let kofi_id = other_player_id(&mut nia).await;
+        let nia_id = other_player_id(&mut kofi).await;
+
+        kofi.send(serde_json::json!({ "type": "resetPlayer", "target": nia_id }))
+            .await;
+        assert_eq!(kofi.recv("error").await["code"], "forbidden");
+
+        let property = serde_json::json!({ "name": "Gold Mine", "category": "Land", "reward": 40 });
+        nia.send(serde_json::json!({
+            "type": "seedPlayer",
+            "target": kofi_id,
+            "properties": [property],
+            "balance": 5,
+            "pvp_level": 7,
+        }))
+        .await;
+        assert_eq!(nia.recv("playerSeeded").await["id"], kofi_id);
+        kofi.recv("playerSeeded").await;
+        kofi.send(serde_json::json!({ "type": "getProfile" })).await;
+        let profile = kofi.recv("profile").await;
+        assert_eq!(
+            (profile["balance"].clone(), profile["pvp_level"].clone()),
+            (5.into(), 7.into())
+        );
+        assert_eq!(profile["properties"][0]["name"], "Gold Mine");
+
+        nia.send(serde_json::json!({ "type": "resetPlayer", "target": kofi_id }))
+            .await;
+        nia.recv("playerReset").await;
+        kofi.recv("playerReset").await;
+        kofi.send(serde_json::json!({ "type": "getProfile" })).await;
+        let profile = kofi.recv("profile").await;
+        assert_eq!(profile["balance"], STARTING_BALANCE);
+        assert_eq!(profile["pvp_level"], 1);
+        assert!(profile["properties"].as_array().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn admins_can_list_and_reap_disconnected_sessions() {
+        let mut state = ServerState::new();
+        let auth = StaticTokenAuth::parse("mod:nia:admin,player:kofi").unwrap();
+        state.auth = Arc::new(auth);
+        let server = TestServer::with_state(state.clone());
+        let mut nia = server.connect_as("mod").await;
+        let mut kofi = server.connect_as("player").await;
+        let kofi_id = other_player_id(&mut nia).await;
+
+        kofi.send(serde_json::json!({ "type": "listDisconnected" }))
+            .await;
+        assert_eq!(kofi.recv("error").await["code"], "forbidden");
+        kofi.close().await;
+        let kofi_uuid: Uuid = serde_json::from_value(kofi_id.clone()).unwrap();
+        for _ in 0..100 {
+            if state.disconnected.read().await.contains_key(&kofi_uuid) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        nia.send(serde_json::json!({ "type": "listDisconnected" }))
+            .await;
+        let sessions = nia.recv("disconnectedList").await["sessions"].clone();
+        assert_eq!(sessions[0]["id"], kofi_id);
+        assert_eq!(sessions[0]["username"], "kofi");
+        let reap = serde_json::json!({ "type": "reapSession", "id": kofi_id });
+        nia.send(reap.clone()).await;
+        assert_eq!(nia.recv("reaped").await["id"], kofi_id);
+        nia.send(reap).await;
+        assert_eq!(nia.recv("error").await["code"], "unknown_session");
+        nia.send(serde_json::json!({ "type": "listDisconnected" }))
+            .await;
+        let sessions = nia.recv("disconnectedList").await["sessions"].clone();
+        assert_eq!(sessions, serde_json::json!([]));
+    }
+
+    #[actix_web::test]
+    async fn reaped_sessions_get_their_stakes_back() {
+        let mut state = ServerState::new();
+        let auth = StaticTokenAuth::parse("mod:nia:admin,player:kofi").unwrap();
+        state.auth = Arc::new(auth);
+        let server = TestServer::with_state(state.clone());
+        let mut nia = server.connect_as("mod").await;
+        let mut kofi = server.connect_as("player").await;
+        let kofi_id = other_player_id(&mut nia).await;
+        let nia_id = other_player_id(&mut kofi).await;
+        kofi.send(
+            serde_json::json!({ "type": "challenge", "target": nia_id, "stake_amount": 100 }),
+        )
+        .await;
+        nia.recv("challengeRequest").await;
+        kofi.close().await;
+        assert_eq!(
+            nia.recv("opponentDisconnected").await["grace_seconds"],
+            RESUME_GRACE_PERIOD.as_secs()
+        );
+
+        nia.send(serde_json::json!({ "type": "reapSession", "id": kofi_id }))
+            .await;
+        assert_eq!(nia.recv("reaped").await["id"], kofi_id);
+        let notice = nia.recv("opponentDisconnected").await;
+        assert_eq!(
+            (&notice["id"], &notice["grace_seconds"]),
+            (&kofi_id, &serde_json::Value::Null)
+        );
+        let stored = state.storage.load_player("kofi").await.unwrap().unwrap();
+        assert_eq!(stored.balance, STARTING_BALANCE);
+        assert!(state.pending_challenges.read().await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn admins_can_grant_tokens_and_kick() {
+        let mut state = ServerState::new();
+        let auth = StaticTokenAuth::parse("mod:nia:admin,player:kofi").unwrap();
+        state.auth = Arc::new(auth);
+        let server = TestServer::with_state(state);
+        let mut nia = server.connect_as("mod").await;
+        let mut kofi = server.connect_as("player").await;
+        let kofi_id = other_player_id(&mut nia).await;
+        let nia_id = other_player_id(&mut kofi).await;
+
+        kofi.send(serde_json::json!({ "type": "grantTokens", "target": nia_id, "amount": 5 }))
+            .await;
+        assert_eq!(kofi.recv("error").await["code"], "forbidden");
+        kofi.send(serde_json::json!({ "type": "kickPlayer", "target": nia_id }))
+            .await;
+        assert_eq!(kofi.recv("error").await["code"], "forbidden");
+
+        nia.send(serde_json::json!({ "type": "grantTokens", "target": kofi_id, "amount": 250 }))
+            .await;
+        nia.recv("adminActionDone").await;
+        let granted = kofi.recv("tokensGranted").await;
+        assert_eq!(granted["new_balance"], STARTING_BALANCE + 250);
+
+        nia.send(serde_json::json!({ "type": "kickPlayer", "target": kofi_id }))
+            .await;
+        assert_eq!(nia.recv("adminActionDone").await["action"], "kick");
+        kofi.recv("kicked").await;
+        assert!(matches!(
+            kofi.framed.next().await,
+            Some(Ok(Frame::Close(_))) | None
+        ));
+    }
+
+    #[actix_web::test]
+    async fn requests_before_authentication_are_rejected() {
+        let mut state = ServerState::new();
+        state.auth = Arc::new(StaticTokenAuth::parse("secret:kofi").unwrap());
+        let server = TestServer::with_state(state);
+        let mut client = server.handshake().await.unwrap();
+
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "unauthenticated");
+
+        client
+            .send(serde_json::json!({ "type": "authenticate", "token": "wrong" }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "invalid_token");
+
+        client
+            .send(serde_json::json!({ "type": "authenticate", "token": "secret" }))
+            .await;
+        assert_eq!(client.recv("authenticated").await["username"], "kofi");
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(client.recv("profile").await["username"], "kofi");
+    }
+
+    #[actix_web::test]
+    async fn purchase_grants_property() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": "island-1",
+                "category": "Islands",
+            }))
+            .await;
+        let ack = client.recv("purchaseAck").await;
+        assert_eq!(ack["item_id"], "island-1");
+        assert_eq!(ack["balance"], 500);
+
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["properties"][0]["name"], "Islands Item");
+        assert_eq!(profile["daily_reward"], 10);
+        assert_eq!(profile["balance"], 500);
+        assert_eq!(
+            profile["category_breakdown"],
+            serde_json::json!({ "Islands": { "count": 1, "total_reward": 10 } })
+        );
+    }
+
+    #[actix_web::test]
+    async fn purchase_without_funds_is_rejected() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        for item_id in ["island-1", "island-2", "island-3"] {
+            client
+                .send(serde_json::json!({
+                    "type": "purchase",
+                    "item_id": item_id,
+                    "category": "Islands",
+                }))
+                .await;
+        }
+        client.recv("purchaseAck").await;
+        client.recv("purchaseAck").await;
+        let failed = client.recv("purchaseFailed").await;
+        assert_eq!(failed["item_id"], "island-3");
+        assert_eq!(failed["reason"], "insufficient_funds");
+
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["balance"], 0);
+        assert_eq!(profile["properties"].as_array().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn public_profile_hides_inventory_and_balance() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect_as("bob").await;
+        buy(&mut bob, "land-1", "Land").await;
+        let bob_id = other_player_id(&mut alice).await;
+        alice
+            .send(serde_json::json!({ "type": "getPlayerProfile", "target": bob_id }))
+            .await;
+        let profile = alice.recv("publicProfile").await;
+        assert_eq!(profile["username"], "bob");
+        assert_eq!(profile["property_count"], 1);
+        assert_eq!(profile["daily_reward"], 3);
+        assert!(profile.get("balance").is_none());
+        assert!(profile.get("properties").is_none());
+    }
+
+    #[actix_web::test]
+    async fn list_players_excludes_requester() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let _bob = server.connect().await;
+        alice
+            .send(serde_json::json!({ "type": "listPlayers" }))
+            .await;
+        let list = alice.recv("playerList").await;
+        assert_eq!(list["players"].as_array().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn players_can_be_searched_by_prefix() {
+        let server = TestServer::start();
+        let mut amara = server.connect_as("amara").await;
+        let _amadou = server.connect_as("Amadou").await;
+        let _amina = server.connect_as("amina").await;
+        let _kofi = server.connect_as("kofi").await;
+        amara
+            .send(serde_json::json!({ "type": "searchPlayers", "prefix": " AM", "limit": 10 }))
+            .await;
+        let list = amara.recv("playerList").await;
+        let names: Vec<&str> = list["players"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["username"].as_str().unwrap())
+            .collect();
+        // The requester is left out, as in listPlayers.
+        assert_eq!(names, ["Amadou", "amina"]);
+        assert_eq!(list["total"], 2);
+
+        amara
+            .send(serde_json::json!({ "type": "searchPlayers", "prefix": "am", "limit": 1 }))
+            .await;
+        let list = amara.recv("playerList").await;
+        assert_eq!(list["players"].as_array().unwrap().len(), 1);
+        assert_eq!(list["total"], 2);
+
+        amara
+            .send(serde_json::json!({ "type": "searchPlayers", "prefix": "  ", "limit": 10 }))
+            .await;
+        assert_eq!(amara.recv("error").await["code"], "invalid_prefix");
+    }
+
+    #[actix_web::test]
+    async fn busy_players_cannot_be_challenged() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        bob.send(serde_json::json!({ "type": "setStatus", "status": "busy" }))
+            .await;
+        assert_eq!(bob.recv("statusChanged").await["status"], "busy");
+
+        alice
+            .send(serde_json::json!({ "type": "listPlayers" }))
+            .await;
+        let bob_info = alice.recv("playerList").await["players"][0].clone();
+        assert_eq!(bob_info["status"], "busy");
+        alice
+            .send(serde_json::json!({ "type": "listPlayers", "only_challengeable": true }))
+            .await;
+        assert_eq!(alice.recv("playerList").await["total"], 0);
+        let challenge =
+            serde_json::json!({ "type": "challenge", "target": bob_info["id"], "stake_amount": 0 });
+        alice.send(challenge.clone()).await;
+        assert_eq!(alice.recv("error").await["code"], "player_unavailable");
+
+        bob.send(serde_json::json!({ "type": "setStatus", "status": "available" }))
+            .await;
+        bob.recv("statusChanged").await;
+        alice.send(challenge).await;
+        bob.recv("challengeRequest").await;
+    }
+
+    #[actix_web::test]
+    async fn accepted_challenge_is_resolved_for_both_players() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        alice
+            .send(serde_json::json!({ "type": "listPlayers" }))
+            .await;
+        let bob_id = alice.recv("playerList").await["players"][0]["id"].clone();
+        bob.send(serde_json::json!({ "type": "listPlayers" })).await;
+        let alice_id = bob.recv("playerList").await["players"][0]["id"].clone();
+
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 0 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        // Equal levels and rewards: the defender wins.
+        let result = bob.recv("battleResult").await;
+        assert_eq!(result["winner"], bob_id);
+        assert_eq!(alice.recv("battleResult").await, result);
+
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["pvp_level"], 2);
+
+        // The challenge was consumed, so a second accept is stale.
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "no_pending_challenge");
+    }
+
+    #[actix_web::test]
+    async fn matchmaking_pairs_compatible_players() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        alice
+            .send(serde_json::json!({ "type": "joinMatchmaking" }))
+            .await;
+        alice.recv("matchmakingQueued").await;
+        bob.send(serde_json::json!({ "type": "joinMatchmaking" }))
+            .await;
+
+        let found = bob.recv("matchFound").await;
+        assert_eq!(found["opponent"], alice_id);
+        assert_eq!(found["opponent_name"], "alice");
+        let found = alice.recv("matchFound").await;
+        assert_eq!(found["opponent"], bob_id);
+        assert_eq!(found["opponent_name"], "bob");
+    }
+
+    #[actix_web::test]
+    async fn spectators_see_the_battle_resolve() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        let mut carol = server.connect().await;
+
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 0 }))
+            .await;
+        let battle_id = alice.recv("challengeResponse").await["battle_id"].clone();
+        assert_eq!(bob.recv("challengeRequest").await["battle_id"], battle_id);
+        carol
+            .send(serde_json::json!({ "type": "spectateBattle", "battle_id": battle_id }))
+            .await;
+        carol.recv("spectating").await;
+
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        assert_eq!(carol.recv("battleUpdate").await["status"], "started");
+        let result = carol.recv("battleResult").await;
+        assert_eq!(result["battle_id"], battle_id);
+        assert_eq!(result["winner"], bob_id);
+
+        // The battle is over, so there is nothing left to watch.
+        carol
+            .send(serde_json::json!({ "type": "spectateBattle", "battle_id": battle_id }))
+            .await;
+        assert_eq!(carol.recv("error").await["code"], "unknown_battle");
+    }
+
+    #[actix_web::test]
+    async fn winner_takes_both_stakes() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        let challenge =
+            serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 });
+        alice.send(challenge.clone()).await;
+        bob.recv("challengeRequest").await;
+        // The stake is escrowed and the same challenge can't be repeated.
+        alice.send(challenge).await;
+        assert_eq!(alice.recv("error").await["code"], "challenge_pending");
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(alice.recv("profile").await["balance"], 900);
+
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        let result = bob.recv("battleResult").await;
+        assert_eq!(result["winner"], bob_id);
+        assert_eq!(result["pot"], 200);
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["balance"], 1100);
+    }
+
+    #[actix_web::test]
+    async fn stakes_must_be_covered_by_both_players() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let _bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        alice
+            .send(
+                serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 5000 }),
+            )
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "insufficient_stake");
+    }
+
+    #[actix_web::test]
+    async fn expired_challenges_refund_the_stake() {
+        let mut state = ServerState::new();
+        state.challenge_timeout = Duration::ZERO;
+        let server = TestServer::with_state(state.clone());
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        alice.recv("challengeResponse").await;
+
+        assert_eq!(state.expire_challenges().await, 1);
+        assert_eq!(alice.recv("challengeExpired").await["target"], bob_id);
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(alice.recv("profile").await["balance"], STARTING_BALANCE);
+    }
+
+    #[actix_web::test]
+    async fn declined_challenge_notifies_challenger() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        alice
+            .send(serde_json::json!({ "type": "listPlayers" }))
+            .await;
+        let bob_id = alice.recv("playerList").await["players"][0]["id"].clone();
+        bob.send(serde_json::json!({ "type": "listPlayers" })).await;
+        let alice_id = bob.recv("playerList").await["players"][0]["id"].clone();
+
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        bob.send(serde_json::json!({ "type": "declineChallenge", "challenger": alice_id }))
+            .await;
+        assert_eq!(alice.recv("challengeDeclined").await["target"], bob_id);
+        // The declined stake is refunded.
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(alice.recv("profile").await["balance"], STARTING_BALANCE);
+    }
+
+    #[actix_web::test]
+    async fn challengers_can_cancel_unanswered_challenges() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        alice
+            .send(serde_json::json!({ "type": "cancelChallenge", "target": bob_id }))
+            .await;
+        alice.recv("challengeCancelled").await;
+        assert_eq!(bob.recv("challengeCancelled").await["challenger"], alice_id);
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(alice.recv("profile").await["balance"], STARTING_BALANCE);
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "no_pending_challenge");
+
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 0 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        alice.recv("battleResult").await;
+        alice
+            .send(serde_json::json!({ "type": "cancelChallenge", "target": bob_id }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "already_accepted");
+    }
+
+    #[actix_web::test]
+    async fn battles_are_kept_in_both_histories() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        for _ in 0..2 {
+            alice
+                .send(
+                    serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 0 }),
+                )
+                .await;
+            bob.recv("challengeRequest").await;
+            bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+                .await;
+            alice.recv("battleResult").await;
+        }
+
+        alice
+            .send(serde_json::json!({ "type": "getBattleHistory", "limit": 1 }))
+            .await;
+        let alice_history = alice.recv("battleHistory").await;
+        assert_eq!(alice_history["recent"].as_array().unwrap().len(), 1);
+        assert_eq!(alice_history["recent"][0]["opponent"], "bob");
+        bob.send(serde_json::json!({ "type": "getBattleHistory" }))
+            .await;
+        let bob_history = bob.recv("battleHistory").await;
+        assert_eq!(bob_history["recent"].as_array().unwrap().len(), 2);
+        assert_eq!(alice_history["wins"], bob_history["losses"]);
+        assert_eq!(alice_history["losses"], bob_history["wins"]);
+        assert_eq!(
+            alice_history["recent"][0]["won"],
+            !bob_history["recent"][0]["won"].as_bool().unwrap()
+        );
+    }
+
+    #[test]
+    fn battle_history_is_capped() {
+        let mut info = ClientInfo::new("amara".into());
+        for pot in 0..MAX_BATTLE_HISTORY as u64 + 5 {
+            let record = BattleRecord {
+                battle_id: Uuid::new_v4(),
+                opponent: "kofi".into(),
+                won: pot % 2 == 0,
+                pot,
+                forfeited: false,
+                timestamp: 0,
+            };
+            record_battle(&mut info, record);
+        }
+        assert_eq!(info.battle_history.len(), MAX_BATTLE_HISTORY);
+        assert_eq!(info.battle_history[0].pot, 5);
+        assert_eq!((info.wins, info.losses), (28, 27));
+    }
+
+    #[actix_web::test]
+    async fn players_can_gift_tokens_and_properties() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        alice
+            .send(serde_json::json!({ "type": "giftTokens", "target": bob_id, "amount": 150 }))
+            .await;
+        assert_eq!(alice.recv("giftAck").await["kind"]["amount"], 150);
+        let received = bob.recv("giftReceived").await;
+        assert_eq!(received["from"], alice_id);
+        assert_eq!(received["kind"]["type"], "tokens");
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["balance"], STARTING_BALANCE + 150);
+
+        buy(&mut alice, "land-1", "Land").await;
+        let gift = serde_json::json!({
+            "type": "giftProperty",
+            "target": bob_id,
+            "property_name": "Land Item",
+        });
+        alice.send(gift.clone()).await;
+        alice.recv("giftAck").await;
+        let received = bob.recv("giftReceived").await;
+        assert_eq!(received["kind"]["property"]["name"], "Land Item");
+        alice.send(gift).await;
+        assert_eq!(alice.recv("error").await["code"], "not_owned");
+
+        let too_much = STARTING_BALANCE * 2;
+        alice
+            .send(serde_json::json!({ "type": "giftTokens", "target": bob_id, "amount": too_much }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "insufficient_funds");
+        alice
+            .send(serde_json::json!({ "type": "giftTokens", "target": alice_id, "amount": 1 }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "invalid_target");
+        let nobody = Uuid::new_v4();
+        alice
+            .send(serde_json::json!({ "type": "giftTokens", "target": nobody, "amount": 1 }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "unknown_target");
+    }
+
+    #[actix_web::test]
+    async fn transfer_fees_are_burned_at_the_current_rate() {
+        let mut state = ServerState::new();
+        state.admin_token = Some("secret".into());
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
+        let update = |rate: u32| {
+            actix_web::test::TestRequest::post()
+                .uri("/admin/economy")
+                .insert_header((actix_web::http::header::AUTHORIZATION, "Bearer secret"))
+                .set_json(serde_json::json!({ "transfer_fee_bps": rate }))
+                .to_request()
+        };
+        let resp = actix_web::test::call_service(&app, update(MAX_TRANSFER_FEE_BPS)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let resp = actix_web::test::call_service(&app, update(250)).await;
+        assert!(resp.status().is_success());
+
+        let server = TestServer::with_state(state.clone());
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        let gift = |amount: u64| serde_json::json!({ "type": "giftTokens", "target": bob_id, "amount": amount });
+        alice.send(gift(100)).await;
+        let ack = alice.recv("giftAck").await;
+        assert_eq!(
+            (&ack["kind"]["amount"], &ack["kind"]["fee"]),
+            (&98.into(), &2.into())
+        );
+        assert_eq!(bob.recv("giftReceived").await["kind"]["amount"], 98);
+        // Gifts too small to owe a whole token go through free.
+        alice.send(gift(39)).await;
+        assert_eq!(alice.recv("giftAck").await["kind"]["fee"], 0);
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(
+            bob.recv("profile").await["balance"],
+            STARTING_BALANCE + 98 + 39
+        );
+
+        // Trades charge each side on the price of what they receive.
+        buy(&mut alice, "land-1", "Land").await;
+        buy(&mut bob, "islands-1", "Islands").await;
+        alice
+            .send(serde_json::json!({
+                "type": "offerTrade",
+                "target": bob_id,
+                "offer_property": "Land Item",
+                "request_property": "Islands Item",
+            }))
+            .await;
+        bob.recv("tradeOffer").await;
+        bob.send(serde_json::json!({ "type": "respondTrade", "from": alice_id, "accept": true }))
+            .await;
+        assert_eq!(bob.recv("tradeCompleted").await["fee"], 3);
+        assert_eq!(alice.recv("tradeCompleted").await["fee"], 12);
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(
+            alice.recv("profile").await["balance"],
+            STARTING_BALANCE - 139 - 150 - 12
+        );
+        assert_eq!(state.metrics.tokens_burned.get(), 2 + 12 + 3);
+    }
+
+    #[actix_web::test]
+    async fn an_account_cannot_be_signed_in_twice() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut amara = server.connect_as("amara").await;
+        let mut kofi = server.connect_as("kofi").await;
+        let kofi_id = other_player_id(&mut amara).await;
+        let amara_id: Uuid = serde_json::from_value(other_player_id(&mut kofi).await).unwrap();
+
+        let mut twin = server.handshake().await.unwrap();
+        twin.send(serde_json::json!({ "type": "authenticate", "token": "amara" }))
+            .await;
+        assert_eq!(twin.recv("error").await["code"], "already_connected");
+
+        // Tokens given away from the live session are gone for good.
+        amara
+            .send(serde_json::json!({ "type": "giftTokens", "target": kofi_id, "amount": 400 }))
+            .await;
+        amara.recv("giftAck").await;
+        amara.close().await;
+        for _ in 0..100 {
+            if !state.clients.contains_key(&amara_id).await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        twin.send(serde_json::json!({ "type": "authenticate", "token": "amara" }))
+            .await;
+        twin.recv("authenticated").await;
+        twin.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(
+            twin.recv("profile").await["balance"],
+            STARTING_BALANCE - 400
+        );
+        kofi.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(
+            kofi.recv("profile").await["balance"],
+            STARTING_BALANCE + 400
+        );
+    }
+
+    #[actix_web::test]
+    async fn token_gifts_cannot_overflow_the_recipient() {
+        let state = ServerState::new();
+        let (sender, recipient) = (Uuid::new_v4(), Uuid::new_v4());
+        state
+            .clients
+            .insert(sender, ClientInfo::new("amara".into()))
+            .await;
+        let mut rich = ClientInfo::new("kofi".into());
+        rich.balance = u64::MAX;
+        state.clients.insert(recipient, rich).await;
+
+        let err = state
+            .give_gift(sender, recipient, GiftRequest::Tokens(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServerMessage::Error { code, .. } if code == "balance_overflow"));
+        let clients = state.clients.read_all().await;
+        assert_eq!(clients.get(&sender).unwrap().balance, STARTING_BALANCE);
+        assert_eq!(clients.get(&recipient).unwrap().balance, u64::MAX);
+    }
+
+    #[actix_web::test]
+    async fn repeated_declines_back_off_challenges() {
+        let mut state = ServerState::new();
+        state.challenge_cooldown = Duration::from_millis(300);
+        let server = TestServer::with_state(state);
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        let challenge = serde_json::json!({ "type": "challenge", "target": bob_id });
+        let decline = serde_json::json!({ "type": "declineChallenge", "challenger": alice_id });
+
+        alice.send(challenge.clone()).await;
+        bob.recv("challengeRequest").await;
+        bob.send(decline.clone()).await;
+        alice.recv("challengeDeclined").await;
+        alice.send(challenge.clone()).await;
+        let err = alice.recv("error").await;
+        assert_eq!(err["code"], "challenge_cooldown");
+        assert_eq!(err["retry_after_secs"], 1);
+
+        // The second decline doubles the cooldown to 600ms.
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        alice.send(challenge.clone()).await;
+        bob.recv("challengeRequest").await;
+        bob.send(decline).await;
+        alice.recv("challengeDeclined").await;
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        alice.send(challenge.clone()).await;
+        assert_eq!(alice.recv("error").await["code"], "challenge_cooldown");
+
+        // An accepted challenge resets the backoff.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        alice.send(challenge.clone()).await;
+        bob.recv("challengeRequest").await;
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        alice.recv("battleResult").await;
+        alice.send(challenge).await;
+        bob.recv("challengeRequest").await;
+    }
+
+    #[actix_web::test]
+    async fn counterparts_hear_about_disconnects() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        bob.send(
+            serde_json::json!({ "type": "challenge", "target": alice_id, "stake_amount": 100 }),
+        )
+        .await;
+        alice.recv("challengeRequest").await;
+        buy(&mut alice, "land-1", "Land").await;
+        alice
+            .send(serde_json::json!({
+                "type": "offerTrade",
+                "target": bob_id,
+                "offer_property": "Land Item",
+                "request_property": "Land Item",
+            }))
+            .await;
+        bob.recv("tradeOffer").await;
+
+        alice.close().await;
+        let notice = bob.recv("opponentDisconnected").await;
+        assert_eq!(notice["id"], alice_id);
+        assert_eq!(notice["grace_seconds"], RESUME_GRACE_PERIOD.as_secs());
+        // The challenge waits for alice to resume.
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["balance"], STARTING_BALANCE - 100);
+
+        // Signing in afresh gives up the old session and what it left open.
+        let _alice = server.connect_as("alice").await;
+        let notice = bob.recv("opponentDisconnected").await;
+        assert_eq!(notice["id"], alice_id);
+        assert!(notice.get("grace_seconds").is_none());
+        // Bob's escrowed stake is back.
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["balance"], STARTING_BALANCE);
+    }
+
+    #[actix_web::test]
+    async fn trades_can_be_accepted_after_resuming() {
+        let server = TestServer::start();
+        let mut alice = server.handshake().await.unwrap();
+        let welcome = alice.recv("welcome").await;
+        alice
+            .send(serde_json::json!({ "type": "authenticate", "token": "alice" }))
+            .await;
+        alice.recv("authenticated").await;
+        buy(&mut alice, "land-1", "Land").await;
+        let mut bob = server.connect().await;
+        buy(&mut bob, "islands-1", "Islands").await;
+        let alice_id = other_player_id(&mut bob).await;
+        bob.send(serde_json::json!({
+            "type": "offerTrade",
+            "target": alice_id,
+            "offer_property": "Islands Item",
+            "request_property": "Land Item",
+        }))
+        .await;
+        alice.recv("tradeOffer").await;
+
+        alice.close().await;
+        bob.recv("opponentDisconnected").await;
+        let mut alice = resume(&server, &welcome).await;
+        let pending = alice.recv("pendingActions").await;
+        let trade = &pending["trades"][0];
+        assert_eq!(
+            (&trade["target"], &trade["offer_property"]),
+            (&alice_id, &"Islands Item".into())
+        );
+        let bob_id = trade["from"].clone();
+        alice
+            .send(serde_json::json!({ "type": "respondTrade", "from": bob_id, "accept": true }))
+            .await;
+        assert_eq!(
+            alice.recv("tradeCompleted").await["received"],
+            "Islands Item"
+        );
+        assert_eq!(bob.recv("tradeCompleted").await["received"], "Land Item");
+    }
+
+    #[actix_web::test]
+    async fn season_archives_are_read_from_storage() {
+        // An archive from before a restart is only in storage.
+        let state = ServerState::new();
+        let standing = |username: &str| SeasonStanding {
+            username: username.into(),
+            pvp_level: 3,
+            daily_reward: 10,
+        };
+        let standings = (0..SEASON_ARCHIVE_TOP_N + 1)
+            .map(|rank| standing(&format!("player{}", rank)))
+            .collect();
+        let archive = SeasonArchive {
+            season: 1,
+            standings,
+        };
+        state.storage.save_season(&archive).await.unwrap();
+        let server = TestServer::with_state(state);
+        let mut client = server.connect().await;
+
+        client
+            .send(serde_json::json!({ "type": "getSeasonArchive" }))
+            .await;
+        assert_eq!(
+            client.recv("seasonList").await["seasons"],
+            serde_json::json!([1])
+        );
+        client
+            .send(serde_json::json!({ "type": "getSeasonArchive", "season": 1 }))
+            .await;
+        let standings = client.recv("seasonArchive").await["standings"].clone();
+        assert_eq!(standings.as_array().unwrap().len(), SEASON_ARCHIVE_TOP_N);
+        assert_eq!(standings[0]["username"], "player0");
+        client
+            .send(serde_json::json!({ "type": "getSeasonArchive", "season": 2 }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "unknown_season");
+    }
+
+    #[actix_web::test]
+    async fn resumed_sessions_replay_what_they_missed() {
+        let server = TestServer::start();
+        let mut alice = server.handshake().await.unwrap();
+        let welcome = alice.recv_message("welcome").await;
+        assert_eq!(welcome["seq"], 1);
+        alice
+            .send(serde_json::json!({ "type": "authenticate", "token": "alice" }))
+            .await;
+        alice.recv("authenticated").await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let last_seq = alice.recv_message("profile").await["seq"].as_u64().unwrap();
+
+        alice.close().await;
+        bob.recv("opponentDisconnected").await;
+        bob.send(serde_json::json!({ "type": "declineChallenge", "challenger": alice_id }))
+            .await;
+        // Wait for the decline to land while alice is away.
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        bob.recv("profile").await;
+
+        let path = format!(
+            "/ws?resume_token={}&last_seq={}",
+            welcome["welcome"]["resume_token"].as_str().unwrap(),
+            last_seq
+        );
+        let mut alice = server.handshake_at(&path).await.unwrap();
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        // Every frame, replayed or new, continues the numbering.
+        let mut frames = Vec::new();
+        loop {
+            let frame = alice.recv_next().await;
+            assert_eq!(frame["seq"], last_seq + 1 + frames.len() as u64, "{frame}");
+            let done = frame.get("profile").is_some();
+            frames.push(frame);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(frames[0]["challengeDeclined"]["target"], bob_id);
+        let welcomes = frames.iter().filter(|frame| frame.get("welcome").is_some());
+        assert_eq!(welcomes.count(), 1);
+        let profile = frames.last().unwrap();
+        assert_eq!(profile["profile"]["balance"], STARTING_BALANCE);
+    }
+
+    #[actix_web::test]
+    async fn forfeits_against_a_vanished_opponent_refund_and_audit_the_stakes() {
+        let mut state = ServerState::new();
+        state.battle_duration = Duration::from_secs(60);
+        state.battle_grace = Duration::from_secs(5);
+        let server = TestServer::with_state(state.clone());
+        let (mut alice, bob, _, (_, bob_id)) = battle_with_resumable(&server).await;
+        bob.close().await;
+        alice.recv("opponentDisconnected").await;
+        let bob_id: Uuid = serde_json::from_value(bob_id).unwrap();
+        // Bob's session is dropped without settling his battle.
+        state.disconnected.write().await.remove(&bob_id);
+
+        let battle_id = state.active_battles.read().await.keys().next().copied();
+        alice
+            .send(serde_json::json!({ "type": "forfeit", "battle_id": battle_id }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "unknown_target");
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(alice.recv("profile").await["balance"], STARTING_BALANCE);
+        let log = state.audit_log.read().await;
+        let refund = log.back().unwrap();
+        assert_eq!(
+            (refund.account.as_str(), refund.reason.as_str()),
+            ("alice", "stake_refund")
+        );
+        assert!(matches!(
+            refund.change,
+            AuditChange::Balance { old, new } if old + 100 == new && new == STARTING_BALANCE
+        ));
+    }
+
+    #[actix_web::test]
+    async fn forfeits_pay_the_opponent() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 }))
+            .await;
+        let battle_id = bob.recv("challengeRequest").await["battle_id"].clone();
+        let mut carol = server.connect().await;
+        let forfeit = serde_json::json!({ "type": "forfeit", "battle_id": battle_id });
+        carol.send(forfeit.clone()).await;
+        assert_eq!(carol.recv("error").await["code"], "not_in_battle");
+
+        alice.send(forfeit.clone()).await;
+        let result = alice.recv("battleResult").await;
+        assert_eq!((&result["winner"], &result["loser"]), (&bob_id, &alice_id));
+        assert_eq!(result["forfeited"], true);
+        assert_eq!(bob.recv("battleResult").await["pot"], 100);
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["balance"], STARTING_BALANCE + 100);
+        alice.send(forfeit).await;
+        assert_eq!(alice.recv("error").await["code"], "unknown_battle");
+    }
+
+    #[actix_web::test]
+    async fn targets_who_forfeit_pay_both_stakes() {
+        let mut state = ServerState::new();
+        state.battle_duration = Duration::from_secs(60);
+        let server = TestServer::with_state(state);
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        let challenge =
+            serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 });
+
+        // An accepted battle is fought with both stakes in escrow.
+        alice.send(challenge.clone()).await;
+        let battle_id = bob.recv("challengeRequest").await["battle_id"].clone();
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        assert_eq!(bob.recv("battleUpdate").await["status"], "started");
+        assert_eq!(alice.recv("battleUpdate").await["status"], "started");
+        bob.send(serde_json::json!({ "type": "forfeit", "battle_id": battle_id }))
+            .await;
+        let result = bob.recv("battleResult").await;
+        assert_eq!((&result["winner"], &result["loser"]), (&alice_id, &bob_id));
+        assert_eq!(
+            (&result["pot"], &result["forfeited"]),
+            (&200.into(), &true.into())
+        );
+        assert_eq!(alice.recv("battleResult").await["pot"], 200);
+
+        // Forfeiting a pending challenge costs the target their stake too.
+        alice.send(challenge).await;
+        let battle_id = bob.recv("challengeRequest").await["battle_id"].clone();
+        bob.send(serde_json::json!({ "type": "forfeit", "battle_id": battle_id }))
+            .await;
+        assert_eq!(bob.recv("battleResult").await["pot"], 200);
+
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(
+            alice.recv("profile").await["balance"],
+            STARTING_BALANCE + 200
+        );
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["balance"], STARTING_BALANCE - 200);
+    }
+
+    #[actix_web::test]
+    async fn accepted_battles_resolve_after_the_battle_duration() {
+        let mut state = ServerState::new();
+        state.battle_duration = Duration::from_millis(100);
+        let server = TestServer::with_state(state);
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 50 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        bob.recv("battleUpdate").await;
+        let result = bob.recv("battleResult").await;
+        assert_eq!(
+            (&result["pot"], &result["forfeited"]),
+            (&100.into(), &false.into())
+        );
+        assert_eq!(alice.recv("battleResult").await, result);
+    }
+
+    /// Start a battle between a resumable session and a second player,
+    /// and return both clients, the first one's welcome and their ids.
+    async fn battle_with_resumable(
+        server: &TestServer,
+    ) -> (
+        TestClient,
+        TestClient,
+        serde_json::Value,
+        (serde_json::Value, serde_json::Value),
+    ) {
+        let mut alice = server.handshake().await.unwrap();
+        let welcome = alice.recv("welcome").await;
+        alice
+            .send(serde_json::json!({ "type": "authenticate", "token": "alice" }))
+            .await;
+        alice.recv("authenticated").await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 100 }))
+            .await;
+        bob.recv("challengeRequest").await;
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        assert_eq!(bob.recv("battleUpdate").await["status"], "started");
+        assert_eq!(alice.recv("battleUpdate").await["status"], "started");
+        (alice, bob, welcome, (alice_id, bob_id))
+    }
+
+    async fn resume(server: &TestServer, welcome: &serde_json::Value) -> TestClient {
+        let path = format!(
+            "/ws?resume_token={}",
+            welcome["resume_token"].as_str().unwrap()
+        );
+        let mut resumed = server.handshake_at(&path).await.unwrap();
+        resumed.recv("welcome").await;
+        resumed
+    }
+
+    #[actix_web::test]
+    async fn battles_pause_while_a_player_resumes() {
+        let mut state = ServerState::new();
+        state.battle_duration = Duration::from_millis(300);
+        state.battle_grace = Duration::from_secs(5);
+        let server = TestServer::with_state(state.clone());
+        let (alice, mut bob, welcome, (alice_id, bob_id)) = battle_with_resumable(&server).await;
+
+        alice.close().await;
+        let notice = bob.recv("opponentDisconnected").await;
+        assert_eq!(
+            (&notice["id"], &notice["grace_seconds"]),
+            (&alice_id, &5.into())
+        );
+        // The battle is not resolved while alice is away.
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(state.active_battles.read().await.len(), 1);
+
+        let mut alice = resume(&server, &welcome).await;
+        assert_eq!(alice.recv("battleUpdate").await["status"], "resumed");
+        assert_eq!(bob.recv("battleUpdate").await["status"], "resumed");
+        let result = bob.recv("battleResult").await;
+        assert_eq!(
+            (&result["pot"], &result["forfeited"]),
+            (&200.into(), &false.into())
+        );
+        assert_eq!(alice.recv("battleResult").await, result);
+        assert!([&alice_id, &bob_id].contains(&&result["winner"]));
+    }
+
+    #[actix_web::test]
+    async fn players_who_dont_resume_forfeit_their_battles() {
+        let mut state = ServerState::new();
+        state.battle_duration = Duration::from_secs(60);
+        state.battle_grace = Duration::from_millis(100);
+        let server = TestServer::with_state(state.clone());
+        let (alice, mut bob, welcome, (alice_id, bob_id)) = battle_with_resumable(&server).await;
+
+        alice.close().await;
+        assert_eq!(bob.recv("opponentDisconnected").await["grace_seconds"], 1);
+        let result = bob.recv("battleResult").await;
+        assert_eq!((&result["winner"], &result["loser"]), (&bob_id, &alice_id));
+        assert_eq!(
+            (&result["pot"], &result["forfeited"]),
+            (&200.into(), &true.into())
+        );
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(bob.recv("profile").await["balance"], STARTING_BALANCE + 100);
+
+        // Resuming afterwards, alice finds the loss on her record.
+        let mut alice = resume(&server, &welcome).await;
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(
+            alice.recv("profile").await["balance"],
+            STARTING_BALANCE - 100
+        );
+        alice
+            .send(serde_json::json!({ "type": "getBattleHistory" }))
+            .await;
+        let history = alice.recv("battleHistory").await;
+        assert_eq!(history["losses"], 1);
+        assert_eq!(history["recent"][0]["forfeited"], true);
+    }
+
+    #[actix_web::test]
+    async fn presence_changes_are_broadcast() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let bob = server.connect_as("bob").await;
+        let joined = alice.recv("playerJoined").await;
+        assert_eq!(joined["username"], "bob");
+        assert_eq!(joined["pvp_level"], 1);
+
+        bob.close().await;
+        assert_eq!(alice.recv("playerLeft").await["id"], joined["id"]);
+    }
+
+    #[actix_web::test]
+    async fn watchers_receive_purchase_updates() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        bob.send(serde_json::json!({ "type": "listPlayers" })).await;
+        let alice_id = bob.recv("playerList").await["players"][0]["id"].clone();
+
+        bob.send(serde_json::json!({ "type": "watchPlayer", "target": alice_id }))
+            .await;
+        bob.recv("watching").await;
+        alice
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": "land-1",
+                "category": "Land",
+            }))
+            .await;
+        let update = bob.recv("playerUpdate").await;
+        assert_eq!(update["id"], alice_id);
+        assert_eq!(update["event"]["kind"], "purchased");
+        assert_eq!(update["event"]["category"], "Land");
+    }
+
+    #[actix_web::test]
+    async fn friends_see_each_other_come_and_go() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let bob = server.connect_as("bob").await;
+        let bob_id = alice.recv("playerJoined").await["id"].clone();
+
+        alice
+            .send(serde_json::json!({ "type": "addFriend", "target": bob_id }))
+            .await;
+        assert_eq!(alice.recv("friendAdded").await["username"], "bob");
+        alice
+            .send(serde_json::json!({ "type": "addFriend", "target": Uuid::new_v4() }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "unknown_target");
+
+        bob.close().await;
+        let offline = alice.recv("friendOffline").await;
+        assert_eq!(
+            (&offline["id"], &offline["username"]),
+            (&bob_id, &"bob".into())
+        );
+        alice
+            .send(serde_json::json!({ "type": "getFriends" }))
+            .await;
+        let friends = alice.recv("friends").await["friends"].clone();
+        assert_eq!(friends[0]["username"], "bob");
+        assert_eq!(friends[0]["online"], false);
+
+        let _bob = server.connect_as("bob").await;
+        assert_eq!(alice.recv("friendOnline").await["username"], "bob");
+        alice
+            .send(serde_json::json!({ "type": "getFriends" }))
+            .await;
+        let friends = alice.recv("friends").await["friends"].clone();
+        assert_eq!(friends[0]["online"], true);
+    }
+
+    #[actix_web::test]
+    async fn privacy_settings_block_challenges_and_watchers() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        bob.send(serde_json::json!({ "type": "listPlayers" })).await;
+        let alice_id = bob.recv("playerList").await["players"][0]["id"].clone();
+
+        alice
+            .send(serde_json::json!({
+                "type": "updatePrivacy",
+                "allow_challenges": false,
+                "allow_watch": false,
+            }))
+            .await;
+        let privacy = alice.recv("privacy").await;
+        assert_eq!(privacy["allow_challenges"], false);
+        assert_eq!(privacy["allow_whispers"], true);
+
+        bob.send(serde_json::json!({ "type": "challenge", "target": alice_id, "stake_amount": 0 }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "challenges_not_allowed");
+        bob.send(serde_json::json!({ "type": "watchPlayer", "target": alice_id }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "watch_not_allowed");
+    }
+
+    #[actix_web::test]
+    async fn muted_notifications_are_not_pushed() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let bob_id = other_player_id(&mut alice).await;
+        alice
+            .send(serde_json::json!({ "type": "getNotificationPrefs" }))
+            .await;
+        let prefs = alice.recv("notificationPrefs").await;
+        assert_eq!(
+            prefs,
+            serde_json::json!({ "chat": true, "presence": true, "challenges": true })
+        );
+
+        bob.send(serde_json::json!({
+            "type": "setNotificationPrefs",
+            "prefs": { "chat": false, "presence": false, "challenges": false },
+        }))
+        .await;
+        assert_eq!(bob.recv("notificationPrefs").await["chat"], false);
+        let _carol = server.connect_as("carol").await;
+        alice
+            .send(serde_json::json!({ "type": "chatSend", "text": "hello" }))
+            .await;
+        alice.recv("chatMessage").await;
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 0 }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "player_unavailable");
+
+        // Bob saw neither Carol joining nor Alice's message before the
+        // reply to this.
+        bob.send(serde_json::json!({ "type": "getNotificationPrefs" }))
+            .await;
+        loop {
+            let Some(Ok(Frame::Text(text))) = bob.framed.next().await else {
+                panic!("connection closed");
+            };
+            let value: serde_json::Value = serde_json::from_slice(&text).unwrap();
+            assert!(value.get("playerJoined").is_none(), "{}", value);
+            assert!(value.get("chatMessage").is_none(), "{}", value);
+            if value.get("notificationPrefs").is_some() {
+                break;
+            }
+        }
+
+        // Fields left out are turned back on.
+        bob.send(
+            serde_json::json!({ "type": "setNotificationPrefs", "prefs": { "presence": false } }),
+        )
+        .await;
+        assert_eq!(bob.recv("notificationPrefs").await["chat"], true);
+        alice
+            .send(serde_json::json!({ "type": "chatSend", "text": "again" }))
+            .await;
+        assert_eq!(bob.recv("chatMessage").await["text"], "again");
+    }
+
+    /// Buy a property of `category` and wait for the acknowledgement.
+    async fn buy(client: &mut TestClient, item_id: &str, category: &str) {
+        client
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": item_id,
+                "category": category,
+            }))
+            .await;
+        client.recv("purchaseAck").await;
+    }
+
+    #[actix_web::test]
+    async fn only_new_players_get_the_starter_kit() {
+        let mut state = ServerState::new();
+        state.starting_balance = 40;
+        state.starter_properties = Arc::new(vec![Property {
+            name: "Hut".into(),
+            category: "Buildings".into(),
+            reward: 1,
+            level: 1,
+        }]);
+        let server = TestServer::with_state(state.clone());
+        let mut client = server.connect_as("ayo").await;
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["balance"], 40);
+        assert_eq!(profile["properties"][0]["name"], "Hut");
+        client
+            .send(serde_json::json!({ "type": "sell", "property_name": "Hut" }))
+            .await;
+        client.recv("sellAck").await;
+        client.close().await;
+        for _ in 0..100 {
+            if state.clients.len().await == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The returning player keeps what they had.
+        let mut client = server.connect_as("ayo").await;
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert!(profile["properties"].as_array().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn dry_run_purchases_only_preview() {
+        let mut state = ServerState::new();
+        let mut marketplace = default_marketplace();
+        apply_prices(&mut marketplace, HashMap::from([("Islands".into(), 5000)]));
+        state.marketplace = Arc::new(marketplace);
+        let server = TestServer::with_state(state);
+        let mut client = server.connect().await;
+        let price = 150;
+        let preview = serde_json::json!({
+            "type": "purchase",
+            "item_id": "land-1",
+            "category": "Land",
+            "dry_run": true,
+        });
+        client.send(preview.clone()).await;
+        let first = client.recv("purchasePreview").await;
+        assert_eq!(first["price"], price);
+        assert_eq!(first["resulting_balance"], STARTING_BALANCE - price);
+        assert_eq!(first["affordable"], true);
+        // Nothing was bought, so the same preview comes back.
+        client.send(preview).await;
+        assert_eq!(client.recv("purchasePreview").await, first);
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["balance"], STARTING_BALANCE);
+        assert!(profile["properties"].as_array().unwrap().is_empty());
+
+        let islands = serde_json::json!({
+            "type": "purchase",
+            "item_id": "islands-1",
+            "category": "Islands",
+            "dry_run": true,
+        });
+        client.send(islands).await;
+        let preview = client.recv("purchasePreview").await;
+        assert_eq!(preview["price"], 5000);
+        assert_eq!(preview["affordable"], false);
+        assert_eq!(preview["resulting_balance"], STARTING_BALANCE);
+    }
+
+    #[actix_web::test]
+    async fn dry_run_purchases_are_checked_like_real_ones() {
+        let mut state = ServerState::new();
+        state.max_properties = 1;
+        state.inventory_cap = InventoryCap::Prompt;
+        let server = TestServer::with_state(state);
+        let mut client = server.connect().await;
+        let purchase = |item_id: &str, key: Option<&str>, dry_run: bool| {
+            serde_json::json!({
+                "type": "purchase",
+                "item_id": item_id,
+                "category": "Land",
+                "idempotency_key": key,
+                "dry_run": dry_run,
+            })
+        };
+        client
+            .send(purchase("land-1", Some("order-1"), false))
+            .await;
+        let ack = client.recv("purchaseAck").await;
+        // A dry run with the same key sees the purchase already made.
+        client.send(purchase("land-1", Some("order-1"), true)).await;
+        assert_eq!(client.recv("purchaseAck").await, ack);
+
+        client.send(purchase("land-2", None, true)).await;
+        assert_eq!(client.recv("inventoryFull").await["must_free"], 1);
+        // Nothing was held, so freeing the slot buys nothing.
+        client
+            .send(serde_json::json!({ "type": "abandonProperty", "property_name": "Land Item" }))
+            .await;
+        client.recv("propertyAbandoned").await;
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["balance"], ack["balance"]);
+        assert!(profile["properties"].as_array().unwrap().is_empty());
+    }
+
+    /// Id of the only other connected player, as seen by `client`.
+    async fn other_player_id(client: &mut TestClient) -> serde_json::Value {
+        client
+            .send(serde_json::json!({ "type": "listPlayers" }))
+            .await;
+        client.recv("playerList").await["players"][0]["id"].clone()
+    }
+
+    #[actix_web::test]
+    async fn marketplace_lists_purchasable_categories() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({ "type": "getMarketplace" }))
+            .await;
+        let items = client.recv("marketplace").await["items"].clone();
+        let islands = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|item| item["category"] == "Islands")
+            .expect("islands are listed");
+        assert_eq!(islands["price"], 500);
+        assert_eq!(islands["reward"], 10);
+    }
+
+    #[actix_web::test]
+    async fn repeated_idempotency_key_buys_only_once() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        let purchase = serde_json::json!({
+            "type": "purchase",
+            "item_id": "island-1",
+            "category": "Islands",
+            "idempotency_key": "order-1",
+        });
+        client.send(purchase.clone()).await;
+        let first = client.recv("purchaseAck").await;
+        client.send(purchase).await;
+        assert_eq!(client.recv("purchaseAck").await, first);
+
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["properties"].as_array().unwrap().len(), 1);
+        assert_eq!(profile["balance"], STARTING_BALANCE - 500);
+    }
+
+    #[actix_web::test]
+    async fn players_can_pick_a_unique_username() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        alice
+            .send(serde_json::json!({ "type": "setUsername", "username": "  Queen_Amara " }))
+            .await;
+        assert_eq!(
+            alice.recv("usernameChanged").await["username"],
+            "Queen_Amara"
+        );
+        let renamed = bob.recv("playerRenamed").await;
+        assert_eq!(renamed["id"], alice_id);
+        assert_eq!(renamed["username"], "Queen_Amara");
+
+        bob.send(serde_json::json!({ "type": "setUsername", "username": "queen_amara" }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "username_taken");
+        bob.send(serde_json::json!({ "type": "setUsername", "username": "no spaces" }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "invalid_username");
+        bob.send(serde_json::json!({ "type": "setUsername", "username": "ab" }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "invalid_username");
+
+        // Names stay taken while their players are offline.
+        alice.close().await;
+        bob.recv("playerLeft").await;
+        for name in ["QUEEN_AMARA", "Alice"] {
+            bob.send(serde_json::json!({ "type": "setUsername", "username": name }))
+                .await;
+            assert_eq!(bob.recv("error").await["code"], "username_taken");
+        }
+        bob.send(serde_json::json!({ "type": "setUsername", "username": "Bob_the_Bold" }))
+            .await;
+        bob.recv("usernameChanged").await;
+    }
+
+    #[actix_web::test]
+    async fn chosen_username_is_kept_across_logins() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        alice
+            .send(serde_json::json!({ "type": "setUsername", "username": "amara" }))
+            .await;
+        alice.recv("usernameChanged").await;
+        alice.close().await;
+
+        let mut alice = server.connect_as("alice").await;
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(alice.recv("profile").await["username"], "amara");
+    }
+
+    #[actix_web::test]
+    async fn metrics_count_requests_and_purchases() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut client = server.connect().await;
+        buy(&mut client, "land-1", "Land").await;
+
+        let metrics = state.metrics.render();
+        assert!(metrics.contains("messages_received_total{type=\"purchase\"} 1"));
+        assert!(metrics.contains("purchases_completed_total 1"));
+        assert!(metrics.contains("active_connections 1"));
+    }
+
+    #[actix_web::test]
+    async fn player_list_is_paginated_by_username() {
+        let server = TestServer::start();
+        let mut me = server.connect_as("me").await;
+        let _others = [
+            server.connect_as("dee").await,
+            server.connect_as("ada").await,
+            server.connect_as("cy").await,
+            server.connect_as("bea").await,
+        ];
+        let mut names = Vec::new();
+        for offset in [0, 2, 4] {
+            me.send(serde_json::json!({ "type": "listPlayers", "offset": offset, "limit": 2 }))
+                .await;
+            let page = me.recv("playerList").await;
+            assert_eq!(page["total"], 4);
+            assert_eq!(page["offset"], offset);
+            for player in page["players"].as_array().unwrap() {
+                names.push(player["username"].as_str().unwrap().to_owned());
+            }
+        }
+        assert_eq!(names, ["ada", "bea", "cy", "dee"]);
+    }
+
+    #[actix_web::test]
+    async fn selling_refunds_half_the_price() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        buy(&mut client, "building-1", "Buildings").await;
+        client
+            .send(serde_json::json!({ "type": "sell", "property_name": "Buildings Item" }))
+            .await;
+        let ack = client.recv("sellAck").await;
+        assert_eq!(ack["refund"], 50);
+        assert_eq!(ack["new_balance"], 950);
+
+        client
+            .send(serde_json::json!({ "type": "sell", "property_name": "Buildings Item" }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "not_owned");
+    }
+
+    #[actix_web::test]
+    async fn abandoning_removes_one_property_without_a_refund() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        buy(&mut client, "land-1", "Land").await;
+        buy(&mut client, "land-2", "Land").await;
+        let abandon =
+            serde_json::json!({ "type": "abandonProperty", "property_name": "Land Item" });
+        client.send(abandon.clone()).await;
+        let abandoned = client.recv("propertyAbandoned").await;
+        assert_eq!(abandoned["property_name"], "Land Item");
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["properties"].as_array().unwrap().len(), 1);
+        assert_eq!(profile["balance"], 700);
+
+        client.send(abandon.clone()).await;
+        client.recv("propertyAbandoned").await;
+        client.send(abandon).await;
+        assert_eq!(client.recv("error").await["code"], "not_owned");
+    }
+
+    #[actix_web::test]
+    async fn upgrades_raise_reward_and_cost_more_each_level() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        buy(&mut client, "land-1", "Land").await;
+        let upgrade =
+            serde_json::json!({ "type": "upgradeProperty", "property_name": "Land Item" });
+        client.send(upgrade.clone()).await;
+        let upgraded = client.recv("propertyUpgraded").await;
+        assert_eq!(upgraded["new_level"], 2);
+        assert_eq!(upgraded["new_reward"], 6);
+        client.send(upgrade.clone()).await;
+        assert_eq!(client.recv("propertyUpgraded").await["new_reward"], 9);
+
+        // 1000 - 150 for the land - 100 - 200 for the upgrades.
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(client.recv("profile").await["balance"], 550);
+        client.send(upgrade.clone()).await;
+        client.recv("propertyUpgraded").await;
+        client.send(upgrade).await;
+        assert_eq!(client.recv("error").await["code"], "insufficient_funds");
+    }
+
+    #[actix_web::test]
+    async fn accepted_trade_swaps_properties() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        buy(&mut alice, "island-1", "Islands").await;
+        buy(&mut bob, "land-1", "Land").await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        alice
+            .send(serde_json::json!({
+                "type": "offerTrade",
+                "target": bob_id,
+                "offer_property": "Islands Item",
+                "request_property": "Land Item",
+            }))
+            .await;
+        let offer = bob.recv("tradeOffer").await;
+        assert_eq!(offer["from"], alice_id);
+        bob.send(serde_json::json!({ "type": "respondTrade", "from": alice_id, "accept": true }))
+            .await;
+        assert_eq!(bob.recv("tradeCompleted").await["received"], "Islands Item");
+        assert_eq!(alice.recv("tradeCompleted").await["received"], "Land Item");
+
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = alice.recv("profile").await;
+        assert_eq!(profile["properties"][0]["name"], "Land Item");
+    }
+
+    #[actix_web::test]
+    async fn trade_is_rejected_when_requested_property_is_missing() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        buy(&mut alice, "island-1", "Islands").await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        alice
+            .send(serde_json::json!({
+                "type": "offerTrade",
+                "target": bob_id,
+                "offer_property": "Islands Item",
+                "request_property": "Land Item",
+            }))
+            .await;
+        bob.recv("tradeOffer").await;
+        bob.send(serde_json::json!({ "type": "respondTrade", "from": alice_id, "accept": true }))
+            .await;
+        assert_eq!(
+            bob.recv("tradeRejected").await["reason"],
+            "property_unavailable"
+        );
+        assert_eq!(
+            alice.recv("tradeRejected").await["reason"],
+            "property_unavailable"
+        );
+    }
+
+    #[actix_web::test]
+    async fn expired_trade_offer_cannot_be_accepted() {
+        let mut state = ServerState::new();
+        state.trade_offer_timeout = Duration::ZERO;
+        let server = TestServer::with_state(state);
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        buy(&mut alice, "island-1", "Islands").await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+
+        alice
+            .send(serde_json::json!({
+                "type": "offerTrade",
+                "target": bob_id,
+                "offer_property": "Islands Item",
+                "request_property": "Land Item",
+            }))
+            .await;
+        bob.recv("tradeOffer").await;
+        bob.send(serde_json::json!({ "type": "respondTrade", "from": alice_id, "accept": true }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "no_pending_trade");
+    }
+
+    #[actix_web::test]
+    async fn leaderboard_is_served_from_the_snapshot() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        buy(&mut bob, "land-1", "Land").await;
+
+        // Nothing is ranked before the first refresh.
+        alice
+            .send(serde_json::json!({ "type": "getLeaderboard", "limit": 5 }))
+            .await;
+        let entries = alice.recv("leaderboard").await["entries"].clone();
+        assert_eq!(
+            entries,
+            serde_json::json!([{ "rank": 1, "username": "alice", "daily_reward": 0, "pvp_level": 1 }])
+        );
+
+        state.refresh_leaderboard().await;
+        alice
+            .send(serde_json::json!({ "type": "getLeaderboard", "limit": 5 }))
+            .await;
+        let entries = alice.recv("leaderboard").await["entries"].clone();
+        assert_eq!(entries[0]["username"], "bob");
+        assert_eq!(entries[1]["username"], "alice");
+        assert_eq!(entries.as_array().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn unusable_names_from_auth_are_replaced() {
+        let mut state = ServerState::new();
+        let auth = StaticTokenAuth::parse("bad:\u{1b}[2J\u{7},empty:\u{7}").unwrap();
+        state.auth = Arc::new(auth);
+        let server = TestServer::with_state(state);
+        let mut bad = server.handshake().await.unwrap();
+        bad.send(serde_json::json!({ "type": "authenticate", "token": "bad" }))
+            .await;
+        assert_eq!(bad.recv("authenticated").await["username"], "[2J");
+        let mut empty = server.handshake().await.unwrap();
+        empty
+            .send(serde_json::json!({ "type": "authenticate", "token": "empty" }))
+            .await;
+        let authenticated = empty.recv("authenticated").await;
+        let session_id = authenticated["session_id"].as_str().unwrap();
+        let expected = format!("Player-{}", &session_id[..8]);
+        assert_eq!(authenticated["username"], expected);
+    }
+
+    #[actix_web::test]
+    async fn broadcasts_skip_the_excluded_session() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let bob_id: Uuid = serde_json::from_value(bob_id).unwrap();
+
+        let announce = |text: &str| ServerMessage::Announcement {
+            text: text.to_owned(),
+            level: AnnouncementLevel::Info,
+        };
+        assert_eq!(state.broadcast(announce("first"), Some(bob_id)).await, 1);
+        assert_eq!(state.broadcast(announce("second"), None).await, 2);
+        assert_eq!(alice.recv("announcement").await["text"], "first");
+        assert_eq!(alice.recv("announcement").await["text"], "second");
+        // Bob's first announcement is the second one.
+        assert_eq!(bob.recv("announcement").await["text"], "second");
+    }
+
+    #[actix_web::test]
+    async fn chat_is_broadcast_to_everyone() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect().await;
+        alice
+            .send(serde_json::json!({ "type": "chatSend", "text": "  jambo!  " }))
+            .await;
+        for client in [&mut alice, &mut bob] {
+            let chat = client.recv("chatMessage").await;
+            assert_eq!(chat["username"], "alice");
+            assert_eq!(chat["text"], "jambo!");
+            assert_eq!(chat["channel"], DEFAULT_CHANNEL);
+        }
+
+        let long = "a".repeat(MAX_CHAT_LEN + 1);
+        alice
+            .send(serde_json::json!({ "type": "chatSend", "text": long }))
+            .await;
+        assert_eq!(alice.recv("error").await["code"], "chat_too_long");
+    }
+
+    #[actix_web::test]
+    async fn repeated_blocked_chat_mutes_the_account() {
+        let mut state = ServerState::new();
+        state.blocked_words = Arc::new(parse_blocked_words("darn"));
+        state.mute_policy = MutePolicy::parse("strikes=2,mute=60").unwrap();
+        let server = TestServer::with_state(state.clone());
+        let mut alice = server.connect_as("alice").await;
+        let blocked = serde_json::json!({ "type": "chatSend", "text": "darn it" });
+
+        alice.send(blocked.clone()).await;
+        assert_eq!(alice.recv("error").await["code"], "chat_blocked");
+        alice.send(blocked).await;
+        assert_eq!(alice.recv("error").await["code"], "chat_blocked");
+        let until = alice.recv("muted").await["until"].as_u64().unwrap();
+        assert!(until >= unix_now() + 59);
+
+        alice
+            .send(serde_json::json!({ "type": "chatSend", "text": "sorry" }))
+            .await;
+        let err = alice.recv("error").await;
+        assert_eq!(err["code"], "muted");
+        assert_eq!(err["retry_after_secs"], 60);
+
+        // The mute belongs to the account, not the connection.
+        alice.close().await;
+        for _ in 0..100 {
+            if state.clients.len().await == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut again = server.connect_as("alice").await;
+        again
+            .send(serde_json::json!({ "type": "chatSend", "text": "hello?" }))
+            .await;
+        assert_eq!(again.recv("error").await["code"], "muted");
+    }
+
+    #[actix_web::test]
+    async fn chat_only_reaches_channel_members() {
+        let server = TestServer::start();
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let mut carol = server.connect_as("carol").await;
+        for client in [&mut alice, &mut bob] {
+            client
+                .send(serde_json::json!({ "type": "joinChannel", "channel": " Traders " }))
+                .await;
+            assert_eq!(client.recv("channelJoined").await["channel"], "traders");
+        }
+        carol
+            .send(serde_json::json!({ "type": "chatSend", "channel": "traders", "text": "hi" }))
+            .await;
+        assert_eq!(carol.recv("error").await["code"], "not_in_channel");
+
+        alice
+            .send(serde_json::json!({ "type": "chatSend", "channel": "traders", "text": "deal?" }))
+            .await;
+        carol
+            .send(serde_json::json!({ "type": "chatSend", "text": "anyone?" }))
+            .await;
+        // Carol only sees the default channel; Bob sees Alice's message
+        // first since it was sent first.
+        for client in [&mut alice, &mut bob] {
+            let chat = client.recv("chatMessage").await;
+            assert_eq!(chat["channel"], "traders");
+            assert_eq!(chat["text"], "deal?");
+        }
+        assert_eq!(carol.recv("chatMessage").await["text"], "anyone?");
+
+        bob.send(serde_json::json!({ "type": "leaveChannel", "channel": "traders" }))
+            .await;
+        assert_eq!(bob.recv("channelLeft").await["channel"], "traders");
+        bob.send(serde_json::json!({ "type": "leaveChannel", "channel": "traders" }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "not_in_channel");
+    }
+
+    #[actix_web::test]
+    async fn channel_membership_is_capped() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        // The default channel takes one of the slots.
+        for n in 1..MAX_CHANNELS {
+            client
+                .send(
+                    serde_json::json!({ "type": "joinChannel", "channel": format!("room-{}", n) }),
+                )
+                .await;
+            client.recv("channelJoined").await;
+        }
+        client
+            .send(serde_json::json!({ "type": "joinChannel", "channel": "one-too-many" }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "too_many_channels");
+        // Rejoining a channel doesn't need a free slot.
+        client
+            .send(serde_json::json!({ "type": "joinChannel", "channel": "room-1" }))
+            .await;
+        client.recv("channelJoined").await;
+        client
+            .send(serde_json::json!({ "type": "joinChannel", "channel": "bad name" }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "invalid_channel");
+    }
+
+    #[actix_web::test]
+    async fn daily_reward_is_paid_once_per_cooldown() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": "island-1",
+                "category": "Islands",
+            }))
+            .await;
+        client.recv("purchaseAck").await;
+        // Let a day of island rewards accrue.
+        for id in state.clients.ids().await {
+            let mut clients = state.clients.write(&id).await;
+            let info = clients.get_mut(&id).unwrap();
+            info.last_accrued = Some(unix_now() - 86_400);
+        }
+
+        client
+            .send(serde_json::json!({ "type": "claimDailyReward" }))
+            .await;
+        let claimed = client.recv("rewardClaimed").await;
+        assert_eq!(claimed["amount"], 10);
+        client
+            .send(serde_json::json!({ "type": "claimDailyReward" }))
+            .await;
+        let unavailable = client.recv("rewardUnavailable").await;
+        assert_eq!(unavailable["next_claim_at"], claimed["next_claim_at"]);
+
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        assert_eq!(client.recv("profile").await["balance"], 510);
+    }
+
+    #[actix_web::test]
+    async fn daily_reward_cooldown_is_configurable() {
+        let mut state = ServerState::new();
+        state.daily_claim_cooldown = Duration::ZERO;
+        let server = TestServer::with_state(state);
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({ "type": "claimDailyReward" }))
+            .await;
+        client.recv("rewardClaimed").await;
+        client
+            .send(serde_json::json!({ "type": "claimDailyReward" }))
+            .await;
+        client.recv("rewardClaimed").await;
+    }
+
+    #[actix_web::test]
+    async fn purchases_are_restored_from_storage() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut client = server.connect_as("kofi").await;
+        client
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": "land-1",
+                "category": "Land",
+            }))
+            .await;
+        client.recv("purchaseAck").await;
+        let stored = state.storage.load_player("kofi").await.unwrap().unwrap();
+        assert_eq!(stored.balance, 850);
+
+        // A later session for the same player starts from the saved state.
+        for id in state.clients.ids().await {
+            state.clients.write(&id).await.remove(&id);
+        }
+        let mut again = server.connect_as("kofi").await;
+        again
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = again.recv("profile").await;
+        assert_eq!(profile["balance"], 850);
+        assert_eq!(profile["properties"][0]["name"], "Land Item");
+    }
+
+    #[actix_web::test]
+    async fn known_subprotocol_is_echoed_back() {
+        let server = TestServer::start();
+        let negotiated = |offered: &'static [&'static str]| {
+            let request = awc::Client::new()
+                .ws(server.srv.url("/ws"))
+                .protocols(offered.iter().copied());
+            async move {
+                let (response, framed) = request.connect().await.expect("handshake failed");
+                let mut client = TestClient { framed };
+                client.recv("welcome").await;
+                response
+                    .headers()
+                    .get(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+                    .map(|value| value.to_str().unwrap().to_owned())
+            }
+        };
+        let offered = negotiated(&["chat", SUBPROTOCOL]).await;
+        assert_eq!(offered.as_deref(), Some(SUBPROTOCOL));
+        assert_eq!(negotiated(&["africa-universe-v2"]).await, None);
+        assert_eq!(negotiated(&[]).await, None);
+    }
+
+    #[actix_web::test]
+    async fn dropped_session_can_be_resumed() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut client = server.handshake().await.unwrap();
+        let welcome = client.recv("welcome").await;
+        client
+            .send(serde_json::json!({ "type": "authenticate", "token": "zola" }))
+            .await;
+        client.recv("authenticated").await;
+        client
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": "land-1",
+                "category": "Land",
+            }))
+            .await;
+        client.recv("purchaseAck").await;
+        client
+            .send(serde_json::json!({ "type": "setStatus", "status": "away" }))
+            .await;
+        client.recv("statusChanged").await;
+        client.close().await;
+        let session_id: Uuid = serde_json::from_value(welcome["session_id"].clone()).unwrap();
+        for _ in 0..100 {
+            if state.disconnected.read().await.contains_key(&session_id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let bogus = server
+            .handshake_at(&format!("/ws?resume_token={}", Uuid::new_v4()))
+            .await;
+        let fresh = bogus.unwrap().recv("welcome").await;
+        assert_ne!(fresh["session_id"], welcome["session_id"]);
+
+        let path = format!(
+            "/ws?resume_token={}",
+            welcome["resume_token"].as_str().unwrap()
+        );
+        let mut resumed = server.handshake_at(&path).await.unwrap();
+        let rewelcome = resumed.recv("welcome").await;
+        assert_eq!(rewelcome["session_id"], welcome["session_id"]);
+        assert_ne!(rewelcome["resume_token"], welcome["resume_token"]);
+        // Already authenticated as the original player.
+        resumed
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = resumed.recv("profile").await;
+        assert_eq!(profile["username"], "zola");
+        assert_eq!(profile["properties"][0]["name"], "Land Item");
+        let status = state.clients.read(&session_id).await[&session_id].status;
+        assert_eq!(status, PlayerStatus::Available);
+    }
+
+    #[actix_web::test]
+    async fn message_bursts_are_rate_limited() {
+        let mut state = ServerState::new();
+        state.message_rate = 1;
+        state.message_burst = 4;
+        let server = TestServer::with_state(state);
+        // Authenticating already used one of the four tokens.
+        let mut client = server.connect().await;
+        for _ in 0..5 {
+            client
+                .send(serde_json::json!({ "type": "getProfile" }))
+                .await;
+        }
+        for _ in 0..3 {
+            client.recv("profile").await;
+        }
+        assert_eq!(client.recv("error").await["code"], "rate_limited");
+    }
+
+    #[actix_web::test]
+    async fn batches_pay_for_every_message_they_carry() {
+        let mut state = ServerState::new();
+        state.message_rate = 1;
+        state.message_burst = 6;
+        let server = TestServer::with_state(state);
+        // Five tokens are left after authenticating.
+        let mut client = server.connect().await;
+        let profile = serde_json::json!({ "type": "getProfile" });
+        let batch = serde_json::json!({ "type": "batch", "messages": [profile, profile] });
+        for _ in 0..3 {
+            client.send(batch.clone()).await;
+        }
+        for _ in 0..2 {
+            client.recv("batchResult").await;
+        }
+        assert_eq!(client.recv("error").await["code"], "rate_limited");
+    }
+
+    #[actix_web::test]
+    async fn shutdown_notifies_clients_and_saves_players() {
+        let state = ServerState::new();
+        let server = TestServer::with_state(state.clone());
+        let mut client = server.connect_as("nia").await;
+        buy(&mut client, "land-1", "Land").await;
+        // Simulate a change that hasn't been written through yet.
+        for (_, info) in state.clients.write_all().await.iter_mut() {
+            info.pvp_level = 4;
+        }
+
+        state.shutdown("maintenance").await;
+        assert_eq!(client.recv("serverShutdown").await["reason"], "maintenance");
+        let stored = state.storage.load_player("nia").await.unwrap().unwrap();
+        assert_eq!(stored.pvp_level, 4);
+    }
+
+    #[actix_web::test]
+    async fn msgpack_sessions_use_binary_frames() {
+        let server = TestServer::start();
+        let mut client = server.handshake_at("/ws?format=msgpack").await.unwrap();
+        client
+            .send_msgpack(serde_json::json!({ "type": "authenticate", "token": "imani" }))
+            .await;
+        assert_eq!(client.recv("authenticated").await["username"], "imani");
+        client
+            .send_msgpack(serde_json::json!({ "type": "getProfile", "request_id": 3 }))
+            .await;
+        let message = client.recv_message("profile").await;
+        assert_eq!(message["profile"]["balance"], STARTING_BALANCE);
+        assert_eq!(message["request_id"], 3);
+        client
+            .send_msgpack(serde_json::json!({ "type": "fly" }))
+            .await;
+        assert_eq!(
+            client.recv("error").await["detail"],
+            "unknown message type 'fly'"
+        );
+    }
+
+    #[actix_web::test]
+    async fn pretty_json_indents_only_json_sessions() {
+        let mut state = ServerState::new();
+        state.pretty_json = true;
+        let server = TestServer::with_state(state);
+        // Both start with the welcome push.
+        let mut json = server.handshake().await.unwrap();
+        let Some(Ok(Frame::Text(text))) = json.framed.next().await else {
+            panic!("expected a text frame");
+        };
+        let text = std::str::from_utf8(&text).unwrap();
+        assert!(text.starts_with("{\n  \"welcome\""), "{}", text);
+
+        let mut msgpack = server.handshake_at("/ws?format=msgpack").await.unwrap();
+        assert!(matches!(
+            msgpack.framed.next().await,
+            Some(Ok(Frame::Binary(_)))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn connections_beyond_session_limit_are_refused() {
+        let mut state = ServerState::new();
+        state.max_sessions = 2;
+        let server = TestServer::with_state(state);
+        let _first = server.connect().await;
+        let _second = server.connect().await;
+        match server.handshake().await {
+            Err(awc::error::WsClientError::InvalidResponseStatus(status)) => {
+                assert_eq!(status, actix_web::http::StatusCode::SERVICE_UNAVAILABLE)
+            }
+            Err(err) => panic!("unexpected handshake error: {}", err),
+            Ok(_) => panic!("connection beyond the limit was accepted"),
+        }
+    }
+
+    /// Records every mint, or fails them all.
+    #[derive(Default)]
+    struct MockTokenService {
+        minted: std::sync::Mutex<Vec<(String, u64)>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl TokenService for MockTokenService {
+        async fn mint_reward(&self, player: &str, amount: u64) -> Result<(), String> {
+            if self.fail {
+                return Err("ledger unavailable".into());
+            }
+            self.minted
+                .lock()
+                .unwrap()
+                .push((player.to_owned(), amount));
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn purchases_mint_their_reward() {
+        let tokens = Arc::new(MockTokenService::default());
+        let mut state = ServerState::new();
+        state.token_service = tokens.clone();
+        let reward = u64::from(state.marketplace_item("Land").unwrap().reward);
+        let server = TestServer::with_state(state);
+        let mut client = server.connect_as("amara").await;
+        buy(&mut client, "land-1", "Land").await;
+        assert_eq!(*tokens.minted.lock().unwrap(), [("amara".into(), reward)]);
+    }
+
+    #[actix_web::test]
+    async fn failed_mints_keep_the_purchase() {
+        let mut state = ServerState::new();
+        state.token_service = Arc::new(MockTokenService {
+            fail: true,
+            ..Default::default()
+        });
+        let server = TestServer::with_state(state);
+        let mut client = server.connect().await;
+        buy(&mut client, "land-1", "Land").await;
+        assert_eq!(client.recv("error").await["code"], "mint_failed");
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["properties"].as_array().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn inventory_is_filtered_on_the_server() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        buy(&mut client, "land-1", "Land").await;
+        buy(&mut client, "weapon-1", "Weapons").await;
+        buy(&mut client, "building-1", "Buildings").await;
+        let mut inventory = async |filters: serde_json::Value| {
+            let mut msg = serde_json::json!({ "type": "getInventory" });
+            msg.as_object_mut()
+                .unwrap()
+                .extend(filters.as_object().unwrap().clone());
+            client.send(msg).await;
+            let properties = client.recv("inventory").await["properties"].clone();
+            properties
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|p| p["category"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let all = inventory(serde_json::json!({})).await;
+        assert_eq!(all, ["Land", "Weapons", "Buildings"]);
+        let weapons = inventory(serde_json::json!({ "category": "Weapons" })).await;
+        assert_eq!(weapons, ["Weapons"]);
+        let rewarding = inventory(serde_json::json!({ "min_reward": 2 })).await;
+        assert_eq!(rewarding, ["Land", "Buildings"]);
+        let both = inventory(serde_json::json!({ "category": "Land", "min_reward": 5 })).await;
+        assert!(both.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn purchases_beyond_the_property_cap_are_rejected() {
+        let mut state = ServerState::new();
+        state.max_properties = 2;
+        let server = TestServer::with_state(state);
+        let mut client = server.connect().await;
+        buy(&mut client, "land-1", "Land").await;
+        buy(&mut client, "land-2", "Land").await;
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let before = client.recv("profile").await;
+
+        client
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": "land-3",
+                "category": "Land",
+            }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "inventory_full");
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let after = client.recv("profile").await;
+        assert_eq!(after["balance"], before["balance"]);
+        assert_eq!(after["properties"].as_array().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn purchases_at_the_property_cap_wait_for_a_free_slot_in_prompt_mode() {
+        let mut state = ServerState::new();
+        state.max_properties = 2;
+        state.inventory_cap = InventoryCap::Prompt;
+        let server = TestServer::with_state(state);
+        let mut client = server.connect().await;
+        buy(&mut client, "land-1", "Land").await;
+        buy(&mut client, "land-2", "Land").await;
+
+        client
+            .send(serde_json::json!({
+                "type": "purchase",
+                "item_id": "land-3",
+                "category": "Land",
+            }))
+            .await;
+        let full = client.recv("inventoryFull").await;
+        assert_eq!(
+            (&full["item_id"], &full["must_free"]),
+            (&"land-3".into(), &1.into())
+        );
+        client
+            .send(serde_json::json!({ "type": "abandonProperty", "property_name": "Land Item" }))
+            .await;
+        client.recv("propertyAbandoned").await;
+        assert_eq!(client.recv("purchaseAck").await["item_id"], "land-3");
+        client
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = client.recv("profile").await;
+        assert_eq!(profile["properties"].as_array().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn unauthenticated_sessions_time_out() {
+        let mut state = ServerState::new();
+        state.auth_timeout = Duration::from_millis(200);
+        let server = TestServer::with_state(state);
+        let mut idle = server.handshake().await.unwrap();
+        let mut player = server.connect().await;
+        assert_eq!(idle.recv("error").await["code"], "auth_timeout");
+
+        // Authenticated sessions outlive the timeout.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        player
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        player.recv("profile").await;
+    }
+
+    #[actix_web::test]
+    async fn oversized_frames_close_the_session() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        let text = "x".repeat(MAX_FRAME_SIZE + 1);
+        client
+            .send(serde_json::json!({ "type": "chatSend", "text": text }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "message_too_large");
+    }
+
+    #[actix_web::test]
+    async fn messages_are_checked_against_the_protocol_version() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+
+        alice
+            .send(serde_json::json!({ "v": 99, "type": "getProfile" }))
+            .await;
+        let err = alice.recv("error").await;
+        assert_eq!(err["code"], "unsupported_version");
+        assert_eq!(err["server_version"], PROTOCOL_VERSION);
+        alice
+            .send(serde_json::json!({ "v": PROTOCOL_VERSION, "type": "getProfile" }))
+            .await;
+        alice.recv("profile").await;
+
+        // Version 0 challenges are upgraded to the current shape.
+        alice
+            .send(
+                serde_json::json!({ "v": 0, "type": "challenge", "target": bob_id, "stake": true }),
+            )
+            .await;
+        alice.recv("challengeResponse").await;
+        assert_eq!(bob.recv("challengeRequest").await["stake_amount"], 0);
+    }
+
+    #[actix_web::test]
+    async fn hello_reports_enabled_features() {
+        let mut state = ServerState::new();
+        state.features = Features::without("trading").unwrap();
+        let server = TestServer::with_state(state);
+        let mut guest = server.handshake().await.unwrap();
+        guest.send(serde_json::json!({ "type": "hello" })).await;
+        let info = guest.recv("serverInfo").await;
+        assert_eq!(info["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(info["min_protocol_version"], MIN_PROTOCOL_VERSION);
+        assert_eq!(
+            info["features"],
+            serde_json::json!(["msgpack", "matchmaking"])
+        );
+
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({
+                "type": "offerTrade",
+                "target": Uuid::new_v4(),
+                "offer_property": "Land Item",
+                "request_property": "Land Item",
+            }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "feature_disabled");
+        assert!(Features::without("teleport").is_err());
+    }
+
+    #[actix_web::test]
+    async fn invalid_messages_get_bad_request_errors() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        client.send_text("{not json").await;
+        let err = client.recv("error").await;
+        assert_eq!(err["code"], "bad_request");
+        assert_eq!(err["detail"], "message is not valid JSON");
+
+        client.send(serde_json::json!({ "type": "fly" })).await;
+        let err = client.recv("error").await;
+        assert_eq!(err["code"], "bad_request");
+        assert_eq!(err["detail"], "unknown message type 'fly'");
+    }
+
+    #[actix_web::test]
+    async fn challenge_to_unknown_player_is_an_error() {
+        let server = TestServer::start();
+        let mut client = server.connect().await;
+        client
+            .send(serde_json::json!({
+                "type": "challenge",
+                "target": Uuid::new_v4(),
+                "stake_amount": 0,
+            }))
+            .await;
+        assert_eq!(client.recv("error").await["code"], "unknown_target");
+    }
+
+    #[actix_web::test]
+    async fn challenge_is_relayed_to_target() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        alice
+            .send(serde_json::json!({ "type": "listPlayers" }))
+            .await;
+        let bob_id = alice.recv("playerList").await["players"][0]["id"].clone();
+
+        alice
+            .send(serde_json::json!({ "type": "challenge", "target": bob_id, "stake_amount": 5 }))
+            .await;
+        let request = bob.recv("challengeRequest").await;
+        assert_eq!(request["stake_amount"], 5);
+        assert_eq!(request["stake"], serde_json::json!({ "balance": 5 }));
+        assert!(alice.recv("challengeResponse").await["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Challenge sent to"));
+    }
+
+    #[actix_web::test]
+    async fn the_bot_fights_when_nobody_else_is_around() {
+        let mut state = ServerState::new();
+        state.bot = Some(BotConfig {
+            pvp_level: 0,
+            daily_reward_cap: 150,
+        });
+        let server = TestServer::with_state(state);
+        let mut alice = server.connect().await;
+        alice
+            .send(serde_json::json!({ "type": "listPlayers", "only_challengeable": true }))
+            .await;
+        let bot = alice.recv("playerList").await["players"][0].clone();
+        assert_eq!((&bot["bot"], &bot["pvp_level"]), (&true.into(), &0.into()));
+
+        // Wins pay the stake until the daily cap is reached.
+        let challenge =
+            serde_json::json!({ "type": "challenge", "target": bot["id"], "stake_amount": 100 });
+        for pot in [100, 50, 0] {
+            alice.send(challenge.clone()).await;
+            let result = alice.recv("battleResult").await;
+            assert_eq!(
+                (&result["bot"], &result["loser"]),
+                (&true.into(), &bot["id"])
+            );
+            assert_eq!(result["pot"], pot);
+        }
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = alice.recv("profile").await;
+        assert_eq!(profile["balance"], STARTING_BALANCE + 150);
+        assert_eq!(profile["pvp_level"], 1);
+
+        // Players who turn challenges away don't count as opponents.
+        let mut bob = server.connect().await;
+        let privacy =
+            |allow: bool| serde_json::json!({ "type": "updatePrivacy", "allow_challenges": allow });
+        bob.send(privacy(false)).await;
+        bob.recv("privacy").await;
+        alice
+            .send(serde_json::json!({ "type": "listPlayers", "only_challengeable": true }))
+            .await;
+        let players = alice.recv("playerList").await["players"].clone();
+        assert_eq!(players.as_array().unwrap().len(), 1);
+        assert_eq!(players[0]["bot"], true);
+        alice.send(challenge.clone()).await;
+        assert_eq!(alice.recv("battleResult").await["bot"], true);
+
+        bob.send(privacy(true)).await;
+        bob.recv("privacy").await;
+        alice.send(challenge).await;
+        assert_eq!(alice.recv("error").await["code"], "opponent_available");
+    }
+
+    #[actix_web::test]
+    async fn staked_properties_go_to_the_winner() {
+        let server = TestServer::start();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let bob_id = other_player_id(&mut alice).await;
+        let alice_id = other_player_id(&mut bob).await;
+        let challenge = |stake: serde_json::Value| {
+            let mut challenge = serde_json::json!({ "type": "challenge", "target": bob_id });
+            challenge
+                .as_object_mut()
+                .unwrap()
+                .extend(stake.as_object().unwrap().clone());
+            challenge
+        };
+        let staked = serde_json::json!({ "stake_property": "Land Item" });
+        alice.send(challenge(staked.clone())).await;
+        assert_eq!(alice.recv("error").await["code"], "not_owned");
+        buy(&mut alice, "land-1", "Land").await;
+        let both = serde_json::json!({ "stake_property": "Land Item", "stake_amount": 5 });
+        alice.send(challenge(both)).await;
+        assert_eq!(alice.recv("error").await["code"], "invalid_stake");
+
+        // Ownership is checked again once the challenge is accepted.
+        alice.send(challenge(staked.clone())).await;
+        let request = bob.recv("challengeRequest").await;
+        assert_eq!(
+            request["stake"],
+            serde_json::json!({ "property": "Land Item" })
+        );
+        alice
+            .send(serde_json::json!({ "type": "abandonProperty", "property_name": "Land Item" }))
+            .await;
+        alice.recv("propertyAbandoned").await;
+        bob.send(serde_json::json!({ "type": "acceptChallenge", "challenger": alice_id }))
+            .await;
+        assert_eq!(bob.recv("error").await["code"], "stake_unavailable");
+
+        // A challenger who loses hands the property over.
+        buy(&mut alice, "land-1", "Land").await;
+        alice.send(challenge(staked)).await;
+        let battle_id = bob.recv("challengeRequest").await["battle_id"].clone();
+        alice
+            .send(serde_json::json!({ "type": "forfeit", "battle_id": battle_id }))
+            .await;
+        let result = alice.recv("battleResult").await;
+        assert_eq!(result["winner"], bob_id);
+        assert_eq!(result["property"]["name"], "Land Item");
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        assert_eq!(
+            bob.recv("profile").await["properties"][0]["name"],
+            "Land Item"
+        );
+        alice
+            .send(serde_json::json!({ "type": "getProfile" }))
+            .await;
+        let profile = alice.recv("profile").await;
+        assert!(profile["properties"].as_array().unwrap().is_empty());
+
+        // The target stakes nothing, so losing costs them nothing.
+        buy(&mut alice, "land-2", "Land").await;
+        alice
+            .send(challenge(
+                serde_json::json!({ "stake_property": "Land Item" }),
+            ))
+            .await;
+        let battle_id = bob.recv("challengeRequest").await["battle_id"].clone();
+        bob.send(serde_json::json!({ "type": "forfeit", "battle_id": battle_id }))
+            .await;
+        let result = bob.recv("battleResult").await;
+        assert_eq!(result["winner"], alice_id);
+        assert!(result.get("property").is_none());
+        bob.send(serde_json::json!({ "type": "getProfile" })).await;
+        let profile = bob.recv("profile").await;
+        assert_eq!(profile["properties"].as_array().unwrap().len(), 1);
+        assert_eq!(profile["balance"], STARTING_BALANCE);
+    }
+}
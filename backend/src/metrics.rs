@@ -0,0 +1,130 @@
+//! Prometheus metrics, served as text at `GET /metrics`.
+
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+pub struct Metrics {
+    registry: Registry,
+    /// Requests received, labelled by message `type`.
+    pub messages_received: IntCounterVec,
+    pub purchases_completed: IntCounter,
+    pub challenges_sent: IntCounter,
+    /// Resolved battles, labelled by which side won.
+    pub battles: IntCounterVec,
+    pub active_connections: IntGauge,
+    /// Outbound messages that could not be handed to their session.
+    pub dropped_outbound: IntCounter,
+    /// Chat messages a slow session missed, labelled by `session`. The
+    /// series is removed when the session ends.
+    pub chat_dropped: IntCounterVec,
+    /// Tokens removed from circulation by transfer fees.
+    pub tokens_burned: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let messages_received = IntCounterVec::new(
+            Opts::new(
+                "messages_received_total",
+                "Client messages received by type",
+            ),
+            &["type"],
+        )
+        .expect("valid metric");
+        let purchases_completed = IntCounter::new(
+            "purchases_completed_total",
+            "Marketplace purchases completed",
+        )
+        .expect("valid metric");
+        let challenges_sent = IntCounter::new(
+            "challenges_sent_total",
+            "Challenges delivered to their target",
+        )
+        .expect("valid metric");
+        let battles = IntCounterVec::new(
+            Opts::new("battles_total", "Resolved battles by winning side"),
+            &["winner"],
+        )
+        .expect("valid metric");
+        let active_connections = IntGauge::new("active_connections", "Open WebSocket connections")
+            .expect("valid metric");
+        let dropped_outbound = IntCounter::new(
+            "dropped_outbound_messages_total",
+            "Outbound messages that could not be delivered",
+        )
+        .expect("valid metric");
+        let chat_dropped = IntCounterVec::new(
+            Opts::new(
+                "chat_messages_dropped_total",
+                "Chat messages dropped for sessions with a full mailbox",
+            ),
+            &["session"],
+        )
+        .expect("valid metric");
+        let tokens_burned = IntCounter::new(
+            "tokens_burned_total",
+            "Tokens burned by gift and trade fees",
+        )
+        .expect("valid metric");
+        for metric in [
+            Box::new(messages_received.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(purchases_completed.clone()),
+            Box::new(challenges_sent.clone()),
+            Box::new(battles.clone()),
+            Box::new(active_connections.clone()),
+            Box::new(dropped_outbound.clone()),
+            Box::new(chat_dropped.clone()),
+            Box::new(tokens_burned.clone()),
+        ] {
+            registry.register(metric).expect("metric names are unique");
+        }
+        Self {
+            registry,
+            messages_received,
+            purchases_completed,
+            challenges_sent,
+            battles,
+            active_connections,
+            dropped_outbound,
+            chat_dropped,
+            tokens_burned,
+        }
+    }
+
+    /// Content type of `render`'s output.
+    pub fn content_type() -> &'static str {
+        prometheus::TEXT_FORMAT
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("text encoding cannot fail");
+        String::from_utf8(buffer).expect("text format is UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_registered_metrics() {
+        let metrics = Metrics::new();
+        metrics
+            .messages_received
+            .with_label_values(&["purchase"])
+            .inc();
+        metrics.active_connections.set(3);
+        metrics.chat_dropped.with_label_values(&["s1"]).inc();
+        metrics.tokens_burned.inc_by(5);
+        let text = metrics.render();
+        assert!(text.contains("messages_received_total{type=\"purchase\"} 1"));
+        assert!(text.contains("active_connections 3"));
+        assert!(text.contains("chat_messages_dropped_total{session=\"s1\"} 1"));
+        assert!(text.contains("tokens_burned_total 5"));
+        assert!(text.contains("# TYPE purchases_completed_total counter"));
+    }
+}
@@ -0,0 +1,649 @@
+//! Durable player storage. The server keeps connected players in memory
+//! and writes them through to a `Storage` implementation so inventories
+//! survive disconnects and restarts. Archived seasons and player reports
+//! are kept there too.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use tokio::sync::RwLock;
+
+use crate::{BattleRecord, PlayerReport, Property, SeasonArchive};
+
+/// The persisted part of a player, keyed by the username they log in as.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredPlayer {
+    pub username: String,
+    /// Name shown to other players if the player picked one.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    pub pvp_level: u32,
+    pub balance: u64,
+    pub properties: Vec<Property>,
+    /// Unix time of the last daily reward claim.
+    pub last_claim: Option<u64>,
+    /// Unix time up to which property rewards have been credited.
+    #[serde(default)]
+    pub last_accrued: Option<u64>,
+    /// Tokens earned from property rewards over the player's lifetime.
+    #[serde(default)]
+    pub lifetime_rewards: u64,
+    /// Tokens accrued since the last daily claim, which mints them.
+    #[serde(default)]
+    pub unclaimed_rewards: u64,
+    /// Usernames of the players on this player's friends list.
+    #[serde(default)]
+    pub friends: Vec<String>,
+    /// Battles won and lost since the last season reset.
+    #[serde(default)]
+    pub wins: u32,
+    #[serde(default)]
+    pub losses: u32,
+    /// Most recent battles, oldest first.
+    #[serde(default)]
+    pub battle_history: Vec<BattleRecord>,
+}
+
+/// Failure reported by a storage backend.
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<sqlx::Error> for StorageError {
+    fn from(err: sqlx::Error) -> Self {
+        Self(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(err: serde_json::Error) -> Self {
+        Self(err.to_string())
+    }
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Load a player by username, or `None` if they have never been saved.
+    async fn load_player(&self, username: &str) -> Result<Option<StoredPlayer>, StorageError>;
+    /// Insert or replace a player.
+    async fn save_player(&self, player: &StoredPlayer) -> Result<(), StorageError>;
+    /// Every saved player, in no particular order.
+    async fn list_players(&self) -> Result<Vec<StoredPlayer>, StorageError>;
+    /// Usernames of the saved players who log in as or are shown as
+    /// `name`, ignoring ASCII case.
+    async fn find_by_name(&self, name: &str) -> Result<Vec<String>, StorageError>;
+    /// Insert or replace the archive of a completed season.
+    async fn save_season(&self, archive: &SeasonArchive) -> Result<(), StorageError>;
+    /// Every archived season, oldest first.
+    async fn list_seasons(&self) -> Result<Vec<SeasonArchive>, StorageError>;
+    /// Load one archived season, or `None` if it was never archived.
+    async fn load_season(&self, season: u32) -> Result<Option<SeasonArchive>, StorageError>;
+    /// Add a report to the moderation queue.
+    async fn save_report(&self, report: &PlayerReport) -> Result<(), StorageError>;
+    /// The most recent `limit` reports, oldest first.
+    async fn list_reports(&self, limit: usize) -> Result<Vec<PlayerReport>, StorageError>;
+    /// Reports filed by the session `reporter` at or after Unix time `since`.
+    async fn count_reports_since(
+        &self,
+        reporter: uuid::Uuid,
+        since: u64,
+    ) -> Result<usize, StorageError>;
+}
+
+/// Keeps players in a map for the lifetime of the process. Used by tests
+/// and anywhere persistence across restarts is not wanted.
+#[derive(Default)]
+pub struct MemoryStorage {
+    players: RwLock<HashMap<String, StoredPlayer>>,
+    seasons: RwLock<BTreeMap<u32, SeasonArchive>>,
+    reports: RwLock<Vec<PlayerReport>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn load_player(&self, username: &str) -> Result<Option<StoredPlayer>, StorageError> {
+        Ok(self.players.read().await.get(username).cloned())
+    }
+
+    async fn save_player(&self, player: &StoredPlayer) -> Result<(), StorageError> {
+        let mut players = self.players.write().await;
+        players.insert(player.username.clone(), player.clone());
+        Ok(())
+    }
+
+    async fn list_players(&self) -> Result<Vec<StoredPlayer>, StorageError> {
+        Ok(self.players.read().await.values().cloned().collect())
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Vec<String>, StorageError> {
+        let players = self.players.read().await;
+        Ok(players
+            .values()
+            .filter(|player| {
+                player.username.eq_ignore_ascii_case(name)
+                    || player
+                        .display_name
+                        .as_ref()
+                        .is_some_and(|shown| shown.eq_ignore_ascii_case(name))
+            })
+            .map(|player| player.username.clone())
+            .collect())
+    }
+
+    async fn save_season(&self, archive: &SeasonArchive) -> Result<(), StorageError> {
+        let mut seasons = self.seasons.write().await;
+        seasons.insert(archive.season, archive.clone());
+        Ok(())
+    }
+
+    async fn list_seasons(&self) -> Result<Vec<SeasonArchive>, StorageError> {
+        Ok(self.seasons.read().await.values().cloned().collect())
+    }
+
+    async fn load_season(&self, season: u32) -> Result<Option<SeasonArchive>, StorageError> {
+        Ok(self.seasons.read().await.get(&season).cloned())
+    }
+
+    async fn save_report(&self, report: &PlayerReport) -> Result<(), StorageError> {
+        self.reports.write().await.push(report.clone());
+        Ok(())
+    }
+
+    async fn list_reports(&self, limit: usize) -> Result<Vec<PlayerReport>, StorageError> {
+        let reports = self.reports.read().await;
+        Ok(reports[reports.len().saturating_sub(limit)..].to_vec())
+    }
+
+    async fn count_reports_since(
+        &self,
+        reporter: uuid::Uuid,
+        since: u64,
+    ) -> Result<usize, StorageError> {
+        let reports = self.reports.read().await;
+        Ok(reports
+            .iter()
+            .filter(|report| report.reporter == reporter && report.timestamp >= since)
+            .count())
+    }
+}
+
+/// Columns added after the `players` table was first released, with
+/// their definitions. Older databases get them on startup.
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("display_name", "TEXT"),
+    ("last_accrued", "INTEGER"),
+    ("lifetime_rewards", "INTEGER NOT NULL DEFAULT 0"),
+    ("friends", "TEXT NOT NULL DEFAULT '[]'"),
+    ("wins", "INTEGER NOT NULL DEFAULT 0"),
+    ("losses", "INTEGER NOT NULL DEFAULT 0"),
+    ("battle_history", "TEXT NOT NULL DEFAULT '[]'"),
+    ("unclaimed_rewards", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+const PLAYER_COLUMNS: &str = "username, pvp_level, balance, properties, last_claim, display_name, \
+     last_accrued, lifetime_rewards, friends, wins, losses, battle_history, unclaimed_rewards";
+
+/// Stores players in a SQLite database. Properties, friends and battle
+/// history are kept as JSON columns since they are always read and written as a whole.
+/// Season standings are likewise a JSON column of the `seasons` table;
+/// reports get a row each in `reports`.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Open (creating if necessary) the database at `url`, for example
+    /// `sqlite://africa_universe.db`, and make sure the schema exists.
+    pub async fn connect(url: &str) -> Result<Self, StorageError> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS players (
+                username TEXT PRIMARY KEY,
+                pvp_level INTEGER NOT NULL,
+                balance INTEGER NOT NULL,
+                properties TEXT NOT NULL,
+                last_claim INTEGER
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        let columns: Vec<String> = sqlx::query("SELECT name FROM pragma_table_info('players')")
+            .fetch_all(&pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get("name"))
+            .collect::<Result<_, _>>()?;
+        for (column, definition) in ADDED_COLUMNS {
+            if !columns.iter().any(|name| name == column) {
+                let alter = format!("ALTER TABLE players ADD COLUMN {} {}", column, definition);
+                sqlx::query(&alter).execute(&pool).await?;
+            }
+        }
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS seasons (
+                season INTEGER PRIMARY KEY,
+                standings TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                reporter TEXT NOT NULL,
+                reporter_name TEXT NOT NULL,
+                target TEXT NOT NULL,
+                target_name TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                details TEXT NOT NULL,
+                timestamp INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+
+    fn player_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<StoredPlayer, StorageError> {
+        let pvp_level: i64 = row.try_get("pvp_level")?;
+        let balance: i64 = row.try_get("balance")?;
+        let properties: String = row.try_get("properties")?;
+        let last_claim: Option<i64> = row.try_get("last_claim")?;
+        let last_accrued: Option<i64> = row.try_get("last_accrued")?;
+        let lifetime_rewards: i64 = row.try_get("lifetime_rewards")?;
+        let unclaimed_rewards: i64 = row.try_get("unclaimed_rewards")?;
+        let friends: String = row.try_get("friends")?;
+        let wins: i64 = row.try_get("wins")?;
+        let losses: i64 = row.try_get("losses")?;
+        let battle_history: String = row.try_get("battle_history")?;
+        let out_of_range = |column: &str| StorageError(format!("{} is out of range", column));
+        Ok(StoredPlayer {
+            username: row.try_get("username")?,
+            display_name: row.try_get("display_name")?,
+            pvp_level: u32::try_from(pvp_level).map_err(|_| out_of_range("pvp_level"))?,
+            balance: u64::try_from(balance).map_err(|_| out_of_range("balance"))?,
+            properties: serde_json::from_str(&properties)?,
+            last_claim: last_claim
+                .map(u64::try_from)
+                .transpose()
+                .map_err(|_| out_of_range("last_claim"))?,
+            last_accrued: last_accrued
+                .map(u64::try_from)
+                .transpose()
+                .map_err(|_| out_of_range("last_accrued"))?,
+            lifetime_rewards: u64::try_from(lifetime_rewards)
+                .map_err(|_| out_of_range("lifetime_rewards"))?,
+            unclaimed_rewards: u64::try_from(unclaimed_rewards)
+                .map_err(|_| out_of_range("unclaimed_rewards"))?,
+            friends: serde_json::from_str(&friends)?,
+            wins: u32::try_from(wins).map_err(|_| out_of_range("wins"))?,
+            losses: u32::try_from(losses).map_err(|_| out_of_range("losses"))?,
+            battle_history: serde_json::from_str(&battle_history)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn load_player(&self, username: &str) -> Result<Option<StoredPlayer>, StorageError> {
+        let select = format!("SELECT {} FROM players WHERE username = ?", PLAYER_COLUMNS);
+        let row = sqlx::query(&select)
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        row.as_ref().map(Self::player_from_row).transpose()
+    }
+
+    async fn save_player(&self, player: &StoredPlayer) -> Result<(), StorageError> {
+        let out_of_range = |column: &str| StorageError(format!("{} is out of range", column));
+        let balance = i64::try_from(player.balance).map_err(|_| out_of_range("balance"))?;
+        let last_claim = player
+            .last_claim
+            .map(i64::try_from)
+            .transpose()
+            .map_err(|_| out_of_range("last_claim"))?;
+        let last_accrued = player
+            .last_accrued
+            .map(i64::try_from)
+            .transpose()
+            .map_err(|_| out_of_range("last_accrued"))?;
+        let lifetime_rewards =
+            i64::try_from(player.lifetime_rewards).map_err(|_| out_of_range("lifetime_rewards"))?;
+        let unclaimed_rewards = i64::try_from(player.unclaimed_rewards)
+            .map_err(|_| out_of_range("unclaimed_rewards"))?;
+        let insert = format!(
+            "INSERT INTO players ({})
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(username) DO UPDATE SET
+                pvp_level = excluded.pvp_level,
+                balance = excluded.balance,
+                properties = excluded.properties,
+                last_claim = excluded.last_claim,
+                display_name = excluded.display_name,
+                last_accrued = excluded.last_accrued,
+                lifetime_rewards = excluded.lifetime_rewards,
+                friends = excluded.friends,
+                wins = excluded.wins,
+                losses = excluded.losses,
+                battle_history = excluded.battle_history,
+                unclaimed_rewards = excluded.unclaimed_rewards",
+            PLAYER_COLUMNS
+        );
+        sqlx::query(&insert)
+            .bind(&player.username)
+            .bind(i64::from(player.pvp_level))
+            .bind(balance)
+            .bind(serde_json::to_string(&player.properties)?)
+            .bind(last_claim)
+            .bind(&player.display_name)
+            .bind(last_accrued)
+            .bind(lifetime_rewards)
+            .bind(serde_json::to_string(&player.friends)?)
+            .bind(i64::from(player.wins))
+            .bind(i64::from(player.losses))
+            .bind(serde_json::to_string(&player.battle_history)?)
+            .bind(unclaimed_rewards)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_players(&self) -> Result<Vec<StoredPlayer>, StorageError> {
+        let select = format!("SELECT {} FROM players", PLAYER_COLUMNS);
+        let rows = sqlx::query(&select).fetch_all(&self.pool).await?;
+        rows.iter().map(Self::player_from_row).collect()
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Vec<String>, StorageError> {
+        // NOCASE only folds ASCII, like `eq_ignore_ascii_case`.
+        let rows = sqlx::query(
+            "SELECT username FROM players
+             WHERE username = ?1 COLLATE NOCASE OR display_name = ?1 COLLATE NOCASE",
+        )
+        .bind(name)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| row.try_get("username").map_err(StorageError::from))
+            .collect()
+    }
+
+    async fn save_season(&self, archive: &SeasonArchive) -> Result<(), StorageError> {
+        sqlx::query(
+            "INSERT INTO seasons (season, standings) VALUES (?, ?)
+             ON CONFLICT(season) DO UPDATE SET standings = excluded.standings",
+        )
+        .bind(i64::from(archive.season))
+        .bind(serde_json::to_string(&archive.standings)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_seasons(&self) -> Result<Vec<SeasonArchive>, StorageError> {
+        let rows = sqlx::query("SELECT season, standings FROM seasons ORDER BY season")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(season_from_row).collect()
+    }
+
+    async fn load_season(&self, season: u32) -> Result<Option<SeasonArchive>, StorageError> {
+        let row = sqlx::query("SELECT season, standings FROM seasons WHERE season = ?")
+            .bind(i64::from(season))
+            .fetch_optional(&self.pool)
+            .await?;
+        row.as_ref().map(season_from_row).transpose()
+    }
+
+    async fn save_report(&self, report: &PlayerReport) -> Result<(), StorageError> {
+        let timestamp = i64::try_from(report.timestamp)
+            .map_err(|_| StorageError("timestamp is out of range".into()))?;
+        sqlx::query(
+            "INSERT INTO reports
+                (reporter, reporter_name, target, target_name, reason, details, timestamp)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(report.reporter.to_string())
+        .bind(&report.reporter_name)
+        .bind(report.target.to_string())
+        .bind(&report.target_name)
+        .bind(&report.reason)
+        .bind(&report.details)
+        .bind(timestamp)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_reports(&self, limit: usize) -> Result<Vec<PlayerReport>, StorageError> {
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let rows = sqlx::query(
+            "SELECT * FROM (
+                SELECT reporter, reporter_name, target, target_name, reason, details,
+                    timestamp, id
+                FROM reports ORDER BY id DESC LIMIT ?
+             ) ORDER BY id",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(report_from_row).collect()
+    }
+
+    async fn count_reports_since(
+        &self,
+        reporter: uuid::Uuid,
+        since: u64,
+    ) -> Result<usize, StorageError> {
+        let since = i64::try_from(since).unwrap_or(i64::MAX);
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM reports WHERE reporter = ? AND timestamp >= ?",
+        )
+        .bind(reporter.to_string())
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(usize::try_from(count).unwrap_or(0))
+    }
+}
+
+fn report_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<PlayerReport, StorageError> {
+    let id = |column: &str| -> Result<uuid::Uuid, StorageError> {
+        let id: String = row.try_get(column)?;
+        uuid::Uuid::parse_str(&id).map_err(|_| StorageError(format!("{} is not a UUID", column)))
+    };
+    let timestamp: i64 = row.try_get("timestamp")?;
+    Ok(PlayerReport {
+        reporter: id("reporter")?,
+        reporter_name: row.try_get("reporter_name")?,
+        target: id("target")?,
+        target_name: row.try_get("target_name")?,
+        reason: row.try_get("reason")?,
+        details: row.try_get("details")?,
+        timestamp: u64::try_from(timestamp)
+            .map_err(|_| StorageError("timestamp is out of range".into()))?,
+    })
+}
+
+fn season_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<SeasonArchive, StorageError> {
+    let season: i64 = row.try_get("season")?;
+    let standings: String = row.try_get("standings")?;
+    Ok(SeasonArchive {
+        season: u32::try_from(season).map_err(|_| StorageError("season is out of range".into()))?,
+        standings: serde_json::from_str(&standings)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn sqlite_storage_round_trips_players() {
+        let path = std::env::temp_dir().join(format!("players-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        let mut player = StoredPlayer {
+            username: "amara".into(),
+            display_name: Some("Queen Amara".into()),
+            pvp_level: 4,
+            balance: 750,
+            properties: vec![Property {
+                name: "Islands Item".into(),
+                category: "Islands".into(),
+                reward: 10,
+                level: 1,
+            }],
+            last_claim: Some(1_700_000_000),
+            last_accrued: Some(1_700_000_500),
+            lifetime_rewards: 42,
+            unclaimed_rewards: 7,
+            friends: vec!["kofi".into()],
+            wins: 3,
+            losses: 1,
+            battle_history: vec![BattleRecord {
+                battle_id: uuid::Uuid::new_v4(),
+                opponent: "kofi".into(),
+                won: true,
+                pot: 200,
+                forfeited: false,
+                timestamp: 1_700_000_400,
+            }],
+        };
+        {
+            let storage = SqliteStorage::connect(&url).await.unwrap();
+            assert_eq!(storage.load_player("amara").await.unwrap(), None);
+            storage.save_player(&player).await.unwrap();
+            player.balance = 250;
+            storage.save_player(&player).await.unwrap();
+        }
+
+        // Reopening the database sees the last write.
+        let storage = SqliteStorage::connect(&url).await.unwrap();
+        assert_eq!(
+            storage.load_player("amara").await.unwrap(),
+            Some(player.clone())
+        );
+        assert_eq!(
+            storage.find_by_name("QUEEN amara").await.unwrap(),
+            ["amara"]
+        );
+        assert_eq!(storage.find_by_name("Amara").await.unwrap(), ["amara"]);
+        assert!(storage.find_by_name("kofi").await.unwrap().is_empty());
+        assert_eq!(storage.list_players().await.unwrap(), vec![player]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn sqlite_storage_round_trips_seasons() {
+        let path = std::env::temp_dir().join(format!("seasons-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        let archive = |season| SeasonArchive {
+            season,
+            standings: vec![crate::SeasonStanding {
+                username: "amara".into(),
+                pvp_level: season,
+                daily_reward: 10,
+            }],
+        };
+        {
+            let storage = SqliteStorage::connect(&url).await.unwrap();
+            assert!(storage.list_seasons().await.unwrap().is_empty());
+            storage.save_season(&archive(2)).await.unwrap();
+            storage.save_season(&archive(1)).await.unwrap();
+        }
+
+        let storage = SqliteStorage::connect(&url).await.unwrap();
+        assert_eq!(
+            storage.list_seasons().await.unwrap(),
+            vec![archive(1), archive(2)]
+        );
+        assert_eq!(storage.load_season(2).await.unwrap(), Some(archive(2)));
+        assert_eq!(storage.load_season(3).await.unwrap(), None);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn sqlite_storage_keeps_reports() {
+        let path = std::env::temp_dir().join(format!("reports-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        let (reporter, target) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let report = |timestamp| PlayerReport {
+            reporter,
+            reporter_name: "amara".into(),
+            target,
+            target_name: "kofi".into(),
+            reason: "spam".into(),
+            details: String::new(),
+            timestamp,
+        };
+        {
+            let storage = SqliteStorage::connect(&url).await.unwrap();
+            for timestamp in [100, 200, 300] {
+                storage.save_report(&report(timestamp)).await.unwrap();
+            }
+        }
+
+        let storage = SqliteStorage::connect(&url).await.unwrap();
+        assert_eq!(
+            storage.list_reports(2).await.unwrap(),
+            vec![report(200), report(300)]
+        );
+        assert_eq!(storage.count_reports_since(reporter, 200).await.unwrap(), 2);
+        assert_eq!(storage.count_reports_since(target, 0).await.unwrap(), 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn sqlite_storage_upgrades_old_schema() {
+        let path = std::env::temp_dir().join(format!("players-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}", path.display());
+        {
+            let options = SqliteConnectOptions::from_str(&url)
+                .unwrap()
+                .create_if_missing(true);
+            let pool = SqlitePoolOptions::new()
+                .connect_with(options)
+                .await
+                .unwrap();
+            sqlx::query(
+                "CREATE TABLE players (
+                    username TEXT PRIMARY KEY,
+                    pvp_level INTEGER NOT NULL,
+                    balance INTEGER NOT NULL,
+                    properties TEXT NOT NULL,
+                    last_claim INTEGER
+                )",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            sqlx::query("INSERT INTO players VALUES ('kofi', 2, 500, '[]', NULL)")
+                .execute(&pool)
+                .await
+                .unwrap();
+            pool.close().await;
+        }
+
+        let storage = SqliteStorage::connect(&url).await.unwrap();
+        let kofi = storage.load_player("kofi").await.unwrap().unwrap();
+        assert_eq!((kofi.pvp_level, kofi.balance), (2, 500));
+        assert_eq!(kofi.display_name, None);
+        assert_eq!((kofi.lifetime_rewards, kofi.unclaimed_rewards), (0, 0));
+        assert!(kofi.friends.is_empty());
+        assert_eq!((kofi.wins, kofi.losses), (0, 0));
+        assert!(kofi.battle_history.is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,155 @@
+//! End-to-end tests against the real server binary: start it on a free
+//! port and talk to it over WebSocket like a game client would.
+
+use actix_codec::Framed;
+use awc::ws::{Codec, Frame, Message};
+use awc::BoxedSocket;
+use futures_util::{SinkExt, StreamExt};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running server process, killed when dropped.
+struct Server {
+    child: Child,
+    port: u16,
+    database: PathBuf,
+}
+
+impl Server {
+    async fn start() -> Self {
+        // Grab a free port and release it for the server to bind.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("no free port")
+            .port();
+        let database = std::env::temp_dir().join(format!("protocol-{}.db", uuid::Uuid::new_v4()));
+        let child = Command::new(env!("CARGO_BIN_EXE_africa_universe_server"))
+            .env("BIND_ADDR", "127.0.0.1")
+            .env("PORT", port.to_string())
+            .env("DATABASE_URL", format!("sqlite://{}", database.display()))
+            .env_remove("AUTH_TOKENS")
+            .env_remove("ITEM_PRICES")
+            .env_remove("STARTER_CONFIG")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the server");
+        let server = Self {
+            child,
+            port,
+            database,
+        };
+        let started = std::time::Instant::now();
+        while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "server did not start");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        server
+    }
+
+    /// Open a WebSocket and authenticate with `token` as the username.
+    async fn connect_as(&self, token: &str) -> Client {
+        let url = format!("ws://127.0.0.1:{}/ws", self.port);
+        let (_, framed) = awc::Client::new()
+            .ws(url)
+            .connect()
+            .await
+            .expect("websocket handshake failed");
+        let mut client = Client { framed };
+        client
+            .send(serde_json::json!({ "type": "authenticate", "token": token }))
+            .await;
+        client.recv("authenticated").await;
+        client
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.database);
+    }
+}
+
+struct Client {
+    framed: Framed<BoxedSocket, Codec>,
+}
+
+impl Client {
+    async fn send(&mut self, msg: serde_json::Value) {
+        self.framed
+            .send(Message::Text(msg.to_string().into()))
+            .await
+            .expect("failed to send message");
+    }
+
+    /// Wait for the next server message of the given kind and return its
+    /// body. Messages of other kinds are skipped.
+    async fn recv(&mut self, kind: &str) -> serde_json::Value {
+        let wait = async {
+            loop {
+                let frame = self
+                    .framed
+                    .next()
+                    .await
+                    .expect("connection closed")
+                    .expect("protocol error");
+                let Frame::Text(text) = frame else {
+                    continue;
+                };
+                let mut value: serde_json::Value =
+                    serde_json::from_slice(&text).expect("server sent invalid JSON");
+                if let Some(body) = value.get_mut(kind) {
+                    return body.take();
+                }
+            }
+        };
+        tokio::time::timeout(RECV_TIMEOUT, wait)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {}", kind))
+    }
+}
+
+#[actix_web::test]
+async fn profile_and_player_list_over_the_wire() {
+    let server = Server::start().await;
+    let mut amara = server.connect_as("amara").await;
+    amara
+        .send(serde_json::json!({ "type": "getProfile" }))
+        .await;
+    let profile = amara.recv("profile").await;
+    assert_eq!(profile["username"], "amara");
+    assert_eq!(profile["pvp_level"], 1);
+
+    let _kofi = server.connect_as("kofi").await;
+    amara
+        .send(serde_json::json!({ "type": "listPlayers" }))
+        .await;
+    let players = amara.recv("playerList").await["players"].clone();
+    assert_eq!(players.as_array().unwrap().len(), 1);
+    assert_eq!(players[0]["username"], "kofi");
+}
+
+#[actix_web::test]
+async fn purchases_survive_a_reconnect() {
+    let server = Server::start().await;
+    let mut amara = server.connect_as("amara").await;
+    amara
+        .send(serde_json::json!({ "type": "purchase", "item_id": "land-1", "category": "Land" }))
+        .await;
+    amara.recv("purchaseAck").await;
+    drop(amara);
+
+    let mut amara = server.connect_as("amara").await;
+    amara
+        .send(serde_json::json!({ "type": "getProfile" }))
+        .await;
+    let profile = amara.recv("profile").await;
+    assert_eq!(profile["properties"][0]["category"], "Land");
+}